@@ -6,18 +6,35 @@ extern crate rustc_serialize;
 
 use docopt::Docopt;
 use dream::beam::Beam;
+use dream::etf::Term;
+use dream::profile::Profile;
+use dream::{ replay, transform };
+use std::fs::File;
+use std::io::Write;
 use std::path::Path;
+use std::sync::Mutex;
 
 static USAGE: &'static str = "
 Interactive dream
 
 Usage:
-    idream <command> <args>...
+    idream [--schedulers=<n>] [--seed=<n>] [--replay=<file>] [--record=<file>]
+           [--opcode-profile=<file>] [--fuse=<list>] <command> <args>...
     idream [options]
 
 Options:
-    -h, --help      Display this message
-    --list          List installed commands
+    -h, --help          Display this message
+    --list              List installed commands
+    --schedulers=<n>    Number of scheduler threads (default: number of CPUs)
+    --seed=<n>          Schedule deterministically, driven by seed <n>
+    --replay=<file>     Schedule deterministically, replaying <file>
+    --record=<file>     Record the scheduling choices of a --seed or --replay
+                        run to <file>
+    --opcode-profile=<file>
+                        Write the most frequent opcode pairs and triples
+                        executed to <file>
+    --fuse=<list>       Superinstructions to fuse at load time, comma
+                        separated, e.g. move+move (default: all), or none
 ";
 
 #[derive(Debug, RustcDecodable)]
@@ -25,13 +42,20 @@ struct Args {
     arg_command: Option<Command>,
     arg_args: Vec<String>,
     flag_help: bool,
-    flag_list: bool
+    flag_list: bool,
+    flag_schedulers: Option<usize>,
+    flag_seed: Option<u64>,
+    flag_replay: Option<String>,
+    flag_record: Option<String>,
+    flag_opcode_profile: Option<String>,
+    flag_fuse: Option<String>
 }
 
 #[derive(Debug, RustcDecodable)]
 enum Command {
     Module,
-    RTS
+    RTS,
+    Run
 }
 
 #[derive(Debug, RustcDecodable)]
@@ -45,12 +69,14 @@ fn main() {
                             .and_then(|d| d.decode())
                             .unwrap_or_else(|e| e.exit());
     // Either some command is specified...
-    if let Some(command) = args.arg_command {
-        match command {
+    if let Some (ref command) = args.arg_command {
+        match *command {
             Command::Module =>
                 dispatch_module(&args.arg_args[0], &args.arg_args[1..]),
             Command::RTS =>
-                dispatch_rts(&args.arg_args[0], &args.arg_args[1..])
+                dispatch_rts(&args.arg_args[0], &args.arg_args[1..]),
+            Command::Run =>
+                run(&args.arg_args, &args)
         }
     }
     // ..or there are options to handle.
@@ -72,12 +98,103 @@ fn dispatch_module(subcommand: &str, args: &[String]) {
         "code-chunk" => print_code(args),
         "code-labels" => print_labels(args),
         "code-replaced" => print_replaced(args),
+        "docs" => print_docs(args),
+        "chunks" => print_chunks(args),
+        "types" => print_types(args),
         _ => panic!(format!("unrecognized module subcommand: {:?}", subcommand))
     }
 }
 
-fn dispatch_rts(_subcommand: &str, _args: &[String]) {
-    panic!("not implemented yet");
+fn dispatch_rts(subcommand: &str, args: &[String]) {
+    match subcommand {
+        "bifs" => list_bifs(args),
+        _ => panic!(format!("unrecognized rts subcommand: {:?}", subcommand))
+    }
+}
+
+fn list_bifs(_args: &[String]) {
+    let emu = dream::Emu::new();
+    let mut bifs: Vec<String> = emu.bifs.list().iter()
+                                   .map(|&mfa| format_mfa(&emu.atoms, mfa))
+                                   .collect();
+    bifs.sort();
+    for bif in bifs.iter()
+        { println!("{}", bif) }
+}
+
+fn format_mfa(atoms: &dream::AtomTable, (m, f, a): dream::exports::MFA) -> String {
+    let atom = |index| dream::etf::Term::Atom(atoms.get_atom(index).unwrap());
+    format!("{}:{}/{}", atom(m), atom(f), a)
+}
+
+fn cpus() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+// run <beam> <function> <args>...
+// Arguments are integers or atoms.
+fn run(args: &[String], options: &Args) {
+    let arg0 = args[0].to_string();
+    let path = Path::new(&arg0);
+    let module = module_name(&path).unwrap();
+    let mut emu = dream::Emu::new();
+    emu.set_schedulers(options.flag_schedulers.unwrap_or_else(cpus));
+    if let Some (ref file) = options.flag_replay {
+        let choices = replay::load(Path::new(file)).unwrap_or_else(|e| panic!("{}: {}", file, e));
+        emu.set_chooser(replay::Chooser::from_choices(choices));
+    } else if let Some (seed) = options.flag_seed {
+        emu.set_chooser(replay::Chooser::from_seed(seed));
+    } else if options.flag_record.is_some() {
+        panic!("--record needs --seed or --replay: only deterministic runs make choices")
+    }
+    if let Some (ref list) = options.flag_fuse
+        { emu.superinstructions = parse_superinstructions(list) }
+    if options.flag_opcode_profile.is_some()
+        { emu.opcode_profile = Some (Mutex::new(Profile::new())) }
+    emu.load_module(&path).unwrap_or_else(|e| panic!(e));
+    let call_args: Vec<Term> = args[2..].iter()
+                                        .map(|arg| parse_arg(arg))
+                                        .collect();
+    let result = emu.call(module, &args[1], &call_args);
+    if let Some (divergence) = emu.replay_divergence() {
+        println!("error: replay diverged: {}", divergence);
+        std::process::exit(1)
+    }
+    if let Some (ref file) = options.flag_record {
+        replay::save(Path::new(file), &emu.recorded_choices())
+            .unwrap_or_else(|e| panic!("{}: {}", file, e));
+    }
+    if let Some (ref file) = options.flag_opcode_profile {
+        let report = emu.opcode_profile.take().unwrap().into_inner().unwrap().report(40);
+        File::create(file).and_then(|mut f| f.write_all(report.as_bytes()))
+                          .unwrap_or_else(|e| panic!("{}: {}", file, e));
+    }
+    match result {
+        Ok (result) => println!("{}", result),
+        Err (reason) => {
+            println!("error: {}", reason);
+            std::process::exit(1)
+        }
+    }
+}
+
+// Superinstruction names, as in `--fuse=is_nonempty_list+get_list,move+move`.
+fn parse_superinstructions(list: &str) -> Vec<String> {
+    if list == "none"
+        { return vec![] }
+    let names: Vec<String> = list.split(',').map(|name| name.to_string()).collect();
+    if let Some (name) = transform::unknown_superinstruction(&names) {
+        panic!("unknown superinstruction {} (known: {})",
+               name, transform::superinstructions().join(", "))
+    }
+    names
+}
+
+fn parse_arg(arg: &str) -> Term {
+    match arg.parse::<i64>() {
+        Ok (i) => Term::Integer(i),
+        Err (_) => Term::Atom(arg.to_string())
+    }
 }
 
 fn list_module_atoms(args: &[String]) {
@@ -127,7 +244,7 @@ fn print_code(args: &[String]) {
     let code_chunk = dream::code::CodeChunk::from_chunk(&raw_code_chunk).unwrap();
     println!("{}{}",
              format_code_metadata(&code_chunk),
-             format_code(&code_chunk.code));
+             format_code(&code_chunk.code, |_| 1));
 }
 
 fn print_labels(args: &[String]) {
@@ -144,11 +261,126 @@ fn print_replaced(args: &[String]) {
     let arg0 = args[0].to_string();
     let path = Path::new(&arg0);
     let mut loader = dream::loader::State::new(path).unwrap();
+    dream::loader::load_atoms(&mut loader);
     dream::loader::load_code(&mut loader);
+    dream::loader::load_literals(&mut loader);
+    dream::loader::load_imports(&mut loader);
+    dream::loader::load_types(&mut loader);
+    dream::loader::transform_code(&mut loader, &dream::transform::superinstructions());
     dream::loader::load_labels(&mut loader);
     dream::loader::replace_jumps(&mut loader);
     let code = loader.code.unwrap();
-    println!("{}", format_code(&code));
+    println!("{}", format_code(&code, dream::loader::linked_size));
+}
+
+fn print_docs(args: &[String]) {
+    let arg0 = args[0].to_string();
+    let path = Path::new(&arg0);
+    let beam = Beam::from_file(path).unwrap();
+    let docs_chunk = beam.chunk("Docs").expect("no Docs chunk");
+    let docs = dream::docs::Docs::from_chunk(docs_chunk).unwrap();
+    match args.get(1) {
+        None => {
+            let module = module_name(&path).unwrap();
+            print!("{}", format_module_doc(module, &docs));
+            for entry in docs.entries.iter()
+                { print!("\n{}", format_doc_entry(entry, &docs.format)) }
+        },
+        Some (function) => {
+            let (name, arity) = parse_function_spec(function);
+            let entries = docs.find(name, arity);
+            if entries.is_empty()
+                { panic!(format!("no docs for {:?}", function)) }
+            for (i, entry) in entries.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "\n" };
+                print!("{}{}", sep, format_doc_entry(entry, &docs.format));
+            }
+        }
+    }
+}
+
+// Parse `name/arity` or just `name`.
+fn parse_function_spec(spec: &str) -> (&str, Option<u32>) {
+    match spec.rfind('/') {
+        Some (i) => (&spec[..i],
+                     Some (spec[i+1..].parse().expect("invalid arity"))),
+        None => (spec, None)
+    }
+}
+
+fn format_module_doc(module: &str, docs: &dream::docs::Docs) -> String {
+    let format = &docs.format;
+    let mut s = String::new();
+    s.push_str(&format!("\x1b[1m{}\x1b[0m\n\n", module));
+    match docs.module_doc {
+        dream::docs::Doc::Hidden => s.push_str("(hidden)\n"),
+        dream::docs::Doc::Raw(_) => s.push_str(&format!("(documentation in {} format)\n", format)),
+        _ => match docs.module_doc.text() {
+            Some (text) => s.push_str(&dream::docs::render_markdown(text)),
+            None => s.push_str("(no documentation)\n")
+        }
+    }
+    s
+}
+
+fn format_doc_entry(entry: &dream::docs::Entry, format: &str) -> String {
+    let mut s = String::new();
+    s.push_str(&format!("\x1b[1m{} {}/{}\x1b[0m\n",
+                        entry.kind, entry.name, entry.arity));
+    for signature in entry.signature.iter()
+        { s.push_str(&format!("  {}\n", signature)) }
+    let ref metadata = entry.metadata;
+    for key in ["since", "deprecated"].iter() {
+        if let Some (value) = dream::docs::metadata_value(metadata, key)
+                                  .and_then(|v| v.as_string()) {
+            s.push_str(&format!("  ({}: {})\n", key, value));
+        }
+    }
+    s.push('\n');
+    match entry.doc {
+        dream::docs::Doc::Hidden => s.push_str("(hidden)\n"),
+        dream::docs::Doc::Raw(_) => s.push_str(&format!("(documentation in {} format)\n", format)),
+        _ => match entry.doc.text() {
+            Some (text) => s.push_str(&dream::docs::render_markdown(text)),
+            None => s.push_str("(no documentation)\n")
+        }
+    }
+    s
+}
+
+fn print_chunks(args: &[String]) {
+    let arg0 = args[0].to_string();
+    let path = Path::new(&arg0);
+    let beam = Beam::from_file(path).unwrap();
+    for chunk in beam.chunks() {
+        println!("{} {:6} {}", chunk.id, chunk.len, format_chunk_data(&chunk.decode()));
+    }
+}
+
+fn format_chunk_data(data: &dream::beam::ChunkData) -> String {
+    match data {
+        &dream::beam::ChunkData::Types(ref table) =>
+            format!("types v{}: {} entries", table.version, table.types.len()),
+        &dream::beam::ChunkData::Checker(ref checker) =>
+            format!("{}: {} exports", checker.version, checker.exports().len()),
+        &dream::beam::ChunkData::Term(ref term) =>
+            format!("{}", term),
+        &dream::beam::ChunkData::Raw(_) =>
+            "(raw)".to_string()
+    }
+}
+
+fn print_types(args: &[String]) {
+    let arg0 = args[0].to_string();
+    let path = Path::new(&arg0);
+    let beam = Beam::from_file(path).unwrap();
+    let types_chunk = beam.chunk("Type").expect("no Type chunk");
+    let table = dream::beam::TypeTable::from_chunk(types_chunk)
+                                      .expect("unsupported Type chunk");
+    println!("version {}", table.version);
+    for (i, t) in table.types.iter().enumerate() {
+        println!("{:5} {}", i, t);
+    }
 }
 
 fn format_code_metadata(code_chunk: &dream::code::CodeChunk) -> String {
@@ -164,21 +396,33 @@ fn format_code_metadata(code_chunk: &dream::code::CodeChunk) -> String {
     s
 }
 
-fn format_code(code: &Vec<dream::code::Op>) -> String {
+// Instructions are numbered by adding up their sizes: 1 for instruction
+// indices, or their size in the code stream for offsets.
+fn format_code(code: &Vec<dream::code::Op>, size: fn(&dream::code::Op) -> usize) -> String {
     let mut s = String::new();
+    let mut offset = 0;
     for (i, op) in code.iter().enumerate() {
         let sep = if i == 0 { "" } else { "\n" };
-        s.push_str(&format!("{}  {:5} {} {:?}",
-                            sep, i, op.name(), op.args))
+        // `select_val` and `select_tuple_arity` lists as decoded for dispatch.
+        let args = match dream::code::JumpTable::from_op(op) {
+            Some (table) => format!("{:?} {}", &op.args[.. 2], table),
+            None => format!("{:?}", op.args)
+        };
+        s.push_str(&format!("{}  {:5} {} {}",
+                            sep, offset, op.name(), args));
+        offset += size(op);
     }
     s
 }
 
-fn format_labels(labels: &Vec<(dream::Label, dream::CodeIdx)>) -> String {
+fn format_labels(labels: &Vec<Option<dream::CodeIdx>>) -> String {
     let mut s = String::new();
-    for (i, &(label, idx)) in labels.iter().enumerate() {
+    for (i, offset) in labels.iter().enumerate() {
         let sep = if i == 0 { "" } else { "\n" };
-        s.push_str(&format!("{}label {:4} -> code index {:5}", sep, label, idx));
+        match *offset {
+            Some (offset) => s.push_str(&format!("{}label {:4} -> code offset {:5}", sep, i + 1, offset)),
+            None => s.push_str(&format!("{}label {:4} -> undefined", sep, i + 1))
+        }
     }
     s
 }
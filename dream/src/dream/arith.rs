@@ -0,0 +1,266 @@
+// Arithmetic operators: `erlang:'+'/2`, `erlang:'div'/2` and friends.
+//
+// Small integers are used as long as results fit in a machine word and
+// are transparently promoted to bignums otherwise.  Mixing integers with
+// floats gives a float.  Like on the BEAM, non-numeric arguments, division
+// by zero and non-finite float results raise `badarith`.
+
+use bif::{ BifResult, Error };
+use bignum::BigInt;
+use heap::Heap;
+use term::{ Number, Term };
+
+// Largest bignum we're willing to build by shifting, in bits.
+const MAX_SHIFT: i64 = 1 << 24;
+
+pub fn plus(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    arith(heap, a, b, i64::checked_add, BigInt::add, |x, y| x + y)
+}
+
+pub fn minus(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    arith(heap, a, b, i64::checked_sub, BigInt::sub, |x, y| x - y)
+}
+
+pub fn times(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    arith(heap, a, b, i64::checked_mul, BigInt::mul, |x, y| x * y)
+}
+
+// `/` always returns a float.
+pub fn divide(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    let (x, y) = try!( numbers(heap, a, b) );
+    let divisor = y.to_f64();
+    if divisor == 0.0
+        { return Err (Error::Badarith) }
+    float_result(heap, x.to_f64() / divisor)
+}
+
+pub fn int_div(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    let (x, y) = try!( integers(heap, a, b) );
+    match (&x, &y) {
+        (_, &Number::Small(0)) => Err (Error::Badarith),
+        (&Number::Small(i), &Number::Small(j)) if i.checked_div(j).is_some() =>
+            Ok (Term::Small(i / j)),
+        _ => {
+            let (q, _) = try!( x.to_big().div_rem(&y.to_big()).ok_or(Error::Badarith) );
+            Ok (Term::integer(heap, &q))
+        }
+    }
+}
+
+pub fn int_rem(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    let (x, y) = try!( integers(heap, a, b) );
+    match (&x, &y) {
+        (_, &Number::Small(0)) => Err (Error::Badarith),
+        (&Number::Small(i), &Number::Small(j)) =>
+            Ok (Term::Small(i.wrapping_rem(j))),
+        _ => {
+            let (_, r) = try!( x.to_big().div_rem(&y.to_big()).ok_or(Error::Badarith) );
+            Ok (Term::integer(heap, &r))
+        }
+    }
+}
+
+pub fn band(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    bitwise(heap, a, b, |i, j| i & j, BigInt::bitand)
+}
+
+pub fn bor(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    bitwise(heap, a, b, |i, j| i | j, BigInt::bitor)
+}
+
+pub fn bxor(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    bitwise(heap, a, b, |i, j| i ^ j, BigInt::bitxor)
+}
+
+pub fn bsl(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    let (x, y) = try!( integers(heap, a, b) );
+    shift(heap, x, y, false)
+}
+
+pub fn bsr(heap: &mut Heap, a: Term, b: Term) -> BifResult {
+    let (x, y) = try!( integers(heap, a, b) );
+    shift(heap, x, y, true)
+}
+
+pub fn bnot(heap: &mut Heap, a: Term) -> BifResult {
+    match a.number(heap) {
+        Some (Number::Small(i)) => Ok (Term::Small(!i)),
+        Some (Number::Big(b)) => Ok (Term::integer(heap, &b.bitnot())),
+        _ => Err (Error::Badarith)
+    }
+}
+
+// Unary minus.
+pub fn negate(heap: &mut Heap, a: Term) -> BifResult {
+    match a.number(heap) {
+        Some (Number::Small(i)) => match i.checked_neg() {
+            Some (n) => Ok (Term::Small(n)),
+            None => Ok (Term::integer(heap, &BigInt::from_i64(i).neg()))
+        },
+        Some (Number::Big(b)) => Ok (Term::integer(heap, &b.neg())),
+        Some (Number::Float(f)) => Ok (Term::float(heap, -f)),
+        None => Err (Error::Badarith)
+    }
+}
+
+// Unary plus - checks that the argument is a number.
+pub fn unary_plus(heap: &mut Heap, a: Term) -> BifResult {
+    if a.is_number(heap) { Ok (a) } else { Err (Error::Badarith) }
+}
+
+// `abs/1` is a guard BIF, hence `badarg` rather than `badarith`.
+pub fn abs(heap: &mut Heap, a: Term) -> BifResult {
+    match a.number(heap) {
+        Some (Number::Small(i)) if i >= 0 => Ok (a),
+        Some (Number::Small(i)) => match i.checked_neg() {
+            Some (n) => Ok (Term::Small(n)),
+            None => Ok (Term::integer(heap, &BigInt::from_i64(i).abs()))
+        },
+        Some (Number::Big(ref b)) if !b.is_negative() => Ok (a),
+        Some (Number::Big(b)) => Ok (Term::integer(heap, &b.abs())),
+        Some (Number::Float(f)) => Ok (Term::float(heap, f.abs())),
+        None => Err (Error::Badarg)
+    }
+}
+
+fn arith<S, B, F>(heap: &mut Heap, a: Term, b: Term,
+                  small: S, big: B, float: F) -> BifResult
+    where S: Fn(i64, i64) -> Option<i64>,
+          B: Fn(&BigInt, &BigInt) -> BigInt,
+          F: Fn(f64, f64) -> f64
+{
+    if let (Term::Small(i), Term::Small(j)) = (a, b) {
+        if let Some (result) = small(i, j)
+            { return Ok (Term::Small(result)) }
+    }
+    let (x, y) = try!( numbers(heap, a, b) );
+    if x.is_float() || y.is_float() {
+        let (fx, fy) = (x.to_f64(), y.to_f64());
+        // A bignum too big to be represented as a float.
+        if !fx.is_finite() || !fy.is_finite()
+            { return Err (Error::Badarith) }
+        float_result(heap, float(fx, fy))
+    } else {
+        Ok (Term::integer(heap, &big(&x.to_big(), &y.to_big())))
+    }
+}
+
+fn bitwise<S, B>(heap: &mut Heap, a: Term, b: Term, small: S, big: B) -> BifResult
+    where S: Fn(i64, i64) -> i64,
+          B: Fn(&BigInt, &BigInt) -> BigInt
+{
+    match try!( integers(heap, a, b) ) {
+        (Number::Small(i), Number::Small(j)) => Ok (Term::Small(small(i, j))),
+        (x, y) => Ok (Term::integer(heap, &big(&x.to_big(), &y.to_big())))
+    }
+}
+
+fn shift(heap: &mut Heap, x: Number, y: Number, right: bool) -> BifResult {
+    let count = match y {
+        Number::Small(count) => if right { count.saturating_neg() } else { count },
+        // Shifting by a bignum: either everything is shifted out,
+        // or the result is way too big.
+        Number::Big(ref count) => {
+            let left = count.is_negative() == right;
+            if left && x != Number::Small(0)
+                { return Err (Error::SystemLimit) }
+            i64::min_value()
+        },
+        Number::Float(_) => return Err (Error::Badarith)
+    };
+    if count > MAX_SHIFT && x != Number::Small(0)
+        { return Err (Error::SystemLimit) }
+    if let Number::Small(i) = x {
+        if count >= 0 && count < 64 && (i << count) >> count == i
+            { return Ok (Term::Small(i << count)) }
+        if count < 0 && count > -64
+            { return Ok (Term::Small(i >> -count)) }
+        if count <= -64
+            { return Ok (Term::Small(if i < 0 { -1 } else { 0 })) }
+    }
+    let n = x.to_big();
+    let result = if count >= 0 { n.shl(count as usize) }
+                 else { n.shr(count.saturating_neg() as usize) };
+    Ok (Term::integer(heap, &result))
+}
+
+fn numbers(heap: &Heap, a: Term, b: Term) -> Result<(Number, Number), Error> {
+    match (a.number(heap), b.number(heap)) {
+        (Some (x), Some (y)) => Ok ((x, y)),
+        _ => Err (Error::Badarith)
+    }
+}
+
+fn integers(heap: &Heap, a: Term, b: Term) -> Result<(Number, Number), Error> {
+    let (x, y) = try!( numbers(heap, a, b) );
+    if x.is_float() || y.is_float()
+        { return Err (Error::Badarith) }
+    Ok ((x, y))
+}
+
+fn float_result(heap: &mut Heap, f: f64) -> BifResult {
+    if f.is_finite() { Ok (Term::float(heap, f)) } else { Err (Error::Badarith) }
+}
+
+#[cfg(test)]
+fn big(heap: &Heap, t: Term) -> String {
+    match t.number(heap) {
+        Some (Number::Big(b)) => b.to_string(),
+        Some (Number::Small(i)) => i.to_string(),
+        other => panic!("not an integer: {:?}", other)
+    }
+}
+
+#[test]
+fn test_promotion_to_bignum() {
+    let mut heap = Heap::new();
+    let mut acc = Term::Small(1);
+    for i in 1..26
+        { acc = times(&mut heap, acc, Term::Small(i)).unwrap() }
+    assert_eq!("15511210043330985984000000", big(&heap, acc));
+    let fac20 = Term::Small(2432902008176640000);
+    let q = int_div(&mut heap, acc, fac20).unwrap();
+    assert_eq!(Term::Small(6375600), q);
+    let max = Term::Small(i64::max_value());
+    let sum = plus(&mut heap, max, Term::Small(1)).unwrap();
+    assert_eq!("9223372036854775808", big(&heap, sum));
+    assert_eq!(max, minus(&mut heap, sum, Term::Small(1)).unwrap());
+    let min = Term::Small(i64::min_value());
+    let negated = negate(&mut heap, min).unwrap();
+    assert_eq!("9223372036854775808", big(&heap, negated));
+    let absolute = abs(&mut heap, min).unwrap();
+    assert_eq!("9223372036854775808", big(&heap, absolute));
+}
+
+#[test]
+fn test_floats() {
+    let mut heap = Heap::new();
+    let half = divide(&mut heap, Term::Small(1), Term::Small(2)).unwrap();
+    assert_eq!(Some (0.5), half.float_value(&heap));
+    let sum = plus(&mut heap, half, Term::Small(2)).unwrap();
+    assert_eq!(Some (2.5), sum.float_value(&heap));
+    assert_eq!(Err (Error::Badarith), divide(&mut heap, Term::Small(1), Term::Small(0)));
+    let huge = Term::float(&mut heap, 1.0e308);
+    assert_eq!(Err (Error::Badarith), times(&mut heap, huge, Term::Small(10)));
+    assert_eq!(Err (Error::Badarith), int_div(&mut heap, half, Term::Small(1)));
+    assert_eq!(Err (Error::Badarith), band(&mut heap, half, Term::Small(1)));
+}
+
+#[test]
+fn test_integer_ops() {
+    let mut heap = Heap::new();
+    assert_eq!(Ok (Term::Small(-2)), int_div(&mut heap, Term::Small(-7), Term::Small(3)));
+    assert_eq!(Ok (Term::Small(-1)), int_rem(&mut heap, Term::Small(-7), Term::Small(3)));
+    assert_eq!(Err (Error::Badarith), int_rem(&mut heap, Term::Small(1), Term::Small(0)));
+    assert_eq!(Ok (Term::Small(-2)), bsr(&mut heap, Term::Small(-7), Term::Small(2)));
+    assert_eq!(Ok (Term::Small(-1)), bsr(&mut heap, Term::Small(-7), Term::Small(100)));
+    assert_eq!(Ok (Term::Small(-6)), bnot(&mut heap, Term::Small(5)));
+    let two_100 = bsl(&mut heap, Term::Small(1), Term::Small(100)).unwrap();
+    assert_eq!("1267650600228229401496703205376", big(&heap, two_100));
+    assert_eq!(Ok (Term::Small(1)), bsr(&mut heap, two_100, Term::Small(100)));
+    let masked = band(&mut heap, two_100, Term::Small(-2)).unwrap();
+    assert_eq!("1267650600228229401496703205376", big(&heap, masked));
+    assert_eq!(Err (Error::SystemLimit), bsl(&mut heap, Term::Small(1), two_100));
+    assert_eq!(Err (Error::Badarith), plus(&mut heap, Term::Atom(1), Term::Small(1)));
+    assert_eq!(Err (Error::Badarg), abs(&mut heap, Term::Nil));
+}
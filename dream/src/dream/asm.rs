@@ -0,0 +1,302 @@
+// A tiny assembler for test modules.
+//
+// There's no Erlang compiler around when running the tests, so modules
+// exercising the emulator are written in BEAM assembly and turned into
+// .beam files here, in the same format `erlc` produces.
+
+use code::OPERATIONS;
+use etf;
+use std::env;
+use std::fs::{ self, File };
+use std::io::Write;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use super::Emu;
+
+pub enum Arg {
+    U(u32),
+    I(i64),
+    A(&'static str),
+    Nil,
+    X(u32),
+    Y(u32),
+    F(u32),
+    Fr(u32),
+    Lit(etf::Term),
+    List(Vec<Arg>)
+}
+
+pub struct Module {
+    name:           String,
+    atoms:          Vec<String>,
+    code:           Vec<u8>,
+    opcode_max:     u8,
+    next_label:     u32,
+    n_functions:    u32,
+    imports:        Vec<(u32, u32, u32)>,
+    exports:        Vec<(u32, u32, u32)>,
+    literals:       Vec<etf::Term>,
+    strings:        Vec<u8>,
+    // Function, arity, label, index, number of free variables, old unique.
+    funs:           Vec<[u32; 6]>,
+    // Line numbers of `line` instructions, all in the module's own file.
+    lines:          Vec<u32>
+}
+
+const TAG_U: u8 = 0;
+const TAG_I: u8 = 1;
+const TAG_A: u8 = 2;
+const TAG_X: u8 = 3;
+const TAG_Y: u8 = 4;
+const TAG_F: u8 = 5;
+const TAG_Z: u8 = 7;
+
+impl Module {
+
+    pub fn new(name: &str) -> Module {
+        Module { name: name.to_string(),
+                 atoms: vec![name.to_string()],
+                 code: vec![],
+                 opcode_max: 0,
+                 next_label: 1,
+                 n_functions: 0,
+                 imports: vec![],
+                 exports: vec![],
+                 literals: vec![],
+                 strings: vec![],
+                 funs: vec![],
+                 lines: vec![] }
+    }
+
+    pub fn atom(&mut self, name: &str) -> u32 {
+        match self.atoms.iter().position(|a| a == name) {
+            Some (i) => i as u32 + 1,
+            None => {
+                self.atoms.push(name.to_string());
+                self.atoms.len() as u32
+            }
+        }
+    }
+
+    // Reserve a label, to be placed later.
+    pub fn new_label(&mut self) -> u32 {
+        self.next_label += 1;
+        self.next_label - 1
+    }
+
+    pub fn place(&mut self, label: u32) {
+        self.op("label", vec![Arg::U(label)]);
+    }
+
+    pub fn label(&mut self) -> u32 {
+        let label = self.new_label();
+        self.place(label);
+        label
+    }
+
+    // Function header: returns the label of the function's entry point.
+    pub fn function(&mut self, name: &'static str, arity: u32) -> u32 {
+        self.n_functions += 1;
+        self.label();
+        let module = self.name.clone();
+        let m = self.atom(&module);
+        let f = self.atom(name);
+        self.emit("func_info", &[(TAG_A, m as i64), (TAG_A, f as i64), (TAG_U, arity as i64)]);
+        self.label()
+    }
+
+    // A `line` instruction: the code which follows is on line `line`.
+    pub fn line(&mut self, line: u32) {
+        self.lines.push(line);
+        let index = self.lines.len() as u32;
+        self.op("line", vec![Arg::U(index)]);
+    }
+
+    // A string of the bit syntax; returns its offset.
+    pub fn string(&mut self, bytes: &[u8]) -> u32 {
+        self.strings.extend_from_slice(bytes);
+        (self.strings.len() - bytes.len()) as u32
+    }
+
+    pub fn import(&mut self, module: &str, function: &str, arity: u32) -> u32 {
+        let entry = (self.atom(module), self.atom(function), arity);
+        match self.imports.iter().position(|&i| i == entry) {
+            Some (i) => i as u32,
+            None => {
+                self.imports.push(entry);
+                self.imports.len() as u32 - 1
+            }
+        }
+    }
+
+    pub fn export(&mut self, function: &str, arity: u32, label: u32) {
+        let f = self.atom(function);
+        self.exports.push((f, arity, label));
+    }
+
+    // A fun defined by the function at `label`, taking `arity` arguments
+    // followed by `num_free` free variables.  Returns its index.
+    pub fn fun(&mut self, function: &str, arity: u32, label: u32, num_free: u32) -> u32 {
+        let f = self.atom(function);
+        let index = self.funs.len() as u32;
+        self.funs.push([f, arity + num_free, label, index, num_free, 0]);
+        index
+    }
+
+    pub fn op(&mut self, name: &str, args: Vec<Arg>) {
+        let mut out = vec![];
+        for arg in args.iter()
+            { self.encode_arg(&mut out, arg) }
+        let opcode = opcode(name, args.len());
+        self.opcode_max = self.opcode_max.max(opcode);
+        self.code.push(opcode);
+        self.code.extend_from_slice(&out);
+    }
+
+    fn emit(&mut self, name: &str, args: &[(u8, i64)]) {
+        let opcode = opcode(name, args.len());
+        self.opcode_max = self.opcode_max.max(opcode);
+        self.code.push(opcode);
+        for &(tag, value) in args.iter()
+            { encode(&mut self.code, tag, value) }
+    }
+
+    fn encode_arg(&mut self, out: &mut Vec<u8>, arg: &Arg) {
+        match arg {
+            &Arg::U(n) => encode(out, TAG_U, n as i64),
+            &Arg::I(n) => encode(out, TAG_I, n),
+            &Arg::A(name) => {
+                let a = self.atom(name);
+                encode(out, TAG_A, a as i64)
+            },
+            &Arg::Nil => encode(out, TAG_A, 0),
+            &Arg::X(n) => encode(out, TAG_X, n as i64),
+            &Arg::Y(n) => encode(out, TAG_Y, n as i64),
+            &Arg::F(n) => encode(out, TAG_F, n as i64),
+            &Arg::Fr(n) => {
+                out.push(2 << 4 | TAG_Z);
+                encode(out, TAG_U, n as i64)
+            },
+            &Arg::Lit(ref term) => {
+                self.literals.push(term.clone());
+                out.push(4 << 4 | TAG_Z);
+                encode(out, TAG_U, self.literals.len() as i64 - 1)
+            },
+            &Arg::List(ref elements) => {
+                out.push(1 << 4 | TAG_Z);
+                encode(out, TAG_U, elements.len() as i64);
+                for e in elements.iter()
+                    { self.encode_arg(out, e) }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut chunks = vec![];
+        let mut atoms = u32_be(self.atoms.len() as u32);
+        for atom in self.atoms.iter() {
+            atoms.push(atom.len() as u8);
+            atoms.extend_from_slice(atom.as_bytes());
+        }
+        chunks.push(("Atom", atoms));
+        let mut code = vec![];
+        for n in [16, 0, self.opcode_max.max(3) as u32, self.next_label, self.n_functions].iter()
+            { code.extend_from_slice(&u32_be(*n)) }
+        code.extend_from_slice(&self.code);
+        code.push(opcode("int_code_end", 0));
+        chunks.push(("Code", code));
+        chunks.push(("ImpT", table(self.imports.iter().map(|&(m, f, a)| vec![m, f, a]))));
+        chunks.push(("ExpT", table(self.exports.iter().map(|&(f, a, l)| vec![f, a, l]))));
+        let mut literals = u32_be(0);
+        literals.extend_from_slice(&u32_be(self.literals.len() as u32));
+        for literal in self.literals.iter() {
+            let encoded = etf::encode(literal);
+            literals.extend_from_slice(&u32_be(encoded.len() as u32));
+            literals.extend_from_slice(&encoded);
+        }
+        chunks.push(("LitT", literals));
+        chunks.push(("StrT", self.strings.clone()));
+        chunks.push(("FunT", table(self.funs.iter().map(|f| f.to_vec()))));
+        if !self.lines.is_empty() {
+            let n = self.lines.len() as u32;
+            let mut lines = vec![];
+            for field in [0, 0, n, n, 0].iter()
+                { lines.extend_from_slice(&u32_be(*field)) }
+            for &line in self.lines.iter()
+                { encode(&mut lines, TAG_I, line as i64) }
+            chunks.push(("Line", lines));
+        }
+        let mut body = b"BEAM".to_vec();
+        for &(id, ref data) in chunks.iter() {
+            body.extend_from_slice(id.as_bytes());
+            body.extend_from_slice(&u32_be(data.len() as u32));
+            body.extend_from_slice(data);
+            while body.len() % 4 != 0
+                { body.push(0) }
+        }
+        let mut beam = b"FOR1".to_vec();
+        beam.extend_from_slice(&u32_be(body.len() as u32));
+        beam.extend_from_slice(&body);
+        beam
+    }
+
+    // Write the module to a fresh temporary directory and load it.
+    pub fn load(&self, emu: &mut Emu) -> Result<(), String> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!("dream-asm-{}-{}",
+                                               ::std::process::id(),
+                                               COUNTER.fetch_add(1, Ordering::SeqCst)));
+        try!( fs::create_dir_all(&dir).map_err(|e| e.to_string()) );
+        let path = dir.join(format!("{}.beam", self.name));
+        try!( File::create(&path).and_then(|mut f| f.write_all(&self.to_bytes()))
+                                 .map_err(|e| e.to_string()) );
+        let result = emu.load_module(&path);
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+
+}
+
+fn opcode(name: &str, arity: usize) -> u8 {
+    match OPERATIONS.iter().find(|&&(_, (n, _))| n == name) {
+        Some (&(code, (_, a))) if a as usize == arity => code,
+        Some (&(_, (_, a))) => panic!("{} takes {} operands, not {}", name, a, arity),
+        None => panic!("unknown operation {}", name)
+    }
+}
+
+// Compact term encoding of a single operand.
+fn encode(out: &mut Vec<u8>, tag: u8, value: i64) {
+    if value >= 0 && value < 16 {
+        out.push((value as u8) << 4 | tag);
+    } else if value >= 0 && value < 2048 {
+        out.push(((value >> 3) as u8 & 0b1110_0000) | 0b1000 | tag);
+        out.push(value as u8);
+    } else {
+        // Big-endian two's complement, as short as possible (but at least
+        // 2 bytes), preceded by its length.
+        let mut bytes = value.to_be_bytes().to_vec();
+        while bytes.len() > 2 &&
+              ((bytes[0] == 0 && bytes[1] & 0x80 == 0) ||
+               (bytes[0] == 0xff && bytes[1] & 0x80 != 0))
+            { bytes.remove(0); }
+        out.push(((bytes.len() - 2) as u8) << 5 | 0b11000 | tag);
+        out.extend_from_slice(&bytes);
+    }
+}
+
+fn u32_be(n: u32) -> Vec<u8> {
+    n.to_be_bytes().to_vec()
+}
+
+fn table<I: Iterator<Item = Vec<u32>>>(entries: I) -> Vec<u8> {
+    let mut data = vec![];
+    let mut n = 0;
+    for entry in entries {
+        for field in entry.iter()
+            { data.extend_from_slice(&u32_be(*field)) }
+        n += 1;
+    }
+    let mut chunk = u32_be(n);
+    chunk.extend_from_slice(&data);
+    chunk
+}
@@ -1,5 +1,6 @@
 use beam;
 use std::collections::HashMap;
+use std::sync::RwLock;
 
 #[cfg(test)]
 use std::path::Path;
@@ -7,7 +8,12 @@ use std::path::Path;
 pub type AtomIndex = usize;
 pub type Atom = String;
 
+// Shared by all schedulers: atoms can be added by running code.
 pub struct AtomTable {
+    atoms: RwLock<Atoms>
+}
+
+struct Atoms {
     // index to atom
     i_to_a: Vec<Atom>,
     // atom to index
@@ -19,7 +25,7 @@ impl AtomTable {
     pub fn from_chunk(chunk: &beam::Chunk) -> AtomTable {
         let ref data = chunk.data;
         let mut offset = 4;
-        let mut atoms = AtomTable::new();
+        let atoms = AtomTable::new();
         while offset < data.len() {
             let len = u8::from_be(data[offset]) as usize;
             let (from, to) = (offset + 1, offset + 1 + len);
@@ -33,37 +39,34 @@ impl AtomTable {
     pub fn new() -> AtomTable {
         let mut i_to_a = vec![];
         i_to_a.push("".to_string());
-        AtomTable { i_to_a: i_to_a,
-                    a_to_i: HashMap::new() }
+        AtomTable { atoms: RwLock::new(Atoms { i_to_a: i_to_a,
+                                               a_to_i: HashMap::new() }) }
     }
 
     pub fn list(&self) -> Vec<(AtomIndex, Atom)> {
-        self.i_to_a.iter().map(|i| i.clone()).enumerate().skip(1).collect()
+        let atoms = self.atoms.read().unwrap();
+        atoms.i_to_a.iter().map(|i| i.clone()).enumerate().skip(1).collect()
     }
 
-    pub fn add(&mut self, atom: &str) -> AtomIndex {
+    pub fn add(&self, atom: &str) -> AtomIndex {
         if let Some (index) = self.get_index(atom)
-            { index }
-        else {
-            let index = self.i_to_a.len() as AtomIndex;
-            self.i_to_a.push(atom.to_string());
-            self.a_to_i.insert(atom.to_string(), index);
-            index
-        }
+            { return index }
+        let mut atoms = self.atoms.write().unwrap();
+        // Somebody might have added it since we looked.
+        if let Some (&index) = atoms.a_to_i.get(atom)
+            { return index }
+        let index = atoms.i_to_a.len() as AtomIndex;
+        atoms.i_to_a.push(atom.to_string());
+        atoms.a_to_i.insert(atom.to_string(), index);
+        index
     }
 
     pub fn get_atom(&self, index: AtomIndex) -> Option<Atom> {
-        if index < self.i_to_a.len()
-            { Some (self.i_to_a[index].clone()) }
-        else
-            { None }
+        self.atoms.read().unwrap().i_to_a.get(index).cloned()
     }
 
     pub fn get_index(&self, atom: &str) -> Option<AtomIndex> {
-        match self.a_to_i.get(atom) {
-            Some (index) => Some (*index),
-            None => None
-        }
+        self.atoms.read().unwrap().a_to_i.get(atom).cloned()
     }
 
 }
@@ -90,20 +93,20 @@ fn test_atom_table_from_chunk() {
 
 #[test]
 fn add_atom() {
-    let mut atoms = AtomTable::new();
+    let atoms = AtomTable::new();
     assert_eq!(1, atoms.add("atom1"));
 }
 
 #[test]
 fn get_atom_index() {
-    let mut atoms = AtomTable::new();
+    let atoms = AtomTable::new();
     atoms.add("atom1");
     assert_eq!(Some (1), atoms.get_index("atom1"));
 }
 
 #[test]
 fn list_atoms() {
-    let mut atoms = AtomTable::new();
+    let atoms = AtomTable::new();
     atoms.add("atom1");
     atoms.add("atom2");
     assert_eq!(vec![(1, "atom1".to_string()),
@@ -1,3 +1,5 @@
+use etf;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::mem;
@@ -43,6 +45,266 @@ impl Beam {
         self.chunks.iter().find(|&chunk| chunk.id == name)
     }
 
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+}
+
+// Decoded contents of a chunk.
+#[derive(Debug)]
+pub enum ChunkData {
+    // `Type` - argument and register types inferred by the compiler.
+    Types(TypeTable),
+    // `ExCk` - Elixir's type checker information.
+    Checker(Checker),
+    // Chunks which are just `term_to_binary/1` output:
+    // `Attr`, `CInf`, `Dbgi`, `Docs`, `Meta`.
+    Term(etf::Term),
+    // Anything else, or a chunk which failed to decode.
+    Raw(Vec<u8>)
+}
+
+impl Chunk {
+
+    // Never fails - chunks we can't make sense of are returned as raw bytes.
+    pub fn decode(&self) -> ChunkData {
+        let decoded = match &self.id[..] {
+            "Type" => TypeTable::from_chunk(self).map(ChunkData::Types),
+            "ExCk" => Checker::from_chunk(self).map(ChunkData::Checker),
+            "Attr" | "CInf" | "Dbgi" | "Docs" | "Meta" =>
+                etf::decode(&self.data).ok().map(ChunkData::Term),
+            _ => None
+        };
+        decoded.unwrap_or_else(|| ChunkData::Raw(self.data.clone()))
+    }
+
+}
+
+// Type bits, see `beam_types.hrl` in the compiler application.
+pub const TYPE_ATOM: u16            = 1 << 0;
+pub const TYPE_BITSTRING: u16       = 1 << 1;
+pub const TYPE_BS_MATCHSTATE: u16   = 1 << 2;
+pub const TYPE_CONS: u16            = 1 << 3;
+pub const TYPE_FLOAT: u16           = 1 << 4;
+pub const TYPE_FUN: u16             = 1 << 5;
+pub const TYPE_INTEGER: u16         = 1 << 6;
+pub const TYPE_MAP: u16             = 1 << 7;
+pub const TYPE_NIL: u16             = 1 << 8;
+pub const TYPE_PID: u16             = 1 << 9;
+pub const TYPE_PORT: u16            = 1 << 10;
+pub const TYPE_REFERENCE: u16       = 1 << 11;
+pub const TYPE_TUPLE: u16           = 1 << 12;
+pub const TYPE_ANY: u16             = (1 << 13) - 1;
+
+const TYPE_HAS_LOWER_BOUND: u16     = 1 << 13;
+const TYPE_HAS_UPPER_BOUND: u16     = 1 << 14;
+const TYPE_HAS_UNIT: u16            = 1 << 15;
+
+// Type chunk versions we know: OTP 25 wrote 1, later releases added
+// unit information.
+const MAX_TYPES_VERSION: u32 = 3;
+
+const TYPE_NAMES: &'static [(u16, &'static str)] =
+    &[(TYPE_ATOM, "atom"),
+      (TYPE_BITSTRING, "bitstring"),
+      (TYPE_BS_MATCHSTATE, "bs_matchstate"),
+      (TYPE_CONS, "cons"),
+      (TYPE_FLOAT, "float"),
+      (TYPE_FUN, "fun"),
+      (TYPE_INTEGER, "integer"),
+      (TYPE_MAP, "map"),
+      (TYPE_NIL, "nil"),
+      (TYPE_PID, "pid"),
+      (TYPE_PORT, "port"),
+      (TYPE_REFERENCE, "reference"),
+      (TYPE_TUPLE, "tuple")];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Type {
+    // Union of `TYPE_*` bits.
+    pub bits:   u16,
+    // Integer range, if known.
+    pub min:    Option<i64>,
+    pub max:    Option<i64>,
+    // Bitstring unit, if known.
+    pub unit:   Option<u8>
+}
+
+impl Type {
+
+    pub fn any() -> Type {
+        Type { bits: TYPE_ANY, min: None, max: None, unit: None }
+    }
+
+    pub fn is_any(&self) -> bool {
+        self.bits == TYPE_ANY
+    }
+
+    // True if the value is guaranteed to be of the given type(s).
+    pub fn is(&self, bits: u16) -> bool {
+        self.bits & !bits == 0
+    }
+
+    // Integer range fitting in a machine word, if the type is such an integer.
+    pub fn integer_range(&self) -> Option<(i64, i64)> {
+        match (self.is(TYPE_INTEGER), self.min, self.max) {
+            (true, Some (min), Some (max)) => Some ((min, max)),
+            _ => None
+        }
+    }
+
+}
+
+impl fmt::Display for Type {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_any()
+            { return write!(f, "any") }
+        if self.bits == 0
+            { return write!(f, "none") }
+        let mut first = true;
+        for &(bit, name) in TYPE_NAMES.iter() {
+            if self.bits & bit == 0
+                { continue }
+            if !first
+                { try!( write!(f, " | ") ) }
+            first = false;
+            try!( write!(f, "{}", name) );
+            if bit == TYPE_INTEGER && (self.min.is_some() || self.max.is_some()) {
+                let bound = |b: Option<i64>| b.map_or("_".to_string(), |b| b.to_string());
+                try!( write!(f, "({}..{})", bound(self.min), bound(self.max)) );
+            }
+            if bit == TYPE_BITSTRING {
+                if let Some (unit) = self.unit
+                    { try!( write!(f, "(unit:{})", unit) ) }
+            }
+        }
+        Ok (())
+    }
+
+}
+
+#[derive(Debug)]
+pub struct TypeTable {
+    pub version:    u32,
+    pub types:      Vec<Type>
+}
+
+impl TypeTable {
+
+    pub fn from_chunk(chunk: &Chunk) -> Option<TypeTable> {
+        let ref data = chunk.data;
+        if chunk.id != "Type" || data.len() < 8
+            { return None }
+        let version = be_u32(&data[0..4]);
+        let count = be_u32(&data[4..8]) as usize;
+        if version == 0 || version > MAX_TYPES_VERSION
+            { return None }
+        let mut types = vec![];
+        let mut i = 8;
+        while types.len() < count {
+            let (t, read) = match decode_type(&data[i..]) {
+                Some (decoded) => decoded,
+                None => return None
+            };
+            types.push(t);
+            i += read;
+        }
+        Some (TypeTable { version: version, types: types })
+    }
+
+    // Type of a type-tagged operand; unknown indices mean "anything".
+    pub fn get(&self, index: usize) -> Type {
+        self.types.get(index).cloned().unwrap_or(Type::any())
+    }
+
+}
+
+fn decode_type(data: &[u8]) -> Option<(Type, usize)> {
+    if data.len() < 2
+        { return None }
+    let bits = (data[0] as u16) << 8 | data[1] as u16;
+    let mut t = Type { bits: bits & TYPE_ANY, min: None, max: None, unit: None };
+    let mut i = 2;
+    if bits & TYPE_HAS_LOWER_BOUND != 0 {
+        if data.len() < i + 8
+            { return None }
+        t.min = Some (be_u64(&data[i .. i + 8]) as i64);
+        i += 8;
+    }
+    if bits & TYPE_HAS_UPPER_BOUND != 0 {
+        if data.len() < i + 8
+            { return None }
+        t.max = Some (be_u64(&data[i .. i + 8]) as i64);
+        i += 8;
+    }
+    if bits & TYPE_HAS_UNIT != 0 {
+        if data.len() < i + 1
+            { return None }
+        // Stored as `Unit - 1`.
+        t.unit = Some (data[i] + 1);
+        i += 1;
+    }
+    Some ((t, i))
+}
+
+// `ExCk` chunk: `{elixir_checker_v1, Info}` where `Info` is a map with
+// `exports`, `mode` and the like.  Its contents change between Elixir
+// releases, so apart from the version we keep it as a term.
+#[derive(Debug)]
+pub struct Checker {
+    pub version:    String,
+    pub info:       etf::Term
+}
+
+impl Checker {
+
+    pub fn from_chunk(chunk: &Chunk) -> Option<Checker> {
+        let term = match etf::decode(&chunk.data) {
+            Ok (term) => term,
+            Err (_) => return None
+        };
+        let (version, info) = match term.as_tuple() {
+            Some (t) if t.len() == 2 => match t[0].as_atom() {
+                Some (version) => (version.to_string(), t[1].clone()),
+                None => return None
+            },
+            _ => return None
+        };
+        Some (Checker { version: version, info: info })
+    }
+
+    // `{Function, Arity}` and the associated info (`kind`, `deprecated_reason`, ...).
+    pub fn exports(&self) -> Vec<((String, u32), &etf::Term)> {
+        let exports = self.info.as_map()
+            .and_then(|pairs| pairs.iter().find(|&&(ref k, _)| k.as_atom() == Some ("exports")))
+            .and_then(|&(_, ref v)| v.as_list())
+            .unwrap_or(&[]);
+        exports.iter().filter_map(|export| {
+            let pair = match export.as_tuple() {
+                Some (pair) if pair.len() == 2 => pair,
+                _ => return None
+            };
+            let fa = match pair[0].as_tuple() {
+                Some (fa) if fa.len() == 2 => fa,
+                _ => return None
+            };
+            match (fa[0].as_atom(), fa[1].as_integer()) {
+                (Some (f), Some (a)) => Some (((f.to_string(), a as u32), &pair[1])),
+                _ => None
+            }
+        }).collect()
+    }
+
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as u32)
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64)
 }
 
 fn read_file(path: &Path) -> Result<Vec<u8>, String> {
@@ -149,3 +411,47 @@ fn test_loading_beam_from_file() {
         Err (e) => panic!(e)
     }
 }
+
+#[test]
+fn test_decode_type_chunk() {
+    let data = vec![0, 0, 0, 1, 0, 0, 0, 3,
+                    // any
+                    0x1f, 0xff,
+                    // integer(0..10)
+                    0x60, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10,
+                    // atom | nil
+                    0x01, 0x01];
+    let chunk = Chunk { id: "Type".to_string(), len: data.len() as u32, data: data };
+    match chunk.decode() {
+        ChunkData::Types(table) => {
+            assert_eq!(1, table.version);
+            assert!(table.get(0).is_any());
+            assert_eq!(Some ((0, 10)), table.get(1).integer_range());
+            assert_eq!("atom | nil", table.get(2).to_string());
+            assert!(table.get(2).is(TYPE_ATOM | TYPE_NIL));
+            assert!(table.get(7).is_any());
+        },
+        other => panic!("unexpected chunk data: {:?}", other)
+    }
+}
+
+#[test]
+fn test_decode_unknown_chunks_as_raw() {
+    let path = Path::new("../erlang/fac.beam");
+    let beam = Beam::from_file(&path).unwrap();
+    match beam.chunk("Code").unwrap().decode() {
+        ChunkData::Raw(data) => assert_eq!(172, data.len()),
+        other => panic!("unexpected chunk data: {:?}", other)
+    }
+    match beam.chunk("Attr").unwrap().decode() {
+        ChunkData::Term(term) => assert!(term.as_list().is_some()),
+        other => panic!("unexpected chunk data: {:?}", other)
+    }
+    // Unsupported Type chunk version.
+    let data = vec![0, 0, 0, 99, 0, 0, 0, 0];
+    let chunk = Chunk { id: "Type".to_string(), len: 8, data: data };
+    match chunk.decode() {
+        ChunkData::Raw(_) => {},
+        other => panic!("unexpected chunk data: {:?}", other)
+    }
+}
@@ -0,0 +1,357 @@
+// Built-in functions: Erlang functions implemented in Rust.
+//
+// BIFs live in a `BifTable` keyed by MFA in the emulator's atom space.
+// The table is filled with `DEFAULT_BIFS` when the emulator starts and is
+// consulted by the loader when it resolves a module's imports (the `ImpT`
+// chunk): an import found in the table becomes `Import::Bif`, so `bif0/1/2`,
+// `gc_bif1/2/3` and `call_ext*` instructions referring to it call the Rust
+// function directly, without looking it up at run time.
+//
+// To add a native BIF:
+//
+// 1. write a function of type `Bif`; it gets the emulator, the calling
+//    process and exactly as many arguments as its arity and allocates
+//    any compound result on the process heap (`p.heap`),
+// 2. add it to `DEFAULT_BIFS`, or call `Emu::register_bif` from
+//    embedding code.
+//
+// Imports are resolved at load time, so a BIF has to be registered before
+// any module calling it is loaded.
+
+use arith;
+use atoms::AtomTable;
+use binary;
+use compare;
+use exports::MFA;
+use interp::Class;
+use list;
+use map;
+use math;
+use process::{ self, Process };
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use term::Term;
+use tuple;
+use super::Emu;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    Badarg,
+    Badarith,
+    SystemLimit,
+    // exit/1, error/1 and throw/1: an exception of the given class.
+    Exit(Term),
+    Error(Term),
+    Throw(Term),
+    // raise/3: class, reason and stack trace.
+    Raise(Class, Term, Term)
+}
+
+pub type BifResult = Result<Term, Error>;
+
+pub type Bif = fn(&Emu, &mut Process, &[Term]) -> BifResult;
+
+pub struct BifTable {
+    mfa_to_bif: HashMap<MFA, Bif>
+}
+
+impl BifTable {
+
+    pub fn new() -> BifTable {
+        BifTable { mfa_to_bif: HashMap::new() }
+    }
+
+    pub fn put(&mut self, mfa: MFA, bif: Bif) {
+        self.mfa_to_bif.insert(mfa, bif);
+    }
+
+    pub fn get(&self, mfa: MFA) -> Option<Bif> {
+        self.mfa_to_bif.get(&mfa).cloned()
+    }
+
+    pub fn list(&self) -> Vec<MFA> {
+        self.mfa_to_bif.keys().cloned().collect()
+    }
+
+}
+
+// Module, function, arity and implementation of BIFs available at startup.
+pub const DEFAULT_BIFS: &'static [(&'static str, &'static str, usize, Bif)] = &[
+    ("erlang", "+",    2, plus),
+    ("erlang", "-",    2, minus),
+    ("erlang", "*",    2, times),
+    ("erlang", "/",    2, divide),
+    ("erlang", "div",  2, int_div),
+    ("erlang", "rem",  2, int_rem),
+    ("erlang", "band", 2, band),
+    ("erlang", "bor",  2, bor),
+    ("erlang", "bxor", 2, bxor),
+    ("erlang", "bsl",  2, bsl),
+    ("erlang", "bsr",  2, bsr),
+    ("erlang", "bnot", 1, bnot),
+    ("erlang", "-",    1, negate),
+    ("erlang", "+",    1, unary_plus),
+    ("erlang", "abs",  1, abs),
+    ("erlang", "==",   2, eq),
+    ("erlang", "/=",   2, ne),
+    ("erlang", "=:=",  2, eq_exact),
+    ("erlang", "=/=",  2, ne_exact),
+    ("erlang", "<",    2, lt),
+    ("erlang", "=<",   2, le),
+    ("erlang", ">",    2, gt),
+    ("erlang", ">=",   2, ge),
+    ("erlang", "spawn", 1, process::spawn_1),
+    ("erlang", "spawn", 3, process::spawn_3),
+    ("erlang", "spawn_link", 1, process::spawn_link_1),
+    ("erlang", "spawn_link", 3, process::spawn_link_3),
+    ("erlang", "self", 0, process::self_0),
+    ("erlang", "make_ref", 0, process::make_ref_0),
+    ("erlang", "exit", 1, process::exit_1),
+    ("erlang", "error", 1, error_1),
+    ("erlang", "error", 2, error_2),
+    ("erlang", "throw", 1, throw_1),
+    ("erlang", "raise", 3, raise_3),
+    ("erlang", "get_stacktrace", 0, get_stacktrace_0),
+    ("erlang", "function_exported", 3, function_exported_3),
+    ("erlang", "exit", 2, process::exit_2),
+    ("erlang", "link", 1, process::link_1),
+    ("erlang", "unlink", 1, process::unlink_1),
+    ("erlang", "monitor", 2, process::monitor_2),
+    ("erlang", "demonitor", 1, process::demonitor_1),
+    ("erlang", "demonitor", 2, process::demonitor_2),
+    ("erlang", "send", 2, process::send_2),
+    ("erlang", "!", 2, process::send_2),
+    ("erlang", "process_flag", 2, process::process_flag_2),
+    ("erlang", "garbage_collect", 0, process::garbage_collect_0),
+    ("erlang", "garbage_collect", 1, process::garbage_collect_1),
+    ("erlang", "system_flag", 2, process::system_flag_2),
+    ("erlang", "byte_size", 1, binary::byte_size_1),
+    ("erlang", "is_binary", 1, binary::is_binary_1),
+    ("erlang", "is_bitstring", 1, binary::is_bitstring_1),
+    ("erlang", "bit_size", 1, binary::bit_size_1),
+    ("erlang", "binary_part", 3, binary::binary_part_3),
+    ("erlang", "split_binary", 2, binary::split_binary_2),
+    ("erlang", "binary_to_list", 1, binary::binary_to_list_1),
+    ("erlang", "list_to_binary", 1, binary::list_to_binary_1),
+    ("erlang", "memory", 1, binary::memory_1),
+    ("erlang", "is_map", 1, map::is_map_1),
+    ("erlang", "map_size", 1, map::size_1),
+    ("erlang", "map_get", 2, map::get_2),
+    ("erlang", "is_map_key", 2, map::is_key_2),
+    ("maps", "get", 2, map::get_2),
+    ("maps", "get", 3, map::get_3),
+    ("maps", "find", 2, map::find_2),
+    ("maps", "is_key", 2, map::is_key_2),
+    ("maps", "put", 3, map::put_3),
+    ("maps", "remove", 2, map::remove_2),
+    ("maps", "size", 1, map::size_1),
+    ("maps", "keys", 1, map::keys_1),
+    ("maps", "values", 1, map::values_1),
+    ("maps", "to_list", 1, map::to_list_1),
+    ("maps", "from_list", 1, map::from_list_1),
+    ("maps", "merge", 2, map::merge_2),
+    ("erlang", "hd", 1, list::hd_1),
+    ("erlang", "tl", 1, list::tl_1),
+    ("erlang", "length", 1, list::length_1),
+    ("erlang", "++", 2, list::append_2),
+    ("erlang", "--", 2, list::subtract_2),
+    ("lists", "reverse", 2, list::reverse_2),
+    ("lists", "keyfind", 3, list::keyfind_3),
+    ("lists", "member", 2, list::member_2),
+    ("erlang", "element", 2, tuple::element_2),
+    ("erlang", "setelement", 3, tuple::setelement_3),
+    ("erlang", "tuple_size", 1, tuple::tuple_size_1),
+    ("erlang", "make_tuple", 2, tuple::make_tuple_2),
+    ("erlang", "list_to_tuple", 1, tuple::list_to_tuple_1),
+    ("erlang", "tuple_to_list", 1, tuple::tuple_to_list_1),
+    ("math", "pi", 0, math::pi_0),
+    ("math", "sqrt", 1, math::sqrt_1),
+    ("math", "exp", 1, math::exp_1),
+    ("math", "log", 1, math::log_1),
+    ("math", "log2", 1, math::log2_1),
+    ("math", "log10", 1, math::log10_1),
+    ("math", "sin", 1, math::sin_1),
+    ("math", "cos", 1, math::cos_1),
+    ("math", "tan", 1, math::tan_1),
+    ("math", "asin", 1, math::asin_1),
+    ("math", "acos", 1, math::acos_1),
+    ("math", "atan", 1, math::atan_1),
+    ("math", "sinh", 1, math::sinh_1),
+    ("math", "cosh", 1, math::cosh_1),
+    ("math", "tanh", 1, math::tanh_1),
+    ("math", "asinh", 1, math::asinh_1),
+    ("math", "acosh", 1, math::acosh_1),
+    ("math", "atanh", 1, math::atanh_1),
+    ("math", "ceil", 1, math::ceil_1),
+    ("math", "floor", 1, math::floor_1),
+    ("math", "pow", 2, math::pow_2),
+    ("math", "atan2", 2, math::atan2_2),
+    ("math", "fmod", 2, math::fmod_2)
+];
+
+pub fn register_defaults(atoms: &AtomTable, bifs: &mut BifTable) {
+    for &(module, function, arity, bif) in DEFAULT_BIFS.iter() {
+        let mfa = (atoms.add(module), atoms.add(function), arity);
+        bifs.put(mfa, bif);
+    }
+}
+
+fn plus(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::plus(&mut p.heap, args[0], args[1])
+}
+
+fn minus(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::minus(&mut p.heap, args[0], args[1])
+}
+
+fn times(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::times(&mut p.heap, args[0], args[1])
+}
+
+fn divide(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::divide(&mut p.heap, args[0], args[1])
+}
+
+fn int_div(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::int_div(&mut p.heap, args[0], args[1])
+}
+
+fn int_rem(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::int_rem(&mut p.heap, args[0], args[1])
+}
+
+fn band(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::band(&mut p.heap, args[0], args[1])
+}
+
+fn bor(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::bor(&mut p.heap, args[0], args[1])
+}
+
+fn bxor(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::bxor(&mut p.heap, args[0], args[1])
+}
+
+fn bsl(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::bsl(&mut p.heap, args[0], args[1])
+}
+
+fn bsr(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::bsr(&mut p.heap, args[0], args[1])
+}
+
+fn bnot(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::bnot(&mut p.heap, args[0])
+}
+
+fn negate(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::negate(&mut p.heap, args[0])
+}
+
+fn unary_plus(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::unary_plus(&mut p.heap, args[0])
+}
+
+fn abs(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    arith::abs(&mut p.heap, args[0])
+}
+
+fn eq(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering == Ordering::Equal))
+}
+
+fn ne(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering != Ordering::Equal))
+}
+
+fn eq_exact(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare_exact(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering == Ordering::Equal))
+}
+
+fn ne_exact(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare_exact(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering != Ordering::Equal))
+}
+
+fn lt(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering == Ordering::Less))
+}
+
+fn le(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering != Ordering::Greater))
+}
+
+fn gt(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering == Ordering::Greater))
+}
+
+fn ge(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let ordering = compare::compare(&p.heap, &emu.atoms, args[0], args[1]);
+    Ok (boolean(emu, ordering != Ordering::Less))
+}
+
+// The `true` or `false` atom.
+// erlang:error/1
+fn error_1(_: &Emu, _: &mut Process, args: &[Term]) -> BifResult {
+    Err (Error::Error(args[0]))
+}
+
+// erlang:error/2; the arguments don't make it to the stack trace.
+fn error_2(_: &Emu, _: &mut Process, args: &[Term]) -> BifResult {
+    Err (Error::Error(args[0]))
+}
+
+// erlang:throw/1
+fn throw_1(_: &Emu, _: &mut Process, args: &[Term]) -> BifResult {
+    Err (Error::Throw(args[0]))
+}
+
+// erlang:raise/3
+fn raise_3(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let class = match args[0] {
+        Term::Atom(a) => emu.atoms.get_atom(a).and_then(|name| Class::from_name(&name)),
+        _ => None
+    };
+    match (class, args[2].list_elements(&p.heap)) {
+        (Some (class), Some (_)) => Err (Error::Raise(class, args[1], args[2])),
+        _ => Err (Error::Badarg)
+    }
+}
+
+// erlang:get_stacktrace/0: the stack trace of the last exception caught.
+fn get_stacktrace_0(_: &Emu, p: &mut Process, _: &[Term]) -> BifResult {
+    Ok (p.stacktrace.map(|(_, stacktrace)| stacktrace).unwrap_or(Term::Nil))
+}
+
+// erlang:function_exported/3: true for loaded Erlang functions only, not
+// for BIFs.
+fn function_exported_3(emu: &Emu, _: &mut Process, args: &[Term]) -> BifResult {
+    match (args[0], args[1], args[2]) {
+        (Term::Atom(m), Term::Atom(f), Term::Small(a)) if a >= 0 =>
+            Ok (boolean(emu, emu.exports.get((m, f, a as usize)).is_some())),
+        _ => Err (Error::Badarg)
+    }
+}
+
+pub fn boolean(emu: &Emu, b: bool) -> Term {
+    Term::Atom(emu.atoms.add(if b { "true" } else { "false" }))
+}
+
+#[test]
+fn test_register_defaults() {
+    let atoms = AtomTable::new();
+    let mut bifs = BifTable::new();
+    register_defaults(&atoms, &mut bifs);
+    let erlang = atoms.get_index("erlang").unwrap();
+    let minus = atoms.get_index("-").unwrap();
+    assert!(bifs.get((erlang, minus, 1)).is_some());
+    assert!(bifs.get((erlang, minus, 2)).is_some());
+    assert!(bifs.get((erlang, minus, 3)).is_none());
+    assert_eq!(DEFAULT_BIFS.len(), bifs.list().len());
+}
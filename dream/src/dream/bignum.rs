@@ -0,0 +1,459 @@
+// Arbitrary precision integers.
+//
+// Just enough of a bignum library to implement Erlang's integer arithmetic:
+// sign-magnitude representation with little-endian base 2^32 digits.
+// Bitwise operations behave as if numbers were in infinite two's complement,
+// which is what Erlang specifies.
+
+use std::cmp::Ordering;
+
+pub type Digit = u32;
+
+const DIGIT_BITS: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BigInt {
+    negative:   bool,
+    // Little-endian, without leading (most significant) zero digits.
+    // Zero is an empty vector and is never negative.
+    digits:     Vec<Digit>
+}
+
+impl BigInt {
+
+    pub fn zero() -> BigInt {
+        BigInt { negative: false, digits: vec![] }
+    }
+
+    pub fn from_i64(i: i64) -> BigInt {
+        let magnitude = i.wrapping_abs() as u64;
+        BigInt::from_parts(i < 0, vec![magnitude as Digit, (magnitude >> 32) as Digit])
+    }
+
+    pub fn from_parts(negative: bool, digits: Vec<Digit>) -> BigInt {
+        let mut n = BigInt { negative: negative, digits: digits };
+        n.normalize();
+        n
+    }
+
+    // Little-endian base 256 magnitude, as found in the external term format.
+    pub fn from_bytes_le(negative: bool, bytes: &[u8]) -> BigInt {
+        let digits = bytes.chunks(4)
+                          .map(|c| c.iter().rev().fold(0, |acc, &b| acc << 8 | b as Digit))
+                          .collect();
+        BigInt::from_parts(negative, digits)
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn digits(&self) -> &[Digit] {
+        &self.digits
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2
+            { return None }
+        let magnitude = self.digits.iter().rev()
+                                   .fold(0u64, |acc, &d| acc << 32 | d as u64);
+        if !self.negative && magnitude <= i64::max_value() as u64
+            { Some (magnitude as i64) }
+        else if self.negative && magnitude <= 1 << 63
+            { Some ((magnitude as i64).wrapping_neg()) }
+        else
+            { None }
+    }
+
+    // Nearest float; infinite if out of range.
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self.digits.iter().rev()
+                                   .fold(0f64, |acc, &d| acc * 4294967296.0 + d as f64);
+        if self.negative { -magnitude } else { magnitude }
+    }
+
+    // Integer part of a finite float.
+    pub fn from_f64(f: f64) -> BigInt {
+        let negative = f < 0.0;
+        let mut magnitude = f.abs().trunc();
+        let mut digits = vec![];
+        while magnitude >= 1.0 {
+            let d = magnitude % 4294967296.0;
+            digits.push(d as Digit);
+            magnitude = ((magnitude - d) / 4294967296.0).trunc();
+        }
+        BigInt::from_parts(negative, digits)
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.digits.clone())
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::from_parts(false, self.digits.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            BigInt::from_parts(self.negative, add_magnitudes(&self.digits, &other.digits))
+        } else {
+            match cmp_magnitudes(&self.digits, &other.digits) {
+                Ordering::Less =>
+                    BigInt::from_parts(other.negative,
+                                       sub_magnitudes(&other.digits, &self.digits)),
+                _ =>
+                    BigInt::from_parts(self.negative,
+                                       sub_magnitudes(&self.digits, &other.digits))
+            }
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        let mut product = vec![0; self.digits.len() + other.digits.len()];
+        for (i, &a) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.digits.iter().enumerate() {
+                let acc = product[i + j] as u64 + a as u64 * b as u64 + carry;
+                product[i + j] = acc as Digit;
+                carry = acc >> DIGIT_BITS;
+            }
+            product[i + other.digits.len()] = carry as Digit;
+        }
+        BigInt::from_parts(self.negative != other.negative, product)
+    }
+
+    // Truncating division, like Erlang's `div` and `rem`:
+    // the quotient is rounded towards zero and the remainder
+    // has the sign of the dividend.
+    pub fn div_rem(&self, divisor: &BigInt) -> Option<(BigInt, BigInt)> {
+        if divisor.is_zero()
+            { return None }
+        let (q, r) = divmod_magnitudes(&self.digits, &divisor.digits);
+        Some ((BigInt::from_parts(self.negative != divisor.negative, q),
+               BigInt::from_parts(self.negative, r)))
+    }
+
+    pub fn shl(&self, bits: usize) -> BigInt {
+        let (words, bits) = (bits / DIGIT_BITS, bits % DIGIT_BITS);
+        let mut digits = vec![0; words];
+        let mut carry = 0;
+        for &d in self.digits.iter() {
+            if bits == 0 {
+                digits.push(d);
+            } else {
+                digits.push(d << bits | carry);
+                carry = d >> (DIGIT_BITS - bits);
+            }
+        }
+        digits.push(carry);
+        BigInt::from_parts(self.negative, digits)
+    }
+
+    // Arithmetic shift, i.e. rounds towards negative infinity.
+    pub fn shr(&self, bits: usize) -> BigInt {
+        if self.negative {
+            // -((|x| - 1) >> n) - 1
+            let one = BigInt::from_i64(1);
+            return self.abs().sub(&one).shr(bits).neg().sub(&one)
+        }
+        let (words, bits) = (bits / DIGIT_BITS, bits % DIGIT_BITS);
+        if words >= self.digits.len()
+            { return BigInt::zero() }
+        let src = &self.digits[words..];
+        let mut digits = Vec::with_capacity(src.len());
+        for (i, &d) in src.iter().enumerate() {
+            let high = if bits == 0 { 0 }
+                       else { src.get(i + 1).map_or(0, |&h| h << (DIGIT_BITS - bits)) };
+            digits.push(d >> bits | high);
+        }
+        BigInt::from_parts(false, digits)
+    }
+
+    pub fn bitand(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a & b)
+    }
+
+    pub fn bitor(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a | b)
+    }
+
+    pub fn bitxor(&self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+
+    // bnot X == -X - 1
+    pub fn bitnot(&self) -> BigInt {
+        self.neg().sub(&BigInt::from_i64(1))
+    }
+
+    fn bitwise<F>(&self, other: &BigInt, op: F) -> BigInt where F: Fn(Digit, Digit) -> Digit {
+        // One extra digit for the sign.
+        let len = ::std::cmp::max(self.digits.len(), other.digits.len()) + 1;
+        let a = self.to_twos_complement(len);
+        let b = other.to_twos_complement(len);
+        let result: Vec<Digit> = a.iter().zip(b.iter()).map(|(&a, &b)| op(a, b)).collect();
+        BigInt::from_twos_complement(result)
+    }
+
+    fn to_twos_complement(&self, len: usize) -> Vec<Digit> {
+        let mut digits = self.digits.clone();
+        digits.resize(len, 0);
+        if self.negative {
+            let mut carry = 1u64;
+            for d in digits.iter_mut() {
+                let acc = (!*d) as u64 + carry;
+                *d = acc as Digit;
+                carry = acc >> DIGIT_BITS;
+            }
+        }
+        digits
+    }
+
+    fn from_twos_complement(digits: Vec<Digit>) -> BigInt {
+        let negative = digits.last().map_or(false, |&d| d >> (DIGIT_BITS - 1) == 1);
+        if !negative
+            { return BigInt::from_parts(false, digits) }
+        let mut magnitude = digits;
+        let mut carry = 1u64;
+        for d in magnitude.iter_mut() {
+            let acc = (!*d) as u64 + carry;
+            *d = acc as Digit;
+            carry = acc >> DIGIT_BITS;
+        }
+        BigInt::from_parts(true, magnitude)
+    }
+
+    fn normalize(&mut self) {
+        while self.digits.last() == Some (&0)
+            { self.digits.pop(); }
+        if self.digits.is_empty()
+            { self.negative = false }
+    }
+
+}
+
+impl Ord for BigInt {
+
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitudes(&self.digits, &other.digits),
+            (true, true) => cmp_magnitudes(&other.digits, &self.digits)
+        }
+    }
+
+}
+
+impl PartialOrd for BigInt {
+
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some (self.cmp(other))
+    }
+
+}
+
+impl ::std::fmt::Display for BigInt {
+
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        if self.is_zero()
+            { return write!(f, "0") }
+        // Peel off 9 decimal digits at a time.
+        let billion = vec![1000000000];
+        let mut chunks = vec![];
+        let mut n = self.digits.clone();
+        while !n.is_empty() {
+            let (mut q, r) = divmod_magnitudes(&n, &billion);
+            chunks.push(r.first().cloned().unwrap_or(0));
+            while q.last() == Some (&0)
+                { q.pop(); }
+            n = q;
+        }
+        let mut s = String::new();
+        if self.negative
+            { s.push('-') }
+        s.push_str(&chunks.pop().unwrap().to_string());
+        while let Some (chunk) = chunks.pop()
+            { s.push_str(&format!("{:09}", chunk)) }
+        write!(f, "{}", s)
+    }
+
+}
+
+fn cmp_magnitudes(a: &[Digit], b: &[Digit]) -> Ordering {
+    a.len().cmp(&b.len())
+           .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[Digit], b: &[Digit]) -> Vec<Digit> {
+    let len = ::std::cmp::max(a.len(), b.len());
+    let mut sum = Vec::with_capacity(len + 1);
+    let mut carry = 0u64;
+    for i in 0..len {
+        let acc = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(acc as Digit);
+        carry = acc >> DIGIT_BITS;
+    }
+    sum.push(carry as Digit);
+    sum
+}
+
+// Requires `a >= b`.
+fn sub_magnitudes(a: &[Digit], b: &[Digit]) -> Vec<Digit> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for i in 0..a.len() {
+        let mut acc = a[i] as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = if acc < 0 { acc += 1 << DIGIT_BITS; 1 } else { 0 };
+        difference.push(acc as Digit);
+    }
+    difference
+}
+
+// Long division: a digit at a time for single digit divisors, otherwise
+// Knuth's algorithm D (The Art of Computer Programming, vol. 2, 4.3.1),
+// which estimates each quotient digit from the leading digits and
+// corrects it at most twice.
+fn divmod_magnitudes(a: &[Digit], b: &[Digit]) -> (Vec<Digit>, Vec<Digit>) {
+    if b.len() == 1 {
+        let divisor = b[0] as u64;
+        let mut quotient = vec![0; a.len()];
+        let mut rem = 0u64;
+        for i in (0..a.len()).rev() {
+            let acc = rem << DIGIT_BITS | a[i] as u64;
+            quotient[i] = (acc / divisor) as Digit;
+            rem = acc % divisor;
+        }
+        return (quotient, vec![rem as Digit])
+    }
+    if cmp_magnitudes(a, b) == Ordering::Less
+        { return (vec![], a.to_vec()) }
+    let base = 1u64 << DIGIT_BITS;
+    let (n, m) = (b.len(), a.len() - b.len());
+    // Normalize, so that the divisor's top digit has its top bit set.
+    let shift = b[n - 1].leading_zeros();
+    let v = shl_digits(b, shift);
+    let mut u = shl_digits(a, shift);
+    u.resize(a.len() + 1, 0);
+    let mut quotient = vec![0; m + 1];
+    for j in (0 .. m + 1).rev() {
+        let top = (u[j + n] as u64) << DIGIT_BITS | u[j + n - 1] as u64;
+        let mut qhat = top / v[n - 1] as u64;
+        let mut rhat = top % v[n - 1] as u64;
+        while qhat >= base || qhat * v[n - 2] as u64 > (rhat << DIGIT_BITS | u[j + n - 2] as u64) {
+            qhat -= 1;
+            rhat += v[n - 1] as u64;
+            if rhat >= base
+                { break }
+        }
+        // u[j ..= j + n] -= qhat * v
+        let (mut borrow, mut carry) = (0i64, 0u64);
+        for i in 0..n {
+            let product = qhat * v[i] as u64 + carry;
+            carry = product >> DIGIT_BITS;
+            let acc = u[i + j] as i64 - borrow - (product as Digit) as i64;
+            u[i + j] = acc as Digit;
+            borrow = if acc < 0 { 1 } else { 0 };
+        }
+        let acc = u[j + n] as i64 - borrow - carry as i64;
+        u[j + n] = acc as Digit;
+        if acc < 0 {
+            // qhat was one too big: add v back.
+            qhat -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let sum = u[i + j] as u64 + v[i] as u64 + carry;
+                u[i + j] = sum as Digit;
+                carry = sum >> DIGIT_BITS;
+            }
+            u[j + n] = u[j + n].wrapping_add(carry as Digit);
+        }
+        quotient[j] = qhat as Digit;
+    }
+    u.truncate(n);
+    (quotient, shr_digits(&u, shift))
+}
+
+// Shift by less than a digit; the result has one more digit.
+fn shl_digits(a: &[Digit], shift: u32) -> Vec<Digit> {
+    let mut shifted = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0;
+    for &d in a {
+        shifted.push(d << shift | carry);
+        carry = if shift == 0 { 0 } else { d >> (DIGIT_BITS as u32 - shift) };
+    }
+    shifted.push(carry);
+    shifted.truncate(if shift == 0 { a.len() } else { a.len() + 1 });
+    shifted
+}
+
+fn shr_digits(a: &[Digit], shift: u32) -> Vec<Digit> {
+    (0 .. a.len()).map(|i| {
+                      let high = if shift == 0 { 0 }
+                                 else { a.get(i + 1).map_or(0, |&d| d << (DIGIT_BITS as u32 - shift)) };
+                      a[i] >> shift | high
+                  })
+                  .collect()
+}
+
+#[cfg(test)]
+fn factorial(n: i64) -> BigInt {
+    (1..n + 1).fold(BigInt::from_i64(1), |acc, i| acc.mul(&BigInt::from_i64(i)))
+}
+
+#[test]
+fn test_arithmetic() {
+    let fac25 = factorial(25);
+    assert_eq!("15511210043330985984000000", fac25.to_string());
+    assert_eq!(None, fac25.to_i64());
+    let (q, r) = fac25.div_rem(&factorial(20)).unwrap();
+    assert_eq!("6375600", q.to_string());
+    assert!(r.is_zero());
+    let (q, r) = fac25.neg().div_rem(&BigInt::from_i64(7)).unwrap();
+    assert_eq!(fac25.neg(), q.mul(&BigInt::from_i64(7)).add(&r));
+    assert!(r.is_negative() || r.is_zero());
+    assert_eq!(Some (i64::min_value()), BigInt::from_i64(i64::min_value()).to_i64());
+    assert_eq!(BigInt::zero(), fac25.sub(&fac25));
+}
+
+#[test]
+fn test_long_division() {
+    let check = |a: &BigInt, b: &BigInt| {
+        let (q, r) = a.div_rem(b).unwrap();
+        assert_eq!(*a, q.mul(b).add(&r));
+        assert_eq!(Ordering::Less, cmp_magnitudes(&r.digits, &b.digits));
+        (q, r)
+    };
+    let (q, r) = check(&factorial(200), &factorial(150));
+    assert_eq!((151..201).fold(BigInt::from_i64(1), |acc, i| acc.mul(&BigInt::from_i64(i))), q);
+    assert!(r.is_zero());
+    check(&factorial(100).add(&BigInt::from_i64(12345)), &factorial(30).sub(&BigInt::from_i64(1)));
+    check(&BigInt::from_i64(-1).shl(200).neg(), &BigInt::from_i64(3).shl(70).add(&BigInt::from_i64(1)));
+    // A case where the estimated quotient digit is one too big.
+    let a = BigInt::from_parts(false, vec![0, 0, 0x8000_0000, 0x7fff_ffff]);
+    let b = BigInt::from_parts(false, vec![1, 0, 0x8000_0000]);
+    let (q, r) = check(&a, &b);
+    assert_eq!(&[0xffff_fffe][..], q.digits());
+    assert_eq!(&[2, 0xffff_ffff, 0x7fff_ffff][..], r.digits());
+}
+
+#[test]
+fn test_bitwise() {
+    let big = BigInt::from_i64(1).shl(100);
+    let minus_one = BigInt::from_i64(-1);
+    assert_eq!(big, big.bitand(&minus_one));
+    assert_eq!(minus_one, big.bitor(&minus_one));
+    assert_eq!(big.neg().sub(&BigInt::from_i64(1)), big.bitnot());
+    assert_eq!(Some (-1), BigInt::from_i64(-5).shr(3).to_i64());
+    assert_eq!(Some (1), big.shr(100).to_i64());
+    assert_eq!(Some (-6 & 3), BigInt::from_i64(-6).bitand(&BigInt::from_i64(3)).to_i64());
+    assert_eq!(Some (-6 ^ 3), BigInt::from_i64(-6).bitxor(&BigInt::from_i64(3)).to_i64());
+}
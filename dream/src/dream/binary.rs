@@ -0,0 +1,625 @@
+// Binaries and bitstrings.
+//
+// Like on the real BEAM, small binaries live on the process heap while
+// larger ones live off-heap, where they are shared - never copied - between
+// processes and freed once the last reference goes away.  A process refers
+// to an off-heap binary through a ProcBin: a boxed object holding an index
+// into the heap's list of off-heap binaries (`Heap::off_heap`).  The
+// garbage collector drops the references of ProcBins which didn't survive.
+// A sub-binary is a slice of a heap binary or ProcBin, which it keeps
+// alive; bitstrings whose size isn't a whole number of bytes are always
+// sub-binaries.
+//
+// Heap layout, after the header:
+//
+//   Header::HeapBin(bytes)     `Raw` offset of the bytes in the byte area of
+//                              the heap, see `heap`
+//   Header::ProcBin(bytes)     `Raw` index into `Heap::off_heap`
+//   Header::SubBin(bits)       `Small` bit offset, the original binary
+//   Header::MatchCtx(slots)    the binary being matched, `Small` position
+//                              and saved positions, see `interp`
+//
+// Bits are numbered from the most significant bit of the first byte.  This
+// module also encodes and decodes the segments of the bit syntax.
+
+use bif::{ self, BifResult };
+use bignum::BigInt;
+use process::Process;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use heap::Heap;
+use term::{ Number, Term };
+use super::Emu;
+
+// Binaries up to this many bytes live on the process heap.
+pub const HEAP_BINARY_LIMIT: usize = 64;
+
+// Bytes held by off-heap binaries, for `erlang:memory(binary)`.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+// An off-heap binary; `Arc` keeps the reference count.
+#[derive(Debug)]
+pub struct Binary {
+    bytes:  Vec<u8>
+}
+
+impl Binary {
+
+    pub fn new(bytes: Vec<u8>) -> Arc<Binary> {
+        ALLOCATED.fetch_add(bytes.len(), Ordering::SeqCst);
+        Arc::new(Binary { bytes: bytes })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+}
+
+impl Drop for Binary {
+
+    fn drop(&mut self) {
+        ALLOCATED.fetch_sub(self.bytes.len(), Ordering::SeqCst);
+    }
+
+}
+
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::SeqCst)
+}
+
+// Segment flags of bit syntax instructions.
+pub const FLAG_LITTLE: u32 = 0x02;
+pub const FLAG_SIGNED: u32 = 0x04;
+pub const FLAG_NATIVE: u32 = 0x10;
+
+fn little_endian(flags: u32) -> bool {
+    flags & FLAG_LITTLE != 0 || (flags & FLAG_NATIVE != 0 && cfg!(target_endian = "little"))
+}
+
+// A bitstring: `size` bits of `bytes` from bit `offset` on.
+pub struct Bits<'h> {
+    pub bytes:  Cow<'h, [u8]>,
+    pub offset: usize,
+    pub size:   usize
+}
+
+impl<'h> Bits<'h> {
+
+    pub fn bit(&self, i: usize) -> u8 {
+        let j = self.offset + i;
+        (self.bytes[j / 8] >> (7 - j % 8)) & 1
+    }
+
+    // `n` bits from `at` on, packed from the most significant bit of the
+    // first byte; the last byte is padded with zeros.
+    pub fn read_bytes(&self, at: usize, n: usize) -> Vec<u8> {
+        let (start, shift) = ((self.offset + at) / 8, (self.offset + at) % 8);
+        let mut bytes = Vec::with_capacity((n + 7) / 8);
+        for i in start .. start + (n + 7) / 8 {
+            let byte = if shift == 0 { self.bytes[i] }
+                       else { self.bytes[i] << shift |
+                              self.bytes.get(i + 1).map_or(0, |b| b >> (8 - shift)) };
+            bytes.push(byte);
+        }
+        if n % 8 != 0
+            { *bytes.last_mut().unwrap() &= 0xff << (8 - n % 8) }
+        bytes
+    }
+
+    // The whole bitstring as bytes, if its size is a whole number of bytes.
+    pub fn into_bytes(self) -> Option<Cow<'h, [u8]>> {
+        if self.size % 8 != 0
+            { return None }
+        Some (match self.bytes {
+            Cow::Borrowed(bytes) if self.offset % 8 == 0 =>
+                Cow::Borrowed(&bytes[self.offset / 8 .. (self.offset + self.size) / 8]),
+            _ => Cow::Owned(self.read_bytes(0, self.size))
+        })
+    }
+
+}
+
+// Bits being put together by the bit syntax.
+pub struct Builder {
+    bytes:  Vec<u8>,
+    size:   usize
+}
+
+impl Builder {
+
+    pub fn new() -> Builder {
+        Builder { bytes: vec![], size: 0 }
+    }
+
+    // Size so far, in bits.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn put_bit(&mut self, bit: u8) {
+        if self.size % 8 == 0
+            { self.bytes.push(0) }
+        if bit != 0
+            { *self.bytes.last_mut().unwrap() |= 0x80 >> (self.size % 8) }
+        self.size += 1;
+    }
+
+    // The first `n` bits of `bytes`.
+    pub fn put_bytes(&mut self, bytes: &[u8], n: usize) {
+        if self.size % 8 == 0 {
+            self.bytes.extend_from_slice(&bytes[.. (n + 7) / 8]);
+            self.size += n;
+            if n % 8 != 0
+                { *self.bytes.last_mut().unwrap() &= 0xff << (8 - n % 8) }
+            return
+        }
+        for i in 0 .. n
+            { self.put_bit((bytes[i / 8] >> (7 - i % 8)) & 1) }
+    }
+
+    pub fn put_bits(&mut self, bits: &Bits, at: usize, n: usize) {
+        self.put_bytes(&bits.read_bytes(at, n), n)
+    }
+
+    pub fn to_term(&self, heap: &mut Heap) -> Term {
+        Term::bitstring(heap, &self.bytes, self.size)
+    }
+
+}
+
+// Two's complement of `n`, in `size` bits, as little-endian bytes.
+fn integer_bytes(n: &Number, size: usize) -> Vec<u8> {
+    let len = (size + 7) / 8;
+    let mut bytes = match *n {
+        Number::Small(i) => {
+            let mut bytes = i.to_le_bytes().to_vec();
+            bytes.resize(len.max(8), if i < 0 { 0xff } else { 0 });
+            bytes
+        },
+        _ => {
+            let mask = BigInt::from_i64(1).shl(size).sub(&BigInt::from_i64(1));
+            let mut bytes = vec![];
+            for d in n.to_big().bitand(&mask).digits().iter()
+                { bytes.extend_from_slice(&d.to_le_bytes()) }
+            bytes.resize(len.max(bytes.len()), 0);
+            bytes
+        }
+    };
+    bytes.truncate(len);
+    bytes
+}
+
+// An integer segment of `size` bits.
+pub fn put_integer(b: &mut Builder, n: &Number, size: usize, flags: u32) {
+    let bytes = integer_bytes(n, size);
+    if little_endian(flags) {
+        b.put_bytes(&bytes, size / 8 * 8);
+        if size % 8 != 0 {
+            let rest = bytes[size / 8] << (8 - size % 8);
+            b.put_bytes(&[rest], size % 8);
+        }
+    } else if size % 8 == 0 {
+        let big_endian: Vec<u8> = bytes.into_iter().rev().collect();
+        b.put_bytes(&big_endian, size);
+    } else {
+        for i in (0 .. size).rev()
+            { b.put_bit((bytes[i / 8] >> (i % 8)) & 1) }
+    }
+}
+
+pub fn get_integer(bits: &Bits, at: usize, size: usize, flags: u32) -> Number {
+    let mut bytes = vec![0; (size + 7) / 8];
+    if little_endian(flags) {
+        let read = bits.read_bytes(at, size);
+        bytes[.. size / 8].copy_from_slice(&read[.. size / 8]);
+        if size % 8 != 0
+            { bytes[size / 8] = read[size / 8] >> (8 - size % 8) }
+    } else {
+        for i in 0 .. size {
+            let j = size - 1 - i;
+            bytes[j / 8] |= bits.bit(at + i) << (j % 8);
+        }
+    }
+    let negative = flags & FLAG_SIGNED != 0 && size > 0 &&
+                   bytes[(size - 1) / 8] >> ((size - 1) % 8) & 1 != 0;
+    if size < 64 || (size == 64 && (negative || bytes[7] & 0x80 == 0)) {
+        let mut word = [if negative { 0xff } else { 0 }; 8];
+        word[.. bytes.len()].copy_from_slice(&bytes);
+        let mut i = i64::from_le_bytes(word);
+        if negative && size < 64
+            { i |= -1 << size }
+        return Number::Small(i)
+    }
+    let n = BigInt::from_bytes_le(false, &bytes);
+    if negative { Number::Big(n.sub(&BigInt::from_i64(1).shl(size))) }
+    else { Number::Big(n) }
+}
+
+// A float segment of 16, 32 or 64 bits.
+pub fn put_float(b: &mut Builder, f: f64, size: usize, flags: u32) -> bool {
+    let bits = match size {
+        64 => f.to_bits(),
+        32 if f.is_finite() && (f as f32).is_finite() => (f as f32).to_bits() as u64,
+        16 => match half_from_f64(f) {
+            Some (h) => h as u64,
+            None => return false
+        },
+        _ => return false
+    };
+    put_integer(b, &Number::Small(bits as i64), size, flags);
+    true
+}
+
+// Fails on NaN and infinities, like on the real BEAM.
+pub fn get_float(bits: &Bits, at: usize, size: usize, flags: u32) -> Option<f64> {
+    // Read signed so that 64 bits fit in a small integer.
+    let raw = match get_integer(bits, at, size, flags | FLAG_SIGNED) {
+        Number::Small(i) => i as u64,
+        _ => return None
+    };
+    let f = match size {
+        64 => f64::from_bits(raw),
+        32 => f32::from_bits(raw as u32) as f64,
+        16 => half_to_f64(raw as u16),
+        _ => return None
+    };
+    if f.is_finite() { Some (f) } else { None }
+}
+
+fn half_to_f64(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let (exponent, fraction) = ((h >> 10) & 0x1f, (h & 0x3ff) as f64);
+    sign * match exponent {
+        0 => fraction * 2f64.powi(-24),
+        0x1f => if fraction == 0.0 { ::std::f64::INFINITY } else { ::std::f64::NAN },
+        e => (1.0 + fraction / 1024.0) * 2f64.powi(e as i32 - 15)
+    }
+}
+
+// Rounds to the nearest half precision float; none if it's out of range.
+fn half_from_f64(f: f64) -> Option<u16> {
+    if !f.is_finite()
+        { return None }
+    let sign = if f.is_sign_negative() { 0x8000 } else { 0 };
+    let a = f.abs();
+    if a == 0.0
+        { return Some (sign) }
+    let exponent = a.log2().floor() as i32;
+    if exponent < -14 {
+        let fraction = (a / 2f64.powi(-24)).round() as u16;
+        return Some (sign | fraction)
+    }
+    let mut fraction = ((a / 2f64.powi(exponent) - 1.0) * 1024.0).round() as u32;
+    let mut exponent = exponent + 15;
+    if fraction == 1024 {
+        fraction = 0;
+        exponent += 1;
+    }
+    if exponent >= 0x1f
+        { return None }
+    Some (sign | (exponent as u16) << 10 | fraction as u16)
+}
+
+fn valid_code_point(c: i64) -> bool {
+    c >= 0 && c <= 0x10ffff && !(c >= 0xd800 && c <= 0xdfff)
+}
+
+// UTF-8, UTF-16 or UTF-32 (`width` 8, 16 or 32) encoding of `c`.
+pub fn put_utf(b: &mut Builder, c: i64, width: usize, flags: u32) -> bool {
+    if !valid_code_point(c)
+        { return false }
+    match width {
+        8 => {
+            let mut buffer = [0; 4];
+            let encoded = ::std::char::from_u32(c as u32).unwrap().encode_utf8(&mut buffer);
+            b.put_bytes(encoded.as_bytes(), 8 * encoded.len());
+        },
+        16 => {
+            let mut buffer = [0; 2];
+            for &unit in ::std::char::from_u32(c as u32).unwrap().encode_utf16(&mut buffer).iter()
+                { put_integer(b, &Number::Small(unit as i64), 16, flags) }
+        },
+        _ => put_integer(b, &Number::Small(c), 32, flags)
+    }
+    true
+}
+
+pub fn utf_size(c: i64, width: usize) -> Option<usize> {
+    if !valid_code_point(c)
+        { return None }
+    Some (match width {
+        8 if c < 0x80 => 8,
+        8 if c < 0x800 => 16,
+        8 if c < 0x10000 => 24,
+        8 => 32,
+        16 if c < 0x10000 => 16,
+        16 => 32,
+        _ => 32
+    })
+}
+
+// A code point and the number of bits it takes, if there's a valid one at
+// `at`.
+pub fn get_utf(bits: &Bits, at: usize, width: usize, flags: u32) -> Option<(i64, usize)> {
+    let left = bits.size - at;
+    let unit = |i: usize, n: usize| match get_integer(bits, at + i, n, flags & !FLAG_SIGNED) {
+        Number::Small(u) => u,
+        _ => -1
+    };
+    match width {
+        8 => {
+            if left < 8
+                { return None }
+            let first = unit(0, 8);
+            let (len, mut c) = match first {
+                0 ..= 0x7f => return Some ((first, 8)),
+                0xc2 ..= 0xdf => (2, first & 0x1f),
+                0xe0 ..= 0xef => (3, first & 0x0f),
+                0xf0 ..= 0xf4 => (4, first & 0x07),
+                _ => return None
+            };
+            if left < 8 * len
+                { return None }
+            for i in 1 .. len {
+                let byte = unit(8 * i, 8);
+                if byte & 0xc0 != 0x80
+                    { return None }
+                c = c << 6 | byte & 0x3f;
+            }
+            // No overlong encodings.
+            let min = [0, 0, 0x80, 0x800, 0x10000][len];
+            if c < min || !valid_code_point(c) { None } else { Some ((c, 8 * len)) }
+        },
+        16 => {
+            if left < 16
+                { return None }
+            let first = unit(0, 16);
+            if first < 0xd800 || first > 0xdfff
+                { return Some ((first, 16)) }
+            if first > 0xdbff || left < 32
+                { return None }
+            let second = unit(16, 16);
+            if second < 0xdc00 || second > 0xdfff
+                { return None }
+            Some ((0x10000 + ((first - 0xd800) << 10 | (second - 0xdc00)), 32))
+        },
+        _ => {
+            if left < 32
+                { return None }
+            let c = unit(0, 32);
+            if valid_code_point(c) { Some ((c, 32)) } else { None }
+        }
+    }
+}
+
+fn binary_arg<'h>(p: &'h Process, t: Term) -> Result<Cow<'h, [u8]>, bif::Error> {
+    t.binary_bytes(&p.heap).ok_or(bif::Error::Badarg)
+}
+
+fn index_arg(t: Term) -> Result<usize, bif::Error> {
+    match t {
+        Term::Small(i) if i >= 0 => Ok (i as usize),
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:byte_size/1
+pub fn byte_size_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    Ok (Term::Small(try!( binary_arg(p, args[0]) ).len() as i64))
+}
+
+// erlang:bit_size/1
+pub fn bit_size_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    match args[0].bit_size(&p.heap) {
+        Some (bits) => Ok (Term::Small(bits as i64)),
+        None => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:is_bitstring/1
+pub fn is_bitstring_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    Ok (bif::boolean(emu, args[0].is_bitstring(&p.heap)))
+}
+
+// erlang:is_binary/1
+pub fn is_binary_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    Ok (bif::boolean(emu, args[0].is_binary(&p.heap)))
+}
+
+// erlang:binary_part/3; a sub-binary, with a negative length counting
+// back from the start position.
+pub fn binary_part_3(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let (start, len) = match (args[1], args[2]) {
+        (Term::Small(start), Term::Small(len)) if len < 0 => (start + len, -len),
+        (Term::Small(start), Term::Small(len)) => (start, len),
+        _ => return Err (bif::Error::Badarg)
+    };
+    let (start, len) = (try!( index_arg(Term::Small(start)) ), len as usize);
+    if !args[0].is_binary(&p.heap)
+        { return Err (bif::Error::Badarg) }
+    Term::sub_binary(&mut p.heap, args[0], start, len).ok_or(bif::Error::Badarg)
+}
+
+// erlang:split_binary/2
+pub fn split_binary_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let size = try!( binary_arg(p, args[0]) ).len();
+    let pos = try!( index_arg(args[1]) );
+    if pos > size
+        { return Err (bif::Error::Badarg) }
+    let before = Term::sub_binary(&mut p.heap, args[0], 0, pos).unwrap();
+    let after = Term::sub_binary(&mut p.heap, args[0], pos, size - pos).unwrap();
+    Ok (Term::tuple(&mut p.heap, &[before, after]))
+}
+
+// erlang:binary_to_list/1
+pub fn binary_to_list_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let bytes: Vec<Term> = try!( binary_arg(p, args[0]) ).iter()
+                                                         .map(|&b| Term::Small(b as i64))
+                                                         .collect();
+    Ok (Term::list(&mut p.heap, &bytes))
+}
+
+// erlang:list_to_binary/1; the argument is an iolist: a possibly deep list
+// of bytes and binaries, maybe with a binary tail.
+pub fn list_to_binary_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    match args[0] {
+        Term::Cons(_) | Term::Nil => {},
+        _ => return Err (bif::Error::Badarg)
+    }
+    let mut bytes = vec![];
+    let mut pending = vec![args[0]];
+    while let Some (t) = pending.pop() {
+        match t {
+            Term::Nil => {},
+            Term::Cons(ptr) => {
+                pending.push(p.heap.get(ptr + 1));
+                match p.heap.get(ptr) {
+                    Term::Small(b) if b >= 0 && b < 256 => bytes.push(b as u8),
+                    head @ Term::Cons(_) | head @ Term::Nil => pending.push(head),
+                    head => bytes.extend_from_slice(&try!( binary_arg(p, head) ))
+                }
+            },
+            _ => bytes.extend_from_slice(&try!( binary_arg(p, t) ))
+        }
+    }
+    Ok (Term::binary(&mut p.heap, &bytes))
+}
+
+// erlang:memory/1; only `binary` is known.
+pub fn memory_1(emu: &Emu, _: &mut Process, args: &[Term]) -> BifResult {
+    match args[0] {
+        Term::Atom(a) if emu.atoms.get_atom(a).as_ref().map(|a| &a[..]) == Some ("binary") =>
+            Ok (Term::Small(allocated() as i64)),
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+#[test]
+fn test_binaries() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("bins");
+    let bif = |m: &mut Module, name: &'static str, module: &str, function: &str, arity: u32| {
+        let import = m.import(module, function, arity);
+        let f = m.function(name, arity);
+        m.op("call_ext_only", vec![Arg::U(arity), Arg::U(import)]);
+        m.export(name, arity, f);
+    };
+    bif(&mut m, "size", "erlang", "byte_size", 1);
+    bif(&mut m, "part", "erlang", "binary_part", 3);
+    bif(&mut m, "split", "erlang", "split_binary", 2);
+    bif(&mut m, "to_list", "erlang", "binary_to_list", 1);
+    bif(&mut m, "from_list", "erlang", "list_to_binary", 1);
+    // memory(B) -> {byte_size(B), erlang:memory(binary)}.
+    let memory_1 = m.import("erlang", "memory", 1);
+    let byte_size_1 = m.import("erlang", "byte_size", 1);
+    let memory = m.function("memory", 1);
+    m.op("allocate", vec![Arg::U(1), Arg::U(1)]);
+    m.op("gc_bif1", vec![Arg::F(0), Arg::U(1), Arg::U(byte_size_1), Arg::X(0), Arg::Y(0)]);
+    m.op("move", vec![Arg::A("binary"), Arg::X(0)]);
+    m.op("call_ext", vec![Arg::U(1), Arg::U(memory_1)]);
+    m.op("test_heap", vec![Arg::U(3), Arg::U(1)]);
+    m.op("put_tuple", vec![Arg::U(2), Arg::X(1)]);
+    m.op("put", vec![Arg::Y(0)]);
+    m.op("put", vec![Arg::X(0)]);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("deallocate", vec![Arg::U(1)]);
+    m.op("return", vec![]);
+    m.export("memory", 1, memory);
+    let mut emu = Emu::new();
+    m.load(&mut emu).unwrap();
+    let call = |function: &str, args: &[etf::Term]| match emu.call("bins", function, args) {
+        Ok (t) => t.to_string(),
+        Err (t) => format!("failed: {}", t)
+    };
+    let (small, large) = (etf::Term::Binary(b"hello".to_vec()), etf::Term::Binary(vec![7; 1000]));
+    let int = etf::Term::Integer;
+    assert_eq!("5", call("size", &[small.clone()]));
+    assert_eq!("1000", call("size", &[large.clone()]));
+    assert_eq!("<<\"ell\">>", call("part", &[small.clone(), int(1), int(3)]));
+    assert_eq!("<<\"ell\">>", call("part", &[small.clone(), int(4), int(-3)]));
+    assert_eq!("<<7,7>>", call("part", &[large.clone(), int(998), int(2)]));
+    assert_eq!("failed: badarg", call("part", &[small.clone(), int(3), int(3)]));
+    assert_eq!("{<<\"he\">>,<<\"llo\">>}", call("split", &[small.clone(), int(2)]));
+    assert_eq!("\"hello\"", call("to_list", &[small.clone()]));
+    let iolist = etf::Term::List(vec![int(104),
+                                      etf::Term::List(vec![small.clone()], Box::new(etf::Term::Nil))],
+                                 Box::new(etf::Term::Binary(b"!".to_vec())));
+    assert_eq!("<<\"hhello!\">>", call("from_list", &[iolist]));
+    assert_eq!("failed: badarg", call("from_list", &[small.clone()]));
+    // The large binary is off-heap while the process holds it.
+    let result = emu.call("bins", "memory", &[large]).unwrap();
+    match result {
+        etf::Term::Tuple(ref elements) => match elements[1] {
+            etf::Term::Integer(bytes) => assert!(bytes >= 1000),
+            _ => panic!("unexpected result {}", result)
+        },
+        _ => panic!("unexpected result {}", result)
+    }
+}
+
+#[test]
+fn test_segments() {
+    let bits = |b: &Builder| Bits { bytes: Cow::Owned(b.bytes.clone()), offset: 0, size: b.size };
+    let mut b = Builder::new();
+    put_integer(&mut b, &Number::Small(0x1234), 16, 0);
+    put_integer(&mut b, &Number::Small(0x1234), 16, FLAG_LITTLE);
+    assert_eq!(vec![0x12, 0x34, 0x34, 0x12], b.bytes);
+    // <<16#123:12/little>> is <<16#23,1:4>>.
+    let mut b = Builder::new();
+    put_integer(&mut b, &Number::Small(0x123), 12, FLAG_LITTLE);
+    put_integer(&mut b, &Number::Small(-1), 3, 0);
+    assert_eq!((vec![0x23, 0x1e], 15), (b.bytes.clone(), b.size()));
+    assert_eq!(Number::Small(0x123), get_integer(&bits(&b), 0, 12, FLAG_LITTLE));
+    assert_eq!(Number::Small(-1), get_integer(&bits(&b), 12, 3, FLAG_SIGNED));
+    assert_eq!(Number::Small(7), get_integer(&bits(&b), 12, 3, 0));
+    // Unaligned reads, and integers too large for a word.
+    assert_eq!(vec![0x31, 0xe0], bits(&b).read_bytes(4, 11));
+    let big = BigInt::from_i64(1).shl(70);
+    for n in [big.clone(), big.neg()].iter() {
+        let mut b = Builder::new();
+        put_integer(&mut b, &Number::Big(n.clone()), 75, 0);
+        put_integer(&mut b, &Number::Big(n.clone()), 72, FLAG_LITTLE);
+        assert_eq!(Number::Big(n.clone()), get_integer(&bits(&b), 0, 75, FLAG_SIGNED));
+        assert_eq!(Number::Big(n.clone()), get_integer(&bits(&b), 75, 72, FLAG_SIGNED | FLAG_LITTLE));
+    }
+    let mut b = Builder::new();
+    put_integer(&mut b, &Number::Small(-1), 64, 0);
+    assert_eq!(Number::Big(BigInt::from_i64(1).shl(64).sub(&BigInt::from_i64(1))),
+               get_integer(&bits(&b), 0, 64, 0));
+    // Floats.
+    let mut b = Builder::new();
+    for &size in [64, 32, 16].iter()
+        { assert!(put_float(&mut b, -1.5, size, 0)) }
+    assert_eq!(vec![0xbe, 0x00], b.bytes[12 ..].to_vec());
+    assert_eq!(Some (-1.5), get_float(&bits(&b), 0, 64, 0));
+    assert_eq!(Some (-1.5), get_float(&bits(&b), 64, 32, 0));
+    assert_eq!(Some (-1.5), get_float(&bits(&b), 96, 16, 0));
+    assert!(!put_float(&mut b, 1e300, 32, 0));
+    assert!(!put_float(&mut b, 1e6, 16, 0));
+    let mut b = Builder::new();
+    put_integer(&mut b, &Number::Small(0x7ff8_0000_0000_0000), 64, 0);
+    assert_eq!(None, get_float(&bits(&b), 0, 64, 0));
+    // UTF-8, UTF-16 and UTF-32.
+    let mut b = Builder::new();
+    for &width in [8, 16, 32].iter() {
+        assert!(put_utf(&mut b, 0xe9, width, 0));
+        assert!(put_utf(&mut b, 0x1f600, width, FLAG_LITTLE));
+    }
+    assert_eq!(vec![0xc3, 0xa9, 0xf0, 0x9f, 0x98, 0x80, 0x00, 0xe9, 0x3d, 0xd8, 0x00, 0xde],
+               b.bytes[.. 12].to_vec());
+    let mut at = 0;
+    for &width in [8, 16, 32].iter() {
+        assert_eq!(Some ((0xe9, utf_size(0xe9, width).unwrap())), get_utf(&bits(&b), at, width, 0));
+        at += utf_size(0xe9, width).unwrap();
+        assert_eq!(Some ((0x1f600, utf_size(0x1f600, width).unwrap())),
+                   get_utf(&bits(&b), at, width, FLAG_LITTLE));
+        at += utf_size(0x1f600, width).unwrap();
+    }
+    assert!(!put_utf(&mut b, 0xd800, 8, 0));
+    // An overlong encoding of 0.
+    let overlong = Bits { bytes: Cow::Owned(vec![0xc0, 0x80]), offset: 0, size: 16 };
+    assert_eq!(None, get_utf(&overlong, 0, 8, 0));
+}
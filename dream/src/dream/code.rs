@@ -1,5 +1,7 @@
 use std;
+use std::fmt;
 use super::beam;
+use super::bignum::BigInt;
 
 #[derive(Debug)]
 pub struct CodeChunk {
@@ -18,7 +20,23 @@ pub struct CodeChunk {
 
     // Possibly more data here depending on `info_fields_len` value.
 
-    pub code:               Vec<Op>
+    pub code:               Vec<Op>,
+
+    // Integer operands too big for an `(ArgTag, u32)` pair;
+    // referred to by `(ArgTag::o, index)`.
+    pub big_integers:       Vec<BigInt>,
+
+    // Registers of `code` which are type-tagged (OTP 25+).
+    pub typed_operands:     Vec<TypedOperand>
+}
+
+// A type-tagged register: the instruction and operand positions in the
+// code, and the index of the register's type in the `Type` chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TypedOperand {
+    pub op:         usize,
+    pub arg:        usize,
+    pub type_index: u32
 }
 
 #[derive(Debug)]
@@ -59,7 +77,7 @@ impl CodeChunk {
         let opcode_max = u32_from_be(&chunk.data[8..12]);
         if opcode_max > BEAMOpcode::max_opcode() as u32
             { return unsupported_opcode(opcode_max) }
-        let ops = try!(load_bytecode(&chunk.data[code_start..]));
+        let (ops, big_integers, typed_operands) = try!(load_bytecode(&chunk.data[code_start..]));
         Ok (CodeChunk {
                 id: chunk.id.clone(),
                 len: chunk.len,
@@ -68,7 +86,9 @@ impl CodeChunk {
                 opcode_max: opcode_max,
                 n_labels: u32_from_be(&chunk.data[12..16]),
                 n_functions: u32_from_be(&chunk.data[16..20]),
-                code: ops
+                code: ops,
+                big_integers: big_integers,
+                typed_operands: typed_operands
         })
     }
 
@@ -80,76 +100,166 @@ impl CodeChunk {
 //   use a macro in test to avoid opcode name repetition and learn how
 //   to stringify identifiers in macros
 
-fn load_bytecode(bytecode: &[u8]) -> Result<Vec<Op>, Error> {
+fn load_bytecode(bytecode: &[u8]) -> Result<(Vec<Op>, Vec<BigInt>, Vec<TypedOperand>), Error> {
     let mut i = 0;
     let mut opcodes = vec![];
+    let mut big_integers = vec![];
+    let mut typed = vec![];
     while i < bytecode.len() {
-        match load_operation(&mut i, bytecode) {
+        let n = opcodes.len();
+        match load_operation(&mut i, bytecode, &mut big_integers, (n, &mut typed)) {
             Ok (op) => opcodes.push(op),
             Err (reason) => return Err (reason)
         }
     }
-    Ok (opcodes)
+    Ok ((opcodes, big_integers, typed))
 }
 
-fn load_operation(pi: &mut usize,
-                  bytecode: &[u8]) -> Result<Op, Error> {
+fn load_operation(pi: &mut usize, bytecode: &[u8], big_integers: &mut Vec<BigInt>,
+                  typed: (usize, &mut Vec<TypedOperand>)) -> Result<Op, Error> {
     let i = *pi;
+    *pi += 1;
     BEAMOpcode::from_u8(bytecode[i])
                .ok_or(Error::UnsupportedOpcode(bytecode[i] as u32))
                .and_then(|opcode| {
-                   load_args(opcode, pi, bytecode)
+                   load_args(opcode, pi, bytecode, big_integers, typed)
                    .map(|args| Op { code: opcode, args: args } )
                })
 }
 
-fn load_args(opcode: BEAMOpcode, pi: &mut usize,
-             bytecode: &[u8]) -> Result<Vec<(ArgTag, u32)>, Error> {
-    let from = *pi + 1;
-    let to = from + opcode.arity() as usize;
-    *pi = to;
-    if to > bytecode.len()
-        { return Err ( Error::InvalidChunk ) }
-    transform_args(opcode.arity(), &bytecode[from..to])
-}
-
-fn transform_args(nargs: u8, bytes: &[u8]) -> Result<Vec<(ArgTag, u32)>, Error> {
-    let mut i = 0;
+// Decode `opcode.arity()` operands in the compact term encoding.
+// Extended lists (e.g. of `select_val`) are flattened: a `(z, n)` operand
+// is followed by its `n` elements.  Type-tagged registers are added to
+// `typed`, as operands of instruction `op`.
+fn load_args(opcode: BEAMOpcode, pi: &mut usize, bytecode: &[u8], big_integers: &mut Vec<BigInt>,
+             (op, typed): (usize, &mut Vec<TypedOperand>)) -> Result<Vec<(ArgTag, u32)>, Error> {
     let mut opargs = vec![];
-    while i < bytes.len() {
-        match transform_arg(bytes[i], &bytes[i+1..]) {
-            Ok ((oparg, nbytes)) => {
-                i += nbytes;
-                opargs.push(oparg)
+    for _ in 0..opcode.arity() {
+        match try!( transform_arg(pi, bytecode, big_integers) ) {
+            Operand::Single(arg) => opargs.push(arg),
+            Operand::Typed(register, type_index) => {
+                typed.push(TypedOperand { op: op, arg: opargs.len(), type_index: type_index });
+                opargs.push(register)
             },
-            Err (reason) => return Err (reason)
+            Operand::List(n) => {
+                opargs.push((ArgTag::z, n));
+                for _ in 0..n
+                    { opargs.push(try!( transform_list_element(pi, bytecode,
+                                                               big_integers) )) }
+            },
+            Operand::AllocList(n) => {
+                opargs.push((ArgTag::z, 2 * n));
+                for _ in 0 .. 2 * n
+                    { opargs.push((ArgTag::u, try!( unsigned(pi, bytecode) ))) }
+            }
         }
     }
     Ok (opargs)
 }
 
-fn transform_arg(arg: u8, rest: &[u8]) -> Result<((ArgTag, u32), usize), Error> {
-    if let Some (tag) = ArgTag::from_u8(arg & 0b111) {
-        match tag {
-            ArgTag::z =>
-                panic!("complex terms (aka literals) not supported"),
-            ArgTag::u | ArgTag::i | ArgTag::a | ArgTag::x | ArgTag::y | ArgTag::f =>
-                value(arg, rest).map(|(v, consumed)| ((tag, v), consumed) )
-        }
-    } else {
-        Err (Error::InvalidTag)
+enum Operand {
+    Single((ArgTag, u32)),
+    Typed((ArgTag, u32), u32),
+    List(u32),
+    AllocList(u32)
+}
+
+// A single operand in the compact term encoding, e.g. an item of the
+// `Line` chunk.
+pub fn read_operand(pi: &mut usize, bytes: &[u8]) -> Result<(ArgTag, u32), Error> {
+    transform_list_element(pi, bytes, &mut vec![])
+}
+
+fn transform_list_element(pi: &mut usize, bytecode: &[u8],
+                          big_integers: &mut Vec<BigInt>) -> Result<(ArgTag, u32), Error> {
+    match try!( transform_arg(pi, bytecode, big_integers) ) {
+        Operand::Single(arg) |
+        Operand::Typed(arg, _) => Ok (arg),
+        _ => Err (Error::InvalidTag)
+    }
+}
+
+fn transform_arg(pi: &mut usize, bytecode: &[u8],
+                 big_integers: &mut Vec<BigInt>) -> Result<Operand, Error> {
+    let arg = try!( bytecode.get(*pi).cloned().ok_or(Error::InvalidChunk) );
+    let tag = try!( ArgTag::from_u8(arg & 0b111).ok_or(Error::InvalidTag) );
+    if let ArgTag::z = tag
+        { return transform_extended(pi, bytecode, big_integers) }
+    let n = try!( value(pi, bytecode) );
+    match (tag, n.to_i64()) {
+        (ArgTag::i, Some (i)) if i >= i32::min_value() as i64 && i <= i32::max_value() as i64 =>
+            Ok (Operand::Single((ArgTag::i, i as i32 as u32))),
+        // Integers which don't fit in an operand become literals at load time.
+        (ArgTag::i, _) => {
+            big_integers.push(n);
+            Ok (Operand::Single((ArgTag::o, big_integers.len() as u32 - 1)))
+        },
+        (tag, Some (u)) if u >= 0 && u <= u32::max_value() as i64 =>
+            Ok (Operand::Single((tag, u as u32))),
+        _ => Err (Error::InvalidTag)
+    }
+}
+
+fn transform_extended(pi: &mut usize, bytecode: &[u8],
+                      big_integers: &mut Vec<BigInt>) -> Result<Operand, Error> {
+    let arg = bytecode[*pi];
+    *pi += 1;
+    if arg & 0b1000 != 0
+        { return Err (Error::InvalidTag) }
+    match arg >> 4 {
+        1 => unsigned(pi, bytecode).map(Operand::List),
+        2 => unsigned(pi, bytecode).map(|n| Operand::Single((ArgTag::fr, n))),
+        3 => unsigned(pi, bytecode).map(Operand::AllocList),
+        4 => unsigned(pi, bytecode).map(|n| Operand::Single((ArgTag::q, n))),
+        // Type-tagged register: the register followed by an index into
+        // the `Type` chunk.
+        5 => {
+            let register = try!( transform_list_element(pi, bytecode, big_integers) );
+            let type_index = try!( unsigned(pi, bytecode) );
+            Ok (Operand::Typed(register, type_index))
+        },
+        // 0 used to be an inline float, not emitted since R11.
+        _ => Err (Error::InvalidTag)
+    }
+}
+
+fn unsigned(pi: &mut usize, bytecode: &[u8]) -> Result<u32, Error> {
+    let n = try!( value(pi, bytecode) );
+    match n.to_i64() {
+        Some (u) if u >= 0 && u <= u32::max_value() as i64 => Ok (u as u32),
+        _ => Err (Error::InvalidTag)
     }
 }
 
-fn value(arg: u8, rest: &[u8]) -> Result<(u32, usize), Error> {
+// Operand value; small values are packed with the tag into a single byte,
+// bigger ones follow in up to 2 bytes, even bigger ones are stored
+// as a big-endian two's complement number preceded by its length.
+fn value(pi: &mut usize, bytecode: &[u8]) -> Result<BigInt, Error> {
+    let arg = try!( bytecode.get(*pi).cloned().ok_or(Error::InvalidChunk) );
+    *pi += 1;
     if arg & 0b1000 == 0 {
-        Ok ( ((arg >> 4) as u32, 1) )
+        Ok ( BigInt::from_i64((arg >> 4) as i64) )
     } else if arg & 0x10 == 0 {
-        let tmp = (arg & 0b1110_0000) as u32;
-        let n = (tmp << 3) | rest[0] as u32;
-        Ok ( (n, 2) )
+        let next = try!( bytecode.get(*pi).cloned().ok_or(Error::InvalidChunk) );
+        *pi += 1;
+        let tmp = (arg & 0b1110_0000) as i64;
+        Ok ( BigInt::from_i64((tmp << 3) | next as i64) )
     } else {
-        Err (Error::InvalidTag)
+        let len = if arg >> 5 == 7 {
+            try!( unsigned(pi, bytecode) ) as usize + 9
+        } else {
+            (arg >> 5) as usize + 2
+        };
+        if *pi + len > bytecode.len()
+            { return Err (Error::InvalidChunk) }
+        let bytes = &bytecode[*pi .. *pi + len];
+        *pi += len;
+        let negative = bytes[0] & 0x80 != 0;
+        let le: Vec<u8> = bytes.iter().rev().cloned().collect();
+        let n = BigInt::from_bytes_le(false, &le);
+        // Two's complement: subtract 2^(8 * len).
+        if negative { Ok (n.sub(&BigInt::from_i64(1).shl(8 * len))) }
+        else { Ok (n) }
     }
 }
 
@@ -174,7 +284,7 @@ fn u32_from_be(bytes: &[u8]) -> u32 {
     u32::from_be(unsafe { *(&_u32 as *const u8 as *const u32) })
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Op {
     pub code: BEAMOpcode,
     pub args: Vec<(ArgTag, u32)>
@@ -182,32 +292,324 @@ pub struct Op {
 
 impl Op {
     pub fn name(&self) -> &'static str {
-        (OPERATIONS[self.code as usize].1).0
+        self.code.name()
+    }
+
+    // Number of words in the code stream.
+    pub fn size(&self) -> usize {
+        1 + self.args.len()
+    }
+
+    pub fn encode(&self, out: &mut Vec<Word>) {
+        out.push(INSTRUCTION | (self.args.len() as Word) << 8 | self.code as u8 as Word);
+        for &(tag, value) in self.args.iter()
+            { out.push((tag as u8 as Word) << 32 | value as Word) }
+    }
+
+    // The instruction at offset `at` of a code stream.
+    pub fn decode(code: &[Word], at: usize) -> Op {
+        let args = (0 .. num_operands(code[at])).map(|n| operand(code[at + 1 + n])).collect();
+        Op { code: opcode(code[at]), args: args }
+    }
+}
+
+// Loaded code is a flat stream of words rather than a vector of `Op`s:
+// each instruction is a word holding its opcode and number of operands,
+// followed by a word for each operand holding its tag and value.
+// Instruction words are marked, so the stream can be walked backwards too.
+pub type Word = u64;
+
+const INSTRUCTION: Word = 1 << 63;
+
+pub fn is_instruction(w: Word) -> bool {
+    w & INSTRUCTION != 0
+}
+
+// Decoding is on the hot path of the interpreter, so rather than matching
+// the opcode again, rely on instruction words being written by `encode`
+// only, from a valid `BEAMOpcode`.
+pub fn opcode(w: Word) -> BEAMOpcode {
+    debug_assert!(is_instruction(w) && BEAMOpcode::from_code(w as u8).is_some());
+    unsafe { ::std::mem::transmute(w as u8) }
+}
+
+pub fn num_operands(w: Word) -> usize {
+    (w >> 8) as u8 as usize
+}
+
+pub fn operand(w: Word) -> (ArgTag, u32) {
+    (ArgTag::from_code((w >> 32) as u8), w as u32)
+}
+
+// A value of a `select_val` list (integer or atom, nil being atom 0), or an
+// arity of a `select_tuple_arity` one.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum SelectKey {
+    Integer(i64),
+    Atom(u32)
+}
+
+// The list of a `select_val` or `select_tuple_arity`, decoded once at load
+// time instead of being searched from the start on each dispatch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JumpTable {
+    // Integers from the given one on, each with its jump target; values
+    // missing from the list jump to the fail label.
+    Dense(i64, Vec<u32>),
+    // Values in order for a binary search, with their jump targets.
+    Sorted(Vec<(SelectKey, u32)>)
+}
+
+impl JumpTable {
+
+    // The table of `select_val Src Fail {Value Label ...}` or
+    // `select_tuple_arity Src Fail {Arity Label ...}`, with jumps already
+    // resolved.  None for other operations, and for lists of other values,
+    // e.g. bignums, which are left for a linear search.
+    pub fn from_op(op: &Op) -> Option<JumpTable> {
+        match op.code {
+            BEAMOpcode::select_val | BEAMOpcode::select_tuple_arity => {},
+            _ => return None
+        }
+        let (fail, len) = match (op.args.get(1), op.args.get(2)) {
+            (Some (&(ArgTag::f, fail)), Some (&(ArgTag::z, len))) => (fail, len as usize),
+            _ => return None
+        };
+        if op.args.len() != 3 + len
+            { return None }
+        let mut pairs = vec![];
+        for pair in op.args[3 ..].chunks(2) {
+            let key = match pair[0] {
+                (ArgTag::i, value) => SelectKey::Integer(value as i32 as i64),
+                (ArgTag::u, arity) => SelectKey::Integer(arity as i64),
+                (ArgTag::a, atom) => SelectKey::Atom(atom),
+                _ => return None
+            };
+            match pair.get(1) {
+                Some (&(ArgTag::f, target)) => pairs.push((key, target)),
+                _ => return None
+            }
+        }
+        pairs.sort_by_key(|&(key, _)| key);
+        pairs.dedup_by_key(|&mut (key, _)| key);
+        let integers: Vec<(i64, u32)> = pairs.iter().filter_map(|&(key, target)| match key {
+            SelectKey::Integer(i) => Some ((i, target)),
+            _ => None
+        }).collect();
+        // Dense if all integers, with no more gaps than values.
+        if !integers.is_empty() && integers.len() == pairs.len() {
+            let (min, max) = (integers[0].0, integers[integers.len() - 1].0);
+            if max - min < 2 * integers.len() as i64 {
+                let mut targets = vec![fail; (max - min + 1) as usize];
+                for &(i, target) in integers.iter()
+                    { targets[(i - min) as usize] = target }
+                return Some (JumpTable::Dense(min, targets))
+            }
+        }
+        Some (JumpTable::Sorted(pairs))
+    }
+
+    // The jump target of the key, None meaning the fail label.
+    pub fn lookup(&self, key: SelectKey) -> Option<u32> {
+        match *self {
+            JumpTable::Dense(min, ref targets) => match key {
+                SelectKey::Integer(i) if i >= min && i - min < targets.len() as i64 =>
+                    Some (targets[(i - min) as usize]),
+                _ => None
+            },
+            JumpTable::Sorted(ref pairs) =>
+                pairs.binary_search_by_key(&key, |&(key, _)| key)
+                     .ok()
+                     .map(|i| pairs[i].1)
+        }
+    }
+
+}
+
+impl fmt::Display for JumpTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JumpTable::Dense(min, ref targets) => {
+                try!( write!(f, "dense {} .. {} ->", min, min + targets.len() as i64 - 1) );
+                for target in targets.iter()
+                    { try!( write!(f, " {}", target) ) }
+                Ok (())
+            },
+            JumpTable::Sorted(ref pairs) => {
+                try!( write!(f, "sorted") );
+                for &(key, target) in pairs.iter() {
+                    match key {
+                        SelectKey::Integer(i) => try!( write!(f, " {} -> {}", i, target) ),
+                        SelectKey::Atom(a) => try!( write!(f, " atom {} -> {}", a, target) )
+                    }
+                }
+                Ok (())
+            }
+        }
     }
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
 pub enum BEAMOpcode {
-    label               = 1,
-    func_info           = 2,
-    int_code_end        = 3,
-    call                = 4,
-    call_only           = 6,
-    allocate_zero       = 14,
-    test_heap           = 16,
-    deallocate          = 18,
-    return_             = 19,
-    is_eq_exact         = 43,
-    is_tuple            = 57,
-    test_arity          = 58,
-    move_               = 64,
-    get_tuple_element   = 66,
-    put_tuple           = 70,
-    put                 = 71,
-    call_ext_only       = 78,
-    gc_bif2             = 125,
-    line                = 153
+    label                = 1,
+    func_info            = 2,
+    int_code_end         = 3,
+    call                 = 4,
+    call_last            = 5,
+    call_only            = 6,
+    call_ext             = 7,
+    call_ext_last        = 8,
+    bif0                 = 9,
+    bif1                 = 10,
+    bif2                 = 11,
+    allocate             = 12,
+    allocate_heap        = 13,
+    allocate_zero        = 14,
+    allocate_heap_zero   = 15,
+    test_heap            = 16,
+    deallocate           = 18,
+    return_              = 19,
+    send                 = 20,
+    remove_message       = 21,
+    timeout              = 22,
+    loop_rec             = 23,
+    loop_rec_end         = 24,
+    wait                 = 25,
+    wait_timeout         = 26,
+    is_lt                = 39,
+    is_ge                = 40,
+    is_eq                = 41,
+    is_ne                = 42,
+    is_eq_exact          = 43,
+    is_ne_exact          = 44,
+    is_integer           = 45,
+    is_float             = 46,
+    is_number            = 47,
+    is_atom              = 48,
+    is_nil               = 52,
+    is_binary            = 53,
+    is_list              = 55,
+    is_nonempty_list     = 56,
+    is_tuple             = 57,
+    test_arity           = 58,
+    select_val           = 59,
+    select_tuple_arity   = 60,
+    jump                 = 61,
+    catch                = 62,
+    catch_end            = 63,
+    move_                = 64,
+    get_list             = 65,
+    get_tuple_element    = 66,
+    set_tuple_element    = 67,
+    put_list             = 69,
+    put_tuple            = 70,
+    put                  = 71,
+    badmatch             = 72,
+    if_end               = 73,
+    case_end             = 74,
+    call_fun             = 75,
+    is_function          = 77,
+    call_ext_only        = 78,
+    bs_put_integer       = 89,
+    bs_put_binary        = 90,
+    bs_put_float         = 91,
+    bs_put_string        = 92,
+    fclearerror          = 94,
+    fcheckerror          = 95,
+    fmove                = 96,
+    fconv                = 97,
+    fadd                 = 98,
+    fsub                 = 99,
+    fmul                 = 100,
+    fdiv                 = 101,
+    fnegate              = 102,
+    make_fun2            = 103,
+    try                  = 104,
+    try_end              = 105,
+    try_case             = 106,
+    try_case_end         = 107,
+    raise                = 108,
+    bs_init2             = 109,
+    bs_add               = 111,
+    apply                = 112,
+    apply_last           = 113,
+    is_function2         = 115,
+    bs_start_match2      = 116,
+    bs_get_integer2      = 117,
+    bs_get_float2        = 118,
+    bs_get_binary2       = 119,
+    bs_skip_bits2        = 120,
+    bs_test_tail2        = 121,
+    bs_save2             = 122,
+    bs_restore2          = 123,
+    gc_bif1              = 124,
+    gc_bif2              = 125,
+    is_bitstr            = 129,
+    bs_context_to_binary = 130,
+    bs_test_unit         = 131,
+    bs_match_string      = 132,
+    bs_append            = 134,
+    bs_private_append    = 135,
+    bs_init_bits         = 137,
+    bs_get_utf8          = 138,
+    bs_skip_utf8         = 139,
+    bs_get_utf16         = 140,
+    bs_skip_utf16        = 141,
+    bs_get_utf32         = 142,
+    bs_skip_utf32        = 143,
+    bs_utf8_size         = 144,
+    bs_put_utf8          = 145,
+    bs_utf16_size        = 146,
+    bs_put_utf16         = 147,
+    bs_put_utf32         = 148,
+    recv_mark            = 150,
+    recv_set             = 151,
+    gc_bif3              = 152,
+    line                 = 153,
+    put_map_assoc        = 154,
+    put_map_exact        = 155,
+    is_map               = 156,
+    has_map_fields       = 157,
+    get_map_elements     = 158,
+    get_hd               = 162,
+    get_tl               = 163,
+    bs_get_tail          = 165,
+    bs_start_match3      = 166,
+    bs_get_position      = 167,
+    bs_set_position      = 168,
+    bs_start_match4      = 170,
+    bs_create_bin        = 177,
+    update_record        = 181,
+    bs_match             = 182,
+    // Internal instructions, created at load time by `transform`; they
+    // never appear in .beam files.
+    move_x_x             = 200,
+    move_c_x             = 201,
+    is_eq_exact_immed    = 202,
+    is_tuple_of_arity    = 203,
+    // Superinstructions, see `transform::SUPERINSTRUCTIONS`.
+    is_tuple_arity_get   = 204,
+    is_nonempty_get_list = 205,
+    move2_x_x            = 206,
+    // Calls of erlang:apply/2,3.
+    apply_list           = 207,
+    apply_list_last      = 208,
+    apply_list_only      = 209,
+    // More specialised instructions.
+    get_tuple_element_x  = 210,
+    put_list_x           = 211,
+    i_plus               = 212,
+    i_minus              = 213,
+    i_times              = 214,
+    is_integer_x         = 215,
+    is_atom_x            = 216,
+    is_nil_x             = 217,
+    is_list_x            = 218,
+    is_nonempty_list_x   = 219,
+    is_tuple_x           = 220,
+    test_heap_u          = 221
 }
 
 impl BEAMOpcode {
@@ -218,46 +620,325 @@ impl BEAMOpcode {
             2   => Some ( BEAMOpcode::func_info ),
             3   => Some ( BEAMOpcode::int_code_end ),
             4   => Some ( BEAMOpcode::call ),
+            5   => Some ( BEAMOpcode::call_last ),
             6   => Some ( BEAMOpcode::call_only ),
+            7   => Some ( BEAMOpcode::call_ext ),
+            8   => Some ( BEAMOpcode::call_ext_last ),
+            9   => Some ( BEAMOpcode::bif0 ),
+            10  => Some ( BEAMOpcode::bif1 ),
+            11  => Some ( BEAMOpcode::bif2 ),
+            12  => Some ( BEAMOpcode::allocate ),
+            13  => Some ( BEAMOpcode::allocate_heap ),
             14  => Some ( BEAMOpcode::allocate_zero ),
+            15  => Some ( BEAMOpcode::allocate_heap_zero ),
             16  => Some ( BEAMOpcode::test_heap ),
             18  => Some ( BEAMOpcode::deallocate ),
             19  => Some ( BEAMOpcode::return_ ),
+            20  => Some ( BEAMOpcode::send ),
+            21  => Some ( BEAMOpcode::remove_message ),
+            22  => Some ( BEAMOpcode::timeout ),
+            23  => Some ( BEAMOpcode::loop_rec ),
+            24  => Some ( BEAMOpcode::loop_rec_end ),
+            25  => Some ( BEAMOpcode::wait ),
+            26  => Some ( BEAMOpcode::wait_timeout ),
+            39  => Some ( BEAMOpcode::is_lt ),
+            40  => Some ( BEAMOpcode::is_ge ),
+            41  => Some ( BEAMOpcode::is_eq ),
+            42  => Some ( BEAMOpcode::is_ne ),
             43  => Some ( BEAMOpcode::is_eq_exact ),
+            44  => Some ( BEAMOpcode::is_ne_exact ),
+            45  => Some ( BEAMOpcode::is_integer ),
+            46  => Some ( BEAMOpcode::is_float ),
+            47  => Some ( BEAMOpcode::is_number ),
+            48  => Some ( BEAMOpcode::is_atom ),
+            52  => Some ( BEAMOpcode::is_nil ),
+            53  => Some ( BEAMOpcode::is_binary ),
+            55  => Some ( BEAMOpcode::is_list ),
+            56  => Some ( BEAMOpcode::is_nonempty_list ),
             57  => Some ( BEAMOpcode::is_tuple ),
             58  => Some ( BEAMOpcode::test_arity ),
+            59  => Some ( BEAMOpcode::select_val ),
+            60  => Some ( BEAMOpcode::select_tuple_arity ),
+            61  => Some ( BEAMOpcode::jump ),
+            62  => Some ( BEAMOpcode::catch ),
+            63  => Some ( BEAMOpcode::catch_end ),
             64  => Some ( BEAMOpcode::move_ ),
+            65  => Some ( BEAMOpcode::get_list ),
             66  => Some ( BEAMOpcode::get_tuple_element ),
+            67  => Some ( BEAMOpcode::set_tuple_element ),
+            69  => Some ( BEAMOpcode::put_list ),
             70  => Some ( BEAMOpcode::put_tuple ),
             71  => Some ( BEAMOpcode::put ),
+            72  => Some ( BEAMOpcode::badmatch ),
+            73  => Some ( BEAMOpcode::if_end ),
+            74  => Some ( BEAMOpcode::case_end ),
+            75  => Some ( BEAMOpcode::call_fun ),
+            77  => Some ( BEAMOpcode::is_function ),
             78  => Some ( BEAMOpcode::call_ext_only ),
+            89  => Some ( BEAMOpcode::bs_put_integer ),
+            90  => Some ( BEAMOpcode::bs_put_binary ),
+            91  => Some ( BEAMOpcode::bs_put_float ),
+            92  => Some ( BEAMOpcode::bs_put_string ),
+            94  => Some ( BEAMOpcode::fclearerror ),
+            95  => Some ( BEAMOpcode::fcheckerror ),
+            96  => Some ( BEAMOpcode::fmove ),
+            97  => Some ( BEAMOpcode::fconv ),
+            98  => Some ( BEAMOpcode::fadd ),
+            99  => Some ( BEAMOpcode::fsub ),
+            100 => Some ( BEAMOpcode::fmul ),
+            101 => Some ( BEAMOpcode::fdiv ),
+            102 => Some ( BEAMOpcode::fnegate ),
+            103 => Some ( BEAMOpcode::make_fun2 ),
+            104 => Some ( BEAMOpcode::try ),
+            105 => Some ( BEAMOpcode::try_end ),
+            106 => Some ( BEAMOpcode::try_case ),
+            107 => Some ( BEAMOpcode::try_case_end ),
+            108 => Some ( BEAMOpcode::raise ),
+            109 => Some ( BEAMOpcode::bs_init2 ),
+            111 => Some ( BEAMOpcode::bs_add ),
+            112 => Some ( BEAMOpcode::apply ),
+            113 => Some ( BEAMOpcode::apply_last ),
+            115 => Some ( BEAMOpcode::is_function2 ),
+            116 => Some ( BEAMOpcode::bs_start_match2 ),
+            117 => Some ( BEAMOpcode::bs_get_integer2 ),
+            118 => Some ( BEAMOpcode::bs_get_float2 ),
+            119 => Some ( BEAMOpcode::bs_get_binary2 ),
+            120 => Some ( BEAMOpcode::bs_skip_bits2 ),
+            121 => Some ( BEAMOpcode::bs_test_tail2 ),
+            122 => Some ( BEAMOpcode::bs_save2 ),
+            123 => Some ( BEAMOpcode::bs_restore2 ),
+            124 => Some ( BEAMOpcode::gc_bif1 ),
             125 => Some ( BEAMOpcode::gc_bif2 ),
+            129 => Some ( BEAMOpcode::is_bitstr ),
+            130 => Some ( BEAMOpcode::bs_context_to_binary ),
+            131 => Some ( BEAMOpcode::bs_test_unit ),
+            132 => Some ( BEAMOpcode::bs_match_string ),
+            134 => Some ( BEAMOpcode::bs_append ),
+            135 => Some ( BEAMOpcode::bs_private_append ),
+            137 => Some ( BEAMOpcode::bs_init_bits ),
+            138 => Some ( BEAMOpcode::bs_get_utf8 ),
+            139 => Some ( BEAMOpcode::bs_skip_utf8 ),
+            140 => Some ( BEAMOpcode::bs_get_utf16 ),
+            141 => Some ( BEAMOpcode::bs_skip_utf16 ),
+            142 => Some ( BEAMOpcode::bs_get_utf32 ),
+            143 => Some ( BEAMOpcode::bs_skip_utf32 ),
+            144 => Some ( BEAMOpcode::bs_utf8_size ),
+            145 => Some ( BEAMOpcode::bs_put_utf8 ),
+            146 => Some ( BEAMOpcode::bs_utf16_size ),
+            147 => Some ( BEAMOpcode::bs_put_utf16 ),
+            148 => Some ( BEAMOpcode::bs_put_utf32 ),
+            150 => Some ( BEAMOpcode::recv_mark ),
+            151 => Some ( BEAMOpcode::recv_set ),
+            152 => Some ( BEAMOpcode::gc_bif3 ),
             153 => Some ( BEAMOpcode::line ),
+            154 => Some ( BEAMOpcode::put_map_assoc ),
+            155 => Some ( BEAMOpcode::put_map_exact ),
+            156 => Some ( BEAMOpcode::is_map ),
+            157 => Some ( BEAMOpcode::has_map_fields ),
+            158 => Some ( BEAMOpcode::get_map_elements ),
+            162 => Some ( BEAMOpcode::get_hd ),
+            163 => Some ( BEAMOpcode::get_tl ),
+            165 => Some ( BEAMOpcode::bs_get_tail ),
+            166 => Some ( BEAMOpcode::bs_start_match3 ),
+            167 => Some ( BEAMOpcode::bs_get_position ),
+            168 => Some ( BEAMOpcode::bs_set_position ),
+            170 => Some ( BEAMOpcode::bs_start_match4 ),
+            177 => Some ( BEAMOpcode::bs_create_bin ),
+            181 => Some ( BEAMOpcode::update_record ),
+            182 => Some ( BEAMOpcode::bs_match ),
             _   => None
         }
     }
 
     fn arity(self) -> u8 {
         match self {
-            BEAMOpcode::label             => 1,
-            BEAMOpcode::func_info         => 3,
-            BEAMOpcode::int_code_end      => 0,
-            BEAMOpcode::call              => 2,
-            BEAMOpcode::call_only         => 2,
-            BEAMOpcode::allocate_zero     => 2,
-            BEAMOpcode::test_heap         => 2,
-            BEAMOpcode::deallocate        => 1,
-            BEAMOpcode::return_           => 0,
-            BEAMOpcode::is_eq_exact       => 3,
-            BEAMOpcode::is_tuple          => 2,
-            BEAMOpcode::test_arity        => 3,
-            BEAMOpcode::move_             => 2,
-            BEAMOpcode::get_tuple_element => 3,
-            BEAMOpcode::put_tuple         => 2,
-            BEAMOpcode::put               => 1,
-            BEAMOpcode::call_ext_only     => 2,
-            BEAMOpcode::gc_bif2           => 6,
-            BEAMOpcode::line              => 1
+            BEAMOpcode::label                => 1,
+            BEAMOpcode::func_info            => 3,
+            BEAMOpcode::int_code_end         => 0,
+            BEAMOpcode::call                 => 2,
+            BEAMOpcode::call_last            => 3,
+            BEAMOpcode::call_only            => 2,
+            BEAMOpcode::call_ext             => 2,
+            BEAMOpcode::call_ext_last        => 3,
+            BEAMOpcode::bif0                 => 2,
+            BEAMOpcode::bif1                 => 4,
+            BEAMOpcode::bif2                 => 5,
+            BEAMOpcode::allocate             => 2,
+            BEAMOpcode::allocate_heap        => 3,
+            BEAMOpcode::allocate_zero        => 2,
+            BEAMOpcode::allocate_heap_zero   => 3,
+            BEAMOpcode::test_heap            => 2,
+            BEAMOpcode::deallocate           => 1,
+            BEAMOpcode::return_              => 0,
+            BEAMOpcode::send                 => 0,
+            BEAMOpcode::remove_message       => 0,
+            BEAMOpcode::timeout              => 0,
+            BEAMOpcode::loop_rec             => 2,
+            BEAMOpcode::loop_rec_end         => 1,
+            BEAMOpcode::wait                 => 1,
+            BEAMOpcode::wait_timeout         => 2,
+            BEAMOpcode::is_lt                => 3,
+            BEAMOpcode::is_ge                => 3,
+            BEAMOpcode::is_eq                => 3,
+            BEAMOpcode::is_ne                => 3,
+            BEAMOpcode::is_eq_exact          => 3,
+            BEAMOpcode::is_ne_exact          => 3,
+            BEAMOpcode::is_integer           => 2,
+            BEAMOpcode::is_float             => 2,
+            BEAMOpcode::is_number            => 2,
+            BEAMOpcode::is_atom              => 2,
+            BEAMOpcode::is_nil               => 2,
+            BEAMOpcode::is_binary            => 2,
+            BEAMOpcode::is_list              => 2,
+            BEAMOpcode::is_nonempty_list     => 2,
+            BEAMOpcode::is_tuple             => 2,
+            BEAMOpcode::test_arity           => 3,
+            BEAMOpcode::select_val           => 3,
+            BEAMOpcode::select_tuple_arity   => 3,
+            BEAMOpcode::jump                 => 1,
+            BEAMOpcode::catch                => 2,
+            BEAMOpcode::catch_end            => 1,
+            BEAMOpcode::move_                => 2,
+            BEAMOpcode::get_list             => 3,
+            BEAMOpcode::get_tuple_element    => 3,
+            BEAMOpcode::set_tuple_element    => 3,
+            BEAMOpcode::put_list             => 3,
+            BEAMOpcode::put_tuple            => 2,
+            BEAMOpcode::put                  => 1,
+            BEAMOpcode::badmatch             => 1,
+            BEAMOpcode::if_end               => 0,
+            BEAMOpcode::case_end             => 1,
+            BEAMOpcode::call_fun             => 1,
+            BEAMOpcode::is_function          => 2,
+            BEAMOpcode::call_ext_only        => 2,
+            BEAMOpcode::bs_put_integer       => 5,
+            BEAMOpcode::bs_put_binary        => 5,
+            BEAMOpcode::bs_put_float         => 5,
+            BEAMOpcode::bs_put_string        => 2,
+            BEAMOpcode::fclearerror          => 0,
+            BEAMOpcode::fcheckerror          => 1,
+            BEAMOpcode::fmove                => 2,
+            BEAMOpcode::fconv                => 2,
+            BEAMOpcode::fadd                 => 4,
+            BEAMOpcode::fsub                 => 4,
+            BEAMOpcode::fmul                 => 4,
+            BEAMOpcode::fdiv                 => 4,
+            BEAMOpcode::fnegate              => 3,
+            BEAMOpcode::make_fun2            => 1,
+            BEAMOpcode::try                  => 2,
+            BEAMOpcode::try_end              => 1,
+            BEAMOpcode::try_case             => 1,
+            BEAMOpcode::try_case_end         => 1,
+            BEAMOpcode::raise                => 2,
+            BEAMOpcode::bs_init2             => 6,
+            BEAMOpcode::bs_add               => 5,
+            BEAMOpcode::apply                => 1,
+            BEAMOpcode::apply_last           => 2,
+            BEAMOpcode::is_function2         => 3,
+            BEAMOpcode::bs_start_match2      => 5,
+            BEAMOpcode::bs_get_integer2      => 7,
+            BEAMOpcode::bs_get_float2        => 7,
+            BEAMOpcode::bs_get_binary2       => 7,
+            BEAMOpcode::bs_skip_bits2        => 5,
+            BEAMOpcode::bs_test_tail2        => 3,
+            BEAMOpcode::bs_save2             => 2,
+            BEAMOpcode::bs_restore2          => 2,
+            BEAMOpcode::gc_bif1              => 5,
+            BEAMOpcode::gc_bif2              => 6,
+            BEAMOpcode::is_bitstr            => 2,
+            BEAMOpcode::bs_context_to_binary => 1,
+            BEAMOpcode::bs_test_unit         => 3,
+            BEAMOpcode::bs_match_string      => 4,
+            BEAMOpcode::bs_append            => 8,
+            BEAMOpcode::bs_private_append    => 6,
+            BEAMOpcode::bs_init_bits         => 6,
+            BEAMOpcode::bs_get_utf8          => 5,
+            BEAMOpcode::bs_skip_utf8         => 4,
+            BEAMOpcode::bs_get_utf16         => 5,
+            BEAMOpcode::bs_skip_utf16        => 4,
+            BEAMOpcode::bs_get_utf32         => 5,
+            BEAMOpcode::bs_skip_utf32        => 4,
+            BEAMOpcode::bs_utf8_size         => 3,
+            BEAMOpcode::bs_put_utf8          => 3,
+            BEAMOpcode::bs_utf16_size        => 3,
+            BEAMOpcode::bs_put_utf16         => 3,
+            BEAMOpcode::bs_put_utf32         => 3,
+            BEAMOpcode::recv_mark            => 1,
+            BEAMOpcode::recv_set             => 1,
+            BEAMOpcode::gc_bif3              => 7,
+            BEAMOpcode::line                 => 1,
+            BEAMOpcode::put_map_assoc        => 5,
+            BEAMOpcode::put_map_exact        => 5,
+            BEAMOpcode::is_map               => 2,
+            BEAMOpcode::has_map_fields       => 3,
+            BEAMOpcode::get_map_elements     => 3,
+            BEAMOpcode::get_hd               => 2,
+            BEAMOpcode::get_tl               => 2,
+            BEAMOpcode::bs_get_tail          => 3,
+            BEAMOpcode::bs_start_match3      => 4,
+            BEAMOpcode::bs_get_position      => 3,
+            BEAMOpcode::bs_set_position      => 2,
+            BEAMOpcode::bs_start_match4      => 4,
+            BEAMOpcode::bs_create_bin        => 6,
+            BEAMOpcode::update_record        => 5,
+            BEAMOpcode::bs_match             => 3,
+            BEAMOpcode::move_x_x             => 2,
+            BEAMOpcode::move_c_x             => 2,
+            BEAMOpcode::is_eq_exact_immed    => 3,
+            BEAMOpcode::is_tuple_of_arity    => 3,
+            BEAMOpcode::is_tuple_arity_get   => 5,
+            BEAMOpcode::is_nonempty_get_list => 4,
+            BEAMOpcode::move2_x_x            => 4,
+            BEAMOpcode::apply_list           => 1,
+            BEAMOpcode::apply_list_last      => 2,
+            BEAMOpcode::apply_list_only      => 1,
+            BEAMOpcode::get_tuple_element_x  => 3,
+            BEAMOpcode::put_list_x           => 3,
+            BEAMOpcode::i_plus               => 5,
+            BEAMOpcode::i_minus              => 5,
+            BEAMOpcode::i_times              => 5,
+            BEAMOpcode::is_integer_x         => 2,
+            BEAMOpcode::is_atom_x            => 2,
+            BEAMOpcode::is_nil_x             => 2,
+            BEAMOpcode::is_list_x            => 2,
+            BEAMOpcode::is_nonempty_list_x   => 2,
+            BEAMOpcode::is_tuple_x           => 2,
+            BEAMOpcode::test_heap_u          => 2
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        OPERATIONS.iter().chain(INTERNAL_OPERATIONS.iter())
+                  .find(|&&(code, _)| code == self as u8)
+                  .map_or("(invalid opcode)", |&(_, (name, _))| name)
+    }
+
+    // Any opcode of loaded code, including internal ones.
+    pub fn from_code(code: u8) -> Option<BEAMOpcode> {
+        match code {
+            200 => Some ( BEAMOpcode::move_x_x ),
+            201 => Some ( BEAMOpcode::move_c_x ),
+            202 => Some ( BEAMOpcode::is_eq_exact_immed ),
+            203 => Some ( BEAMOpcode::is_tuple_of_arity ),
+            204 => Some ( BEAMOpcode::is_tuple_arity_get ),
+            205 => Some ( BEAMOpcode::is_nonempty_get_list ),
+            206 => Some ( BEAMOpcode::move2_x_x ),
+            207 => Some ( BEAMOpcode::apply_list ),
+            208 => Some ( BEAMOpcode::apply_list_last ),
+            209 => Some ( BEAMOpcode::apply_list_only ),
+            210 => Some ( BEAMOpcode::get_tuple_element_x ),
+            211 => Some ( BEAMOpcode::put_list_x ),
+            212 => Some ( BEAMOpcode::i_plus ),
+            213 => Some ( BEAMOpcode::i_minus ),
+            214 => Some ( BEAMOpcode::i_times ),
+            215 => Some ( BEAMOpcode::is_integer_x ),
+            216 => Some ( BEAMOpcode::is_atom_x ),
+            217 => Some ( BEAMOpcode::is_nil_x ),
+            218 => Some ( BEAMOpcode::is_list_x ),
+            219 => Some ( BEAMOpcode::is_nonempty_list_x ),
+            220 => Some ( BEAMOpcode::is_tuple_x ),
+            221 => Some ( BEAMOpcode::test_heap_u ),
+            _   => BEAMOpcode::from_u8(code)
         }
     }
 
@@ -278,7 +959,7 @@ impl BEAMOpcode {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ArgTag {
     u,
     i,
@@ -286,7 +967,21 @@ pub enum ArgTag {
     x,
     y,
     f,
-    z
+    h,
+    // Extended list; the value is the number of operands which follow.
+    z,
+    // Tags below don't appear in .beam files, they're created by the loader
+    // (the names come from `beam_load.c`):
+    // literal,
+    q,
+    // float register,
+    fr,
+    // integer which didn't fit in the operand, i.e. a future literal.
+    o,
+    // and ours: index into `Emu::jump_tables`,
+    t,
+    // index into `Emu::exports`.
+    e
 }
 
 impl ArgTag {
@@ -299,35 +994,218 @@ impl ArgTag {
             3 => Some (ArgTag::x),
             4 => Some (ArgTag::y),
             5 => Some (ArgTag::f),
+            6 => Some (ArgTag::h),
             7 => Some (ArgTag::z),
             _ => None
         }
     }
 
+    // The inverse of `tag as u8`, for operands of loaded code.
+    fn from_code(code: u8) -> ArgTag {
+        match code {
+            0 => ArgTag::u,
+            1 => ArgTag::i,
+            2 => ArgTag::a,
+            3 => ArgTag::x,
+            4 => ArgTag::y,
+            5 => ArgTag::f,
+            6 => ArgTag::h,
+            7 => ArgTag::z,
+            8 => ArgTag::q,
+            9 => ArgTag::fr,
+            10 => ArgTag::o,
+            11 => ArgTag::t,
+            _ => ArgTag::e
+        }
+    }
+
+}
+
+#[test]
+fn test_jump_tables() {
+    let select = |code, list: Vec<(ArgTag, u32)>| {
+        let mut args = vec![(ArgTag::x, 0), (ArgTag::f, 99), (ArgTag::z, list.len() as u32)];
+        args.extend(list);
+        JumpTable::from_op(&Op { code: code, args: args })
+    };
+    // 1, 2 and 4, but not 3.
+    let dense = select(BEAMOpcode::select_val,
+                       vec![(ArgTag::i, 4), (ArgTag::f, 40), (ArgTag::i, 1), (ArgTag::f, 10),
+                            (ArgTag::i, 2), (ArgTag::f, 20)]).unwrap();
+    assert_eq!(JumpTable::Dense(1, vec![10, 20, 99, 40]), dense);
+    assert_eq!("dense 1 .. 4 -> 10 20 99 40", dense.to_string());
+    assert_eq!(Some (20), dense.lookup(SelectKey::Integer(2)));
+    assert_eq!(None, dense.lookup(SelectKey::Integer(5)));
+    assert_eq!(None, dense.lookup(SelectKey::Atom(1)));
+    let sparse = select(BEAMOpcode::select_val,
+                        vec![(ArgTag::i, 1000), (ArgTag::f, 10), (ArgTag::i, -7i32 as u32), (ArgTag::f, 20),
+                             (ArgTag::a, 0), (ArgTag::f, 30)]).unwrap();
+    assert_eq!("sorted -7 -> 20 1000 -> 10 atom 0 -> 30", sparse.to_string());
+    assert_eq!(Some (30), sparse.lookup(SelectKey::Atom(0)));
+    assert_eq!(Some (10), sparse.lookup(SelectKey::Integer(1000)));
+    assert_eq!(None, sparse.lookup(SelectKey::Integer(999)));
+    let arities = select(BEAMOpcode::select_tuple_arity,
+                         vec![(ArgTag::u, 3), (ArgTag::f, 30), (ArgTag::u, 2), (ArgTag::f, 20)]).unwrap();
+    assert_eq!(Some (30), arities.lookup(SelectKey::Integer(3)));
+    // Bignums are literals: no table.
+    assert_eq!(None, select(BEAMOpcode::select_val, vec![(ArgTag::q, 0), (ArgTag::f, 10)]));
+}
+
+#[test]
+fn test_code_stream() {
+    let ops = vec![
+        Op { code: BEAMOpcode::move_c_x, args: vec![(ArgTag::q, 7), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::return_, args: vec![] },
+        Op { code: BEAMOpcode::select_val, args: vec![(ArgTag::x, 0), (ArgTag::f, 3), (ArgTag::t, 1)] }
+    ];
+    let mut code = vec![];
+    for op in ops.iter()
+        { op.encode(&mut code) }
+    assert_eq!(ops.iter().map(|op| op.size()).sum::<usize>(), code.len());
+    let starts: Vec<usize> = (0 .. code.len()).filter(|&i| is_instruction(code[i])).collect();
+    assert_eq!(vec![0, 3, 4], starts);
+    for (&at, op) in starts.iter().zip(ops.iter()) {
+        let decoded = Op::decode(&code, at);
+        assert_eq!((op.code, &op.args), (decoded.code, &decoded.args));
+    }
 }
 
 #[test]
 fn test_max_opcode() {
-    assert_eq!( 153, BEAMOpcode::max_opcode() );
+    assert_eq!( 182, BEAMOpcode::max_opcode() );
 }
 
 trait BEAMOps<T> {
-    fn call_ext_only()      -> T;
-    fn call_only()          -> T;
-    fn func_info()          -> T;
-    fn gc_bif2()            -> T;
-    fn get_tuple_element()  -> T;
-    fn int_code_end()       -> T;
-    fn is_eq_exact()        -> T;
-    fn is_tuple()           -> T;
-    fn label()              -> T;
-    fn line()               -> T;
-    fn move_()              -> T;
-    fn put()                -> T;
-    fn put_tuple()          -> T;
-    fn return_()            -> T;
-    fn test_arity()         -> T;
-    fn test_heap()          -> T;
+    fn allocate()             -> T;
+    fn allocate_heap()        -> T;
+    fn allocate_heap_zero()   -> T;
+    fn allocate_zero()        -> T;
+    fn apply()                -> T;
+    fn apply_last()           -> T;
+    fn badmatch()             -> T;
+    fn bif0()                 -> T;
+    fn bif1()                 -> T;
+    fn bif2()                 -> T;
+    fn bs_add()               -> T;
+    fn bs_append()            -> T;
+    fn bs_context_to_binary() -> T;
+    fn bs_create_bin()        -> T;
+    fn bs_get_binary2()       -> T;
+    fn bs_get_float2()        -> T;
+    fn bs_get_integer2()      -> T;
+    fn bs_get_position()      -> T;
+    fn bs_get_tail()          -> T;
+    fn bs_get_utf16()         -> T;
+    fn bs_get_utf32()         -> T;
+    fn bs_get_utf8()          -> T;
+    fn bs_init2()             -> T;
+    fn bs_init_bits()         -> T;
+    fn bs_match()             -> T;
+    fn bs_match_string()      -> T;
+    fn bs_private_append()    -> T;
+    fn bs_put_binary()        -> T;
+    fn bs_put_float()         -> T;
+    fn bs_put_integer()       -> T;
+    fn bs_put_string()        -> T;
+    fn bs_put_utf16()         -> T;
+    fn bs_put_utf32()         -> T;
+    fn bs_put_utf8()          -> T;
+    fn bs_restore2()          -> T;
+    fn bs_save2()             -> T;
+    fn bs_set_position()      -> T;
+    fn bs_skip_bits2()        -> T;
+    fn bs_skip_utf16()        -> T;
+    fn bs_skip_utf32()        -> T;
+    fn bs_skip_utf8()         -> T;
+    fn bs_start_match2()      -> T;
+    fn bs_start_match3()      -> T;
+    fn bs_start_match4()      -> T;
+    fn bs_test_tail2()        -> T;
+    fn bs_test_unit()         -> T;
+    fn bs_utf16_size()        -> T;
+    fn bs_utf8_size()         -> T;
+    fn call()                 -> T;
+    fn call_ext()             -> T;
+    fn call_ext_last()        -> T;
+    fn call_ext_only()        -> T;
+    fn call_fun()             -> T;
+    fn call_last()            -> T;
+    fn call_only()            -> T;
+    fn case_end()             -> T;
+    fn catch()                -> T;
+    fn catch_end()            -> T;
+    fn deallocate()           -> T;
+    fn fadd()                 -> T;
+    fn fcheckerror()          -> T;
+    fn fclearerror()          -> T;
+    fn fconv()                -> T;
+    fn fdiv()                 -> T;
+    fn fmove()                -> T;
+    fn fmul()                 -> T;
+    fn fnegate()              -> T;
+    fn fsub()                 -> T;
+    fn func_info()            -> T;
+    fn gc_bif1()              -> T;
+    fn gc_bif2()              -> T;
+    fn gc_bif3()              -> T;
+    fn get_hd()               -> T;
+    fn get_list()             -> T;
+    fn get_map_elements()     -> T;
+    fn get_tl()               -> T;
+    fn get_tuple_element()    -> T;
+    fn has_map_fields()       -> T;
+    fn if_end()               -> T;
+    fn int_code_end()         -> T;
+    fn is_atom()              -> T;
+    fn is_binary()            -> T;
+    fn is_bitstr()            -> T;
+    fn is_eq()                -> T;
+    fn is_eq_exact()          -> T;
+    fn is_float()             -> T;
+    fn is_function()          -> T;
+    fn is_function2()         -> T;
+    fn is_ge()                -> T;
+    fn is_integer()           -> T;
+    fn is_list()              -> T;
+    fn is_lt()                -> T;
+    fn is_map()               -> T;
+    fn is_ne()                -> T;
+    fn is_ne_exact()          -> T;
+    fn is_nil()               -> T;
+    fn is_nonempty_list()     -> T;
+    fn is_number()            -> T;
+    fn is_tuple()             -> T;
+    fn jump()                 -> T;
+    fn label()                -> T;
+    fn line()                 -> T;
+    fn loop_rec()             -> T;
+    fn loop_rec_end()         -> T;
+    fn make_fun2()            -> T;
+    fn move_()                -> T;
+    fn put()                  -> T;
+    fn put_list()             -> T;
+    fn put_map_assoc()        -> T;
+    fn put_map_exact()        -> T;
+    fn put_tuple()            -> T;
+    fn raise()                -> T;
+    fn recv_mark()            -> T;
+    fn recv_set()             -> T;
+    fn remove_message()       -> T;
+    fn return_()              -> T;
+    fn select_tuple_arity()   -> T;
+    fn select_val()           -> T;
+    fn send()                 -> T;
+    fn set_tuple_element()    -> T;
+    fn test_arity()           -> T;
+    fn test_heap()            -> T;
+    fn timeout()              -> T;
+    fn try()                  -> T;
+    fn try_case()             -> T;
+    fn try_case_end()         -> T;
+    fn try_end()              -> T;
+    fn update_record()        -> T;
+    fn wait()                 -> T;
+    fn wait_timeout()         -> T;
 }
 
 pub const OPERATIONS: &'static [(u8, (&'static str, u8))] =
@@ -489,4 +1367,81 @@ pub const OPERATIONS: &'static [(u8, (&'static str, u8))] =
       (155,("put_map_exact",5)),
       (156,("is_map",2)),
       (157,("has_map_fields",3)),
-      (158,("get_map_elements",3))];
+      (158,("get_map_elements",3)),
+      (159,("is_tagged_tuple",4)),
+      (160,("build_stacktrace",0)),
+      (161,("raw_raise",0)),
+      (162,("get_hd",2)),
+      (163,("get_tl",2)),
+      (164,("put_tuple2",2)),
+      (165,("bs_get_tail",3)),
+      (166,("bs_start_match3",4)),
+      (167,("bs_get_position",3)),
+      (168,("bs_set_position",2)),
+      (169,("swap",2)),
+      (170,("bs_start_match4",4)),
+      (171,("make_fun3",3)),
+      (172,("init_yregs",1)),
+      (173,("recv_marker_bind",2)),
+      (174,("recv_marker_clear",1)),
+      (175,("recv_marker_reserve",1)),
+      (176,("recv_marker_use",1)),
+      (177,("bs_create_bin",6)),
+      (178,("call_fun2",3)),
+      (179,("nif_start",0)),
+      (180,("badrecord",1)),
+      (181,("update_record",5)),
+      (182,("bs_match",3)),
+      (183,("executable_line",2))];
+
+// Instructions only `transform` creates, with their operands:
+//
+//   move_x_x Src Dst               both X registers
+//   move_c_x Const Dst             an immediate literal, an X register
+//   is_eq_exact_immed Fail Src Const
+//                                  an X register, an immediate literal
+//   is_tuple_of_arity Fail Src Arity
+//                                  an X register, a number
+//   is_tuple_arity_get Fail Src Arity Index Dst
+//                                  is_tuple_of_arity, then get_tuple_element
+//   is_nonempty_get_list Fail Src Head Tail
+//                                  is_nonempty_list, then get_list
+//   move2_x_x Src1 Dst1 Src2 Dst2  two move_x_x, in order
+//   apply_list Arity               call_ext of erlang:apply/Arity: the
+//                                  function (a fun, or a module and a
+//                                  function name) and the argument list
+//                                  are in the X registers
+//   apply_list_last Arity Dealloc
+//   apply_list_only Arity
+//   get_tuple_element_x Src Index Dst
+//                                  X registers, a number
+//   put_list_x Head Tail Dst       all X registers
+//   i_plus Fail Live Src1 Src2 Dst gc_bif2 of erlang:+/2, -/2 or */2 on
+//   i_minus ...                    X registers or integers, into an X
+//   i_times ...                    register; the import is gone
+//   is_integer_x Fail Src          the type test of an X register
+//   is_atom_x, is_nil_x, is_list_x, is_nonempty_list_x, is_tuple_x
+//   test_heap_u Need Live          a number of words, no allocation list
+pub const INTERNAL_OPERATIONS: &'static [(u8, (&'static str, u8))] =
+    &[(200,("move_x_x",2)),
+      (201,("move_c_x",2)),
+      (202,("is_eq_exact_immed",3)),
+      (203,("is_tuple_of_arity",3)),
+      (204,("is_tuple_arity_get",5)),
+      (205,("is_nonempty_get_list",4)),
+      (206,("move2_x_x",4)),
+      (207,("apply_list",1)),
+      (208,("apply_list_last",2)),
+      (209,("apply_list_only",1)),
+      (210,("get_tuple_element_x",3)),
+      (211,("put_list_x",3)),
+      (212,("i_plus",5)),
+      (213,("i_minus",5)),
+      (214,("i_times",5)),
+      (215,("is_integer_x",2)),
+      (216,("is_atom_x",2)),
+      (217,("is_nil_x",2)),
+      (218,("is_list_x",2)),
+      (219,("is_nonempty_list_x",2)),
+      (220,("is_tuple_x",2)),
+      (221,("test_heap_u",2))];
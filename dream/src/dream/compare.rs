@@ -0,0 +1,207 @@
+// Term comparison: `==`, `=:=`, `<` and friends.
+//
+// Terms of different types are ordered as
+//
+//   number < atom < reference < fun < port < pid < tuple < map < nil
+//          < list < bitstring
+//
+// Numbers compare by value, so `1 == 1.0`.  Exact comparison (`=:=`) also
+// tells integers from floats: an integer is then smaller than the float of
+// the same value.  Atoms compare by name, tuples by size and then element
+// by element, lists element by element, binaries byte by byte.  Maps
+// compare by size, then by their keys in term order (exactly, whatever the
+// comparison) and then by the values of those keys.
+
+use atoms::AtomTable;
+use bignum::BigInt;
+use heap::Heap;
+use map;
+use std::cmp::Ordering;
+use term::{ Header, Number, Term };
+
+pub fn compare(heap: &Heap, atoms: &AtomTable, a: Term, b: Term) -> Ordering {
+    cmp(heap, atoms, a, b, false)
+}
+
+pub fn compare_exact(heap: &Heap, atoms: &AtomTable, a: Term, b: Term) -> Ordering {
+    cmp(heap, atoms, a, b, true)
+}
+
+// `==`
+pub fn eq(heap: &Heap, atoms: &AtomTable, a: Term, b: Term) -> bool {
+    a == b || compare(heap, atoms, a, b) == Ordering::Equal
+}
+
+// `=:=`
+pub fn eq_exact(heap: &Heap, atoms: &AtomTable, a: Term, b: Term) -> bool {
+    a == b || compare_exact(heap, atoms, a, b) == Ordering::Equal
+}
+
+// Position of the term's type in the standard order.
+// Types we don't have yet keep their place.
+fn rank(heap: &Heap, t: Term) -> u8 {
+    match t {
+        Term::Small(_) => 0,
+        Term::Atom(_) => 1,
+        Term::Ref(_) => 2,
+        // port 4
+        Term::Pid(_) => 5,
+        Term::Boxed(_) => match t.header(heap) {
+            Some (Header::Fun(_)) => 3,
+            Some (Header::Tuple(_)) => 6,
+            Some (Header::FlatMap(_)) | Some (Header::HashMap(_)) => 7,
+            _ if t.is_bitstring(heap) => 10,
+            _ => 0
+        },
+        Term::Nil => 8,
+        Term::Cons(_) => 9,
+        _ => 11
+    }
+}
+
+fn cmp(heap: &Heap, atoms: &AtomTable, a: Term, b: Term, exact: bool) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    // Loop instead of recursing on list tails.
+    loop {
+        if a == b
+            { return Ordering::Equal }
+        let (rank_a, rank_b) = (rank(heap, a), rank(heap, b));
+        if rank_a != rank_b
+            { return rank_a.cmp(&rank_b) }
+        match (a, b) {
+            (Term::Atom(x), Term::Atom(y)) =>
+                return atoms.get_atom(x).cmp(&atoms.get_atom(y)),
+            (Term::Pid(x), Term::Pid(y)) |
+            (Term::Ref(x), Term::Ref(y)) => return x.cmp(&y),
+            (Term::Cons(x), Term::Cons(y)) => {
+                let heads = cmp(heap, atoms, heap.get(x), heap.get(y), exact);
+                if heads != Ordering::Equal
+                    { return heads }
+                a = heap.get(x + 1);
+                b = heap.get(y + 1);
+            },
+            _ => return cmp_boxed(heap, atoms, a, b, exact)
+        }
+    }
+}
+
+// Terms of the same rank which are neither atoms, pids nor lists.
+fn cmp_boxed(heap: &Heap, atoms: &AtomTable, a: Term, b: Term, exact: bool) -> Ordering {
+    if let (Some (xs), Some (ys)) = (a.tuple_elements(heap), b.tuple_elements(heap))
+        { return cmp_elements(heap, atoms, xs, ys, exact) }
+    // Funs by index, then by free variables.
+    if let (Some ((x, xs)), Some ((y, ys))) = (a.fun_parts(heap), b.fun_parts(heap)) {
+        return if x != y { x.cmp(&y) }
+               else { cmp_elements(heap, atoms, xs, ys, exact) }
+    }
+    if let (Some (xs), Some (ys)) = (map::sorted_pairs(heap, atoms, a),
+                                     map::sorted_pairs(heap, atoms, b)) {
+        if xs.len() != ys.len()
+            { return xs.len().cmp(&ys.len()) }
+        let (keys_x, values_x): (Vec<Term>, Vec<Term>) = xs.into_iter().unzip();
+        let (keys_y, values_y): (Vec<Term>, Vec<Term>) = ys.into_iter().unzip();
+        return cmp_elements(heap, atoms, &keys_x, &keys_y, true)
+                 .then_with(|| cmp_elements(heap, atoms, &values_x, &values_y, exact))
+    }
+    // Bitstrings bit by bit, a prefix before the longer bitstring.
+    if let (Some (xs), Some (ys)) = (a.bits(heap), b.bits(heap)) {
+        let common = xs.size.min(ys.size);
+        return xs.read_bytes(0, common).cmp(&ys.read_bytes(0, common))
+                 .then(xs.size.cmp(&ys.size))
+    }
+    match (a.number(heap), b.number(heap)) {
+        (Some (x), Some (y)) => compare_numbers(&x, &y, exact),
+        _ => Ordering::Equal
+    }
+}
+
+// Sizes first, then element by element.
+fn cmp_elements(heap: &Heap, atoms: &AtomTable, xs: &[Term], ys: &[Term], exact: bool)
+    -> Ordering
+{
+    if xs.len() != ys.len()
+        { return xs.len().cmp(&ys.len()) }
+    for (&x, &y) in xs.iter().zip(ys.iter()) {
+        let elements = cmp(heap, atoms, x, y, exact);
+        if elements != Ordering::Equal
+            { return elements }
+    }
+    Ordering::Equal
+}
+
+fn compare_numbers(a: &Number, b: &Number, exact: bool) -> Ordering {
+    match (a, b) {
+        (&Number::Small(x), &Number::Small(y)) => x.cmp(&y),
+        (&Number::Float(x), &Number::Float(y)) =>
+            x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        (&Number::Float(_), _) => compare_numbers(b, a, exact).reverse(),
+        (_, &Number::Float(y)) => {
+            let by_value = compare_integer_float(&a.to_big(), y);
+            if by_value == Ordering::Equal && exact { Ordering::Less }
+            else { by_value }
+        },
+        _ => a.to_big().cmp(&b.to_big())
+    }
+}
+
+// Exact, unlike converting the integer to a float.
+fn compare_integer_float(i: &BigInt, f: f64) -> Ordering {
+    let integral = i.cmp(&BigInt::from_f64(f));
+    if integral != Ordering::Equal
+        { return integral }
+    let fraction = f - f.trunc();
+    if fraction > 0.0 { Ordering::Less }
+    else if fraction < 0.0 { Ordering::Greater }
+    else { Ordering::Equal }
+}
+
+#[test]
+fn test_numbers() {
+    let mut heap = Heap::new();
+    let atoms = AtomTable::new();
+    let one = Term::float(&mut heap, 1.0);
+    let big = Term::integer(&mut heap, &BigInt::from_i64(i64::max_value()).mul(&BigInt::from_i64(4)));
+    let huge = Term::float(&mut heap, 1.0e30);
+    let half = Term::float(&mut heap, -0.5);
+    assert!(eq(&heap, &atoms, Term::Small(1), one));
+    assert!(!eq_exact(&heap, &atoms, Term::Small(1), one));
+    assert_eq!(Ordering::Less, compare_exact(&heap, &atoms, Term::Small(1), one));
+    assert_eq!(Ordering::Less, compare(&heap, &atoms, big, huge));
+    assert_eq!(Ordering::Greater, compare(&heap, &atoms, big, Term::Small(3)));
+    assert_eq!(Ordering::Less, compare(&heap, &atoms, half, Term::Small(0)));
+    assert_eq!(Ordering::Less, compare(&heap, &atoms, Term::Small(-1), half));
+    // 2^53 + 1 isn't representable as a float.
+    let f = Term::float(&mut heap, 9007199254740992.0);
+    assert_eq!(Ordering::Greater, compare(&heap, &atoms, Term::Small(9007199254740993), f));
+}
+
+#[test]
+fn test_standard_order() {
+    let mut heap = Heap::new();
+    let atoms = AtomTable::new();
+    let (a, b) = (Term::Atom(atoms.add("b")), Term::Atom(atoms.add("a")));
+    let t1 = Term::tuple(&mut heap, &[a]);
+    let t2 = Term::tuple(&mut heap, &[a, b]);
+    let t3 = Term::tuple(&mut heap, &[b, a]);
+    let l1 = Term::cons(&mut heap, Term::Small(1), Term::Nil);
+    let l2 = Term::cons(&mut heap, Term::Small(1), Term::Small(2));
+    // Tails compare as terms too: `[1|2] < [1]` since numbers sort before nil.
+    let bin1 = Term::binary(&mut heap, b"ab");
+    let bin2 = Term::binary(&mut heap, &[b'b'; 100]);
+    // <<"a",1:1>> and <<"a",0:2>>.
+    let bits1 = Term::bitstring(&mut heap, &[b'a', 0x80], 9);
+    let bits2 = Term::bitstring(&mut heap, &[b'a', 0], 10);
+    // #{a => 1}, #{b => 0} and #{a => 1, b => 0}.
+    let m1 = map::from_pairs(&mut heap, &atoms, &[(b, Term::Small(1))]);
+    let m2 = map::from_pairs(&mut heap, &atoms, &[(a, Term::Small(0))]);
+    let m3 = map::from_pairs(&mut heap, &atoms, &[(a, Term::Small(0)), (b, Term::Small(1))]);
+    let sorted = [Term::Small(10), b, a, t1, t3, t2, m1, m2, m3, Term::Nil, l2, l1,
+                  bits2, bin1, bits1, bin2];
+    for (i, &x) in sorted.iter().enumerate() {
+        for (j, &y) in sorted.iter().enumerate() {
+            assert_eq!(i.cmp(&j), compare(&heap, &atoms, x, y), "{:?} {:?}", x, y);
+        }
+    }
+    let t4 = Term::tuple(&mut heap, &[b, a]);
+    assert!(eq_exact(&heap, &atoms, t3, t4));
+}
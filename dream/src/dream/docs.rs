@@ -0,0 +1,384 @@
+// EEP-48 documentation chunk.
+//
+// The `Docs` chunk is a `term_to_binary/1` encoded `docs_v1` record:
+//
+//     {docs_v1, Anno, BeamLanguage, Format, ModuleDoc, Metadata, Docs}
+//
+// where each element of `Docs` is:
+//
+//     {{Kind, Name, Arity}, Anno, Signature, Doc, Metadata}
+//
+// See http://erlang.org/eeps/eep-0048.html for details.
+
+use super::beam;
+use super::etf;
+use super::etf::Term;
+
+#[derive(Debug)]
+pub struct Docs {
+    pub anno:           Term,
+    pub beam_language:  String,
+    // MIME type of the doc values, usually "text/markdown".
+    pub format:         String,
+    pub module_doc:     Doc,
+    pub metadata:       Vec<(Term, Term)>,
+    pub entries:        Vec<Entry>
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    // `function`, `type`, `callback`, ...
+    pub kind:       String,
+    pub name:       String,
+    pub arity:      u32,
+    pub anno:       Term,
+    pub signature:  Vec<String>,
+    pub doc:        Doc,
+    pub metadata:   Vec<(Term, Term)>
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Doc {
+    // No documentation was written.
+    None,
+    // Explicitly hidden from documentation, i.e. `@private` or `-doc false`.
+    Hidden,
+    // Doc values by language, e.g. `("en", "Computes factorial.")`.
+    Text(Vec<(String, String)>),
+    // Doc values which aren't text, e.g. `application/erlang+html` docs
+    // of OTP modules: the whole map, undecoded.
+    Raw(Term)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Decode(etf::Error),
+    // The term decodes fine, but isn't a valid `docs_v1` record;
+    // the string describes which part is wrong.
+    Malformed(&'static str)
+}
+
+pub type DocsResult<T> = Result<T, Error>;
+
+impl Docs {
+
+    pub fn from_chunk(chunk: &beam::Chunk) -> DocsResult<Docs> {
+        let term = try!( etf::decode(&chunk.data).map_err(Error::Decode) );
+        Docs::from_term(&term)
+    }
+
+    pub fn from_term(term: &Term) -> DocsResult<Docs> {
+        let record = try!( term.as_tuple()
+                               .ok_or(Error::Malformed("docs_v1 record")) );
+        if record.len() != 7 || record[0].as_atom() != Some ("docs_v1")
+            { return Err (Error::Malformed("docs_v1 record")) }
+        let beam_language = try!( record[2].as_atom()
+                                           .ok_or(Error::Malformed("beam language")) );
+        let format = try!( record[3].as_string()
+                                    .ok_or(Error::Malformed("format")) );
+        let module_doc = try!( doc_from_term(&record[4]) );
+        let metadata = try!( metadata_from_term(&record[5]) );
+        let entry_terms = try!( record[6].as_list()
+                                         .ok_or(Error::Malformed("docs list")) );
+        let mut entries = vec![];
+        for entry in entry_terms
+            { entries.push(try!( entry_from_term(entry) )) }
+        Ok (Docs { anno: record[1].clone(),
+                   beam_language: beam_language.to_string(),
+                   format: format,
+                   module_doc: module_doc,
+                   metadata: metadata,
+                   entries: entries })
+    }
+
+    // Entries of a given name, optionally narrowed down to a given arity.
+    pub fn find(&self, name: &str, arity: Option<u32>) -> Vec<&Entry> {
+        self.entries.iter()
+            .filter(|e| e.name == name && arity.map_or(true, |a| a == e.arity))
+            .collect()
+    }
+
+}
+
+impl Doc {
+
+    // Doc in the preferred language - English if available.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            &Doc::Text(ref texts) =>
+                texts.iter().find(|&&(ref lang, _)| lang == "en")
+                     .or(texts.first())
+                     .map(|&(_, ref text)| &text[..]),
+            _ => None
+        }
+    }
+
+}
+
+// Look up a metadata value by an atom key, e.g. `since` or `deprecated`.
+pub fn metadata_value<'a>(metadata: &'a [(Term, Term)], key: &str) -> Option<&'a Term> {
+    metadata.iter()
+            .find(|&&(ref k, _)| k.as_atom() == Some (key))
+            .map(|&(_, ref v)| v)
+}
+
+// Erlang functions have at most this many arguments.
+const MAX_ARITY: i64 = 255;
+
+fn entry_from_term(term: &Term) -> DocsResult<Entry> {
+    let entry = try!( term.as_tuple().ok_or(Error::Malformed("doc entry")) );
+    if entry.len() != 5
+        { return Err (Error::Malformed("doc entry")) }
+    let kna = try!( entry[0].as_tuple().ok_or(Error::Malformed("doc entry key")) );
+    if kna.len() != 3
+        { return Err (Error::Malformed("doc entry key")) }
+    let kind = try!( kna[0].as_atom().ok_or(Error::Malformed("doc entry kind")) );
+    let name = try!( kna[1].as_atom().ok_or(Error::Malformed("doc entry name")) );
+    let arity = try!( kna[2].as_integer()
+                            .filter(|&a| a >= 0 && a <= MAX_ARITY)
+                            .ok_or(Error::Malformed("doc entry arity")) );
+    let signature_terms = try!( entry[2].as_list()
+                                        .ok_or(Error::Malformed("signature")) );
+    let mut signature = vec![];
+    for s in signature_terms
+        { signature.push(try!( s.as_string().ok_or(Error::Malformed("signature")) )) }
+    Ok (Entry { kind: kind.to_string(),
+                name: name.to_string(),
+                arity: arity as u32,
+                anno: entry[1].clone(),
+                signature: signature,
+                doc: try!( doc_from_term(&entry[3]) ),
+                metadata: try!( metadata_from_term(&entry[4]) ) })
+}
+
+fn doc_from_term(term: &Term) -> DocsResult<Doc> {
+    match term.as_atom() {
+        Some ("none") => return Ok (Doc::None),
+        Some ("hidden") => return Ok (Doc::Hidden),
+        _ => {}
+    }
+    let pairs = try!( term.as_map().ok_or(Error::Malformed("doc value")) );
+    let mut texts = vec![];
+    for &(ref lang, ref text) in pairs {
+        let lang = try!( lang.as_string().ok_or(Error::Malformed("doc language")) );
+        // Only text formats (Markdown, plain text) are rendered, others
+        // are kept as they are.
+        match text.as_string() {
+            Some (text) => texts.push((lang, text)),
+            None => return Ok (Doc::Raw(term.clone()))
+        }
+    }
+    Ok (Doc::Text(texts))
+}
+
+fn metadata_from_term(term: &Term) -> DocsResult<Vec<(Term, Term)>> {
+    term.as_map()
+        .map(|pairs| pairs.to_vec())
+        .ok_or(Error::Malformed("metadata"))
+}
+
+const BOLD: &'static str = "\x1b[1m";
+const ITALIC: &'static str = "\x1b[3m";
+const UNDERLINE: &'static str = "\x1b[4m";
+const CODE: &'static str = "\x1b[36m";
+const RESET: &'static str = "\x1b[0m";
+
+// Render a Markdown doc value for display in a terminal.
+//
+// Only the subset of Markdown commonly used in Erlang docs is understood:
+// headings, fenced code blocks, lists, block quotes, inline code, emphasis
+// and links.  Anything else is printed verbatim.
+pub fn render_markdown(markdown: &str) -> String {
+    let mut s = String::new();
+    let mut in_code_block = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+            continue
+        }
+        if in_code_block {
+            s.push_str(&format!("    {}{}{}\n", CODE, line, RESET));
+        } else if trimmed.starts_with('#') {
+            let level = trimmed.chars().take_while(|&c| c == '#').count();
+            let style = if level == 1 { UNDERLINE } else { "" };
+            s.push_str(&format!("{}{}{}{}\n", BOLD, style,
+                                render_inline(trimmed[level..].trim()), RESET));
+        } else if trimmed.starts_with("- ") || trimmed.starts_with("* ") {
+            let indent = line.len() - trimmed.len();
+            s.push_str(&format!("{}  • {}\n", &line[..indent],
+                                render_inline(&trimmed[2..])));
+        } else if trimmed.starts_with('>') {
+            s.push_str(&format!("  │ {}\n",
+                                render_inline(trimmed[1..].trim_start())));
+        } else {
+            s.push_str(&render_inline(line));
+            s.push('\n');
+        }
+    }
+    s
+}
+
+fn render_inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut s = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '`' {
+            if let Some (end) = find_char(&chars, i + 1, '`') {
+                let code: String = chars[i+1 .. end].iter().cloned().collect();
+                s.push_str(&format!("{}{}{}", CODE, code, RESET));
+                i = end + 1;
+                continue
+            }
+        } else if c == '*' && chars.get(i + 1) == Some (&'*') {
+            if let Some (end) = find_pair(&chars, i + 2, '*') {
+                let bold: String = chars[i+2 .. end].iter().cloned().collect();
+                s.push_str(&format!("{}{}{}", BOLD, render_inline(&bold), RESET));
+                i = end + 2;
+                continue
+            }
+        } else if c == '*' || (c == '_' && at_word_boundary(&chars, i)) {
+            let end = if c == '*' { find_char(&chars, i + 1, c) }
+                      else { find_underscore_end(&chars, i + 1) };
+            if let Some (end) = end {
+                let em: String = chars[i+1 .. end].iter().cloned().collect();
+                s.push_str(&format!("{}{}{}", ITALIC, render_inline(&em), RESET));
+                i = end + 1;
+                continue
+            }
+        } else if c == '[' {
+            if let Some (close) = find_char(&chars, i + 1, ']') {
+                if chars.get(close + 1) == Some (&'(') {
+                    if let Some (end) = find_char(&chars, close + 2, ')') {
+                        let label: String = chars[i+1 .. close].iter().cloned().collect();
+                        let url: String = chars[close+2 .. end].iter().cloned().collect();
+                        s.push_str(&render_inline(&label));
+                        if url.starts_with("http")
+                            { s.push_str(&format!(" <{}{}{}>", UNDERLINE, url, RESET)) }
+                        i = end + 1;
+                        continue
+                    }
+                }
+            }
+        }
+        s.push(c);
+        i += 1;
+    }
+    s
+}
+
+fn find_char(chars: &[char], from: usize, c: char) -> Option<usize> {
+    (from..chars.len()).find(|&i| chars[i] == c)
+}
+
+// `_` delimits emphasis only around words, as in `_this_`, not inside
+// them, as in `list_to_atom`.
+fn at_word_boundary(chars: &[char], i: usize) -> bool {
+    i == 0 || !chars[i - 1].is_alphanumeric()
+}
+
+fn find_underscore_end(chars: &[char], from: usize) -> Option<usize> {
+    (from + 1 .. chars.len()).find(|&i| chars[i] == '_' &&
+                                        chars.get(i + 1).map_or(true, |c| !c.is_alphanumeric()))
+}
+
+fn find_pair(chars: &[char], from: usize, c: char) -> Option<usize> {
+    (from..chars.len()).find(|&i| chars[i] == c && chars.get(i + 1) == Some (&c))
+}
+
+#[cfg(test)]
+fn binary(s: &str) -> Term {
+    Term::Binary(s.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+fn atom(s: &str) -> Term {
+    Term::Atom(s.to_string())
+}
+
+#[cfg(test)]
+fn list(elements: Vec<Term>) -> Term {
+    Term::List(elements, Box::new(Term::Nil))
+}
+
+#[test]
+fn test_docs_from_term() {
+    let en = |text| Term::Map(vec![(binary("en"), binary(text))]);
+    let fac = Term::Tuple(vec![
+        Term::Tuple(vec![atom("function"), atom("fac"), Term::Integer(1)]),
+        Term::Integer(5),
+        list(vec![binary("fac(N)")]),
+        en("Computes `N!`."),
+        Term::Map(vec![(atom("since"), binary("1.0"))])]);
+    let helper = Term::Tuple(vec![
+        Term::Tuple(vec![atom("function"), atom("helper"), Term::Integer(2)]),
+        Term::Integer(9),
+        list(vec![binary("helper(A, B)")]),
+        atom("hidden"),
+        Term::Map(vec![])]);
+    let term = Term::Tuple(vec![atom("docs_v1"),
+                                Term::Integer(1),
+                                atom("erlang"),
+                                binary("text/markdown"),
+                                en("Factorials."),
+                                Term::Map(vec![]),
+                                list(vec![fac, helper])]);
+    let docs = Docs::from_term(&term).unwrap();
+    assert_eq!("erlang", docs.beam_language);
+    assert_eq!("text/markdown", docs.format);
+    assert_eq!(Some ("Factorials."), docs.module_doc.text());
+    assert_eq!(2, docs.entries.len());
+    let found = docs.find("fac", Some (1));
+    assert_eq!(1, found.len());
+    assert_eq!(vec!["fac(N)".to_string()], found[0].signature);
+    assert_eq!(Some (&binary("1.0")), metadata_value(&found[0].metadata, "since"));
+    assert_eq!(Doc::Hidden, docs.find("helper", None)[0].doc);
+    assert!(docs.find("fac", Some (2)).is_empty());
+}
+
+#[test]
+fn test_docs_from_malformed_term() {
+    let term = Term::Tuple(vec![atom("docs_v2")]);
+    match Docs::from_term(&term) {
+        Err (Error::Malformed(_)) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn test_render_markdown() {
+    let rendered = render_markdown("# Title\nUse `fac/1`, **not** _this_.\n- item\n```\nfac(3).\n```");
+    let expected = format!("{b}{u}Title{r}\n\
+                            Use {c}fac/1{r}, {b}not{r} {i}this{r}.\n  \
+                            • item\n    \
+                            {c}fac(3).{r}\n",
+                           b = BOLD, u = UNDERLINE, c = CODE, i = ITALIC, r = RESET);
+    assert_eq!(expected, rendered);
+    // Underscores inside names aren't emphasis.
+    assert_eq!(format!("See list_to_atom/1 and {i}my_fun_name{r}.\n", i = ITALIC, r = RESET),
+               render_markdown("See list_to_atom/1 and _my_fun_name_."));
+}
+
+#[test]
+fn test_docs_not_in_text_format() {
+    let html = Term::Map(vec![(binary("en"), list(vec![Term::Tuple(vec![atom("p"), list(vec![]),
+                                                                           list(vec![binary("Text.")])])]))]);
+    let entry = |arity| Term::Tuple(vec![
+        Term::Tuple(vec![atom("function"), atom("f"), Term::Integer(arity)]),
+        Term::Integer(1),
+        list(vec![]),
+        html.clone(),
+        Term::Map(vec![])]);
+    let docs = |entries| Term::Tuple(vec![atom("docs_v1"), Term::Integer(1), atom("erlang"),
+                                          binary("application/erlang+html"), atom("none"),
+                                          Term::Map(vec![]), list(entries)]);
+    let decoded = Docs::from_term(&docs(vec![entry(1)])).unwrap();
+    assert_eq!(Doc::Raw(html.clone()), decoded.entries[0].doc);
+    assert_eq!(None, decoded.entries[0].doc.text());
+    match Docs::from_term(&docs(vec![entry(1 << 32)])) {
+        Err (Error::Malformed("doc entry arity")) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
@@ -0,0 +1,669 @@
+// External Term Format decoding and encoding.
+//
+// Several .beam chunks (`Attr`, `CInf`, `Docs`, `ExCk`, ...) are nothing more
+// than `term_to_binary/1` output, so we need a way to read them back.
+// Encoding is uncompressed, like `term_to_binary/1` without options.
+// See http://erlang.org/doc/apps/erts/erl_ext_dist.html for the format.
+
+use flate2::read::ZlibDecoder;
+use std::fmt;
+use std::io::Read;
+
+const VERSION: u8 = 131;
+
+const COMPRESSED: u8 = 80;
+const NEW_FLOAT_EXT: u8 = 70;
+const BIT_BINARY_EXT: u8 = 77;
+pub const NEW_PID_EXT: u8 = 88;
+const NEW_PORT_EXT: u8 = 89;
+pub const NEWER_REFERENCE_EXT: u8 = 90;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const REFERENCE_EXT: u8 = 101;
+const PORT_EXT: u8 = 102;
+const PID_EXT: u8 = 103;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const NEW_FUN_EXT: u8 = 112;
+pub const EXPORT_EXT: u8 = 113;
+const NEW_REFERENCE_EXT: u8 = 114;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    Atom(String),
+    Integer(i64),
+    // Sign (true if negative) and little-endian magnitude bytes.
+    BigInteger(bool, Vec<u8>),
+    Float(f64),
+    Binary(Vec<u8>),
+    // Bytes and the number of bits used in the last byte.
+    BitBinary(Vec<u8>, u8),
+    Tuple(Vec<Term>),
+    Nil,
+    // Elements and the tail, which is `Nil` for proper lists.
+    List(Vec<Term>, Box<Term>),
+    Map(Vec<(Term, Term)>),
+    // Pids, ports, references and funs can't be meaningfully used outside
+    // of the node which created them, so we keep their encoding verbatim.
+    Opaque(u8, Vec<u8>)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // Missing 131 version byte.
+    InvalidVersion(u8),
+
+    // A tag this decoder doesn't know about.
+    UnsupportedTag(u8),
+
+    // Data ends in the middle of a term.
+    UnexpectedEnd,
+
+    // Compressed term which fails to inflate.
+    InvalidCompressedData,
+
+    // Trailing bytes after the term.
+    TrailingData
+}
+
+pub type DecodeResult<T> = Result<T, Error>;
+
+pub fn decode(data: &[u8]) -> DecodeResult<Term> {
+    let mut reader = Reader { data: data, pos: 0 };
+    let version = try!( reader.u8() );
+    if version != VERSION
+        { return Err (Error::InvalidVersion(version)) }
+    if reader.peek() == Some (COMPRESSED) {
+        try!( reader.u8() );
+        let size = try!( reader.u32() ) as usize;
+        let mut inflated = vec![];
+        try!( ZlibDecoder::new(reader.rest())
+                          .read_to_end(&mut inflated)
+                          .map_err(|_| Error::InvalidCompressedData) );
+        if inflated.len() != size
+            { return Err (Error::InvalidCompressedData) }
+        return decode_whole(&inflated)
+    }
+    decode_whole(reader.rest())
+}
+
+fn decode_whole(data: &[u8]) -> DecodeResult<Term> {
+    let mut reader = Reader { data: data, pos: 0 };
+    let term = try!( decode_term(&mut reader) );
+    if reader.pos != data.len()
+        { return Err (Error::TrailingData) }
+    Ok (term)
+}
+
+fn decode_term(r: &mut Reader) -> DecodeResult<Term> {
+    let tag = try!( r.u8() );
+    match tag {
+        SMALL_INTEGER_EXT => Ok (Term::Integer(try!( r.u8() ) as i64)),
+        INTEGER_EXT => Ok (Term::Integer(try!( r.u32() ) as i32 as i64)),
+        NEW_FLOAT_EXT => {
+            let bits = try!( r.u64() );
+            Ok (Term::Float(f64::from_bits(bits)))
+        },
+        FLOAT_EXT => {
+            let bytes = try!( r.bytes(31) );
+            let s = String::from_utf8_lossy(bytes);
+            s.trim_end_matches('\0').trim().parse()
+             .map(Term::Float)
+             .or(Err (Error::UnsupportedTag(tag)))
+        },
+        ATOM_EXT | ATOM_UTF8_EXT => {
+            let len = try!( r.u16() ) as usize;
+            atom(tag, try!( r.bytes(len) ))
+        },
+        SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+            let len = try!( r.u8() ) as usize;
+            atom(tag, try!( r.bytes(len) ))
+        },
+        SMALL_TUPLE_EXT => {
+            let arity = try!( r.u8() ) as usize;
+            decode_terms(r, arity).map(Term::Tuple)
+        },
+        LARGE_TUPLE_EXT => {
+            let arity = try!( r.u32() ) as usize;
+            decode_terms(r, arity).map(Term::Tuple)
+        },
+        NIL_EXT => Ok (Term::Nil),
+        STRING_EXT => {
+            let len = try!( r.u16() ) as usize;
+            let chars = try!( r.bytes(len) ).iter()
+                                            .map(|&c| Term::Integer(c as i64))
+                                            .collect();
+            Ok (Term::List(chars, Box::new(Term::Nil)))
+        },
+        LIST_EXT => {
+            let len = try!( r.u32() ) as usize;
+            let elements = try!( decode_terms(r, len) );
+            let tail = try!( decode_term(r) );
+            Ok (Term::List(elements, Box::new(tail)))
+        },
+        BINARY_EXT => {
+            let len = try!( r.u32() ) as usize;
+            Ok (Term::Binary(try!( r.bytes(len) ).to_vec()))
+        },
+        BIT_BINARY_EXT => {
+            let len = try!( r.u32() ) as usize;
+            let bits = try!( r.u8() );
+            Ok (Term::BitBinary(try!( r.bytes(len) ).to_vec(), bits))
+        },
+        SMALL_BIG_EXT => {
+            let n = try!( r.u8() ) as usize;
+            big(r, n)
+        },
+        LARGE_BIG_EXT => {
+            let n = try!( r.u32() ) as usize;
+            big(r, n)
+        },
+        MAP_EXT => {
+            let arity = try!( r.u32() ) as usize;
+            let mut pairs = vec![];
+            for _ in 0..arity {
+                let key = try!( decode_term(r) );
+                let value = try!( decode_term(r) );
+                pairs.push((key, value));
+            }
+            Ok (Term::Map(pairs))
+        },
+        PID_EXT | NEW_PID_EXT | PORT_EXT | NEW_PORT_EXT |
+        REFERENCE_EXT | NEW_REFERENCE_EXT | NEWER_REFERENCE_EXT |
+        NEW_FUN_EXT | EXPORT_EXT =>
+            opaque(r, tag),
+        _ => Err (Error::UnsupportedTag(tag))
+    }
+}
+
+fn decode_terms(r: &mut Reader, n: usize) -> DecodeResult<Vec<Term>> {
+    let mut terms = vec![];
+    for _ in 0..n
+        { terms.push(try!( decode_term(r) )) }
+    Ok (terms)
+}
+
+fn atom(tag: u8, bytes: &[u8]) -> DecodeResult<Term> {
+    match tag {
+        // Latin-1: each byte is a code point.
+        ATOM_EXT | SMALL_ATOM_EXT =>
+            Ok (Term::Atom(bytes.iter().map(|&b| b as char).collect())),
+        _ =>
+            Ok (Term::Atom(String::from_utf8_lossy(bytes).into_owned()))
+    }
+}
+
+fn big(r: &mut Reader, n: usize) -> DecodeResult<Term> {
+    let sign = try!( r.u8() );
+    let digits = try!( r.bytes(n) );
+    // Normalize to a small integer if it fits.
+    if n <= 8 {
+        let mut magnitude: u64 = 0;
+        for (i, &d) in digits.iter().enumerate()
+            { magnitude |= (d as u64) << (8 * i) }
+        if sign == 0 && magnitude <= i64::max_value() as u64
+            { return Ok (Term::Integer(magnitude as i64)) }
+        if sign != 0 && magnitude <= 1 << 63
+            { return Ok (Term::Integer((magnitude as i64).wrapping_neg())) }
+    }
+    Ok (Term::BigInteger(sign != 0, digits.to_vec()))
+}
+
+// Skip over an opaque term remembering its raw encoding.
+fn opaque(r: &mut Reader, tag: u8) -> DecodeResult<Term> {
+    let start = r.pos;
+    match tag {
+        PID_EXT | NEW_PID_EXT => {
+            try!( decode_term(r) );
+            try!( r.bytes(if tag == PID_EXT { 9 } else { 12 }) );
+        },
+        PORT_EXT | NEW_PORT_EXT => {
+            try!( decode_term(r) );
+            try!( r.bytes(if tag == PORT_EXT { 5 } else { 8 }) );
+        },
+        REFERENCE_EXT => {
+            try!( decode_term(r) );
+            try!( r.bytes(5) );
+        },
+        NEW_REFERENCE_EXT | NEWER_REFERENCE_EXT => {
+            let len = try!( r.u16() ) as usize;
+            try!( decode_term(r) );
+            let creation = if tag == NEW_REFERENCE_EXT { 1 } else { 4 };
+            try!( r.bytes(creation + 4 * len) );
+        },
+        EXPORT_EXT => {
+            try!( decode_terms(r, 3) );
+        },
+        NEW_FUN_EXT => {
+            let size = try!( r.u32() ) as usize;
+            // The size includes itself, but not the tag.
+            try!( r.bytes(size.saturating_sub(4)) );
+        },
+        _ => return Err (Error::UnsupportedTag(tag))
+    }
+    Ok (Term::Opaque(tag, r.data[start .. r.pos].to_vec()))
+}
+
+pub fn encode(term: &Term) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    encode_term(&mut out, term);
+    out
+}
+
+fn encode_term(out: &mut Vec<u8>, term: &Term) {
+    match term {
+        &Term::Atom(ref name) => {
+            let bytes = name.as_bytes();
+            if bytes.len() < 256 {
+                out.push(SMALL_ATOM_UTF8_EXT);
+                out.push(bytes.len() as u8);
+            } else {
+                out.push(ATOM_UTF8_EXT);
+                push_u16(out, bytes.len() as u16);
+            }
+            out.extend_from_slice(bytes);
+        },
+        &Term::Integer(i) if i >= 0 && i < 256 => {
+            out.push(SMALL_INTEGER_EXT);
+            out.push(i as u8);
+        },
+        &Term::Integer(i) if i >= i32::min_value() as i64 && i <= i32::max_value() as i64 => {
+            out.push(INTEGER_EXT);
+            push_u32(out, i as i32 as u32);
+        },
+        &Term::Integer(i) => {
+            let mut digits = vec![];
+            let mut magnitude = (i as i128).abs() as u128;
+            while magnitude > 0 {
+                digits.push(magnitude as u8);
+                magnitude >>= 8;
+            }
+            encode_big(out, i < 0, &digits);
+        },
+        &Term::BigInteger(negative, ref digits) => encode_big(out, negative, digits),
+        &Term::Float(f) => {
+            out.push(NEW_FLOAT_EXT);
+            out.extend_from_slice(&f.to_bits().to_be_bytes());
+        },
+        &Term::Binary(ref bytes) => {
+            out.push(BINARY_EXT);
+            push_u32(out, bytes.len() as u32);
+            out.extend_from_slice(bytes);
+        },
+        &Term::BitBinary(ref bytes, bits) => {
+            out.push(BIT_BINARY_EXT);
+            push_u32(out, bytes.len() as u32);
+            out.push(bits);
+            out.extend_from_slice(bytes);
+        },
+        &Term::Tuple(ref elements) => {
+            if elements.len() < 256 {
+                out.push(SMALL_TUPLE_EXT);
+                out.push(elements.len() as u8);
+            } else {
+                out.push(LARGE_TUPLE_EXT);
+                push_u32(out, elements.len() as u32);
+            }
+            for e in elements.iter()
+                { encode_term(out, e) }
+        },
+        &Term::Nil => out.push(NIL_EXT),
+        &Term::List(ref elements, ref tail) => {
+            out.push(LIST_EXT);
+            push_u32(out, elements.len() as u32);
+            for e in elements.iter()
+                { encode_term(out, e) }
+            encode_term(out, tail);
+        },
+        &Term::Map(ref pairs) => {
+            out.push(MAP_EXT);
+            push_u32(out, pairs.len() as u32);
+            for &(ref k, ref v) in pairs.iter() {
+                encode_term(out, k);
+                encode_term(out, v);
+            }
+        },
+        &Term::Opaque(tag, ref bytes) => {
+            out.push(tag);
+            out.extend_from_slice(bytes);
+        }
+    }
+}
+
+fn encode_big(out: &mut Vec<u8>, negative: bool, digits: &[u8]) {
+    if digits.len() < 256 {
+        out.push(SMALL_BIG_EXT);
+        out.push(digits.len() as u8);
+    } else {
+        out.push(LARGE_BIG_EXT);
+        push_u32(out, digits.len() as u32);
+    }
+    out.push(negative as u8);
+    out.extend_from_slice(digits);
+}
+
+fn push_u16(out: &mut Vec<u8>, n: u16) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+struct Reader<'a> {
+    data:   &'a [u8],
+    pos:    usize
+}
+
+impl<'a> Reader<'a> {
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).cloned()
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    fn bytes(&mut self, n: usize) -> DecodeResult<&'a [u8]> {
+        if self.data.len() - self.pos < n
+            { return Err (Error::UnexpectedEnd) }
+        let bytes = &self.data[self.pos .. self.pos + n];
+        self.pos += n;
+        Ok (bytes)
+    }
+
+    fn u8(&mut self) -> DecodeResult<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> DecodeResult<u16> {
+        self.bytes(2).map(|b| (b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn u32(&mut self) -> DecodeResult<u32> {
+        self.bytes(4).map(|b| b.iter().fold(0, |acc, &x| acc << 8 | x as u32))
+    }
+
+    fn u64(&mut self) -> DecodeResult<u64> {
+        self.bytes(8).map(|b| b.iter().fold(0, |acc, &x| acc << 8 | x as u64))
+    }
+
+}
+
+impl Term {
+
+    pub fn as_atom(&self) -> Option<&str> {
+        match self {
+            &Term::Atom(ref name) => Some (name),
+            _ => None
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            &Term::Integer(i) => Some (i),
+            _ => None
+        }
+    }
+
+    pub fn as_binary(&self) -> Option<&[u8]> {
+        match self {
+            &Term::Binary(ref bytes) => Some (bytes),
+            _ => None
+        }
+    }
+
+    pub fn as_tuple(&self) -> Option<&[Term]> {
+        match self {
+            &Term::Tuple(ref elements) => Some (elements),
+            _ => None
+        }
+    }
+
+    // Elements of a proper list; `[]` is an empty list too.
+    pub fn as_list(&self) -> Option<&[Term]> {
+        match self {
+            &Term::Nil => Some (&[]),
+            &Term::List(ref elements, ref tail) if **tail == Term::Nil =>
+                Some (elements),
+            _ => None
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&[(Term, Term)]> {
+        match self {
+            &Term::Map(ref pairs) => Some (pairs),
+            _ => None
+        }
+    }
+
+    // Binaries, and Erlang strings - lists of code points.
+    pub fn as_string(&self) -> Option<String> {
+        if let Some (bytes) = self.as_binary()
+            { return Some (String::from_utf8_lossy(bytes).into_owned()) }
+        self.as_list().and_then(|chars| {
+            chars.iter()
+                 .map(|c| c.as_integer()
+                           .and_then(|i| ::std::char::from_u32(i as u32)))
+                 .collect()
+        })
+    }
+
+}
+
+// Erlang syntax, roughly what `io:format("~p", [Term])` would print.
+impl fmt::Display for Term {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Term::Atom(ref name) => write_atom(f, name),
+            &Term::Integer(i) => write!(f, "{}", i),
+            &Term::BigInteger(negative, ref digits) =>
+                write!(f, "{}{}", if negative { "-" } else { "" }, big_to_decimal(digits)),
+            &Term::Float(x) => write!(f, "{:?}", x),
+            &Term::Binary(ref bytes) => write_binary(f, bytes, None),
+            &Term::BitBinary(ref bytes, bits) => write_binary(f, bytes, Some (bits)),
+            &Term::Tuple(ref elements) => {
+                try!( write!(f, "{{") );
+                try!( write_seq(f, elements) );
+                write!(f, "}}")
+            },
+            &Term::Nil => write!(f, "[]"),
+            &Term::List(ref elements, ref tail) => {
+                if let Some (s) = self.as_string() {
+                    if s.chars().all(|c| !c.is_control() || c == '\n')
+                        { return write!(f, "{:?}", s) }
+                }
+                try!( write!(f, "[") );
+                try!( write_seq(f, elements) );
+                if **tail != Term::Nil
+                    { try!( write!(f, "|{}", tail) ) }
+                write!(f, "]")
+            },
+            &Term::Map(ref pairs) => {
+                try!( write!(f, "#{{") );
+                for (i, &(ref k, ref v)) in pairs.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    try!( write!(f, "{}{} => {}", sep, k, v) );
+                }
+                write!(f, "}}")
+            },
+            &Term::Opaque(NEW_PID_EXT, ref bytes) if pid_number(bytes).is_some() =>
+                write!(f, "<0.{}.0>", pid_number(bytes).unwrap()),
+            &Term::Opaque(NEWER_REFERENCE_EXT, ref bytes) if ref_number(bytes).is_some() =>
+                write!(f, "#Ref<0.0.0.{}>", ref_number(bytes).unwrap()),
+            &Term::Opaque(EXPORT_EXT, ref bytes) if export_mfa(bytes).is_some() => {
+                let (m, fun, a) = export_mfa(bytes).unwrap();
+                write!(f, "fun {}:{}/{}", m, fun, a)
+            },
+            &Term::Opaque(tag, _) => write!(f, "#Opaque<{}>", tag)
+        }
+    }
+
+}
+
+fn pid_number(bytes: &[u8]) -> Option<u32> {
+    let mut reader = Reader { data: bytes, pos: 0 };
+    try_opt!( decode_term(&mut reader).ok() );
+    reader.u32().ok()
+}
+
+// The first id word, the only one `term::to_etf` fills in.
+fn ref_number(bytes: &[u8]) -> Option<u32> {
+    let mut reader = Reader { data: bytes, pos: 0 };
+    try_opt!( reader.u16().ok() );
+    try_opt!( decode_term(&mut reader).ok() );
+    try_opt!( reader.u32().ok() );
+    reader.u32().ok()
+}
+
+fn export_mfa(bytes: &[u8]) -> Option<(Term, Term, Term)> {
+    let mut reader = Reader { data: bytes, pos: 0 };
+    let mut mfa = try_opt!( decode_terms(&mut reader, 3).ok() );
+    let a = mfa.pop().unwrap();
+    let f = mfa.pop().unwrap();
+    Some ((mfa.pop().unwrap(), f, a))
+}
+
+fn write_atom(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    let mut chars = name.chars();
+    let bare = match chars.next() {
+        Some (c) => c.is_lowercase() &&
+                    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '@'),
+        None => false
+    };
+    if bare { write!(f, "{}", name) }
+    else { write!(f, "'{}'", name.replace("'", "\\'")) }
+}
+
+fn write_binary(f: &mut fmt::Formatter, bytes: &[u8], bits: Option<u8>) -> fmt::Result {
+    let printable = bits.is_none() &&
+                    bytes.iter().all(|&b| b >= 0x20 && b < 0x7f || b == b'\n');
+    if printable {
+        return write!(f, "<<{:?}>>", String::from_utf8_lossy(bytes))
+    }
+    try!( write!(f, "<<") );
+    for (i, b) in bytes.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        match bits {
+            Some (bits) if i == bytes.len() - 1 && bits != 8 =>
+                try!( write!(f, "{}{}:{}", sep, b >> (8 - bits), bits) ),
+            _ => try!( write!(f, "{}{}", sep, b) )
+        }
+    }
+    write!(f, ">>")
+}
+
+fn write_seq(f: &mut fmt::Formatter, terms: &[Term]) -> fmt::Result {
+    for (i, t) in terms.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        try!( write!(f, "{}{}", sep, t) );
+    }
+    Ok (())
+}
+
+// Little-endian base 256 digits to a decimal string.
+fn big_to_decimal(digits: &[u8]) -> String {
+    let mut n: Vec<u8> = digits.iter().rev().cloned().collect();
+    let mut decimal = vec![];
+    while n.iter().any(|&d| d != 0) {
+        let mut rem = 0u32;
+        for d in n.iter_mut() {
+            let acc = rem << 8 | *d as u32;
+            *d = (acc / 10) as u8;
+            rem = acc % 10;
+        }
+        decimal.push((b'0' + rem as u8) as char);
+    }
+    if decimal.is_empty()
+        { return "0".to_string() }
+    decimal.iter().rev().cloned().collect()
+}
+
+#[test]
+fn test_decode_atoms_and_integers() {
+    // term_to_binary({docs_v1, 1, -1, 300})
+    let data = [131, 104, 4, 100, 0, 7, 100, 111, 99, 115, 95, 118, 49,
+                97, 1, 98, 255, 255, 255, 255, 98, 0, 0, 1, 44];
+    let expected = Term::Tuple(vec![Term::Atom("docs_v1".to_string()),
+                                    Term::Integer(1),
+                                    Term::Integer(-1),
+                                    Term::Integer(300)]);
+    assert_eq!(expected, decode(&data).unwrap());
+}
+
+#[test]
+fn test_decode_lists_maps_and_binaries() {
+    // term_to_binary([#{<<"en">> => "ab"}])
+    let data = [131, 108, 0, 0, 0, 1,
+                116, 0, 0, 0, 1,
+                109, 0, 0, 0, 2, 101, 110,
+                107, 0, 2, 97, 98,
+                106];
+    let term = decode(&data).unwrap();
+    let list = term.as_list().unwrap();
+    let pairs = list[0].as_map().unwrap();
+    assert_eq!(Some ("en".to_string()), pairs[0].0.as_string());
+    assert_eq!(Some ("ab".to_string()), pairs[0].1.as_string());
+}
+
+#[test]
+fn test_decode_big_and_compressed() {
+    // term_to_binary(1 bsl 64)
+    let big = [131, 110, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    assert_eq!(Term::BigInteger(false, vec![0, 0, 0, 0, 0, 0, 0, 0, 1]),
+               decode(&big).unwrap());
+    // term_to_binary(lists:duplicate(20, 0), [compressed])
+    let compressed = [131, 80, 0, 0, 0, 23, 120, 156, 203, 102, 16, 97, 192,
+                      2, 0, 11, 88, 0, 128];
+    assert_eq!(Term::List(vec![Term::Integer(0); 20], Box::new(Term::Nil)),
+               decode(&compressed).unwrap());
+}
+
+#[test]
+fn test_display() {
+    let term = Term::Tuple(vec![Term::Atom("ok".to_string()),
+                                Term::Atom("Not bare".to_string()),
+                                Term::List(vec![Term::Integer(104), Term::Integer(105)],
+                                           Box::new(Term::Nil)),
+                                Term::Binary(vec![1, 2]),
+                                Term::BigInteger(true, vec![0, 0, 0, 0, 0, 0, 0, 0, 1]),
+                                Term::Map(vec![(Term::Integer(1), Term::Nil)])]);
+    assert_eq!("{ok,'Not bare',\"hi\",<<1,2>>,-18446744073709551616,#{1 => []}}",
+               term.to_string());
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    let terms = vec![Term::Atom("ok".to_string()),
+                     Term::Integer(-1),
+                     Term::Integer(1 << 40),
+                     Term::Float(0.25),
+                     Term::Tuple(vec![Term::Nil, Term::Binary(vec![1, 2])]),
+                     Term::List(vec![Term::Integer(300)], Box::new(Term::Atom("t".to_string()))),
+                     Term::Map(vec![(Term::Integer(1), Term::BitBinary(vec![128], 1))])];
+    for term in terms.iter()
+        { assert_eq!(Ok (term.clone()), decode(&encode(term))) }
+    assert_eq!(vec![131, 97, 5], encode(&Term::Integer(5)));
+}
+
+#[test]
+fn test_decode_truncated() {
+    match decode(&[131, 104, 2, 97, 1]) {
+        Err (Error::UnexpectedEnd) => {},
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
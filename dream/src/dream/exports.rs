@@ -2,6 +2,7 @@ use std;
 use std::collections::HashMap;
 use super::atoms;
 use super::beam;
+use super::bif::Bif;
 
 pub type Module = atoms::AtomIndex;
 pub type Function = atoms::AtomIndex;
@@ -9,33 +10,94 @@ pub type Arity = usize;
 pub type MFA = (Module, Function, Arity);
 pub type CodeIdx = u32;
 
+pub type ExportIdx = u32;
+
+// The export table.  Its entries are never removed, so call sites refer to
+// them by index, linked at load time: loading a module updates the entries
+// of its functions in place, which takes effect on their next call.
 pub struct ExportTable {
-    mfa_to_ci: HashMap<MFA, CodeIdx>
+    mfa_to_index:   HashMap<MFA, ExportIdx>,
+    entries:        Vec<Export>
+}
+
+pub struct Export {
+    pub mfa:    MFA,
+    // None while the function isn't loaded.
+    pub code:   Option<CodeIdx>
 }
 
 impl ExportTable {
 
     pub fn new() -> ExportTable {
-        ExportTable { mfa_to_ci: HashMap::new() }
+        ExportTable { mfa_to_index: HashMap::new(), entries: vec![] }
+    }
+
+    // The entry for `mfa`, added if it doesn't exist yet.
+    pub fn entry(&mut self, mfa: MFA) -> ExportIdx {
+        let entries = &mut self.entries;
+        *self.mfa_to_index.entry(mfa).or_insert_with(|| {
+            entries.push(Export { mfa: mfa, code: None });
+            entries.len() as ExportIdx - 1
+        })
     }
 
     pub fn put(&mut self, mfa: MFA, code_index: CodeIdx) {
-        self.mfa_to_ci.insert(mfa, code_index);
+        let index = self.entry(mfa);
+        self.entries[index as usize].code = Some (code_index);
+    }
+
+    // Forget the functions of `module`, before loading a new version.
+    pub fn unload(&mut self, module: Module) {
+        for export in self.entries.iter_mut().filter(|export| export.mfa.0 == module)
+            { export.code = None }
     }
 
     pub fn get(&self, mfa: MFA) -> Option<CodeIdx> {
-        match self.mfa_to_ci.get(&mfa) {
-            Some (index) => Some (*index),
-            None => None
-        }
+        self.mfa_to_index.get(&mfa).and_then(|&index| self.code(index))
+    }
+
+    #[inline(always)]
+    pub fn code(&self, index: ExportIdx) -> Option<CodeIdx> {
+        self.entries[index as usize].code
+    }
+
+    pub fn mfa(&self, index: ExportIdx) -> MFA {
+        self.entries[index as usize].mfa
     }
 
     pub fn list(&self) -> Vec<(MFA, CodeIdx)> {
-        self.mfa_to_ci.iter().map(|(k,v)| (*k, *v)).collect()
+        self.entries.iter()
+                    .filter_map(|export| export.code.map(|code| (export.mfa, code)))
+                    .collect()
     }
 
 }
 
+// A function imported by a module (an `ImpT` entry), resolved at load time.
+// Calls to BIFs go straight to the Rust function, other calls go through
+// the function's export table entry.
+#[derive(Clone, Copy)]
+pub enum Import {
+    Bif(Bif),
+    Export(ExportIdx)
+}
+
+// A fun defined by a loaded module (a `FunT` entry).  Its arity includes
+// the free variables, which are passed after the regular arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FunEntry {
+    pub mfa:        MFA,
+    pub code:       CodeIdx,
+    pub num_free:   usize
+}
+
+// A source location, from the `Line` chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file:   String,
+    pub line:   u32
+}
+
 pub fn from_chunk(chunk: &beam::Chunk) -> Vec<ChunkExport> {
     let mut exports = vec![];
     for export_data in chunk.data[4..].chunks(12) {
@@ -86,6 +148,25 @@ fn get_exported_function() {
     assert_eq!(Some (0), et.get(mfa));
 }
 
+#[test]
+fn reload_exported_function() {
+    let mut et = ExportTable::new();
+    let (f, g) = ((0, 1, 0), (0, 2, 0));
+    // Linked before the function is loaded.
+    let index = et.entry(f);
+    assert_eq!(None, et.code(index));
+    et.put(f, 10);
+    et.put(g, 20);
+    assert_eq!(Some (10), et.code(index));
+    // Reloaded without `g`.
+    et.unload(0);
+    et.put(f, 30);
+    assert_eq!(index, et.entry(f));
+    assert_eq!(Some (30), et.code(index));
+    assert_eq!(None, et.get(g));
+    assert_eq!(vec![(f, 30)], et.list());
+}
+
 #[test]
 fn list_exports() {
     let mut et = ExportTable::new();
@@ -0,0 +1,584 @@
+// A process heap is collected by a generational copying (Cheney) collector.
+// Everything is allocated in the young generation.  A minor collection
+// copies the live young data to a fresh young generation, except data
+// which survived a collection already - below the high water mark - which
+// is promoted to the old generation.  A major collection ("fullsweep")
+// copies all live data of both generations to a fresh young generation.
+// Terms almost always refer to older ones, so old data hardly ever points
+// into the young generation and a minor collection can leave the old
+// generation alone.  The exceptions are updates in place with `Heap::set`:
+// the old words they point into the young generation from are remembered,
+// and the next minor collection treats them as roots.
+//
+// Off-heap binaries referred to by the heap are listed in `off_heap`; a
+// collection drops those which are no longer referred to, see `binary`.
+// The bytes of heap binaries are kept in a byte area next to each
+// generation, so they can be borrowed as they are; a collection copies
+// them along with the binary.
+//
+// Pointers into the old generation have the `OLD` bit set; a collection
+// only happens at points where the roots - registers, stack, mailbox -
+// are known, see `Process::garbage_collect`.
+
+use binary::Binary;
+use term::{ Header, Ptr, Term };
+use std::mem;
+use std::sync::Arc;
+
+const OLD: Ptr = !(!0 >> 1);
+
+// The smallest size of the young generation, in words; sizes grow along
+// a Fibonacci sequence from there, like on the real BEAM.
+pub const MIN_HEAP_SIZE: usize = 233;
+
+// Number of minor collections between two major ones, unless set by
+// `erlang:system_flag(fullsweep_after, N)`.
+pub const FULLSWEEP_AFTER: usize = 65535;
+
+// Off-heap binaries referred to since the last collection call for
+// another one when they add up to this many words.
+pub const BIN_VHEAP_SIZE: usize = 46422;
+
+// A heap is a flat vector of words (terms), just like on the real BEAM.
+// Compound terms are laid out as a header word followed by their contents
+// and referred to by `Term::Boxed` or `Term::Cons` pointers.
+#[derive(Debug)]
+pub struct Heap {
+    young:              Vec<Term>,
+    old:                Vec<Term>,
+    // The bytes of the heap binaries of each generation.
+    young_bytes:        Vec<u8>,
+    old_bytes:          Vec<u8>,
+    // Young words below this survived a collection; the next minor one
+    // promotes them.
+    high_water:         usize,
+    // Size of the young generation, and of the old one, which call for a
+    // collection.
+    size:               usize,
+    old_size:           usize,
+    // Minor collections since the last major one.
+    minor_gcs:          usize,
+    pub fullsweep_after: usize,
+    // Off-heap binaries and the ProcBins referring to them, the ProcBins
+    // holding their index in the list.
+    off_heap:           Vec<(Ptr, Arc<Binary>)>,
+    // Size of the off-heap binaries added since the last collection.
+    bin_vheap:          usize,
+    // Old words which `set` made point into the young generation.
+    remembered:         Vec<Ptr>
+}
+
+impl Heap {
+
+    pub fn new() -> Heap {
+        Heap { young: vec![],
+               old: vec![],
+               young_bytes: vec![],
+               old_bytes: vec![],
+               high_water: 0,
+               size: MIN_HEAP_SIZE,
+               old_size: MIN_HEAP_SIZE,
+               minor_gcs: 0,
+               fullsweep_after: FULLSWEEP_AFTER,
+               off_heap: vec![],
+               bin_vheap: 0,
+               remembered: vec![] }
+    }
+
+    // Copy `words` onto the heap, returning a pointer to the first one.
+    pub fn alloc(&mut self, words: &[Term]) -> Ptr {
+        let ptr = self.young.len();
+        self.young.extend_from_slice(words);
+        ptr
+    }
+
+    // A ProcBin referring to `binary`.
+    pub fn alloc_proc_bin(&mut self, binary: Arc<Binary>) -> Ptr {
+        let index = self.off_heap.len();
+        let ptr = self.alloc(&[Term::Header(Header::ProcBin(binary.bytes().len())),
+                               Term::Raw(index as u64)]);
+        self.bin_vheap += binary.bytes().len() / 8;
+        self.off_heap.push((ptr, binary));
+        ptr
+    }
+
+    pub fn off_heap_binary(&self, index: usize) -> &Arc<Binary> {
+        &self.off_heap[index].1
+    }
+
+    // A heap binary holding a copy of `bytes`.
+    pub fn alloc_heap_bin(&mut self, bytes: &[u8]) -> Ptr {
+        let offset = self.young_bytes.len();
+        self.young_bytes.extend_from_slice(bytes);
+        self.alloc(&[Term::Header(Header::HeapBin(bytes.len())), Term::Raw(offset as u64)])
+    }
+
+    // The bytes of the heap binary at `ptr`.
+    pub fn heap_bin_bytes(&self, ptr: Ptr) -> Option<&[u8]> {
+        match (self.get(ptr), self.get(ptr + 1)) {
+            (Term::Header(Header::HeapBin(size)), Term::Raw(offset)) => {
+                let bytes = if ptr & OLD != 0 { &self.old_bytes } else { &self.young_bytes };
+                bytes.get(offset as usize .. offset as usize + size)
+            },
+            _ => None
+        }
+    }
+
+    pub fn get(&self, ptr: Ptr) -> Term {
+        if ptr & OLD != 0 { self.old[ptr & !OLD] }
+        else { self.young[ptr] }
+    }
+
+    // Update a word in place.  This is the write barrier: a word of the
+    // old generation which is made to point into the young one is
+    // remembered, or a minor collection would miss the young data.
+    pub fn set(&mut self, ptr: Ptr, term: Term) {
+        if ptr & OLD == 0 {
+            self.young[ptr] = term;
+            return
+        }
+        match term {
+            Term::Cons(to) | Term::Boxed(to) if to & OLD == 0 => self.remembered.push(ptr),
+            _ => {}
+        }
+        self.old[ptr & !OLD] = term;
+    }
+
+    pub fn slice(&self, ptr: Ptr, len: usize) -> &[Term] {
+        if ptr & OLD != 0 { &self.old[ptr & !OLD .. (ptr & !OLD) + len] }
+        else { &self.young[ptr .. ptr + len] }
+    }
+
+    // Number of words in use.
+    pub fn len(&self) -> usize {
+        self.young.len() + self.old.len()
+    }
+
+    // Whether allocating `need` more words calls for a collection first.
+    pub fn needs_gc(&self, need: usize) -> bool {
+        self.young_words() + need > self.size || self.bin_vheap > BIN_VHEAP_SIZE
+    }
+
+    // Start a collection: a major one if `fullsweep_after` minor ones
+    // happened since the last, or if the old generation is full.
+    // Evacuate every root with the collector, then `finish` it.
+    pub fn collect<'a>(&'a mut self, major: bool) -> Collector<'a> {
+        let major = major || self.minor_gcs >= self.fullsweep_after ||
+                    self.old.len() + self.high_water > self.old_size;
+        let from_young = mem::replace(&mut self.young, vec![]);
+        let from_old = if major { mem::replace(&mut self.old, vec![]) } else { vec![] };
+        let from_young_bytes = mem::replace(&mut self.young_bytes, vec![]);
+        let from_old_bytes = if major { mem::replace(&mut self.old_bytes, vec![]) } else { vec![] };
+        let promoted = self.old.len();
+        Collector { heap: self, major: major, from_young: from_young,
+                    from_old: from_old, from_young_bytes: from_young_bytes,
+                    from_old_bytes: from_old_bytes, promoted: promoted }
+    }
+
+    // Words of the young generation, counting its heap binary bytes.
+    fn young_words(&self) -> usize {
+        self.young.len() + (self.young_bytes.len() + 7) / 8
+    }
+
+    // Deep copy of `term`, which lives on `src`, to this heap.
+    // List spines are walked iteratively, so long lists don't blow the
+    // Rust stack.
+    pub fn copy_term(&mut self, src: &Heap, term: Term) -> Term {
+        match term {
+            Term::Cons(_) => {
+                let mut heads = vec![];
+                let mut t = term;
+                while let Term::Cons(ptr) = t {
+                    heads.push(self.copy_term(src, src.get(ptr)));
+                    t = src.get(ptr + 1);
+                }
+                let mut list = self.copy_term(src, t);
+                for head in heads.into_iter().rev()
+                    { list = Term::Cons(self.alloc(&[head, list])) }
+                list
+            },
+            Term::Boxed(ptr) => match src.get(ptr) {
+                // Off-heap binaries are shared, not copied.
+                Term::Header(Header::ProcBin(_)) => match src.get(ptr + 1) {
+                    Term::Raw(index) => {
+                        let binary = src.off_heap_binary(index as usize).clone();
+                        Term::Boxed(self.alloc_proc_bin(binary))
+                    },
+                    _ => term
+                },
+                Term::Header(Header::HeapBin(_)) => match src.heap_bin_bytes(ptr) {
+                    Some (bytes) => Term::Boxed(self.alloc_heap_bin(bytes)),
+                    None => term
+                },
+                Term::Header(header) => {
+                    let mut words = vec![Term::Header(header)];
+                    for &word in src.slice(ptr + 1, header.size()) {
+                        words.push(match header {
+                            _ if header.has_terms() => self.copy_term(src, word),
+                            _ => word
+                        })
+                    }
+                    Term::Boxed(self.alloc(&words))
+                },
+                _ => term
+            },
+            _ => term
+        }
+    }
+
+}
+
+// A collection in progress.  Live data is copied from the old spaces
+// (`from_young` and, in a major collection, `from_old`) to the heap,
+// leaving a `Term::Moved` forwarding pointer in place of each object.
+pub struct Collector<'a> {
+    heap:           &'a mut Heap,
+    major:          bool,
+    from_young:     Vec<Term>,
+    from_old:       Vec<Term>,
+    from_young_bytes: Vec<u8>,
+    from_old_bytes: Vec<u8>,
+    // Where the data promoted by a minor collection starts.
+    promoted:       usize
+}
+
+impl<'a> Collector<'a> {
+
+    // Copy what `root` points to, returning the new root.
+    pub fn evacuate(&mut self, root: Term) -> Term {
+        match root {
+            Term::Cons(ptr) => Term::Cons(self.forward(ptr)),
+            Term::Boxed(ptr) => Term::Boxed(self.forward(ptr)),
+            _ => root
+        }
+    }
+
+    fn forward(&mut self, ptr: Ptr) -> Ptr {
+        let old = ptr & OLD != 0;
+        if old && !self.major
+            { return ptr }
+        let i = ptr & !OLD;
+        let heap = &mut *self.heap;
+        let from = if old { &mut self.from_old } else { &mut self.from_young };
+        // A cons cell has no header; its head is never one.
+        let len = match from[i] {
+            Term::Moved(to) => return to,
+            Term::Header(header) => 1 + header.size(),
+            _ => 2
+        };
+        let promote = !self.major && i < heap.high_water;
+        let to = if promote {
+            heap.old.extend_from_slice(&from[i .. i + len]);
+            (heap.old.len() - len) | OLD
+        } else {
+            heap.young.extend_from_slice(&from[i .. i + len]);
+            heap.young.len() - len
+        };
+        // The bytes of a heap binary go to the byte area of its generation.
+        if let (Term::Header(Header::HeapBin(size)), Term::Raw(offset)) = (from[i], from[i + 1]) {
+            let from_bytes = if old { &self.from_old_bytes } else { &self.from_young_bytes };
+            let to_bytes = if promote { &mut heap.old_bytes } else { &mut heap.young_bytes };
+            let offset = offset as usize;
+            let to_offset = to_bytes.len();
+            to_bytes.extend_from_slice(&from_bytes[offset .. offset + size]);
+            heap.set(to + 1, Term::Raw(to_offset as u64));
+        }
+        from[i] = Term::Moved(to);
+        to
+    }
+
+    // Evacuate the terms in the copied objects, and in the objects they
+    // bring along, until everything live is copied.  `need` is the number
+    // of words about to be allocated.
+    pub fn finish(mut self, need: usize) {
+        // Remembered words are roots of a minor collection; `set` remembers
+        // them again if they still point into the young generation.  A
+        // major collection leaves no old generation behind.  A word stored
+        // to more than once is remembered as many times, but must only be
+        // evacuated once.
+        let mut remembered = mem::replace(&mut self.heap.remembered, vec![]);
+        remembered.sort();
+        remembered.dedup();
+        if !self.major {
+            for ptr in remembered {
+                let term = self.heap.get(ptr);
+                let term = self.evacuate(term);
+                self.heap.set(ptr, term);
+            }
+        }
+        let (mut young, mut old) = (0, self.promoted);
+        loop {
+            if young < self.heap.young.len() {
+                young = self.scan(young, false);
+            } else if old < self.heap.old.len() {
+                old = self.scan(old, true);
+            } else {
+                break
+            }
+        }
+        // Keep the off-heap binaries of the ProcBins which were copied, or
+        // left alone in the old generation, updating their indices.
+        for (ptr, binary) in mem::replace(&mut self.heap.off_heap, vec![]) {
+            let to = if ptr & OLD != 0 && !self.major {
+                ptr
+            } else {
+                let from = if ptr & OLD != 0 { &self.from_old } else { &self.from_young };
+                match from[ptr & !OLD] {
+                    Term::Moved(to) => to,
+                    _ => continue
+                }
+            };
+            let index = self.heap.off_heap.len();
+            self.heap.set(to + 1, Term::Raw(index as u64));
+            self.heap.off_heap.push((to, binary));
+        }
+        let heap = self.heap;
+        heap.bin_vheap = 0;
+        heap.high_water = heap.young.len();
+        if self.major {
+            heap.minor_gcs = 0;
+            heap.old_size = heap_size(2 * heap.young.len());
+        } else {
+            heap.minor_gcs += 1;
+        }
+        // Grow the young generation if it's mostly live data, shrink it if
+        // it's mostly garbage.
+        let live = heap.young_words() + need;
+        if 4 * live > 3 * heap.size {
+            heap.size = heap_size(live + live / 3);
+        } else if 4 * live < heap.size {
+            heap.size = heap_size(2 * live);
+        }
+    }
+
+    // Evacuate the terms in the object at `i`, returning where the next
+    // one starts.
+    fn scan(&mut self, i: usize, old: bool) -> usize {
+        let word = if old { self.heap.old[i] } else { self.heap.young[i] };
+        let (start, len) = match word {
+            Term::Header(header) if header.has_terms() => (i + 1, header.size()),
+            Term::Header(header) => return i + 1 + header.size(),
+            _ => (i, 2)
+        };
+        for j in start .. start + len {
+            let t = if old { self.heap.old[j] } else { self.heap.young[j] };
+            let t = self.evacuate(t);
+            if old { self.heap.old[j] = t } else { self.heap.young[j] = t }
+        }
+        start + len
+    }
+
+}
+
+// The smallest heap size of at least `words`.
+fn heap_size(words: usize) -> usize {
+    let (mut a, mut b) = (MIN_HEAP_SIZE, 377);
+    while a < words {
+        let next = a + b;
+        a = b;
+        b = next;
+    }
+    a
+}
+
+#[test]
+fn test_alloc() {
+    let mut heap = Heap::new();
+    let p1 = heap.alloc(&[Term::Nil, Term::Small(1)]);
+    let p2 = heap.alloc(&[Term::Small(2)]);
+    assert_eq!(0, p1);
+    assert_eq!(2, p2);
+    assert_eq!(Term::Small(1), heap.get(p1 + 1));
+    heap.set(p2, Term::Nil);
+    assert_eq!(&[Term::Nil, Term::Small(1), Term::Nil], heap.slice(0, 3));
+}
+
+#[test]
+fn test_copy_term() {
+    let mut src = Heap::new();
+    let f = Term::float(&mut src, 0.5);
+    let list = Term::cons(&mut src, f, Term::Nil);
+    let t = Term::tuple(&mut src, &[Term::Small(1), list]);
+    let mut dst = Heap::new();
+    dst.alloc(&[Term::Nil]);
+    let copy = dst.copy_term(&src, t);
+    assert_eq!(src.len() + 1, dst.len());
+    let elements = copy.tuple_elements(&dst).unwrap();
+    assert_eq!(Term::Small(1), elements[0]);
+    match elements[1] {
+        Term::Cons(ptr) => assert_eq!(Some (0.5), dst.get(ptr).float_value(&dst)),
+        other => panic!("not a list: {:?}", other)
+    }
+}
+
+#[test]
+fn test_collect() {
+    let mut heap = Heap::new();
+    let f = Term::float(&mut heap, 0.5);
+    Term::tuple(&mut heap, &[Term::Small(1), Term::Small(2)]);
+    let shared = Term::tuple(&mut heap, &[f]);
+    let list = Term::cons(&mut heap, shared, Term::Nil);
+    let mut roots = [Term::tuple(&mut heap, &[list, shared]), Term::Small(7)];
+    {
+        let mut gc = heap.collect(false);
+        for root in roots.iter_mut()
+            { *root = gc.evacuate(*root) }
+        gc.finish(0);
+    }
+    // The garbage tuple is gone, the shared one copied once.
+    assert_eq!(2 + 2 + 2 + 3, heap.len());
+    assert_eq!(Term::Small(7), roots[1]);
+    let elements = roots[0].tuple_elements(&heap).unwrap().to_vec();
+    match elements[0] {
+        Term::Cons(ptr) => assert_eq!(elements[1], heap.get(ptr)),
+        other => panic!("not a list: {:?}", other)
+    }
+    let inner = elements[1].tuple_elements(&heap).unwrap()[0];
+    assert_eq!(Some (0.5), inner.float_value(&heap));
+}
+
+#[test]
+fn test_generations() {
+    let collect = |heap: &mut Heap, root: &mut Term| {
+        let mut gc = heap.collect(false);
+        *root = gc.evacuate(*root);
+        gc.finish(0);
+    };
+    let mut heap = Heap::new();
+    let mut root = Term::tuple(&mut heap, &[Term::Small(1)]);
+    // Survivors of a minor collection are promoted by the next one, and
+    // left alone after that.
+    collect(&mut heap, &mut root);
+    assert_eq!((2, 0), (heap.young.len(), heap.old.len()));
+    collect(&mut heap, &mut root);
+    assert_eq!((0, 2), (heap.young.len(), heap.old.len()));
+    root = Term::tuple(&mut heap, &[root]);
+    collect(&mut heap, &mut root);
+    assert_eq!((2, 2), (heap.young.len(), heap.old.len()));
+    assert_eq!(3, heap.minor_gcs);
+    // A major collection brings everything back to the young generation.
+    heap.fullsweep_after = 2;
+    collect(&mut heap, &mut root);
+    assert_eq!((4, 0), (heap.young.len(), heap.old.len()));
+    assert_eq!(0, heap.minor_gcs);
+    let inner = root.tuple_elements(&heap).unwrap()[0];
+    assert_eq!(Term::Small(1), inner.tuple_elements(&heap).unwrap()[0]);
+}
+
+#[test]
+fn test_write_barrier() {
+    let collect = |heap: &mut Heap, root: &mut Term| {
+        let mut gc = heap.collect(false);
+        *root = gc.evacuate(*root);
+        gc.finish(0);
+    };
+    let mut heap = Heap::new();
+    let mut root = Term::tuple(&mut heap, &[Term::Nil]);
+    collect(&mut heap, &mut root);
+    collect(&mut heap, &mut root);
+    assert_eq!((0, 2), (heap.young.len(), heap.old.len()));
+    // The old tuple is updated to refer to a young float, which survives
+    // minor collections, first in the young generation, then promoted.
+    let f = Term::float(&mut heap, 0.5);
+    let ptr = match root { Term::Boxed(ptr) => ptr, _ => panic!("not boxed") };
+    heap.set(ptr + 1, f);
+    Term::tuple(&mut heap, &[Term::Small(1)]);
+    collect(&mut heap, &mut root);
+    assert_eq!(1, heap.remembered.len());
+    let inner = root.tuple_elements(&heap).unwrap()[0];
+    assert_eq!(Some (0.5), inner.float_value(&heap));
+    collect(&mut heap, &mut root);
+    assert!(heap.remembered.is_empty());
+    assert_eq!(0, heap.young.len());
+    let inner = root.tuple_elements(&heap).unwrap()[0];
+    assert_eq!(Some (0.5), inner.float_value(&heap));
+}
+
+#[test]
+fn test_write_barrier_repeated() {
+    let collect = |heap: &mut Heap, root: &mut Term| {
+        let mut gc = heap.collect(false);
+        *root = gc.evacuate(*root);
+        gc.finish(0);
+    };
+    let mut heap = Heap::new();
+    let mut root = Term::tuple(&mut heap, &[Term::Nil]);
+    collect(&mut heap, &mut root);
+    collect(&mut heap, &mut root);
+    let ptr = match root { Term::Boxed(ptr) => ptr, _ => panic!("not boxed") };
+    // The same old word is updated twice before a minor collection.
+    let f = Term::float(&mut heap, 1.5);
+    heap.set(ptr + 1, f);
+    let f = Term::float(&mut heap, 2.5);
+    heap.set(ptr + 1, f);
+    assert_eq!(2, heap.remembered.len());
+    collect(&mut heap, &mut root);
+    assert_eq!(1, heap.remembered.len());
+    let inner = root.tuple_elements(&heap).unwrap()[0];
+    assert_eq!(Some (2.5), inner.float_value(&heap));
+}
+
+#[test]
+fn test_heap_binaries() {
+    use std::borrow::Cow;
+    let collect = |heap: &mut Heap, root: &mut Term| {
+        let mut gc = heap.collect(false);
+        *root = gc.evacuate(*root);
+        gc.finish(0);
+    };
+    let mut heap = Heap::new();
+    Term::binary(&mut heap, b"garbage");
+    let mut root = Term::binary(&mut heap, b"heap binary");
+    // The bytes are borrowed, in place, before and after being promoted.
+    for _ in 0 .. 3 {
+        match root.binary_bytes(&heap) {
+            Some (Cow::Borrowed(bytes)) => assert_eq!(&b"heap binary"[..], bytes),
+            other => panic!("not borrowed: {:?}", other)
+        }
+        collect(&mut heap, &mut root);
+    }
+    assert_eq!((0, 11), (heap.young_bytes.len(), heap.old_bytes.len()));
+    let mut other = Heap::new();
+    let copy = other.copy_term(&heap, root);
+    assert_eq!(&b"heap binary"[..], &*copy.binary_bytes(&other).unwrap());
+}
+
+#[test]
+fn test_heap_size() {
+    assert_eq!(MIN_HEAP_SIZE, heap_size(0));
+    assert_eq!(610, heap_size(400));
+    assert_eq!(987, heap_size(987));
+}
+
+#[test]
+fn test_off_heap_binaries() {
+    let mut heap = Heap::new();
+    let kept = Term::binary(&mut heap, &[1; 100]);
+    let dropped = Term::binary(&mut heap, &[2; 100]);
+    let small = Term::binary(&mut heap, b"small");
+    assert_eq!(Some (Header::HeapBin(5)), small.header(&heap));
+    let weak = |t: Term, heap: &Heap| match heap.get(match t { Term::Boxed(ptr) => ptr + 1, _ => 0 }) {
+        Term::Raw(index) => Arc::downgrade(heap.off_heap_binary(index as usize)),
+        _ => panic!("not a ProcBin")
+    };
+    let (kept_binary, dropped_binary) = (weak(kept, &heap), weak(dropped, &heap));
+    // Copies share the binary.
+    let mut other = Heap::new();
+    let copy = other.copy_term(&heap, kept);
+    assert_eq!(2, kept_binary.upgrade().map_or(0, |b| Arc::strong_count(&b) - 1));
+    // A sub-binary keeps its binary alive.
+    let mut root = Term::sub_binary(&mut heap, kept, 98, 2).unwrap();
+    root = Term::sub_binary(&mut heap, root, 1, 1).unwrap();
+    {
+        let mut gc = heap.collect(true);
+        root = gc.evacuate(root);
+        gc.finish(0);
+    }
+    assert!(dropped_binary.upgrade().is_none());
+    assert_eq!(1, heap.off_heap.len());
+    assert_eq!(&[1][..], &*root.binary_bytes(&heap).unwrap());
+    drop(heap);
+    assert_eq!(&[1; 100][..], &*copy.binary_bytes(&other).unwrap());
+    drop(other);
+    assert!(kept_binary.upgrade().is_none());
+}
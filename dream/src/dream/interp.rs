@@ -0,0 +1,2787 @@
+// The interpreter loop.
+//
+// Instructions are executed straight from the emulator's code area, as
+// linked by the loader: jump targets are code indices, atoms are indices
+// into the emulator's atom table, literals, imports and funs are indices
+// into the emulator's literal, import and fun tables.
+//
+// `run` executes a single process until it returns, fails, runs out of
+// reductions - one reduction is spent on each function or BIF call - or
+// waits for a message.
+//
+// Errors become exceptions of class `error`, `exit` or `throw`, with a
+// stack trace of where they happened.  `catch` and `try` put the code
+// index of their handler in a Y register; an exception unwinds the stack
+// to the frame of the innermost one and continues there, with the class,
+// reason and stack trace in x(1) .. x(3) for `catch_end` or `try_case` to
+// pick up.  A process without a handler fails.
+
+use atoms::AtomTable;
+use arith;
+use bif;
+use binary::{ self, Bits, Builder };
+use code::{ self, ArgTag, BEAMOpcode, SelectKey };
+use compare;
+use exports::{ CodeIdx, Import, Location, MFA };
+use heap::Heap;
+#[cfg(feature = "jit")]
+use jit;
+use map;
+use process::{ self, Process };
+use profile::{ self, Profile };
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::sync::Mutex;
+use term::{ Header, Number, Ptr, Term };
+use super::Emu;
+
+pub const MAX_X_REGS: usize = 1024;
+pub const MAX_FLOAT_REGS: usize = 256;
+
+// Code index 0 holds `int_code_end`: returning there ends the process.
+pub const HALT: CodeIdx = 0;
+
+// Stack traces have at most this many entries.
+pub const STACKTRACE_DEPTH: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Bif(bif::Error),
+    FunctionClause(MFA),
+    Undef(MFA),
+    // Calling something which isn't a fun, or a fun with the wrong arity.
+    Badfun(Term),
+    Badarity(Term),
+    // The value no pattern matched.
+    Badmatch(Term),
+    CaseClause(Term),
+    IfClause,
+    TryClause(Term),
+    // Map operations on something which isn't a map, or on a missing key.
+    Badmap(Term),
+    Badkey(Term),
+    // An `after` time which isn't a non-negative integer or `infinity`.
+    TimeoutValue,
+    // Malformed code, e.g. an operand of unexpected type, at the given index.
+    BadCode(CodeIdx)
+}
+
+impl Error {
+
+    pub fn class(&self) -> Class {
+        match *self {
+            Error::Bif(bif::Error::Exit(_)) => Class::Exit,
+            Error::Bif(bif::Error::Throw(_)) => Class::Throw,
+            Error::Bif(bif::Error::Raise(class, _, _)) => class,
+            _ => Class::Error
+        }
+    }
+
+    // The exception reason, e.g. `badarith`.
+    pub fn reason(&self, heap: &mut Heap, atoms: &AtomTable) -> Term {
+        let atom = |name| Term::Atom(atoms.add(name));
+        match *self {
+            Error::Bif(bif::Error::Badarg) => atom("badarg"),
+            Error::Bif(bif::Error::Badarith) => atom("badarith"),
+            Error::Bif(bif::Error::SystemLimit) => atom("system_limit"),
+            Error::Bif(bif::Error::Exit(reason)) |
+            Error::Bif(bif::Error::Error(reason)) |
+            Error::Bif(bif::Error::Throw(reason)) |
+            Error::Bif(bif::Error::Raise(_, reason, _)) => reason,
+            Error::FunctionClause(_) => atom("function_clause"),
+            Error::Undef(_) => atom("undef"),
+            Error::IfClause => atom("if_clause"),
+            Error::TimeoutValue => atom("timeout_value"),
+            Error::Badmatch(t) => {
+                let tag = atom("badmatch");
+                Term::tuple(heap, &[tag, t])
+            },
+            Error::CaseClause(t) => {
+                let tag = atom("case_clause");
+                Term::tuple(heap, &[tag, t])
+            },
+            Error::TryClause(t) => {
+                let tag = atom("try_clause");
+                Term::tuple(heap, &[tag, t])
+            },
+            Error::Badmap(t) => {
+                let tag = atom("badmap");
+                Term::tuple(heap, &[tag, t])
+            },
+            Error::Badkey(t) => {
+                let tag = atom("badkey");
+                Term::tuple(heap, &[tag, t])
+            },
+            Error::Badfun(f) => {
+                let tag = atom("badfun");
+                Term::tuple(heap, &[tag, f])
+            },
+            Error::Badarity(f) => {
+                let tag = atom("badarity");
+                Term::tuple(heap, &[tag, f])
+            },
+            Error::BadCode(ip) => {
+                let tag = atom("bad_code");
+                Term::tuple(heap, &[tag, Term::Small(ip as i64)])
+            }
+        }
+    }
+
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Class {
+    Error,
+    Exit,
+    Throw
+}
+
+impl Class {
+
+    pub fn from_name(name: &str) -> Option<Class> {
+        match name {
+            "error" => Some (Class::Error),
+            "exit" => Some (Class::Exit),
+            "throw" => Some (Class::Throw),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Class::Error => "error",
+            Class::Exit => "exit",
+            Class::Throw => "throw"
+        }
+    }
+
+}
+
+// An exception raised by a process; the terms live on its heap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exception {
+    pub class:      Class,
+    pub reason:     Term,
+    pub stacktrace: Term
+}
+
+impl Exception {
+
+    // The reason of an uncaught exception: a throw nobody caught is an
+    // error.
+    pub fn error_reason(&self, heap: &mut Heap, atoms: &AtomTable) -> Term {
+        match self.class {
+            Class::Throw => {
+                let tag = Term::Atom(atoms.add("nocatch"));
+                Term::tuple(heap, &[tag, self.reason])
+            },
+            _ => self.reason
+        }
+    }
+
+    // The exit reason of a process failing with the exception: errors
+    // come with their stack trace.
+    pub fn exit_reason(&self, heap: &mut Heap, atoms: &AtomTable) -> Term {
+        match self.class {
+            Class::Exit => self.reason,
+            _ => {
+                let reason = self.error_reason(heap, atoms);
+                Term::tuple(heap, &[reason, self.stacktrace])
+            }
+        }
+    }
+
+    // The value of a `catch` expression.
+    fn caught(&self, heap: &mut Heap, atoms: &AtomTable) -> Term {
+        let tag = Term::Atom(atoms.add("EXIT"));
+        match self.class {
+            Class::Throw => self.reason,
+            Class::Exit => Term::tuple(heap, &[tag, self.reason]),
+            Class::Error => {
+                let reason = Term::tuple(heap, &[self.reason, self.stacktrace]);
+                Term::tuple(heap, &[tag, reason])
+            }
+        }
+    }
+
+}
+
+pub enum Outcome {
+    // The process returned from its initial call; the result is in x(0).
+    Returned,
+    // The process ran out of reductions.
+    Yielded,
+    // The process waits for a message; it continues at `ip` when one
+    // arrives.
+    Waiting,
+    Failed(Exception)
+}
+
+type Operand = (ArgTag, u32);
+
+pub fn run(emu: &Emu, p: &mut Process) -> Outcome {
+    // See `spawn_mfa`.
+    if p.ip == HALT {
+        let e = Error::Undef(p.initial_call);
+        return Outcome::Failed(exception(emu, p, e))
+    }
+    loop {
+        match execute(emu, p) {
+            Ok (outcome) => return outcome,
+            Err (e) => {
+                let exception = exception(emu, p, e);
+                if !catch(emu, p, exception)
+                    { return Outcome::Failed(exception) }
+            }
+        }
+    }
+}
+
+fn execute(emu: &Emu, p: &mut Process) -> Result<Outcome, Error> {
+    if let Some (ref profile) = emu.opcode_profile
+        { return execute_profiled(emu, p, profile) }
+    #[cfg(feature = "jit")]
+    {
+        if emu.jit.enabled
+            { return jit::execute(emu, p) }
+    }
+    loop {
+        if let Some (outcome) = try!( instruction(emu, p) )
+            { return Ok (outcome) }
+    }
+}
+
+// `execute`, counting opcode sequences into `profile` at the end of the
+// time slice.
+fn execute_profiled(emu: &Emu, p: &mut Process, profile: &Mutex<Profile>) -> Result<Outcome, Error> {
+    let mut counts = Profile::new();
+    let mut sequence = profile::Sequence::new();
+    let result = loop {
+        let ip = p.ip;
+        counts.count(&mut sequence, ip, code::opcode(emu.code[ip as usize]), following(emu, ip));
+        match instruction(emu, p) {
+            Ok (None) => {},
+            Ok (Some (outcome)) => break Ok (outcome),
+            Err (e) => break Err (e)
+        }
+    };
+    profile.lock().unwrap().merge(counts);
+    result
+}
+
+// Execute the instruction at `p.ip`; an outcome ends the time slice.
+#[inline(always)]
+pub fn instruction(emu: &Emu, p: &mut Process) -> Result<Option<Outcome>, Error> {
+    let ip = p.ip;
+    let opcode = code::opcode(emu.code[ip as usize]);
+    match opcode {
+        BEAMOpcode::label |
+        BEAMOpcode::line => step(emu, p),
+        BEAMOpcode::func_info => {
+            let (m, f, a) = (arg(emu, p, 0), arg(emu, p, 1), arg(emu, p, 2));
+            return Err (Error::FunctionClause((m.1 as usize, f.1 as usize, a.1 as usize)))
+        },
+        BEAMOpcode::int_code_end => return Ok (Some (Outcome::Returned)),
+        BEAMOpcode::call => {
+            p.cp = following(emu, ip);
+            p.ip = arg(emu, p, 1).1;
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::call_last => {
+            let n = arg(emu, p, 2).1;
+            try!( deallocate(p, n) );
+            p.ip = arg(emu, p, 1).1;
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::call_only => {
+            p.ip = arg(emu, p, 1).1;
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::call_ext => {
+            p.cp = following(emu, ip);
+            try!( call_ext(emu, p) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::call_ext_last => {
+            let n = arg(emu, p, 2).1;
+            try!( deallocate(p, n) );
+            try!( call_ext(emu, p) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::call_ext_only => {
+            try!( call_ext(emu, p) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::bif0 => {
+            let dst = arg(emu, p, 1);
+            let result = try!( call_bif(emu, p, arg(emu, p, 0).1, &[]) );
+            try!( store(p, dst, result) );
+            step(emu, p);
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::bif1 |
+        BEAMOpcode::bif2 |
+        BEAMOpcode::gc_bif1 |
+        BEAMOpcode::gc_bif2 |
+        BEAMOpcode::gc_bif3 => {
+            // bif: Fail Bif Args... Dst
+            // gc_bif: Fail Live Bif Args... Dst
+            let nargs = match opcode {
+                BEAMOpcode::bif1 | BEAMOpcode::gc_bif1 => 1,
+                BEAMOpcode::bif2 | BEAMOpcode::gc_bif2 => 2,
+                _ => 3
+            };
+            let first = match opcode {
+                BEAMOpcode::bif1 | BEAMOpcode::bif2 => 2,
+                _ => 3
+            };
+            let mut args = vec![];
+            for n in first .. first + nargs {
+                let operand = arg(emu, p, n);
+                args.push(try!( fetch(emu, p, operand) ));
+            }
+            // The BIF may allocate: collect first if the heap is
+            // full, keeping the live X registers and the arguments.
+            if first == 3 && p.heap.needs_gc(0) {
+                let live = arg(emu, p, 1).1 as usize;
+                p.garbage_collect(0, live, &mut args, false);
+            }
+            let (fail, dst) = (arg(emu, p, 0).1, arg(emu, p, first + nargs));
+            let import = arg(emu, p, first - 1).1;
+            match call_bif(emu, p, import, &args) {
+                Ok (result) => {
+                    try!( store(p, dst, result) );
+                    step(emu, p);
+                },
+                Err (_) if fail != 0 => p.ip = fail,
+                Err (e) => return Err (e)
+            }
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::i_plus |
+        BEAMOpcode::i_minus |
+        BEAMOpcode::i_times => {
+            // i_plus: Fail Live Src1 Src2 Dst
+            let (a, b) = (x_or_integer(p, arg(emu, p, 2)), x_or_integer(p, arg(emu, p, 3)));
+            let small = match (a, b) {
+                (Term::Small(a), Term::Small(b)) => match opcode {
+                    BEAMOpcode::i_plus => a.checked_add(b),
+                    BEAMOpcode::i_minus => a.checked_sub(b),
+                    _ => a.checked_mul(b)
+                },
+                _ => None
+            };
+            // Anything but small integers is up to the BIF.
+            let result = match small {
+                Some (n) => Ok (Term::Small(n)),
+                None => {
+                    let mut args = [a, b];
+                    if p.heap.needs_gc(0) {
+                        let live = arg(emu, p, 1).1 as usize;
+                        p.garbage_collect(0, live, &mut args, false);
+                    }
+                    match opcode {
+                        BEAMOpcode::i_plus => arith::plus(&mut p.heap, args[0], args[1]),
+                        BEAMOpcode::i_minus => arith::minus(&mut p.heap, args[0], args[1]),
+                        _ => arith::times(&mut p.heap, args[0], args[1])
+                    }
+                }
+            };
+            let (fail, dst) = (arg(emu, p, 0).1, arg(emu, p, 4).1 as usize);
+            match result {
+                Ok (result) => {
+                    p.x[dst] = result;
+                    step(emu, p);
+                },
+                Err (_) if fail != 0 => p.ip = fail,
+                Err (e) => return Err (Error::Bif(e))
+            }
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::allocate |
+        BEAMOpcode::allocate_zero |
+        BEAMOpcode::allocate_heap |
+        BEAMOpcode::allocate_heap_zero => {
+            // Y registers are always initialized, so the `_zero`
+            // variants are no different.
+            // allocate_heap: StackNeed HeapNeed Live
+            let n = arg(emu, p, 0).1 as usize;
+            if opcode == BEAMOpcode::allocate_heap ||
+               opcode == BEAMOpcode::allocate_heap_zero {
+                let (need, next) = heap_need(emu, p, 1);
+                let live = arg(emu, p, next).1 as usize;
+                if p.heap.needs_gc(need)
+                    { p.garbage_collect(need, live, &mut [], false) }
+            }
+            let cp = p.cp;
+            p.stack.push(Term::CP(cp));
+            for _ in 0 .. n
+                { p.stack.push(Term::Nil) }
+            step(emu, p);
+        },
+        BEAMOpcode::test_heap => {
+            // test_heap: HeapNeed Live
+            let (need, next) = heap_need(emu, p, 0);
+            let live = arg(emu, p, next).1 as usize;
+            if p.heap.needs_gc(need)
+                { p.garbage_collect(need, live, &mut [], false) }
+            step(emu, p);
+        },
+        BEAMOpcode::test_heap_u => {
+            let (need, live) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1 as usize);
+            if p.heap.needs_gc(need)
+                { p.garbage_collect(need, live, &mut [], false) }
+            step(emu, p);
+        },
+        BEAMOpcode::deallocate => {
+            let n = arg(emu, p, 0).1;
+            try!( deallocate(p, n) );
+            step(emu, p);
+        },
+        BEAMOpcode::return_ => {
+            // The continuation pointer is spent, so it doesn't show
+            // up in stack traces.
+            p.ip = p.cp;
+            p.cp = HALT;
+        },
+        BEAMOpcode::is_lt |
+        BEAMOpcode::is_ge |
+        BEAMOpcode::is_eq |
+        BEAMOpcode::is_ne |
+        BEAMOpcode::is_eq_exact |
+        BEAMOpcode::is_ne_exact => {
+            let (a, b) = (arg(emu, p, 1), arg(emu, p, 2));
+            let (a, b) = (try!( fetch(emu, p, a) ), try!( fetch(emu, p, b) ));
+            let ordering = match opcode {
+                BEAMOpcode::is_eq_exact |
+                BEAMOpcode::is_ne_exact =>
+                    compare::compare_exact(&p.heap, &emu.atoms, a, b),
+                _ => compare::compare(&p.heap, &emu.atoms, a, b)
+            };
+            let passed = match opcode {
+                BEAMOpcode::is_lt => ordering == Ordering::Less,
+                BEAMOpcode::is_ge => ordering != Ordering::Less,
+                BEAMOpcode::is_eq |
+                BEAMOpcode::is_eq_exact => ordering == Ordering::Equal,
+                _ => ordering != Ordering::Equal
+            };
+            test(emu, p, passed);
+        },
+        BEAMOpcode::is_integer |
+        BEAMOpcode::is_float |
+        BEAMOpcode::is_number |
+        BEAMOpcode::is_atom |
+        BEAMOpcode::is_binary |
+        BEAMOpcode::is_bitstr |
+        BEAMOpcode::is_tuple |
+        BEAMOpcode::is_map |
+        BEAMOpcode::is_list |
+        BEAMOpcode::is_nonempty_list |
+        BEAMOpcode::is_nil |
+        BEAMOpcode::is_function => {
+            let src = arg(emu, p, 1);
+            let t = try!( fetch(emu, p, src) );
+            let passed = {
+                let ref heap = p.heap;
+                match opcode {
+                    BEAMOpcode::is_integer => t.is_integer(heap),
+                    BEAMOpcode::is_float => t.float_value(heap).is_some(),
+                    BEAMOpcode::is_number => t.is_number(heap),
+                    BEAMOpcode::is_atom => t.is_atom(),
+                    BEAMOpcode::is_binary => t.is_binary(heap),
+                    BEAMOpcode::is_bitstr => t.is_bitstring(heap),
+                    BEAMOpcode::is_function => t.fun_parts(heap).is_some(),
+                    BEAMOpcode::is_map => map::is_map(heap, t),
+                    BEAMOpcode::is_list => t == Term::Nil || t.is_cons(),
+                    BEAMOpcode::is_nonempty_list => t.is_cons(),
+                    BEAMOpcode::is_nil => t == Term::Nil,
+                    _ => t.tuple_elements(heap).is_some()
+                }
+            };
+            test(emu, p, passed);
+        },
+        BEAMOpcode::is_integer_x |
+        BEAMOpcode::is_atom_x |
+        BEAMOpcode::is_nil_x |
+        BEAMOpcode::is_list_x |
+        BEAMOpcode::is_nonempty_list_x |
+        BEAMOpcode::is_tuple_x => {
+            let t = p.x[arg(emu, p, 1).1 as usize];
+            let passed = match opcode {
+                BEAMOpcode::is_integer_x => t.is_integer(&p.heap),
+                BEAMOpcode::is_atom_x => t.is_atom(),
+                BEAMOpcode::is_nil_x => t == Term::Nil,
+                BEAMOpcode::is_list_x => t == Term::Nil || t.is_cons(),
+                BEAMOpcode::is_nonempty_list_x => t.is_cons(),
+                _ => t.tuple_elements(&p.heap).is_some()
+            };
+            test(emu, p, passed);
+        },
+        BEAMOpcode::is_function2 => {
+            let (src, arity) = (arg(emu, p, 1), arg(emu, p, 2));
+            let (t, arity) = (try!( fetch(emu, p, src) ), try!( fetch(emu, p, arity) ));
+            let passed = match (t.fun_parts(&p.heap), arity) {
+                (Some ((index, free)), Term::Small(a)) =>
+                    emu.funs[index].mfa.2 == free.len() + a as usize,
+                _ => false
+            };
+            test(emu, p, passed);
+        },
+        BEAMOpcode::test_arity => {
+            let src = arg(emu, p, 1);
+            let t = try!( fetch(emu, p, src) );
+            let arity = arg(emu, p, 2).1 as usize;
+            let passed = t.header(&p.heap) == Some (Header::Tuple(arity));
+            test(emu, p, passed);
+        },
+        BEAMOpcode::is_tuple_of_arity => {
+            let (src, arity) = (arg(emu, p, 1).1 as usize, arg(emu, p, 2).1 as usize);
+            let passed = p.x[src].header(&p.heap) == Some (Header::Tuple(arity));
+            test(emu, p, passed);
+        },
+        BEAMOpcode::is_tuple_arity_get => {
+            let (src, arity, index) = (arg(emu, p, 1).1 as usize, arg(emu, p, 2).1 as usize,
+                                       arg(emu, p, 3).1 as usize);
+            let element = p.x[src].tuple_elements(&p.heap)
+                                  .and_then(|elements| if elements.len() == arity { elements.get(index).cloned() }
+                                                       else { None });
+            match element {
+                Some (element) => {
+                    try!( store(p, arg(emu, p, 4), element) );
+                    step(emu, p);
+                },
+                None => test(emu, p, false)
+            }
+        },
+        BEAMOpcode::is_nonempty_get_list => {
+            match p.x[arg(emu, p, 1).1 as usize] {
+                Term::Cons(ptr) => {
+                    let (head, tail) = (p.heap.get(ptr), p.heap.get(ptr + 1));
+                    try!( store(p, arg(emu, p, 2), head) );
+                    try!( store(p, arg(emu, p, 3), tail) );
+                    step(emu, p);
+                },
+                _ => test(emu, p, false)
+            }
+        },
+        BEAMOpcode::is_eq_exact_immed => {
+            // Immediates are equal only if they're the same word.
+            let (src, c) = (arg(emu, p, 1).1 as usize, arg(emu, p, 2).1 as usize);
+            let passed = p.x[src] == emu.literals[c];
+            test(emu, p, passed);
+        },
+        BEAMOpcode::jump => p.ip = arg(emu, p, 0).1,
+        BEAMOpcode::move_ => {
+            let (src, dst) = (arg(emu, p, 0), arg(emu, p, 1));
+            let t = try!( fetch(emu, p, src) );
+            try!( store(p, dst, t) );
+            step(emu, p);
+        },
+        BEAMOpcode::move_x_x => {
+            let (src, dst) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1 as usize);
+            p.x[dst] = p.x[src];
+            step(emu, p);
+        },
+        BEAMOpcode::move2_x_x => {
+            let (src1, dst1) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1 as usize);
+            let (src2, dst2) = (arg(emu, p, 2).1 as usize, arg(emu, p, 3).1 as usize);
+            p.x[dst1] = p.x[src1];
+            p.x[dst2] = p.x[src2];
+            step(emu, p);
+        },
+        BEAMOpcode::move_c_x => {
+            // The constant is an immediate: no need to copy it.
+            let (c, dst) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1 as usize);
+            p.x[dst] = emu.literals[c];
+            step(emu, p);
+        },
+        BEAMOpcode::get_tuple_element => {
+            let (src, index, dst) = (arg(emu, p, 0), arg(emu, p, 1).1 as usize,
+                                     arg(emu, p, 2));
+            let tuple = try!( fetch(emu, p, src) );
+            let element = try!( tuple.tuple_elements(&p.heap)
+                                     .and_then(|elements| elements.get(index).cloned())
+                                     .ok_or(Error::BadCode(ip)) );
+            try!( store(p, dst, element) );
+            step(emu, p);
+        },
+        BEAMOpcode::get_tuple_element_x => {
+            let (src, index, dst) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1 as usize,
+                                     arg(emu, p, 2).1 as usize);
+            p.x[dst] = try!( p.x[src].tuple_elements(&p.heap)
+                                     .and_then(|elements| elements.get(index).cloned())
+                                     .ok_or(Error::BadCode(ip)) );
+            step(emu, p);
+        },
+        BEAMOpcode::select_val |
+        BEAMOpcode::select_tuple_arity => {
+            // select_val Src Fail {Value Label ...}
+            // select_tuple_arity Src Fail {Arity Label ...}
+            // The list is a jump table unless it has values other than
+            // integers and atoms.
+            let src = arg(emu, p, 0);
+            let t = try!( fetch(emu, p, src) );
+            let key = match (opcode, t) {
+                (BEAMOpcode::select_val, Term::Small(i)) => Some (SelectKey::Integer(i)),
+                (BEAMOpcode::select_val, Term::Atom(a)) => Some (SelectKey::Atom(a as u32)),
+                (BEAMOpcode::select_val, Term::Nil) => Some (SelectKey::Atom(0)),
+                (BEAMOpcode::select_val, _) => None,
+                _ => match t.header(&p.heap) {
+                    Some (Header::Tuple(arity)) => Some (SelectKey::Integer(arity as i64)),
+                    _ => None
+                }
+            };
+            let target = match (arg(emu, p, 2), key) {
+                ((ArgTag::t, index), Some (key)) => emu.jump_tables[index as usize].lookup(key),
+                ((ArgTag::t, _), None) => None,
+                _ => try!( select_linear(emu, p, t) )
+            };
+            p.ip = target.unwrap_or(arg(emu, p, 1).1);
+        },
+        BEAMOpcode::get_list |
+        BEAMOpcode::get_hd |
+        BEAMOpcode::get_tl => {
+            // get_list Src Head Tail
+            // get_hd Src Head
+            // get_tl Src Tail
+            let src = arg(emu, p, 0);
+            let ptr = match try!( fetch(emu, p, src) ) {
+                Term::Cons(ptr) => ptr,
+                _ => return Err (Error::BadCode(ip))
+            };
+            let (head, tail) = (p.heap.get(ptr), p.heap.get(ptr + 1));
+            match opcode {
+                BEAMOpcode::get_list => {
+                    try!( store(p, arg(emu, p, 1), head) );
+                    try!( store(p, arg(emu, p, 2), tail) );
+                },
+                BEAMOpcode::get_hd => try!( store(p, arg(emu, p, 1), head) ),
+                _ => try!( store(p, arg(emu, p, 1), tail) )
+            }
+            step(emu, p);
+        },
+        BEAMOpcode::put_list => {
+            // put_list Head Tail Dst
+            let (head, tail, dst) = (arg(emu, p, 0), arg(emu, p, 1), arg(emu, p, 2));
+            let (head, tail) = (try!( fetch(emu, p, head) ), try!( fetch(emu, p, tail) ));
+            let list = Term::cons(&mut p.heap, head, tail);
+            try!( store(p, dst, list) );
+            step(emu, p);
+        },
+        BEAMOpcode::put_list_x => {
+            let (head, tail, dst) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1 as usize,
+                                     arg(emu, p, 2).1 as usize);
+            let (head, tail) = (p.x[head], p.x[tail]);
+            p.x[dst] = Term::cons(&mut p.heap, head, tail);
+            step(emu, p);
+        },
+        BEAMOpcode::set_tuple_element => {
+            // set_tuple_element Value Tuple Index: only on a tuple just
+            // built, so it can be updated in place.
+            let (src, tuple, index) = (arg(emu, p, 0), arg(emu, p, 1), arg(emu, p, 2).1 as usize);
+            let (value, tuple) = (try!( fetch(emu, p, src) ), try!( fetch(emu, p, tuple) ));
+            match (tuple, tuple.header(&p.heap)) {
+                (Term::Boxed(ptr), Some (Header::Tuple(arity))) if index < arity =>
+                    p.heap.set(ptr + 1 + index, value),
+                _ => return Err (Error::BadCode(ip))
+            }
+            step(emu, p);
+        },
+        BEAMOpcode::update_record => {
+            // update_record Hint Size Src Dst {Index Value ...}, with
+            // 1-based indices.  The hint whether the tuple could be
+            // updated in place is ignored: it's always copied.
+            let (size, src, dst) = (arg(emu, p, 1).1 as usize, arg(emu, p, 2), arg(emu, p, 3));
+            let tuple = try!( fetch(emu, p, src) );
+            let mut elements = match tuple.tuple_elements(&p.heap) {
+                Some (elements) if elements.len() == size => elements.to_vec(),
+                _ => return Err (Error::BadCode(ip))
+            };
+            let len = arg(emu, p, 4).1 as usize;
+            for i in 0 .. len / 2 {
+                let (index, value) = (arg(emu, p, 5 + 2 * i).1 as usize, arg(emu, p, 6 + 2 * i));
+                if index < 1 || index > size
+                    { return Err (Error::BadCode(ip)) }
+                elements[index - 1] = try!( fetch(emu, p, value) );
+            }
+            let tuple = Term::tuple(&mut p.heap, &elements);
+            try!( store(p, dst, tuple) );
+            step(emu, p);
+        },
+        BEAMOpcode::put_tuple => {
+            // The elements are given by the `put` instructions which
+            // follow, so the whole sequence is executed at once.
+            let (arity, dst) = (arg(emu, p, 0).1, arg(emu, p, 1));
+            let mut elements = vec![];
+            let mut put = following(emu, ip);
+            for _ in 0 .. arity {
+                if code::opcode(emu.code[put as usize]) != BEAMOpcode::put
+                    { return Err (Error::BadCode(put)) }
+                let src = code::operand(emu.code[put as usize + 1]);
+                elements.push(try!( fetch(emu, p, src) ));
+                put = following(emu, put);
+            }
+            let tuple = Term::tuple(&mut p.heap, &elements);
+            try!( store(p, dst, tuple) );
+            p.ip = put;
+        },
+        BEAMOpcode::put => return Err (Error::BadCode(ip)),
+        BEAMOpcode::send => {
+            let (to, msg) = (p.x[0], p.x[1]);
+            p.x[0] = try!( process::send(emu, p, to, msg).map_err(Error::Bif) );
+            step(emu, p);
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::loop_rec => {
+            // loop_rec Fail Dst: the next message to look at, if any.
+            if p.save == p.mailbox.len()
+                { emu.processes.receive(p) }
+            // Exit signals can't be caught.
+            if let Some (reason) = p.exiting {
+                let exception = Exception { class: Class::Exit, reason: reason,
+                                            stacktrace: Term::Nil };
+                return Ok (Some (Outcome::Failed(exception)))
+            }
+            match p.mailbox.get(p.save).cloned() {
+                Some (msg) => {
+                    let dst = arg(emu, p, 1);
+                    try!( store(p, dst, msg) );
+                    step(emu, p);
+                },
+                None => p.ip = arg(emu, p, 0).1
+            }
+        },
+        BEAMOpcode::loop_rec_end => {
+            // The message didn't match: try the next one.
+            p.save += 1;
+            p.ip = arg(emu, p, 0).1;
+        },
+        BEAMOpcode::remove_message => {
+            let _ = p.mailbox.remove(p.save);
+            p.save = 0;
+            if let Some (id) = p.timer.take()
+                { emu.scheduler.cancel_timer(id) }
+            p.timed_out = false;
+            step(emu, p);
+        },
+        BEAMOpcode::wait => {
+            p.ip = arg(emu, p, 0).1;
+            if p.save == p.mailbox.len()
+                { return Ok (Some (Outcome::Waiting)) }
+        },
+        BEAMOpcode::wait_timeout => {
+            let time = try!( fetch(emu, p, arg(emu, p, 1)) );
+            let ms = match time {
+                Term::Small(ms) if ms >= 0 => Some (ms as u64),
+                Term::Atom(a) if emu.atoms.get_atom(a).as_ref()
+                                    .map(|a| &a[..]) == Some ("infinity") => None,
+                _ => return Err (Error::TimeoutValue)
+            };
+            if p.timed_out || ms == Some (0) {
+                p.timed_out = false;
+                step(emu, p);
+            } else {
+                if let (Some (ms), None) = (ms, p.timer) {
+                    p.timer = Some (emu.scheduler.start_timer(p.pid, ms));
+                }
+                p.ip = arg(emu, p, 0).1;
+                if p.save == p.mailbox.len()
+                    { return Ok (Some (Outcome::Waiting)) }
+            }
+        },
+        BEAMOpcode::timeout => {
+            p.save = 0;
+            p.timer = None;
+            p.timed_out = false;
+            step(emu, p);
+        },
+        BEAMOpcode::recv_mark => {
+            p.recv_mark = Some ((arg(emu, p, 0).1, p.mailbox.len()));
+            step(emu, p);
+        },
+        BEAMOpcode::recv_set => {
+            // Messages which arrived before the mark can't contain the
+            // reference it was made for.
+            if let Some ((label, len)) = p.recv_mark {
+                if label == arg(emu, p, 0).1
+                    { p.save = len }
+            }
+            step(emu, p);
+        },
+        BEAMOpcode::catch |
+        BEAMOpcode::try => {
+            // catch/try Y Handler
+            let (dst, handler) = (arg(emu, p, 0), arg(emu, p, 1).1);
+            try!( store(p, dst, Term::Catch(handler)) );
+            step(emu, p);
+        },
+        BEAMOpcode::try_end => {
+            let dst = arg(emu, p, 0);
+            try!( store(p, dst, Term::Nil) );
+            step(emu, p);
+        },
+        BEAMOpcode::catch_end => {
+            // Reached by falling through with the value of the
+            // expression in x(0), or by an exception.
+            let dst = arg(emu, p, 0);
+            try!( store(p, dst, Term::Nil) );
+            if p.caught {
+                p.caught = false;
+                let exception = Exception { class: try!( class(emu, p.x[1]) ),
+                                            reason: p.x[2], stacktrace: p.x[3] };
+                p.x[0] = exception.caught(&mut p.heap, &emu.atoms);
+            }
+            step(emu, p);
+        },
+        BEAMOpcode::try_case => {
+            // The handler: class, reason and stack trace go to
+            // x(0) .. x(2).
+            let dst = arg(emu, p, 0);
+            try!( store(p, dst, Term::Nil) );
+            p.caught = false;
+            p.x[0] = p.x[1];
+            p.x[1] = p.x[2];
+            p.x[2] = p.x[3];
+            step(emu, p);
+        },
+        BEAMOpcode::raise => {
+            // raise Stacktrace Reason: rethrow an exception caught by
+            // `try` whose class no clause matched.
+            let (stacktrace, reason) = (arg(emu, p, 0), arg(emu, p, 1));
+            let (stacktrace, reason) = (try!( fetch(emu, p, stacktrace) ),
+                                        try!( fetch(emu, p, reason) ));
+            let class = match p.stacktrace {
+                Some ((class, last)) if last == stacktrace => class,
+                _ => Class::Error
+            };
+            return Err (Error::Bif(bif::Error::Raise(class, reason, stacktrace)))
+        },
+        BEAMOpcode::badmatch |
+        BEAMOpcode::case_end |
+        BEAMOpcode::try_case_end => {
+            let src = arg(emu, p, 0);
+            let t = try!( fetch(emu, p, src) );
+            return Err (match opcode {
+                BEAMOpcode::badmatch => Error::Badmatch(t),
+                BEAMOpcode::case_end => Error::CaseClause(t),
+                _ => Error::TryClause(t)
+            })
+        },
+        BEAMOpcode::if_end => return Err (Error::IfClause),
+        BEAMOpcode::make_fun2 => {
+            // The free variables are in x(0) .. x(num_free - 1).
+            let index = arg(emu, p, 0).1 as usize;
+            let num_free = emu.funs[index].num_free;
+            let free = p.x[.. num_free].to_vec();
+            p.x[0] = Term::fun(&mut p.heap, index, &free);
+            step(emu, p);
+        },
+        BEAMOpcode::bs_start_match2 |
+        BEAMOpcode::bs_start_match3 |
+        BEAMOpcode::bs_start_match4 |
+        BEAMOpcode::bs_get_tail |
+        BEAMOpcode::bs_get_position |
+        BEAMOpcode::bs_set_position |
+        BEAMOpcode::bs_save2 |
+        BEAMOpcode::bs_restore2 |
+        BEAMOpcode::bs_context_to_binary |
+        BEAMOpcode::bs_get_integer2 |
+        BEAMOpcode::bs_get_float2 |
+        BEAMOpcode::bs_get_binary2 |
+        BEAMOpcode::bs_skip_bits2 |
+        BEAMOpcode::bs_get_utf8 |
+        BEAMOpcode::bs_get_utf16 |
+        BEAMOpcode::bs_get_utf32 |
+        BEAMOpcode::bs_skip_utf8 |
+        BEAMOpcode::bs_skip_utf16 |
+        BEAMOpcode::bs_skip_utf32 |
+        BEAMOpcode::bs_test_tail2 |
+        BEAMOpcode::bs_test_unit |
+        BEAMOpcode::bs_match_string |
+        BEAMOpcode::bs_match |
+        BEAMOpcode::bs_init2 |
+        BEAMOpcode::bs_init_bits |
+        BEAMOpcode::bs_append |
+        BEAMOpcode::bs_private_append |
+        BEAMOpcode::bs_put_integer |
+        BEAMOpcode::bs_put_float |
+        BEAMOpcode::bs_put_binary |
+        BEAMOpcode::bs_put_utf8 |
+        BEAMOpcode::bs_put_utf16 |
+        BEAMOpcode::bs_put_utf32 |
+        BEAMOpcode::bs_put_string |
+        BEAMOpcode::bs_add |
+        BEAMOpcode::bs_utf8_size |
+        BEAMOpcode::bs_utf16_size |
+        BEAMOpcode::bs_create_bin =>
+            try!( bit_syntax(emu, p, opcode) ),
+        BEAMOpcode::put_map_assoc |
+        BEAMOpcode::put_map_exact |
+        BEAMOpcode::has_map_fields |
+        BEAMOpcode::get_map_elements =>
+            try!( map_op(emu, p, opcode) ),
+        BEAMOpcode::fclearerror |
+        BEAMOpcode::fcheckerror |
+        BEAMOpcode::fmove |
+        BEAMOpcode::fconv |
+        BEAMOpcode::fadd |
+        BEAMOpcode::fsub |
+        BEAMOpcode::fmul |
+        BEAMOpcode::fdiv |
+        BEAMOpcode::fnegate =>
+            try!( float_op(emu, p, opcode) ),
+        BEAMOpcode::call_fun => {
+            // The fun follows its arguments in the X registers.
+            let arity = arg(emu, p, 0).1 as usize;
+            let f = p.x[arity];
+            p.cp = following(emu, ip);
+            try!( call_fun(emu, p, f, arity) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        // apply Arity: the module and function follow the arguments.
+        BEAMOpcode::apply => {
+            let arity = arg(emu, p, 0).1 as usize;
+            let (m, f) = (p.x[arity], p.x[arity + 1]);
+            p.cp = following(emu, ip);
+            try!( apply(emu, p, m, f, arity) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::apply_last => {
+            let (arity, n) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1);
+            let (m, f) = (p.x[arity], p.x[arity + 1]);
+            try!( deallocate(p, n) );
+            try!( apply(emu, p, m, f, arity) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::apply_list => {
+            p.cp = following(emu, ip);
+            try!( apply_list(emu, p, arg(emu, p, 0).1) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::apply_list_last => {
+            let n = arg(emu, p, 1).1;
+            try!( deallocate(p, n) );
+            try!( apply_list(emu, p, arg(emu, p, 0).1) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        },
+        BEAMOpcode::apply_list_only => {
+            try!( apply_list(emu, p, arg(emu, p, 0).1) );
+            if !reduce(p) { return Ok (Some (Outcome::Yielded)) }
+        }
+    }
+    Ok (None)
+}
+
+// Spend a reduction; false if the time slice is over.
+fn reduce(p: &mut Process) -> bool {
+    if p.reductions == 0
+        { return false }
+    p.reductions -= 1;
+    true
+}
+
+// The instruction after the one at `ip`.
+pub fn following(emu: &Emu, ip: CodeIdx) -> CodeIdx {
+    ip + 1 + code::num_operands(emu.code[ip as usize]) as CodeIdx
+}
+
+// Go on with the next instruction.
+fn step(emu: &Emu, p: &mut Process) {
+    p.ip = following(emu, p.ip);
+}
+
+fn arg(emu: &Emu, p: &Process, n: usize) -> Operand {
+    code::operand(emu.code[p.ip as usize + 1 + n])
+}
+
+// The number of words needed on the heap, given by operand `n`: either a
+// number of words or an allocation list of words, floats and funs.  Also
+// returns the index of the next operand.
+fn heap_need(emu: &Emu, p: &Process, n: usize) -> (usize, usize) {
+    match arg(emu, p, n) {
+        (ArgTag::z, len) => {
+            let mut need = 0;
+            for i in 0 .. len as usize / 2 {
+                let (kind, count) = (arg(emu, p, n + 1 + 2 * i).1, arg(emu, p, n + 2 + 2 * i).1);
+                // A float is a header and its bits; a fun a header and
+                // its index, the free variables being counted as words.
+                need += count as usize * if kind == 0 { 1 } else { 2 };
+            }
+            (need, n + 1 + len as usize)
+        },
+        (_, words) => (words as usize, n + 1)
+    }
+}
+
+fn fetch(emu: &Emu, p: &mut Process, operand: Operand) -> Result<Term, Error> {
+    match operand {
+        (ArgTag::x, n) => Ok (p.x[n as usize]),
+        (ArgTag::y, n) => p.stack.len().checked_sub(n as usize + 1)
+                                       .map(|i| p.stack[i])
+                                       .ok_or(Error::BadCode(p.ip)),
+        (ArgTag::a, 0) => Ok (Term::Nil),
+        (ArgTag::a, index) => Ok (Term::Atom(index as usize)),
+        (ArgTag::i, value) => Ok (Term::Small(value as i32 as i64)),
+        (ArgTag::u, value) => Ok (Term::Small(value as i64)),
+        (ArgTag::q, index) => {
+            let literal = emu.literals[index as usize];
+            Ok (p.heap.copy_term(&emu.literal_heap, literal))
+        },
+        _ => Err (Error::BadCode(p.ip))
+    }
+}
+
+fn store(p: &mut Process, operand: Operand, t: Term) -> Result<(), Error> {
+    match operand {
+        (ArgTag::x, n) => p.x[n as usize] = t,
+        (ArgTag::y, n) => match p.stack.len().checked_sub(n as usize + 1) {
+            Some (i) => p.stack[i] = t,
+            None => return Err (Error::BadCode(p.ip))
+        },
+        _ => return Err (Error::BadCode(p.ip))
+    }
+    Ok (())
+}
+
+// An operand of `i_plus` and the like: an X register or an integer.
+fn x_or_integer(p: &Process, operand: Operand) -> Term {
+    match operand {
+        (ArgTag::x, n) => p.x[n as usize],
+        (_, value) => Term::Small(value as i32 as i64)
+    }
+}
+
+// Jump to the fail label (the first operand) unless the test passed.
+fn test(emu: &Emu, p: &mut Process, passed: bool) {
+    p.ip = if passed { following(emu, p.ip) } else { arg(emu, p, 0).1 };
+}
+
+// Drop a frame of `n` Y registers and restore its continuation pointer.
+fn deallocate(p: &mut Process, n: u32) -> Result<(), Error> {
+    let len = p.stack.len();
+    if len < n as usize + 1
+        { return Err (Error::BadCode(p.ip)) }
+    p.stack.truncate(len - n as usize);
+    match p.stack.pop() {
+        Some (Term::CP(cp)) => { p.cp = cp; Ok (()) },
+        _ => Err (Error::BadCode(p.ip))
+    }
+}
+
+// call_ext* Arity Import: a BIF returns to the continuation pointer
+// right away, other functions are called through their export table
+// entry, which the loader put in place of the import.
+fn call_ext(emu: &Emu, p: &mut Process) -> Result<(), Error> {
+    let arity = arg(emu, p, 0).1 as usize;
+    match arg(emu, p, 1) {
+        (ArgTag::e, export) => match emu.exports.code(export) {
+            Some (ip) => { p.ip = ip; Ok (()) },
+            None => undefined(emu, p, emu.exports.mfa(export))
+        },
+        (_, import) => match emu.imports[import as usize] {
+            Import::Bif(bif) => call_native(emu, p, bif, arity),
+            Import::Export(_) => Err (Error::BadCode(p.ip))
+        }
+    }
+}
+
+// Call a BIF with the arguments in the X registers, and return.
+fn call_native(emu: &Emu, p: &mut Process, bif: bif::Bif, arity: usize) -> Result<(), Error> {
+    let args = p.x[.. arity].to_vec();
+    p.x[0] = try!( bif(emu, p, &args).map_err(Error::Bif) );
+    p.ip = p.cp;
+    p.cp = HALT;
+    Ok (())
+}
+
+// Call the fun `f` with the `arity` arguments in the X registers.
+fn call_fun(emu: &Emu, p: &mut Process, f: Term, arity: usize) -> Result<(), Error> {
+    let (index, free) = match f.fun_parts(&p.heap) {
+        Some ((index, free)) => (index, free.to_vec()),
+        None => return Err (Error::Badfun(f))
+    };
+    let entry = emu.funs[index];
+    if entry.mfa.2 != arity + free.len()
+        { return Err (Error::Badarity(f)) }
+    if arity + free.len() > MAX_X_REGS
+        { return Err (Error::Bif(bif::Error::SystemLimit)) }
+    p.x[arity .. arity + free.len()].copy_from_slice(&free);
+    p.ip = entry.code;
+    Ok (())
+}
+
+// Call `module:function` with the `arity` arguments in the X registers,
+// looking it up at run time: an exported function, or else a BIF.
+fn apply(emu: &Emu, p: &mut Process, module: Term, function: Term, arity: usize)
+    -> Result<(), Error>
+{
+    let mfa = match (module, function) {
+        (Term::Atom(m), Term::Atom(f)) => (m, f, arity),
+        _ => return Err (Error::Bif(bif::Error::Badarg))
+    };
+    if let Some (ip) = emu.exports.get(mfa) {
+        p.ip = ip;
+        return Ok (())
+    }
+    if let Some (bif) = emu.bifs.get(mfa)
+        { return call_native(emu, p, bif, arity) }
+    let is = |a, name: &str| emu.atoms.get_atom(a).map_or(false, |a| a == name);
+    if is(mfa.0, "erlang") && is(mfa.1, "apply") && (arity == 2 || arity == 3)
+        { return apply_list(emu, p, arity as u32) }
+    undefined(emu, p, mfa)
+}
+
+// erlang:apply/2,3: spread the argument list, the last argument, over
+// the X registers and call the fun or the function before it.
+fn apply_list(emu: &Emu, p: &mut Process, arity: u32) -> Result<(), Error> {
+    let list = p.x[arity as usize - 1];
+    let args = try!( list.list_elements(&p.heap).ok_or(Error::Bif(bif::Error::Badarg)) );
+    if args.len() + 2 > MAX_X_REGS
+        { return Err (Error::Bif(bif::Error::SystemLimit)) }
+    let (x0, x1) = (p.x[0], p.x[1]);
+    p.x[.. args.len()].copy_from_slice(&args);
+    match arity {
+        2 => call_fun(emu, p, x0, args.len()),
+        _ => apply(emu, p, x0, x1, args.len())
+    }
+}
+
+// A call of the missing function `mfa` becomes a call of
+// `undefined_function(Module, Function, Args)` in the error handler
+// module (see `Emu::error_handler`), if that is loaded: it may load the
+// function, say.  Otherwise it's an `undef` error.
+fn undefined(emu: &Emu, p: &mut Process, mfa: MFA) -> Result<(), Error> {
+    let (module, function, arity) = mfa;
+    let handler = (emu.error_handler, emu.atoms.add("undefined_function"), 3);
+    match emu.exports.get(handler) {
+        Some (ip) if module != emu.error_handler => {
+            let args = p.x[.. arity].to_vec();
+            p.x[2] = Term::list(&mut p.heap, &args);
+            p.x[0] = Term::Atom(module);
+            p.x[1] = Term::Atom(function);
+            p.ip = ip;
+            Ok (())
+        },
+        _ => Err (Error::Undef(mfa))
+    }
+}
+
+fn call_bif(emu: &Emu, p: &mut Process, import: u32, args: &[Term])
+    -> Result<Term, Error>
+{
+    match emu.imports[import as usize] {
+        Import::Bif(bif) => bif(emu, p, args).map_err(Error::Bif),
+        Import::Export(export) => Err (Error::Undef(emu.exports.mfa(export)))
+    }
+}
+
+fn class(emu: &Emu, t: Term) -> Result<Class, Error> {
+    match t {
+        Term::Atom(a) => emu.atoms.get_atom(a).and_then(|name| Class::from_name(&name))
+                                              .ok_or(Error::BadCode(HALT)),
+        _ => Err (Error::BadCode(HALT))
+    }
+}
+
+// Turn an error at `p.ip` into an exception.
+fn exception(emu: &Emu, p: &mut Process, e: Error) -> Exception {
+    let class = e.class();
+    let reason = e.reason(&mut p.heap, &emu.atoms);
+    let stacktrace = match e {
+        Error::Bif(bif::Error::Raise(_, _, stacktrace)) => stacktrace,
+        // The function with the arguments it was called with.
+        Error::FunctionClause((_, _, arity)) => {
+            let args = p.x[.. arity].to_vec();
+            stacktrace(emu, p, None, Some (&args))
+        },
+        Error::Undef(mfa) => {
+            let args = p.x[.. mfa.2].to_vec();
+            stacktrace(emu, p, Some (mfa), Some (&args))
+        },
+        _ => stacktrace(emu, p, None, None)
+    };
+    Exception { class: class, reason: reason, stacktrace: stacktrace }
+}
+
+// Unwind the stack to the innermost `catch` or `try` and continue at its
+// handler; false if there's none.
+fn catch(emu: &Emu, p: &mut Process, exception: Exception) -> bool {
+    // The frames above the handler's get dropped: the top of its frame
+    // is where the continuation pointer of the next one is.
+    let mut top = p.stack.len();
+    for i in (0 .. p.stack.len()).rev() {
+        match p.stack[i] {
+            Term::CP(_) => top = i,
+            Term::Catch(handler) => {
+                p.stack.truncate(top);
+                p.ip = handler;
+                p.cp = HALT;
+                p.x[1] = Term::Atom(emu.atoms.add(exception.class.name()));
+                p.x[2] = exception.reason;
+                p.x[3] = exception.stacktrace;
+                p.caught = true;
+                p.stacktrace = Some ((exception.class, exception.stacktrace));
+                return true
+            },
+            _ => {}
+        }
+    }
+    false
+}
+
+// Up to `STACKTRACE_DEPTH` entries of `{Module, Function, Arity, Location}`,
+// innermost first: the function `p.ip` is in, then those the continuation
+// pointer and the return addresses on the stack are in.  `undef` adds the
+// undefined function on top; `args`, if given, replaces the arity of the
+// first entry.
+fn stacktrace(emu: &Emu, p: &mut Process, undef: Option<MFA>, args: Option<&[Term]>) -> Term {
+    let mut entries: Vec<(MFA, Option<&Location>)> = vec![];
+    if let Some (mfa) = undef
+        { entries.push((mfa, None)) }
+    // Call sites, i.e. the instructions before return addresses.
+    let returns = p.stack.iter().rev().filter_map(|t| match *t {
+        Term::CP(cp) => Some (cp),
+        _ => None
+    });
+    let mut sites = vec![p.ip];
+    for cp in Some (p.cp).into_iter().chain(returns) {
+        // The continuation pointer may have been pushed by `allocate`
+        // already, or belong to the call which failed.
+        if cp == HALT
+            { continue }
+        let site = instruction_at(emu, cp - 1);
+        if sites.last() != Some (&site)
+            { sites.push(site) }
+    }
+    for &site in sites.iter() {
+        if let Some (mfa) = function_at(emu, site)
+            { entries.push((mfa, location_at(emu, site))) }
+    }
+    entries.truncate(STACKTRACE_DEPTH);
+    let atoms = &emu.atoms;
+    let mut list = Term::Nil;
+    for (i, &((m, f, arity), location)) in entries.iter().enumerate().rev() {
+        let arity = match args {
+            Some (args) if i == 0 => Term::list(&mut p.heap, args),
+            _ => Term::Small(arity as i64)
+        };
+        let location = match location {
+            Some (location) => {
+                let file = Term::string(&mut p.heap, &location.file);
+                let file = Term::tuple(&mut p.heap, &[Term::Atom(atoms.add("file")), file]);
+                let line = Term::tuple(&mut p.heap, &[Term::Atom(atoms.add("line")),
+                                                      Term::Small(location.line as i64)]);
+                Term::list(&mut p.heap, &[file, line])
+            },
+            None => Term::Nil
+        };
+        let entry = Term::tuple(&mut p.heap, &[Term::Atom(m), Term::Atom(f), arity, location]);
+        list = Term::cons(&mut p.heap, entry, list);
+    }
+    list
+}
+
+// The start of the instruction the word at code index `at` belongs to.
+fn instruction_at(emu: &Emu, at: CodeIdx) -> CodeIdx {
+    let mut at = at;
+    while !code::is_instruction(emu.code[at as usize])
+        { at -= 1 }
+    at
+}
+
+// The instructions before code index `end`, last first.
+fn instructions_before<'a>(emu: &'a Emu, end: CodeIdx) -> impl Iterator<Item = (usize, BEAMOpcode)> + 'a {
+    emu.code[.. end as usize].iter().enumerate().rev()
+                             .filter(|&(_, &w)| code::is_instruction(w))
+                             .map(|(i, &w)| (i, code::opcode(w)))
+}
+
+// The function containing code index `ip`, from its `func_info`.
+fn function_at(emu: &Emu, ip: CodeIdx) -> Option<MFA> {
+    for (i, opcode) in instructions_before(emu, ip + 1) {
+        match opcode {
+            BEAMOpcode::func_info => {
+                let arg = |n: usize| code::operand(emu.code[i + 1 + n]).1 as usize;
+                return Some ((arg(0), arg(1), arg(2)))
+            },
+            BEAMOpcode::int_code_end => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+// The location of the last `line` instruction before code index `ip` in
+// its function.  A function's own `line` comes before its `func_info`.
+fn location_at(emu: &Emu, ip: CodeIdx) -> Option<&Location> {
+    let header = code::opcode(emu.code[ip as usize]) == BEAMOpcode::func_info;
+    let start = if header { ip } else { following(emu, ip) };
+    for (i, opcode) in instructions_before(emu, start) {
+        match opcode {
+            BEAMOpcode::line => return match code::operand(emu.code[i + 1]).1 {
+                0 => None,
+                index => emu.lines.get(index as usize)
+            },
+            BEAMOpcode::label if header => {},
+            BEAMOpcode::func_info |
+            BEAMOpcode::int_code_end => return None,
+            _ if header => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+// Bit syntax instructions.
+//
+// Matching goes through a match context: a boxed object holding the
+// bitstring being matched, the position in bits and, for `bs_save2` and
+// `bs_restore2`, saved positions.  Old-style construction (`bs_init2`,
+// `bs_append`...) collects the bits of the `bs_put_*` instructions which
+// follow in `Process::construction`, while `bs_create_bin` builds a whole
+// binary at once.
+fn bit_syntax(emu: &Emu, p: &mut Process, opcode: BEAMOpcode) -> Result<(), Error> {
+    match opcode {
+        BEAMOpcode::bs_start_match2 |
+        BEAMOpcode::bs_start_match3 |
+        BEAMOpcode::bs_start_match4 => {
+            // bs_start_match2: Fail Bin Live Slots Dst
+            // bs_start_match3: Fail Bin Live Dst
+            // bs_start_match4: Fail Live Bin Dst, Fail being `no_fail` or
+            // `resume` if the binary is known to be one
+            let (src, live, slots, dst) = match opcode {
+                BEAMOpcode::bs_start_match2 =>
+                    (arg(emu, p, 1), arg(emu, p, 2).1, arg(emu, p, 3).1 as usize, arg(emu, p, 4)),
+                BEAMOpcode::bs_start_match3 => (arg(emu, p, 1), arg(emu, p, 2).1, 0, arg(emu, p, 3)),
+                _ => (arg(emu, p, 2), arg(emu, p, 1).1, 0, arg(emu, p, 3))
+            };
+            let mut roots = [try!( fetch(emu, p, src) )];
+            // Matching goes on where a context stands.
+            if slots == 0 && match_ctx(p, roots[0]).is_ok() {
+                try!( store(p, dst, roots[0]) );
+                step(emu, p);
+                return Ok (())
+            }
+            if p.heap.needs_gc(3 + slots)
+                { p.garbage_collect(3 + slots, live as usize, &mut roots, false) }
+            let (bin, pos) = match match_ctx(p, roots[0]) {
+                Ok ((_, bin, pos)) => (bin, pos),
+                Err (_) if roots[0].is_bitstring(&p.heap) => (roots[0], 0),
+                Err (_) => return fail(emu, p, Error::Bif(bif::Error::Badarg))
+            };
+            let mut words = vec![Term::Header(Header::MatchCtx(slots)), bin, Term::Small(pos as i64)];
+            words.resize(3 + slots, Term::Small(0));
+            let ctx = Term::Boxed(p.heap.alloc(&words));
+            try!( store(p, dst, ctx) );
+            step(emu, p);
+        },
+        BEAMOpcode::bs_get_tail |
+        BEAMOpcode::bs_get_position => {
+            // Ctx Dst Live
+            let (src, dst) = (arg(emu, p, 0), arg(emu, p, 1));
+            let ctx = try!( fetch(emu, p, src) );
+            let t = if opcode == BEAMOpcode::bs_get_position {
+                let (_, _, pos) = try!( match_ctx(p, ctx) );
+                Term::Small(pos as i64)
+            } else {
+                try!( match_tail(p, ctx) )
+            };
+            try!( store(p, dst, t) );
+            step(emu, p);
+        },
+        BEAMOpcode::bs_set_position => {
+            // Ctx Pos
+            let (src, pos) = (arg(emu, p, 0), arg(emu, p, 1));
+            let (ctx, pos) = (try!( fetch(emu, p, src) ), try!( fetch(emu, p, pos) ));
+            let (ptr, _, _) = try!( match_ctx(p, ctx) );
+            p.heap.set(ptr + 2, pos);
+            step(emu, p);
+        },
+        BEAMOpcode::bs_save2 |
+        BEAMOpcode::bs_restore2 => {
+            // Ctx Slot, Slot being a number or `start`
+            let src = arg(emu, p, 0);
+            let ctx = try!( fetch(emu, p, src) );
+            let (ptr, _, pos) = try!( match_ctx(p, ctx) );
+            match (opcode, arg(emu, p, 1)) {
+                (BEAMOpcode::bs_save2, (ArgTag::u, slot)) =>
+                    p.heap.set(ptr + 3 + slot as usize, Term::Small(pos as i64)),
+                (BEAMOpcode::bs_save2, _) => {},
+                (_, (ArgTag::u, slot)) => {
+                    let saved = p.heap.get(ptr + 3 + slot as usize);
+                    p.heap.set(ptr + 2, saved);
+                },
+                _ => p.heap.set(ptr + 2, Term::Small(0))
+            }
+            step(emu, p);
+        },
+        BEAMOpcode::bs_context_to_binary => {
+            // Reg: the binary a context matches, a binary stays as it is.
+            let reg = arg(emu, p, 0);
+            let t = try!( fetch(emu, p, reg) );
+            if let Ok ((_, bin, _)) = match_ctx(p, t)
+                { try!( store(p, reg, bin) ) }
+            step(emu, p);
+        },
+        BEAMOpcode::bs_get_integer2 |
+        BEAMOpcode::bs_get_float2 |
+        BEAMOpcode::bs_get_binary2 |
+        BEAMOpcode::bs_skip_bits2 => {
+            // bs_get_*2: Fail Ctx Live Size Unit Flags Dst
+            // bs_skip_bits2: Fail Ctx Size Unit Flags
+            let first = if opcode == BEAMOpcode::bs_skip_bits2 { 2 } else { 3 };
+            let (src, size) = (arg(emu, p, 1), arg(emu, p, first));
+            let (ctx, size) = (try!( fetch(emu, p, src) ), try!( fetch(emu, p, size) ));
+            let unit = arg(emu, p, first + 1).1 as usize;
+            let flags = try!( segment_flags(emu, p, first + 2) );
+            let segment = match opcode {
+                BEAMOpcode::bs_get_integer2 => Segment::Integer,
+                BEAMOpcode::bs_get_float2 => Segment::Float,
+                BEAMOpcode::bs_get_binary2 => Segment::Binary,
+                _ => Segment::Skip
+            };
+            let size = match size {
+                Term::Small(n) if n >= 0 => (n as usize).checked_mul(unit),
+                // The rest, which must be a whole number of units.
+                Term::Atom(_) => {
+                    let left = try!( bits_left(p, ctx) );
+                    if unit == 0 || left % unit == 0 { Some (left) } else { None }
+                },
+                _ => None
+            };
+            let matched = match size {
+                Some (size) => try!( match_segment(p, ctx, segment, size, flags) ),
+                None => None
+            };
+            match matched {
+                Some (t) => {
+                    if segment != Segment::Skip {
+                        let dst = arg(emu, p, 6);
+                        try!( store(p, dst, t) );
+                    }
+                    step(emu, p);
+                },
+                None => p.ip = arg(emu, p, 0).1
+            }
+        },
+        BEAMOpcode::bs_get_utf8 |
+        BEAMOpcode::bs_get_utf16 |
+        BEAMOpcode::bs_get_utf32 |
+        BEAMOpcode::bs_skip_utf8 |
+        BEAMOpcode::bs_skip_utf16 |
+        BEAMOpcode::bs_skip_utf32 => {
+            // bs_get_utf*: Fail Ctx Live Flags Dst
+            // bs_skip_utf*: Fail Ctx Live Flags
+            let width = match opcode {
+                BEAMOpcode::bs_get_utf8 | BEAMOpcode::bs_skip_utf8 => 8,
+                BEAMOpcode::bs_get_utf16 | BEAMOpcode::bs_skip_utf16 => 16,
+                _ => 32
+            };
+            let src = arg(emu, p, 1);
+            let ctx = try!( fetch(emu, p, src) );
+            let flags = try!( segment_flags(emu, p, 3) );
+            let get = match opcode {
+                BEAMOpcode::bs_get_utf8 | BEAMOpcode::bs_get_utf16 | BEAMOpcode::bs_get_utf32 => true,
+                _ => false
+            };
+            match try!( match_segment(p, ctx, Segment::Utf(width), 0, flags) ) {
+                Some (c) => {
+                    if get {
+                        let dst = arg(emu, p, 4);
+                        try!( store(p, dst, c) );
+                    }
+                    step(emu, p);
+                },
+                None => p.ip = arg(emu, p, 0).1
+            }
+        },
+        BEAMOpcode::bs_test_tail2 |
+        BEAMOpcode::bs_test_unit => {
+            // bs_test_tail2: Fail Ctx Bits
+            // bs_test_unit: Fail Ctx Unit
+            let src = arg(emu, p, 1);
+            let ctx = try!( fetch(emu, p, src) );
+            let (left, n) = (try!( bits_left(p, ctx) ), arg(emu, p, 2).1 as usize);
+            let passed = if opcode == BEAMOpcode::bs_test_tail2 { left == n }
+                         else { n == 0 || left % n == 0 };
+            test(emu, p, passed);
+        },
+        BEAMOpcode::bs_match_string => {
+            // Fail Ctx Bits Offset
+            let src = arg(emu, p, 1);
+            let ctx = try!( fetch(emu, p, src) );
+            let (n, offset) = (arg(emu, p, 2).1 as usize, arg(emu, p, 3).1 as usize);
+            let (ptr, bin, pos) = try!( match_ctx(p, ctx) );
+            let matched = pos + n <= try!( bit_size(p, bin) ) && {
+                let string = Bits { bytes: Cow::Borrowed(&emu.strings[offset ..]),
+                                    offset: 0,
+                                    size: n };
+                let bits = try!( bin.bits(&p.heap).ok_or(Error::BadCode(p.ip)) );
+                bits.read_bytes(pos, n) == string.read_bytes(0, n)
+            };
+            if matched
+                { p.heap.set(ptr + 2, Term::Small((pos + n) as i64)) }
+            test(emu, p, matched);
+        },
+        BEAMOpcode::bs_match => {
+            // Fail Ctx Commands
+            let src = arg(emu, p, 1);
+            let ctx = try!( fetch(emu, p, src) );
+            let end = 3 + arg(emu, p, 2).1 as usize;
+            let mut at = 3;
+            while at < end {
+                let (matched, next) = try!( match_command(emu, p, ctx, at) );
+                if !matched {
+                    p.ip = arg(emu, p, 0).1;
+                    return Ok (())
+                }
+                at = next;
+            }
+            step(emu, p);
+        },
+        BEAMOpcode::bs_init2 |
+        BEAMOpcode::bs_init_bits => {
+            // Fail Size Words Live Flags Dst, the size being in bytes for
+            // bs_init2 and in bits for bs_init_bits
+            let (size, words, live, dst) = (arg(emu, p, 1), arg(emu, p, 2).1 as usize,
+                                            arg(emu, p, 3).1 as usize, arg(emu, p, 5));
+            let size = match try!( fetch(emu, p, size) ) {
+                Term::Small(n) if n >= 0 =>
+                    n as usize * if opcode == BEAMOpcode::bs_init2 { 8 } else { 1 },
+                _ => return fail(emu, p, Error::Bif(bif::Error::Badarg))
+            };
+            if p.heap.needs_gc(words)
+                { p.garbage_collect(words, live, &mut [], false) }
+            p.construction = Some ((Builder::new(), size, dst));
+            try!( finish_construction(p) );
+            step(emu, p);
+        },
+        BEAMOpcode::bs_append |
+        BEAMOpcode::bs_private_append => {
+            // bs_append: Fail Size Extra Live Unit Bin Flags Dst
+            // bs_private_append: Fail Size Unit Bin Flags Dst
+            let (size, unit, bin, dst) = match opcode {
+                BEAMOpcode::bs_append =>
+                    (arg(emu, p, 1), arg(emu, p, 4).1 as usize, arg(emu, p, 5), arg(emu, p, 7)),
+                _ => (arg(emu, p, 1), arg(emu, p, 2).1 as usize, arg(emu, p, 3), arg(emu, p, 5))
+            };
+            let mut roots = [try!( fetch(emu, p, bin) )];
+            let size = try!( fetch(emu, p, size) );
+            if opcode == BEAMOpcode::bs_append {
+                let (extra, live) = (arg(emu, p, 2).1 as usize, arg(emu, p, 3).1 as usize);
+                if p.heap.needs_gc(extra)
+                    { p.garbage_collect(extra, live, &mut roots, false) }
+            }
+            let started = match (roots[0].bits(&p.heap), size) {
+                (Some (bits), Term::Small(n)) if n >= 0 && (unit == 0 || bits.size % unit == 0) => {
+                    let mut b = Builder::new();
+                    b.put_bits(&bits, 0, bits.size);
+                    Some ((b, bits.size + n as usize))
+                },
+                _ => None
+            };
+            match started {
+                Some ((b, size)) => p.construction = Some ((b, size, dst)),
+                None => return fail(emu, p, Error::Bif(bif::Error::Badarg))
+            }
+            try!( finish_construction(p) );
+            step(emu, p);
+        },
+        BEAMOpcode::bs_put_integer |
+        BEAMOpcode::bs_put_float |
+        BEAMOpcode::bs_put_binary |
+        BEAMOpcode::bs_put_utf8 |
+        BEAMOpcode::bs_put_utf16 |
+        BEAMOpcode::bs_put_utf32 => {
+            // bs_put_*: Fail Size Unit Flags Src
+            // bs_put_utf*: Fail Flags Src
+            let (segment, size, flags, src) = match opcode {
+                BEAMOpcode::bs_put_integer |
+                BEAMOpcode::bs_put_float |
+                BEAMOpcode::bs_put_binary => {
+                    let segment = match opcode {
+                        BEAMOpcode::bs_put_integer => Segment::Integer,
+                        BEAMOpcode::bs_put_float => Segment::Float,
+                        _ => Segment::Binary
+                    };
+                    let (size, unit, src) = (arg(emu, p, 1), arg(emu, p, 2).1 as usize, arg(emu, p, 4));
+                    let size = match try!( fetch(emu, p, size) ) {
+                        Term::Small(n) if n >= 0 => Some (n as usize * unit),
+                        // `all` of a binary.
+                        _ => None
+                    };
+                    (segment, size, try!( segment_flags(emu, p, 3) ), try!( fetch(emu, p, src) ))
+                },
+                _ => {
+                    let width = match opcode {
+                        BEAMOpcode::bs_put_utf8 => 8,
+                        BEAMOpcode::bs_put_utf16 => 16,
+                        _ => 32
+                    };
+                    let src = arg(emu, p, 2);
+                    (Segment::Utf(width), None, try!( segment_flags(emu, p, 1) ), try!( fetch(emu, p, src) ))
+                }
+            };
+            let put = match p.construction {
+                Some ((ref mut b, _, _)) => put_segment(&p.heap, b, segment, src, size, flags),
+                None => return Err (Error::BadCode(p.ip))
+            };
+            if !put
+                { return fail(emu, p, Error::Bif(bif::Error::Badarg)) }
+            try!( finish_construction(p) );
+            step(emu, p);
+        },
+        BEAMOpcode::bs_put_string => {
+            // Len Offset
+            let (len, offset) = (arg(emu, p, 0).1 as usize, arg(emu, p, 1).1 as usize);
+            match p.construction {
+                Some ((ref mut b, _, _)) => b.put_bytes(&emu.strings[offset .. offset + len], 8 * len),
+                None => return Err (Error::BadCode(p.ip))
+            }
+            try!( finish_construction(p) );
+            step(emu, p);
+        },
+        BEAMOpcode::bs_add => {
+            // Fail Src1 Src2 Unit Dst: Src1 + Src2 * Unit
+            let (a, b) = (arg(emu, p, 1), arg(emu, p, 2));
+            let (a, b) = (try!( fetch(emu, p, a) ), try!( fetch(emu, p, b) ));
+            let unit = arg(emu, p, 3).1 as i64;
+            let sum = match (a, b) {
+                (Term::Small(a), Term::Small(b)) if a >= 0 && b >= 0 =>
+                    b.checked_mul(unit).and_then(|b| a.checked_add(b)),
+                _ => None
+            };
+            match sum {
+                Some (sum) => {
+                    let dst = arg(emu, p, 4);
+                    try!( store(p, dst, Term::Small(sum)) );
+                    step(emu, p);
+                },
+                None => return fail(emu, p, Error::Bif(bif::Error::Badarg))
+            }
+        },
+        BEAMOpcode::bs_utf8_size |
+        BEAMOpcode::bs_utf16_size => {
+            // Fail Src Dst: the size of the encoding in bytes
+            let width = if opcode == BEAMOpcode::bs_utf8_size { 8 } else { 16 };
+            let (src, dst) = (arg(emu, p, 1), arg(emu, p, 2));
+            let size = match try!( fetch(emu, p, src) ) {
+                Term::Small(c) => binary::utf_size(c, width),
+                _ => None
+            };
+            match size {
+                Some (bits) => {
+                    try!( store(p, dst, Term::Small(bits as i64 / 8)) );
+                    step(emu, p);
+                },
+                None => return fail(emu, p, Error::Bif(bif::Error::Badarg))
+            }
+        },
+        BEAMOpcode::bs_create_bin => {
+            // Fail Alloc Live Unit Dst Segments, each segment being
+            // Type Seg Unit Flags Value Size
+            let (need, live) = (arg(emu, p, 1).1 as usize, arg(emu, p, 2).1 as usize);
+            let (unit, dst) = (arg(emu, p, 3).1 as usize, arg(emu, p, 4));
+            if p.heap.needs_gc(need)
+                { p.garbage_collect(need, live, &mut [], false) }
+            let mut b = Builder::new();
+            for at in (6 .. 6 + arg(emu, p, 5).1 as usize).step_by(6) {
+                let kind = try!( atom_name(emu, p, arg(emu, p, at)) );
+                let (seg_unit, value, size) = (arg(emu, p, at + 2).1 as usize,
+                                               arg(emu, p, at + 4), arg(emu, p, at + 5));
+                if kind == "string" {
+                    let (offset, len) = (value.1 as usize, size.1 as usize);
+                    b.put_bytes(&emu.strings[offset .. offset + len], 8 * len);
+                    continue
+                }
+                let segment = match &kind[..] {
+                    "integer" => Segment::Integer,
+                    "float" => Segment::Float,
+                    "binary" | "append" | "private_append" => Segment::Binary,
+                    "utf8" => Segment::Utf(8),
+                    "utf16" => Segment::Utf(16),
+                    "utf32" => Segment::Utf(32),
+                    _ => return Err (Error::BadCode(p.ip))
+                };
+                let flags = try!( segment_flags(emu, p, at + 3) );
+                let (src, size) = (try!( fetch(emu, p, value) ), try!( fetch(emu, p, size) ));
+                let size = match size {
+                    Term::Small(n) if n >= 0 => Some (n as usize * seg_unit),
+                    // `all`, or no size for UTF segments.
+                    Term::Atom(_) | Term::Nil => None,
+                    _ => return fail(emu, p, Error::Bif(bif::Error::Badarg))
+                };
+                if !put_segment(&p.heap, &mut b, segment, src, size, flags)
+                    { return fail(emu, p, Error::Bif(bif::Error::Badarg)) }
+            }
+            if unit > 1 && b.size() % unit != 0
+                { return fail(emu, p, Error::Bif(bif::Error::Badarg)) }
+            let t = b.to_term(&mut p.heap);
+            try!( store(p, dst, t) );
+            step(emu, p);
+        },
+        _ => return Err (Error::BadCode(p.ip))
+    }
+    Ok (())
+}
+
+fn map_op(emu: &Emu, p: &mut Process, opcode: BEAMOpcode) -> Result<(), Error> {
+    match opcode {
+        BEAMOpcode::put_map_assoc |
+        BEAMOpcode::put_map_exact => {
+            // put_map_assoc Fail Src Dst Live {Key Value ...}
+            // put_map_exact Fail Src Dst Live {Key Value ...}, which only
+            // updates keys already there
+            let (src, dst, live) = (arg(emu, p, 1), arg(emu, p, 2), arg(emu, p, 3).1 as usize);
+            let len = arg(emu, p, 4).1 as usize;
+            // The new map takes at most a copy of each node on the way down
+            // to each key, which only matters for HAMTs; a collection now
+            // makes sure there's room for a flatmap at least.
+            if p.heap.needs_gc(0)
+                { p.garbage_collect(0, live, &mut [], false) }
+            let mut m = try!( fetch(emu, p, src) );
+            if !map::is_map(&p.heap, m)
+                { return fail(emu, p, Error::Badmap(m)) }
+            for i in 0 .. len / 2 {
+                let (k, v) = (arg(emu, p, 5 + 2 * i), arg(emu, p, 6 + 2 * i));
+                let (k, v) = (try!( fetch(emu, p, k) ), try!( fetch(emu, p, v) ));
+                if opcode == BEAMOpcode::put_map_exact &&
+                   map::get(&p.heap, &emu.atoms, m, k).is_none()
+                    { return fail(emu, p, Error::Badkey(k)) }
+                m = map::put(&mut p.heap, &emu.atoms, m, k, v).unwrap();
+            }
+            try!( store(p, dst, m) );
+            step(emu, p);
+        },
+        BEAMOpcode::has_map_fields => {
+            // has_map_fields Fail Src {Key ...}
+            let (src, len) = (arg(emu, p, 1), arg(emu, p, 2).1 as usize);
+            let m = try!( fetch(emu, p, src) );
+            let mut passed = map::is_map(&p.heap, m);
+            for i in 0 .. if passed { len } else { 0 } {
+                let k = arg(emu, p, 3 + i);
+                let k = try!( fetch(emu, p, k) );
+                if map::get(&p.heap, &emu.atoms, m, k).is_none() {
+                    passed = false;
+                    break
+                }
+            }
+            test(emu, p, passed);
+        },
+        BEAMOpcode::get_map_elements => {
+            // get_map_elements Fail Src {Key Dst ...}
+            let (src, len) = (arg(emu, p, 1), arg(emu, p, 2).1 as usize);
+            let m = try!( fetch(emu, p, src) );
+            // The common case of a single key, stored as soon as found.
+            if len == 2 {
+                let k = arg(emu, p, 3);
+                let k = try!( fetch(emu, p, k) );
+                return match map::get(&p.heap, &emu.atoms, m, k) {
+                    Some (v) => {
+                        try!( store(p, arg(emu, p, 4), v) );
+                        step(emu, p);
+                        Ok (())
+                    },
+                    None => {
+                        p.ip = arg(emu, p, 0).1;
+                        Ok (())
+                    }
+                }
+            }
+            // Look all the keys up first: a destination may also be a key
+            // or the map.
+            let mut values = vec![];
+            for i in 0 .. len / 2 {
+                let k = arg(emu, p, 3 + 2 * i);
+                let k = try!( fetch(emu, p, k) );
+                match map::get(&p.heap, &emu.atoms, m, k) {
+                    Some (v) => values.push(v),
+                    None => {
+                        p.ip = arg(emu, p, 0).1;
+                        return Ok (())
+                    }
+                }
+            }
+            for (i, v) in values.into_iter().enumerate()
+                { try!( store(p, arg(emu, p, 4 + 2 * i), v) ) }
+            step(emu, p);
+        },
+        _ => return Err (Error::BadCode(p.ip))
+    }
+    Ok (())
+}
+
+// The jump target of the value in a `select_val` list without a jump
+// table, found by comparing with each value in turn.
+fn select_linear(emu: &Emu, p: &mut Process, t: Term) -> Result<Option<CodeIdx>, Error> {
+    let len = arg(emu, p, 2).1 as usize;
+    for i in 0 .. len / 2 {
+        let value = arg(emu, p, 3 + 2 * i);
+        let value = try!( fetch(emu, p, value) );
+        if compare::eq_exact(&p.heap, &emu.atoms, t, value)
+            { return Ok (Some (arg(emu, p, 4 + 2 * i).1)) }
+    }
+    Ok (None)
+}
+
+// Float arithmetic works on the float registers.  Each operation checks
+// its result and raises `badarith` (or jumps to its fail label) if it
+// isn't finite, so `fclearerror` and `fcheckerror` have nothing left to do.
+fn float_op(emu: &Emu, p: &mut Process, opcode: BEAMOpcode) -> Result<(), Error> {
+    match opcode {
+        BEAMOpcode::fclearerror |
+        BEAMOpcode::fcheckerror => {},
+        BEAMOpcode::fmove => {
+            // fmove Src Dst: a float into a float register, or out of one
+            // into an X or Y register, boxed.
+            let (src, dst) = (arg(emu, p, 0), arg(emu, p, 1));
+            let f = try!( fetch_float(emu, p, src) );
+            match dst {
+                (ArgTag::fr, n) => p.fr[n as usize] = f,
+                _ => {
+                    let t = Term::float(&mut p.heap, f);
+                    try!( store(p, dst, t) );
+                }
+            }
+        },
+        BEAMOpcode::fconv => {
+            // fconv Src Dst: a number, converted, into a float register.
+            let (src, dst) = (arg(emu, p, 0), arg(emu, p, 1).1 as usize);
+            let t = try!( fetch(emu, p, src) );
+            let f = match t.number(&p.heap) {
+                Some (n) => n.to_f64(),
+                None => return Err (Error::Bif(bif::Error::Badarith))
+            };
+            if !f.is_finite()
+                { return Err (Error::Bif(bif::Error::Badarith)) }
+            p.fr[dst] = f;
+        },
+        BEAMOpcode::fnegate => {
+            // fnegate Fail Src Dst
+            let (src, dst) = (arg(emu, p, 1), arg(emu, p, 2).1 as usize);
+            p.fr[dst] = -try!( fetch_float(emu, p, src) );
+        },
+        _ => {
+            // fadd Fail A B Dst, and the like.
+            let (a, b, dst) = (arg(emu, p, 1), arg(emu, p, 2), arg(emu, p, 3).1 as usize);
+            let (a, b) = (try!( fetch_float(emu, p, a) ), try!( fetch_float(emu, p, b) ));
+            let f = match opcode {
+                BEAMOpcode::fadd => a + b,
+                BEAMOpcode::fsub => a - b,
+                BEAMOpcode::fmul => a * b,
+                _ => a / b
+            };
+            if !f.is_finite()
+                { return fail(emu, p, Error::Bif(bif::Error::Badarith)) }
+            p.fr[dst] = f;
+        }
+    }
+    step(emu, p);
+    Ok (())
+}
+
+// A float register, or a float in any other operand.
+fn fetch_float(emu: &Emu, p: &mut Process, operand: Operand) -> Result<f64, Error> {
+    match operand {
+        (ArgTag::fr, n) => Ok (p.fr[n as usize]),
+        // Float literals are read where they are rather than copied.
+        (ArgTag::q, index) => emu.literals[index as usize].float_value(&emu.literal_heap)
+                                                          .ok_or(Error::BadCode(p.ip)),
+        _ => {
+            let t = try!( fetch(emu, p, operand) );
+            t.float_value(&p.heap).ok_or(Error::BadCode(p.ip))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Segment {
+    Integer,
+    Float,
+    Binary,
+    // Matching only: moving past some bits.
+    Skip,
+    // UTF-8, UTF-16 or UTF-32.
+    Utf(usize)
+}
+
+// Jump to the fail label (the first operand), or raise `e` if there's none.
+fn fail(emu: &Emu, p: &mut Process, e: Error) -> Result<(), Error> {
+    match arg(emu, p, 0) {
+        (ArgTag::f, label) if label != 0 => {
+            p.ip = label;
+            Ok (())
+        },
+        _ => Err (e)
+    }
+}
+
+fn atom_name(emu: &Emu, p: &Process, operand: Operand) -> Result<String, Error> {
+    match operand {
+        (ArgTag::a, index) if index != 0 =>
+            emu.atoms.get_atom(index as usize).ok_or(Error::BadCode(p.ip)),
+        _ => Err (Error::BadCode(p.ip))
+    }
+}
+
+// Segment flags, given by operand `n`: a number, or a list of flag atoms.
+fn segment_flags(emu: &Emu, p: &Process, n: usize) -> Result<u32, Error> {
+    match arg(emu, p, n) {
+        (ArgTag::u, flags) => Ok (flags),
+        (ArgTag::a, 0) => Ok (0),
+        (ArgTag::q, index) => {
+            let list = emu.literals[index as usize];
+            let names = try!( list.list_elements(&emu.literal_heap).ok_or(Error::BadCode(p.ip)) );
+            let mut flags = 0;
+            for name in names.iter() {
+                let name = match *name {
+                    Term::Atom(a) => emu.atoms.get_atom(a).unwrap_or_default(),
+                    _ => return Err (Error::BadCode(p.ip))
+                };
+                flags |= match &name[..] {
+                    "little" => binary::FLAG_LITTLE,
+                    "signed" => binary::FLAG_SIGNED,
+                    "native" => binary::FLAG_NATIVE,
+                    _ => 0
+                };
+            }
+            Ok (flags)
+        },
+        _ => Err (Error::BadCode(p.ip))
+    }
+}
+
+// The match context's pointer, bitstring and position.
+fn match_ctx(p: &Process, ctx: Term) -> Result<(Ptr, Term, usize), Error> {
+    match (ctx, ctx.header(&p.heap)) {
+        (Term::Boxed(ptr), Some (Header::MatchCtx(_))) => match p.heap.get(ptr + 2) {
+            Term::Small(pos) => Ok ((ptr, p.heap.get(ptr + 1), pos as usize)),
+            _ => Err (Error::BadCode(p.ip))
+        },
+        _ => Err (Error::BadCode(p.ip))
+    }
+}
+
+fn bit_size(p: &Process, bin: Term) -> Result<usize, Error> {
+    bin.bit_size(&p.heap).ok_or(Error::BadCode(p.ip))
+}
+
+fn bits_left(p: &Process, ctx: Term) -> Result<usize, Error> {
+    let (_, bin, pos) = try!( match_ctx(p, ctx) );
+    Ok (try!( bit_size(p, bin) ).saturating_sub(pos))
+}
+
+// What's left to match, as a sub-binary.
+fn match_tail(p: &mut Process, ctx: Term) -> Result<Term, Error> {
+    let (_, bin, pos) = try!( match_ctx(p, ctx) );
+    let size = try!( bit_size(p, bin) );
+    Term::sub_bits(&mut p.heap, bin, pos, size - pos).ok_or(Error::BadCode(p.ip))
+}
+
+// Match a segment of `size` bits (ignored for UTF segments) where the
+// context stands and move past it.  None if it doesn't match.
+fn match_segment(p: &mut Process, ctx: Term, segment: Segment, size: usize, flags: u32)
+    -> Result<Option<Term>, Error>
+{
+    let (ptr, bin, pos) = try!( match_ctx(p, ctx) );
+    let total = try!( bit_size(p, bin) );
+    if pos.checked_add(size).map_or(true, |end| end > total)
+        { return Ok (None) }
+    let (t, size) = {
+        let ip = p.ip;
+        let bits = try!( bin.bits(&p.heap).ok_or(Error::BadCode(ip)) );
+        match segment {
+            Segment::Integer => {
+                let n = binary::get_integer(&bits, pos, size, flags);
+                (Term::number_to_term(&mut p.heap, &n), size)
+            },
+            Segment::Float => match binary::get_float(&bits, pos, size, flags) {
+                Some (f) => (Term::float(&mut p.heap, f), size),
+                None => return Ok (None)
+            },
+            Segment::Binary => {
+                let t = Term::sub_bits(&mut p.heap, bin, pos, size);
+                (try!( t.ok_or(Error::BadCode(ip)) ), size)
+            },
+            Segment::Skip => (Term::Nil, size),
+            Segment::Utf(width) => match binary::get_utf(&bits, pos, width, flags) {
+                Some ((c, size)) => (Term::Small(c), size),
+                None => return Ok (None)
+            }
+        }
+    };
+    p.heap.set(ptr + 2, Term::Small((pos + size) as i64));
+    Ok (Some (t))
+}
+
+// A command of `bs_match` at operand `at`: whether it matched and where
+// the next command is.
+fn match_command(emu: &Emu, p: &mut Process, ctx: Term, at: usize)
+    -> Result<(bool, usize), Error>
+{
+    let command = try!( atom_name(emu, p, arg(emu, p, at)) );
+    let u = |n: usize| arg(emu, p, at + n).1 as usize;
+    Ok (match &command[..] {
+        // Size Unit
+        "ensure_at_least" => {
+            let (size, unit) = (u(1), u(2));
+            let left = try!( bits_left(p, ctx) );
+            (left >= size && (unit <= 1 || (left - size) % unit == 0), at + 3)
+        },
+        // Size
+        "ensure_exactly" => (try!( bits_left(p, ctx) ) == u(1), at + 2),
+        // Live Flags Size Unit Dst
+        "integer" | "binary" => {
+            let segment = if command == "integer" { Segment::Integer } else { Segment::Binary };
+            let (flags, size, dst) = (try!( segment_flags(emu, p, at + 2) ), u(3) * u(4),
+                                      arg(emu, p, at + 5));
+            match try!( match_segment(p, ctx, segment, size, flags) ) {
+                Some (t) => {
+                    try!( store(p, dst, t) );
+                    (true, at + 6)
+                },
+                None => (false, at + 6)
+            }
+        },
+        // Stride
+        "skip" => (try!( match_segment(p, ctx, Segment::Skip, u(1), 0) ).is_some(), at + 2),
+        // Live Unit Dst
+        "get_tail" => {
+            let dst = arg(emu, p, at + 3);
+            let tail = try!( match_tail(p, ctx) );
+            try!( store(p, dst, tail) );
+            let (ptr, bin, _) = try!( match_ctx(p, ctx) );
+            let end = try!( bit_size(p, bin) );
+            p.heap.set(ptr + 2, Term::Small(end as i64));
+            (true, at + 4)
+        },
+        // Live Size Value
+        "=:=" => {
+            let (size, value) = (u(2), arg(emu, p, at + 3));
+            let value = try!( fetch(emu, p, value) );
+            let matched = match try!( match_segment(p, ctx, Segment::Integer, size, 0) ) {
+                Some (t) => compare::eq_exact(&p.heap, &emu.atoms, t, value),
+                None => false
+            };
+            (matched, at + 4)
+        },
+        _ => return Err (Error::BadCode(p.ip))
+    })
+}
+
+// Put a segment of `size` bits - all of a binary, if none - into `b`;
+// false if `src` can't go in such a segment.
+fn put_segment(heap: &Heap, b: &mut Builder, segment: Segment, src: Term, size: Option<usize>,
+               flags: u32) -> bool
+{
+    match (segment, size) {
+        (Segment::Integer, Some (size)) => match src.number(heap) {
+            Some (Number::Float(_)) | None => false,
+            Some (n) => {
+                binary::put_integer(b, &n, size, flags);
+                true
+            }
+        },
+        (Segment::Float, Some (size)) => match src.number(heap) {
+            Some (n) => binary::put_float(b, n.to_f64(), size, flags),
+            None => false
+        },
+        (Segment::Binary, _) => match src.bits(heap) {
+            Some (ref bits) if size.map_or(true, |size| size <= bits.size) => {
+                b.put_bits(bits, 0, size.unwrap_or(bits.size));
+                true
+            },
+            _ => false
+        },
+        (Segment::Utf(width), _) => match src {
+            Term::Small(c) => binary::put_utf(b, c, width, flags),
+            _ => false
+        },
+        _ => false
+    }
+}
+
+// Store the binary under construction once all its bits are in.
+fn finish_construction(p: &mut Process) -> Result<(), Error> {
+    let complete = match p.construction {
+        Some ((ref b, size, _)) => b.size() >= size,
+        None => false
+    };
+    if complete {
+        let (b, _, dst) = p.construction.take().unwrap();
+        let t = b.to_term(&mut p.heap);
+        try!( store(p, dst, t) );
+    }
+    Ok (())
+}
+
+#[test]
+fn test_exceptions() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("exc");
+    let plus = m.import("erlang", "+", 2);
+    let throw_1 = m.import("erlang", "throw", 1);
+    let error_1 = m.import("erlang", "error", 1);
+    let exit_1 = m.import("erlang", "exit", 1);
+    let raise_3 = m.import("erlang", "raise", 3);
+    // thrower(throw) -> throw(ball);
+    // thrower(error) -> error(ball);
+    // thrower(exit) -> exit(ball);
+    // thrower(N) when is_integer(N) -> N + 1.
+    m.line(3);
+    let thrower = m.function("thrower", 1);
+    for &(class, bif) in [("throw", throw_1), ("error", error_1), ("exit", exit_1)].iter() {
+        let next = m.new_label();
+        m.op("is_eq_exact", vec![Arg::F(next), Arg::X(0), Arg::A(class)]);
+        m.op("move", vec![Arg::A("ball"), Arg::X(0)]);
+        m.line(4);
+        m.op("call_ext_only", vec![Arg::U(1), Arg::U(bif)]);
+        m.place(next);
+    }
+    let clause = m.new_label();
+    m.op("is_integer", vec![Arg::F(clause), Arg::X(0)]);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(1), Arg::U(plus), Arg::X(0), Arg::I(1), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(clause);
+    m.op("move", vec![Arg::U(0), Arg::X(1)]);
+    m.op("jump", vec![Arg::F(thrower - 1)]);
+    // matcher(X) -> 1 = X.
+    let matcher = m.function("matcher", 1);
+    let fail = m.new_label();
+    m.op("is_eq_exact", vec![Arg::F(fail), Arg::X(0), Arg::I(1)]);
+    m.op("return", vec![]);
+    m.place(fail);
+    m.op("badmatch", vec![Arg::X(0)]);
+    // raiser(X) -> erlang:raise(exit, X, []).
+    let raiser = m.function("raiser", 1);
+    m.op("move", vec![Arg::X(0), Arg::X(1)]);
+    m.op("move", vec![Arg::A("exit"), Arg::X(0)]);
+    m.op("move", vec![Arg::Nil, Arg::X(2)]);
+    m.op("call_ext_only", vec![Arg::U(3), Arg::U(raise_3)]);
+    // rethrow(X) -> try thrower(X) catch throw:R -> R end.
+    let rethrow = m.function("rethrow", 1);
+    let handler = m.new_label();
+    m.op("allocate", vec![Arg::U(1), Arg::U(1)]);
+    m.op("try", vec![Arg::Y(0), Arg::F(handler)]);
+    m.op("call", vec![Arg::U(1), Arg::F(thrower)]);
+    m.op("try_end", vec![Arg::Y(0)]);
+    m.op("deallocate", vec![Arg::U(1)]);
+    m.op("return", vec![]);
+    m.place(handler);
+    m.op("try_case", vec![Arg::Y(0)]);
+    let reraise = m.new_label();
+    m.op("is_eq_exact", vec![Arg::F(reraise), Arg::X(0), Arg::A("throw")]);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("deallocate", vec![Arg::U(1)]);
+    m.op("return", vec![]);
+    m.place(reraise);
+    m.op("raise", vec![Arg::X(2), Arg::X(1)]);
+    // Name(X) -> catch Target(X).
+    let catcher = |m: &mut Module, name: &'static str, target: u32| {
+        let f = m.function(name, 1);
+        let handler = m.new_label();
+        m.op("allocate", vec![Arg::U(1), Arg::U(1)]);
+        m.op("catch", vec![Arg::Y(0), Arg::F(handler)]);
+        m.line(7);
+        m.op("call", vec![Arg::U(1), Arg::F(target)]);
+        m.place(handler);
+        m.op("catch_end", vec![Arg::Y(0)]);
+        m.op("deallocate", vec![Arg::U(1)]);
+        m.op("return", vec![]);
+        m.export(name, 1, f);
+    };
+    // Name(X) -> try Target(X) of V -> {ok, V} catch C:R -> {C, R} end.
+    let trier = |m: &mut Module, name: &'static str, target: u32| {
+        let f = m.function(name, 1);
+        let handler = m.new_label();
+        m.op("allocate", vec![Arg::U(1), Arg::U(1)]);
+        m.op("try", vec![Arg::Y(0), Arg::F(handler)]);
+        m.op("call", vec![Arg::U(1), Arg::F(target)]);
+        m.op("try_end", vec![Arg::Y(0)]);
+        m.op("put_tuple", vec![Arg::U(2), Arg::X(1)]);
+        m.op("put", vec![Arg::A("ok")]);
+        m.op("put", vec![Arg::X(0)]);
+        m.op("move", vec![Arg::X(1), Arg::X(0)]);
+        m.op("deallocate", vec![Arg::U(1)]);
+        m.op("return", vec![]);
+        m.place(handler);
+        m.op("try_case", vec![Arg::Y(0)]);
+        m.op("put_tuple", vec![Arg::U(2), Arg::X(2)]);
+        m.op("put", vec![Arg::X(0)]);
+        m.op("put", vec![Arg::X(1)]);
+        m.op("move", vec![Arg::X(2), Arg::X(0)]);
+        m.op("deallocate", vec![Arg::U(1)]);
+        m.op("return", vec![]);
+        m.export(name, 1, f);
+    };
+    catcher(&mut m, "catch_thrower", thrower);
+    catcher(&mut m, "catch_rethrow", rethrow);
+    trier(&mut m, "try_thrower", thrower);
+    trier(&mut m, "try_matcher", matcher);
+    trier(&mut m, "try_raiser", raiser);
+    m.export("thrower", 1, thrower);
+    m.export("rethrow", 1, rethrow);
+    for mut emu in ::test_emus() {
+        m.load(&mut emu).unwrap();
+        let atom = |name: &str| etf::Term::Atom(name.to_string());
+        let call = |function: &str, arg: etf::Term| match emu.call("exc", function, &[arg]) {
+            Ok (t) => t.to_string(),
+            Err (t) => format!("failed: {}", t)
+        };
+        assert_eq!("{ok,2}", call("try_thrower", etf::Term::Integer(1)));
+        assert_eq!("{throw,ball}", call("try_thrower", atom("throw")));
+        assert_eq!("{error,ball}", call("try_thrower", atom("error")));
+        assert_eq!("{exit,ball}", call("try_thrower", atom("exit")));
+        assert_eq!("{error,function_clause}", call("try_thrower", atom("foo")));
+        assert_eq!("{error,{badmatch,2}}", call("try_matcher", etf::Term::Integer(2)));
+        assert_eq!("{exit,7}", call("try_raiser", etf::Term::Integer(7)));
+        assert_eq!("2", call("catch_thrower", etf::Term::Integer(1)));
+        assert_eq!("ball", call("catch_thrower", atom("throw")));
+        assert_eq!("{'EXIT',ball}", call("catch_thrower", atom("exit")));
+        // Stack traces, with the arguments of a function no clause matched.
+        assert_eq!("{'EXIT',{ball,[{exc,thrower,1,[{file,\"exc.erl\"},{line,4}]},\
+                    {exc,catch_thrower,1,[{file,\"exc.erl\"},{line,7}]}]}}",
+                   call("catch_thrower", atom("error")));
+        assert_eq!("{'EXIT',{function_clause,[{exc,thrower,[foo],[{file,\"exc.erl\"},{line,3}]},\
+                    {exc,catch_thrower,1,[{file,\"exc.erl\"},{line,7}]}]}}",
+                   call("catch_thrower", atom("foo")));
+        // A rethrown exception keeps its class.
+        assert_eq!("ball", call("rethrow", atom("throw")));
+        assert_eq!("{'EXIT',ball}", call("catch_rethrow", atom("exit")));
+        assert_eq!("failed: ball", call("rethrow", atom("error")));
+        assert_eq!("failed: {nocatch,ball}", call("thrower", atom("throw")));
+    }
+}
+
+#[test]
+fn test_garbage_collection() {
+    use asm::{ Arg, Module };
+    use etf;
+    fn heap_words(_: &Emu, p: &mut Process, _: &[Term]) -> bif::BifResult {
+        Ok (Term::Small(p.heap.len() as i64))
+    }
+    let mut m = Module::new("gc");
+    let minus = m.import("erlang", "-", 2);
+    let heap_words_0 = m.import("gc", "heap_words", 0);
+    let garbage_collect_0 = m.import("erlang", "garbage_collect", 0);
+    // build(0, Acc) -> {heap_words(), Acc};
+    // build(N, Acc) -> _ = {N, ..., N}, build(N - 1, {N, Acc}).
+    let build = m.function("build", 2);
+    let next = m.new_label();
+    m.op("is_eq_exact", vec![Arg::F(next), Arg::X(0), Arg::I(0)]);
+    m.op("bif0", vec![Arg::U(heap_words_0), Arg::X(0)]);
+    m.op("test_heap", vec![Arg::U(3), Arg::U(2)]);
+    m.op("put_tuple", vec![Arg::U(2), Arg::X(2)]);
+    m.op("put", vec![Arg::X(0)]);
+    m.op("put", vec![Arg::X(1)]);
+    m.op("move", vec![Arg::X(2), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(next);
+    m.op("test_heap", vec![Arg::U(14), Arg::U(2)]);
+    m.op("put_tuple", vec![Arg::U(10), Arg::X(2)]);
+    for _ in 0 .. 10
+        { m.op("put", vec![Arg::X(0)]) }
+    m.op("put_tuple", vec![Arg::U(2), Arg::X(1)]);
+    m.op("put", vec![Arg::X(0)]);
+    m.op("put", vec![Arg::X(1)]);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(2), Arg::U(minus), Arg::X(0), Arg::I(1), Arg::X(0)]);
+    m.op("call_only", vec![Arg::U(2), Arg::F(build)]);
+    m.export("build", 2, build);
+    // collect(X) -> garbage_collect(), X.
+    let collect = m.function("collect", 1);
+    m.op("allocate_heap", vec![Arg::U(1), Arg::U(0), Arg::U(1)]);
+    m.op("move", vec![Arg::X(0), Arg::Y(0)]);
+    m.op("call_ext", vec![Arg::U(0), Arg::U(garbage_collect_0)]);
+    m.op("move", vec![Arg::Y(0), Arg::X(0)]);
+    m.op("deallocate", vec![Arg::U(1)]);
+    m.op("return", vec![]);
+    m.export("collect", 1, collect);
+    for mut emu in ::test_emus() {
+        emu.register_bif("gc", "heap_words", 0, heap_words);
+        m.load(&mut emu).unwrap();
+        let n = 300;
+        let result = emu.call("gc", "build", &[etf::Term::Integer(n), etf::Term::Nil]).unwrap();
+        let (words, mut acc) = match result {
+            etf::Term::Tuple(ref elements) => match (&elements[0], &elements[1]) {
+                (&etf::Term::Integer(words), acc) => (words, acc),
+                _ => panic!("unexpected result {}", result)
+            },
+            _ => panic!("unexpected result {}", result)
+        };
+        // 14 words are allocated per round, but only 3 stay live.
+        assert!(words < 4 * n, "heap of {} words", words);
+        for i in 1 .. n + 1 {
+            acc = match *acc {
+                etf::Term::Tuple(ref elements) => {
+                    assert_eq!(etf::Term::Integer(i), elements[0]);
+                    &elements[1]
+                },
+                ref other => panic!("unexpected element {}", other)
+            }
+        }
+        assert_eq!(etf::Term::Nil, *acc);
+        // Y registers are roots, and garbage_collect/0 returns true.
+        assert_eq!("{a,[1.5],{b}}",
+                   emu.call("gc", "collect", &[etf::Term::Tuple(vec![
+                       etf::Term::Atom("a".to_string()),
+                       etf::Term::List(vec![etf::Term::Float(1.5)], Box::new(etf::Term::Nil)),
+                       etf::Term::Tuple(vec![etf::Term::Atom("b".to_string())])])])
+                      .unwrap().to_string());
+    }
+}
+
+#[test]
+fn test_bit_syntax() {
+    use asm::{ Arg, Module };
+    use etf;
+    let little = || Arg::Lit(etf::Term::List(vec![etf::Term::Atom("little".to_string())],
+                                            Box::new(etf::Term::Nil)));
+    let mut m = Module::new("bits");
+    let ok = m.string(b"ok");
+    // build(A, B) -> <<A:8, B:12/little, "ok", 1.5:32/float, B/utf8, A:3>>.
+    let build = m.function("build", 2);
+    m.op("bs_create_bin", vec![
+        Arg::F(0), Arg::U(0), Arg::U(2), Arg::U(1), Arg::X(0),
+        Arg::List(vec![Arg::A("integer"), Arg::U(1), Arg::U(1), Arg::Nil, Arg::X(0), Arg::I(8),
+                       Arg::A("integer"), Arg::U(2), Arg::U(1), little(), Arg::X(1), Arg::I(12),
+                       Arg::A("string"), Arg::U(3), Arg::U(8), Arg::Nil, Arg::U(ok), Arg::U(2),
+                       Arg::A("float"), Arg::U(4), Arg::U(1), Arg::Nil,
+                       Arg::Lit(etf::Term::Float(1.5)), Arg::I(32),
+                       Arg::A("utf8"), Arg::U(5), Arg::U(1), Arg::Nil, Arg::X(1), Arg::A("undefined"),
+                       Arg::A("integer"), Arg::U(6), Arg::U(1), Arg::Nil, Arg::X(0), Arg::I(3)])]);
+    m.op("return", vec![]);
+    m.export("build", 2, build);
+    // parse(<<A:8, B:12/little, "ok", F:32/float, C/utf8, Rest/bits>>) -> {A, B, F, C, Rest};
+    // parse(_) -> nomatch.
+    let parse = m.function("parse", 1);
+    let nomatch = m.new_label();
+    m.op("bs_start_match4", vec![Arg::F(nomatch), Arg::U(1), Arg::X(0), Arg::X(0)]);
+    m.op("bs_match", vec![Arg::F(nomatch), Arg::X(0),
+                          Arg::List(vec![Arg::A("ensure_at_least"), Arg::U(36), Arg::U(1),
+                                         Arg::A("integer"), Arg::U(1), Arg::Nil, Arg::U(8), Arg::U(1), Arg::X(1),
+                                         Arg::A("integer"), Arg::U(2), little(), Arg::U(12), Arg::U(1), Arg::X(2)])]);
+    m.op("bs_match_string", vec![Arg::F(nomatch), Arg::X(0), Arg::U(16), Arg::U(ok)]);
+    m.op("bs_get_float2", vec![Arg::F(nomatch), Arg::X(0), Arg::U(3), Arg::I(32), Arg::U(1),
+                               Arg::U(0), Arg::X(3)]);
+    m.op("bs_get_utf8", vec![Arg::F(nomatch), Arg::X(0), Arg::U(4), Arg::U(0), Arg::X(4)]);
+    m.op("bs_get_tail", vec![Arg::X(0), Arg::X(5), Arg::U(6)]);
+    m.op("test_heap", vec![Arg::U(6), Arg::U(6)]);
+    m.op("put_tuple", vec![Arg::U(5), Arg::X(0)]);
+    for i in 1 .. 6
+        { m.op("put", vec![Arg::X(i)]) }
+    m.op("return", vec![]);
+    m.place(nomatch);
+    m.op("move", vec![Arg::A("nomatch"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("parse", 1, parse);
+    // old(A) -> <<A:16, "ok", A/utf16>>, the old way.
+    let old = m.function("old", 1);
+    m.op("bs_utf16_size", vec![Arg::F(0), Arg::X(0), Arg::X(1)]);
+    m.op("bs_add", vec![Arg::F(0), Arg::I(4), Arg::X(1), Arg::U(1), Arg::X(1)]);
+    m.op("bs_init2", vec![Arg::F(0), Arg::X(1), Arg::U(0), Arg::U(1), Arg::U(0), Arg::X(1)]);
+    m.op("bs_put_integer", vec![Arg::F(0), Arg::I(16), Arg::U(1), Arg::U(0), Arg::X(0)]);
+    m.op("bs_put_string", vec![Arg::U(2), Arg::U(ok)]);
+    m.op("bs_put_utf16", vec![Arg::F(0), Arg::U(0), Arg::X(0)]);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("old", 1, old);
+    // append(Bin, X) -> <<Bin/bits, X:4>>.
+    let append = m.function("append", 2);
+    m.op("bs_append", vec![Arg::F(0), Arg::I(4), Arg::U(0), Arg::U(2), Arg::U(1), Arg::X(0),
+                           Arg::U(0), Arg::X(0)]);
+    m.op("bs_put_integer", vec![Arg::F(0), Arg::I(4), Arg::U(1), Arg::U(0), Arg::X(1)]);
+    m.op("return", vec![]);
+    m.export("append", 2, append);
+    for mut emu in ::test_emus() {
+        m.load(&mut emu).unwrap();
+        let call = |function: &str, args: &[etf::Term]| match emu.call("bits", function, args) {
+            Ok (t) => t.to_string(),
+            Err (t) => format!("failed: {}", t)
+        };
+        let int = etf::Term::Integer;
+        let built = emu.call("bits", "build", &[int(5), int(0xe9)]).unwrap();
+        // 0xe9:12/little is <<16#e9,0:4>>, then "ok", then the float...
+        assert_eq!("<<5,233,6,246,179,252,0,0,12,58,77:7>>", built.to_string());
+        assert_eq!("{5,233,1.5,233,<<5:3>>}", call("parse", &[built]));
+        assert_eq!("nomatch", call("parse", &[etf::Term::Binary(b"short".to_vec())]));
+        assert_eq!("nomatch", call("parse", &[int(1)]));
+        assert_eq!("failed: badarg", call("build", &[etf::Term::Atom("a".to_string()), int(1)]));
+        assert_eq!("<<0,233,111,107,0,233>>", call("old", &[int(0xe9)]));
+        assert_eq!("<<0,0,111,107,216,0,220,0>>", call("old", &[int(0x10000)]));
+        assert_eq!("failed: badarg", call("old", &[int(-1)]));
+        assert_eq!("<<1,15:4>>", call("append", &[etf::Term::Binary(vec![1]), int(15)]));
+        let bits = etf::Term::BitBinary(vec![0xa0], 3);
+        assert_eq!("<<81:7>>", call("append", &[bits, int(1)]));
+    }
+}
+
+#[test]
+fn test_maps() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("maps");
+    // assoc(M, V) -> M#{a => V}.
+    let assoc = m.function("assoc", 2);
+    m.op("put_map_assoc", vec![Arg::F(0), Arg::X(0), Arg::X(0), Arg::U(2),
+                               Arg::List(vec![Arg::A("a"), Arg::X(1)])]);
+    m.op("return", vec![]);
+    m.export("assoc", 2, assoc);
+    // exact(M, V) -> M#{b := V}.
+    let exact = m.function("exact", 2);
+    m.op("put_map_exact", vec![Arg::F(0), Arg::X(0), Arg::X(0), Arg::U(2),
+                               Arg::List(vec![Arg::A("b"), Arg::X(1)])]);
+    m.op("return", vec![]);
+    m.export("exact", 2, exact);
+    // both(#{a := A, b := B}) -> {A, B};
+    // both(#{a := A}) -> A;
+    // both(#{}) -> map;
+    // both(_) -> other.
+    let both = m.function("both", 1);
+    let (one, empty, other) = (m.new_label(), m.new_label(), m.new_label());
+    m.op("is_map", vec![Arg::F(other), Arg::X(0)]);
+    m.op("get_map_elements", vec![Arg::F(one), Arg::X(0),
+                                  Arg::List(vec![Arg::A("a"), Arg::X(1), Arg::A("b"), Arg::X(2)])]);
+    m.op("test_heap", vec![Arg::U(3), Arg::U(3)]);
+    m.op("put_tuple", vec![Arg::U(2), Arg::X(0)]);
+    m.op("put", vec![Arg::X(1)]);
+    m.op("put", vec![Arg::X(2)]);
+    m.op("return", vec![]);
+    m.place(one);
+    m.op("has_map_fields", vec![Arg::F(empty), Arg::X(0), Arg::List(vec![Arg::A("a")])]);
+    m.op("get_map_elements", vec![Arg::F(empty), Arg::X(0),
+                                  Arg::List(vec![Arg::A("a"), Arg::X(0)])]);
+    m.op("return", vec![]);
+    m.place(empty);
+    m.op("move", vec![Arg::A("map"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(other);
+    m.op("move", vec![Arg::A("other"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("both", 1, both);
+    for mut emu in ::test_emus() {
+        m.load(&mut emu).unwrap();
+        let call = |function: &str, args: &[etf::Term]| match emu.call("maps", function, args) {
+            Ok (t) => t.to_string(),
+            Err (t) => format!("failed: {}", t)
+        };
+        let (int, atom) = (etf::Term::Integer, |name: &str| etf::Term::Atom(name.to_string()));
+        let map = |pairs: Vec<(etf::Term, etf::Term)>| etf::Term::Map(pairs);
+        let ab = map(vec![(atom("b"), int(2)), (atom("a"), int(1))]);
+        assert_eq!("#{a => 1,b => 2}", call("assoc", &[ab.clone(), int(1)]));
+        assert_eq!("#{a => 3,b => 2}", call("assoc", &[ab.clone(), int(3)]));
+        assert_eq!("#{a => 1,b => 7}", call("exact", &[ab.clone(), int(7)]));
+        assert_eq!("failed: {badkey,b}", call("exact", &[map(vec![]), int(7)]));
+        assert_eq!("failed: {badmap,[]}", call("assoc", &[etf::Term::Nil, int(7)]));
+        assert_eq!("{1,2}", call("both", &[ab.clone()]));
+        assert_eq!("5", call("both", &[map(vec![(atom("a"), int(5))])]));
+        assert_eq!("map", call("both", &[map(vec![(int(1), int(5))])]));
+        assert_eq!("other", call("both", &[etf::Term::Nil]));
+        // Large maps are HAMTs, and still come out with their keys in order.
+        let large = map((0 .. 40).map(|i| (int(39 - i), int(i))).collect());
+        let result = emu.call("maps", "assoc", &[large, int(-1)]).unwrap();
+        let mut expected: Vec<(etf::Term, etf::Term)> = (0 .. 40).map(|i| (int(i), int(39 - i))).collect();
+        expected.push((atom("a"), int(-1)));
+        assert_eq!(map(expected), result);
+    }
+}
+
+#[test]
+fn test_lists_and_tuples() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("lt");
+    // swap([H|T]) -> [T|H].
+    let swap = m.function("swap", 1);
+    m.op("get_list", vec![Arg::X(0), Arg::X(1), Arg::X(2)]);
+    m.op("test_heap", vec![Arg::U(2), Arg::U(3)]);
+    m.op("put_list", vec![Arg::X(2), Arg::X(1), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("swap", 1, swap);
+    // kind({_}) -> one;
+    // kind({_, _}) -> two;
+    // kind([]) -> nil;
+    // kind([_|T]) -> T;
+    // kind(_) -> other.
+    let kind = m.function("kind", 1);
+    let (one, two, not_tuple, not_nil, other) =
+        (m.new_label(), m.new_label(), m.new_label(), m.new_label(), m.new_label());
+    m.op("is_tuple", vec![Arg::F(not_tuple), Arg::X(0)]);
+    m.op("select_tuple_arity", vec![Arg::X(0), Arg::F(other),
+                                    Arg::List(vec![Arg::U(2), Arg::F(two), Arg::U(1), Arg::F(one)])]);
+    m.place(one);
+    m.op("move", vec![Arg::A("one"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(two);
+    m.op("move", vec![Arg::A("two"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(not_tuple);
+    m.op("is_nil", vec![Arg::F(not_nil), Arg::X(0)]);
+    m.op("move", vec![Arg::A("nil"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(not_nil);
+    m.op("is_nonempty_list", vec![Arg::F(other), Arg::X(0)]);
+    m.op("get_tl", vec![Arg::X(0), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(other);
+    m.op("move", vec![Arg::A("other"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("kind", 1, kind);
+    // head([H|_]) -> H;
+    // head(L) when is_list(L) -> empty;
+    // head(_) -> none.
+    let head = m.function("head", 1);
+    let (empty, none) = (m.new_label(), m.new_label());
+    m.op("is_list", vec![Arg::F(none), Arg::X(0)]);
+    m.op("is_nonempty_list", vec![Arg::F(empty), Arg::X(0)]);
+    m.op("get_hd", vec![Arg::X(0), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(empty);
+    m.op("move", vec![Arg::A("empty"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(none);
+    m.op("move", vec![Arg::A("none"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("head", 1, head);
+    // update(R, V) -> R#r{b = V, a = z}, the second update in place.
+    let update = m.function("update", 2);
+    m.op("test_heap", vec![Arg::U(4), Arg::U(2)]);
+    m.op("update_record", vec![Arg::A("copy"), Arg::U(3), Arg::X(0), Arg::X(0),
+                               Arg::List(vec![Arg::U(3), Arg::X(1)])]);
+    m.op("set_tuple_element", vec![Arg::A("z"), Arg::X(0), Arg::U(1)]);
+    m.op("return", vec![]);
+    m.export("update", 2, update);
+    for mut emu in ::test_emus() {
+        m.load(&mut emu).unwrap();
+        let call = |function: &str, args: &[etf::Term]| match emu.call("lt", function, args) {
+            Ok (t) => t.to_string(),
+            Err (t) => format!("failed: {}", t)
+        };
+        let (int, atom) = (etf::Term::Integer, |name: &str| etf::Term::Atom(name.to_string()));
+        let list = |elements: Vec<etf::Term>| etf::Term::List(elements, Box::new(etf::Term::Nil));
+        assert_eq!("[[2,3]|1]", call("swap", &[list(vec![int(1), int(2), int(3)])]));
+        assert_eq!("one", call("kind", &[etf::Term::Tuple(vec![int(1)])]));
+        assert_eq!("two", call("kind", &[etf::Term::Tuple(vec![int(1), int(2)])]));
+        assert_eq!("other", call("kind", &[etf::Term::Tuple(vec![])]));
+        assert_eq!("nil", call("kind", &[etf::Term::Nil]));
+        assert_eq!("[b]", call("kind", &[list(vec![atom("a"), atom("b")])]));
+        assert_eq!("other", call("kind", &[int(1)]));
+        assert_eq!("none", call("head", &[int(1)]));
+        assert_eq!("empty", call("head", &[etf::Term::Nil]));
+        assert_eq!("a", call("head", &[list(vec![atom("a")])]));
+        let record = etf::Term::Tuple(vec![atom("r"), int(1), int(2)]);
+        assert_eq!("{r,z,7}", call("update", &[record.clone(), int(7)]));
+    }
+}
+
+#[test]
+fn test_float_registers() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("fl");
+    // hyp(A, B) -> -(float(A) * A + B * B) / 2.0, with B a float.
+    let hyp = m.function("hyp", 2);
+    m.op("fclearerror", vec![]);
+    m.op("fconv", vec![Arg::X(0), Arg::Fr(0)]);
+    m.op("fmove", vec![Arg::X(1), Arg::Fr(1)]);
+    m.op("fmul", vec![Arg::F(0), Arg::Fr(0), Arg::Fr(0), Arg::Fr(0)]);
+    m.op("fmul", vec![Arg::F(0), Arg::Fr(1), Arg::Fr(1), Arg::Fr(1)]);
+    m.op("fadd", vec![Arg::F(0), Arg::Fr(0), Arg::Fr(1), Arg::Fr(0)]);
+    m.op("fnegate", vec![Arg::F(0), Arg::Fr(0), Arg::Fr(0)]);
+    m.op("fdiv", vec![Arg::F(0), Arg::Fr(0), Arg::Lit(etf::Term::Float(2.0)), Arg::Fr(0)]);
+    m.op("fcheckerror", vec![Arg::F(0)]);
+    m.op("test_heap", vec![Arg::U(2), Arg::U(0)]);
+    m.op("fmove", vec![Arg::Fr(0), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("hyp", 2, hyp);
+    // diff(A, B) -> A - B, or overflow if it isn't finite.
+    let diff = m.function("diff", 2);
+    let overflow = m.new_label();
+    m.op("fmove", vec![Arg::X(0), Arg::Fr(0)]);
+    m.op("fmove", vec![Arg::X(1), Arg::Fr(1)]);
+    m.op("fsub", vec![Arg::F(overflow), Arg::Fr(0), Arg::Fr(1), Arg::Fr(0)]);
+    m.op("test_heap", vec![Arg::U(2), Arg::U(0)]);
+    m.op("fmove", vec![Arg::Fr(0), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(overflow);
+    m.op("move", vec![Arg::A("overflow"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("diff", 2, diff);
+    for mut emu in ::test_emus() {
+        m.load(&mut emu).unwrap();
+        let call = |function: &str, args: &[etf::Term]| match emu.call("fl", function, args) {
+            Ok (t) => t.to_string(),
+            Err (t) => format!("failed: {}", t)
+        };
+        let (int, float) = (etf::Term::Integer, etf::Term::Float);
+        assert_eq!("-12.5", call("hyp", &[int(3), float(4.0)]));
+        assert_eq!("failed: badarith", call("hyp", &[etf::Term::Nil, float(4.0)]));
+        assert_eq!("failed: badarith", call("hyp", &[int(3), float(1.0e200)]));
+        assert_eq!("0.5", call("diff", &[float(1.0), float(0.5)]));
+        assert_eq!("overflow", call("diff", &[float(-1.0e308), float(1.0e308)]));
+    }
+}
+
+#[test]
+fn test_select_val() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("sel");
+    // A select_val on X with the given values, returning the atom each
+    // value maps to or `other`.
+    let select = |m: &mut Module, name: &'static str, cases: Vec<(Arg, &'static str)>| {
+        let f = m.function(name, 1);
+        let other = m.new_label();
+        let labels: Vec<u32> = cases.iter().map(|_| m.new_label()).collect();
+        let mut list = vec![];
+        let mut results = vec![];
+        for ((value, result), &label) in cases.into_iter().zip(labels.iter()) {
+            list.push(value);
+            list.push(Arg::F(label));
+            results.push(result);
+        }
+        m.op("select_val", vec![Arg::X(0), Arg::F(other), Arg::List(list)]);
+        for (&result, &label) in results.iter().zip(labels.iter()) {
+            m.place(label);
+            m.op("move", vec![Arg::A(result), Arg::X(0)]);
+            m.op("return", vec![]);
+        }
+        m.place(other);
+        m.op("move", vec![Arg::A("other"), Arg::X(0)]);
+        m.op("return", vec![]);
+        m.export(name, 1, f);
+    };
+    select(&mut m, "dense", vec![(Arg::I(3), "three"), (Arg::I(1), "one"), (Arg::I(2), "two")]);
+    select(&mut m, "sparse", vec![(Arg::I(-100), "low"), (Arg::I(100000), "high"),
+                                  (Arg::A("a"), "a"), (Arg::Nil, "nil")]);
+    select(&mut m, "big", vec![(Arg::I(1 << 40), "big"), (Arg::I(1), "one")]);
+    for mut emu in ::test_emus() {
+        m.load(&mut emu).unwrap();
+        assert_eq!(2, emu.jump_tables.len());
+        assert!(emu.jump_tables[0].to_string().starts_with("dense 1 .. 3 ->"));
+        assert!(emu.jump_tables[1].to_string().starts_with("sorted -100 ->"));
+        let call = |function: &str, arg: etf::Term| match emu.call("sel", function, &[arg]) {
+            Ok (t) => t.to_string(),
+            Err (t) => format!("failed: {}", t)
+        };
+        let (int, atom) = (etf::Term::Integer, |name: &str| etf::Term::Atom(name.to_string()));
+        assert_eq!("one", call("dense", int(1)));
+        assert_eq!("three", call("dense", int(3)));
+        assert_eq!("other", call("dense", int(4)));
+        assert_eq!("other", call("dense", atom("a")));
+        assert_eq!("low", call("sparse", int(-100)));
+        assert_eq!("high", call("sparse", int(100000)));
+        assert_eq!("a", call("sparse", atom("a")));
+        assert_eq!("nil", call("sparse", etf::Term::Nil));
+        assert_eq!("other", call("sparse", int(0)));
+        // 1 bsl 40, little-endian.
+        assert_eq!("big", call("big", etf::Term::BigInteger(false, vec![0, 0, 0, 0, 0, 1])));
+        assert_eq!("one", call("big", int(1)));
+        assert_eq!("other", call("big", int(2)));
+    }
+}
+
+#[test]
+fn test_apply() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("dyn");
+    let apply_2 = m.import("erlang", "apply", 2);
+    let apply_3 = m.import("erlang", "apply", 3);
+    let function_exported_3 = m.import("erlang", "function_exported", 3);
+    // id(X) -> X.
+    let id = m.function("id", 1);
+    m.op("return", vec![]);
+    m.export("id", 1, id);
+    // apply3(M, F, Args) -> apply(M, F, Args).
+    let apply3 = m.function("apply3", 3);
+    m.op("call_ext_only", vec![Arg::U(3), Arg::U(apply_3)]);
+    m.export("apply3", 3, apply3);
+    // apply2(X, Args) -> apply(fun(A) -> A + X end, Args).
+    let body = m.function("-apply2/2-fun-0-", 2);
+    let plus = m.import("erlang", "+", 2);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(2), Arg::U(plus), Arg::X(0), Arg::X(1), Arg::X(0)]);
+    m.op("return", vec![]);
+    let fun = m.fun("-apply2/2-fun-0-", 1, body, 1);
+    let apply2 = m.function("apply2", 2);
+    m.op("make_fun2", vec![Arg::U(fun)]);
+    m.op("call_ext_only", vec![Arg::U(2), Arg::U(apply_2)]);
+    m.export("apply2", 2, apply2);
+    // dynamic(M, F, X) -> {M:F(X)}.
+    let dynamic = m.function("dynamic", 3);
+    m.op("allocate", vec![Arg::U(0), Arg::U(3)]);
+    m.op("move", vec![Arg::X(0), Arg::X(3)]);
+    m.op("move", vec![Arg::X(2), Arg::X(0)]);
+    m.op("move", vec![Arg::X(1), Arg::X(2)]);
+    m.op("move", vec![Arg::X(3), Arg::X(1)]);
+    m.op("apply", vec![Arg::U(1)]);
+    m.op("test_heap", vec![Arg::U(2), Arg::U(1)]);
+    m.op("put_tuple", vec![Arg::U(1), Arg::X(1)]);
+    m.op("put", vec![Arg::X(0)]);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("deallocate", vec![Arg::U(0)]);
+    m.op("return", vec![]);
+    m.export("dynamic", 3, dynamic);
+    // last(M, F, X) -> M:F(X).
+    let last = m.function("last", 3);
+    m.op("allocate", vec![Arg::U(0), Arg::U(3)]);
+    m.op("move", vec![Arg::X(0), Arg::X(3)]);
+    m.op("move", vec![Arg::X(2), Arg::X(0)]);
+    m.op("move", vec![Arg::X(1), Arg::X(2)]);
+    m.op("move", vec![Arg::X(3), Arg::X(1)]);
+    m.op("apply_last", vec![Arg::U(1), Arg::U(0)]);
+    m.export("last", 3, last);
+    // exported(M, F, A) -> function_exported(M, F, A).
+    let exported = m.function("exported", 3);
+    m.op("call_ext_only", vec![Arg::U(3), Arg::U(function_exported_3)]);
+    m.export("exported", 3, exported);
+    for mut emu in ::test_emus() {
+        m.load(&mut emu).unwrap();
+        // Calls of erlang:apply/2,3 are specialised at load time.
+        let opcodes: Vec<BEAMOpcode> = (0 .. emu.code.len()).filter(|&i| code::is_instruction(emu.code[i]))
+                                                            .map(|i| code::opcode(emu.code[i]))
+                                                            .collect();
+        assert!(opcodes.contains(&BEAMOpcode::apply_list_only));
+        let call = |emu: &Emu, function: &str, args: &[etf::Term]| match emu.call("dyn", function, args) {
+            Ok (t) => t.to_string(),
+            Err (t) => format!("failed: {}", t)
+        };
+        let (int, atom) = (etf::Term::Integer, |name: &str| etf::Term::Atom(name.to_string()));
+        let list = |elements: Vec<etf::Term>| etf::Term::List(elements, Box::new(etf::Term::Nil));
+        assert_eq!("7", call(&emu, "apply3", &[atom("dyn"), atom("id"), list(vec![int(7)])]));
+        assert_eq!("3", call(&emu, "apply3", &[atom("erlang"), atom("+"), list(vec![int(1), int(2)])]));
+        let nested = list(vec![atom("dyn"), atom("id"), list(vec![int(8)])]);
+        assert_eq!("8", call(&emu, "apply3", &[atom("erlang"), atom("apply"), nested]));
+        assert_eq!("failed: undef", call(&emu, "apply3", &[atom("dyn"), atom("id"), list(vec![])]));
+        assert_eq!("failed: badarg", call(&emu, "apply3", &[int(1), atom("id"), list(vec![])]));
+        assert_eq!("failed: badarg", call(&emu, "apply3", &[atom("dyn"), atom("id"), atom("x")]));
+        assert_eq!("3", call(&emu, "apply2", &[int(1), list(vec![int(2)])]));
+        assert!(call(&emu, "apply2", &[int(1), list(vec![])]).starts_with("failed: {badarity,"));
+        assert_eq!("{x}", call(&emu, "dynamic", &[atom("dyn"), atom("id"), atom("x")]));
+        assert_eq!("{3}", call(&emu, "dynamic", &[atom("erlang"), atom("abs"), int(-3)]));
+        assert_eq!("x", call(&emu, "last", &[atom("dyn"), atom("id"), atom("x")]));
+        assert_eq!("failed: undef", call(&emu, "last", &[atom("dyn"), atom("nope"), atom("x")]));
+        assert_eq!("true", call(&emu, "exported", &[atom("dyn"), atom("id"), int(1)]));
+        assert_eq!("false", call(&emu, "exported", &[atom("dyn"), atom("id"), int(2)]));
+        assert_eq!("false", call(&emu, "exported", &[atom("erlang"), atom("abs"), int(1)]));
+        assert_eq!("failed: badarg", call(&emu, "exported", &[atom("dyn"), atom("id"), int(-1)]));
+        // With an error handler, missing functions are passed to it.
+        let mut h = Module::new("handler");
+        // undefined_function(M, F, Args) -> {M, F, Args}.
+        let undefined_function = h.function("undefined_function", 3);
+        h.op("test_heap", vec![Arg::U(4), Arg::U(3)]);
+        h.op("put_tuple", vec![Arg::U(3), Arg::X(3)]);
+        h.op("put", vec![Arg::X(0)]);
+        h.op("put", vec![Arg::X(1)]);
+        h.op("put", vec![Arg::X(2)]);
+        h.op("move", vec![Arg::X(3), Arg::X(0)]);
+        h.op("return", vec![]);
+        h.export("undefined_function", 3, undefined_function);
+        h.load(&mut emu).unwrap();
+        emu.error_handler = emu.atoms.add("handler");
+        assert_eq!("{dyn,nope,[x]}", call(&emu, "last", &[atom("dyn"), atom("nope"), atom("x")]));
+        assert_eq!("{{nowhere,f,[x]}}", call(&emu, "dynamic", &[atom("nowhere"), atom("f"), atom("x")]));
+        // Except those of the error handler itself.
+        assert_eq!("failed: undef", call(&emu, "last", &[atom("handler"), atom("nope"), atom("x")]));
+    }
+}
@@ -0,0 +1,734 @@
+// A baseline JIT compiler for x86-64, enabled by the `jit` feature.
+//
+// When a module is linked, its code stream is translated to native code,
+// instruction by instruction.  Moves between X registers, jumps, calls
+// and returns are done natively, and so are type tests of X registers and
+// `+`, `-` and `*` of small integers (see `transform`), as far as they
+// don't need the heap or a bignum.  Any other instruction, or case, is a
+// call into the interpreter (see `interp::instruction`), so BIFs, GC and
+// scheduling stay in Rust.  The interpreter goes on up to the next
+// instruction done natively, then native code jumps there through the
+// table of entries.
+//
+// Native code runs on a `Context`, mirroring the process' registers.  It
+// leaves to `execute` with a status when it can't go on by itself, e.g.
+// at the end of the time slice.  Instructions without native code - those
+// of modules which failed to compile, and `interp::HALT` - are
+// interpreted.
+//
+// The interpreter remains the fallback: the JIT may be switched off at
+// any time with `Jit::enabled`, even while processes are running.
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the `jit` feature needs x86-64 Linux");
+
+use code::{ self, ArgTag, BEAMOpcode };
+use exports::CodeIdx;
+use interp::{ self, Error, Outcome, MAX_X_REGS };
+use process::Process;
+use std::mem;
+use std::panic::{ self, AssertUnwindSafe };
+use std::ptr;
+use term::Term;
+use super::Emu;
+
+pub struct Jit {
+    pub enabled:    bool,
+    // The code entering native code, see `trampoline`.
+    trampoline:     Option<Memory>,
+    // Native code of each module.
+    modules:        Vec<Memory>,
+    // Native address of each instruction of the code stream, or 0.
+    entries:        Vec<usize>,
+    // Whether each instruction is done natively, rather than by the
+    // interpreter.
+    native:         Vec<bool>
+}
+
+impl Jit {
+
+    pub fn new() -> Jit {
+        let trampoline = Memory::new(&trampoline().code);
+        Jit { enabled: trampoline.is_some(),
+              trampoline: trampoline,
+              modules: vec![],
+              entries: vec![],
+              native: vec![] }
+    }
+
+    // Translate `code[start ..]`, the code of a module just linked.  On
+    // failure, the module is left to the interpreter.
+    pub fn compile(&mut self, code: &[code::Word], start: usize) {
+        self.entries.resize(code.len(), 0);
+        self.native.resize(code.len(), false);
+        if self.trampoline.is_none()
+            { return }
+        let (asm, offsets) = translate(code, start);
+        if let Some (memory) = Memory::new(&asm.code) {
+            for (ip, offset) in offsets.into_iter().enumerate().skip(start) {
+                if let Some ((offset, native)) = offset {
+                    self.entries[ip] = memory.address + offset;
+                    self.native[ip] = native;
+                }
+            }
+            self.modules.push(memory);
+        }
+    }
+
+    fn entry(&self, ip: CodeIdx) -> Option<usize> {
+        match self.entries.get(ip as usize) {
+            Some (&0) | None => None,
+            Some (&address) => Some (address)
+        }
+    }
+
+}
+
+// What native code operates on.  Only the first fields are accessed from
+// native code, at the offsets below.
+#[repr(C)]
+struct Context<'a> {
+    x:          *mut Term,
+    ip:         CodeIdx,
+    cp:         CodeIdx,
+    reductions: usize,
+    entries:    *const usize,
+    emu:        &'a Emu,
+    p:          *mut Process,
+    // The result of the last interpreted instruction, if it ended the
+    // time slice.
+    result:     Option<Result<Outcome, Error>>
+}
+
+// Registers, and conditions of jumps, as encoded.
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const O: u8 = 0x0;
+const E: u8 = 0x4;
+const NE: u8 = 0x5;
+
+const X: u8 = 0;
+const IP: u8 = 8;
+const CP: u8 = 12;
+const REDUCTIONS: u8 = 16;
+const ENTRIES: u8 = 24;
+
+// Why native code returned to `execute`: `ip` has no native code, the
+// time slice is over, or the interpreter has a result.
+const NEXT: u32 = 0;
+const CONTINUE: u32 = 1;
+const YIELD: u32 = 2;
+const DONE: u32 = 3;
+
+type Enter = unsafe extern "C" fn(ctx: *mut Context, address: usize) -> u32;
+
+pub fn execute(emu: &Emu, p: &mut Process) -> Result<Outcome, Error> {
+    let mut ctx = Context { x: p.x.as_mut_ptr(),
+                            ip: p.ip,
+                            cp: p.cp,
+                            reductions: p.reductions,
+                            entries: emu.jit.entries.as_ptr(),
+                            emu: emu,
+                            p: p,
+                            result: None };
+    let enter: Enter = match emu.jit.trampoline {
+        Some (ref memory) => unsafe { mem::transmute::<usize, Enter>(memory.address) },
+        None => return Err (Error::BadCode(ctx.ip))
+    };
+    loop {
+        let status = match emu.jit.entry(ctx.ip) {
+            Some (address) => unsafe { enter(&mut ctx, address) },
+            None => unsafe { interpret(&mut ctx) }
+        };
+        match status {
+            NEXT | CONTINUE => {},
+            YIELD => {
+                p.ip = ctx.ip;
+                p.cp = ctx.cp;
+                p.reductions = ctx.reductions;
+                return Ok (Outcome::Yielded)
+            },
+            _ => return ctx.result.take().unwrap_or(Err (Error::BadCode(p.ip)))
+        }
+    }
+}
+
+// Called from native code for the instruction at `ctx.ip`, and those
+// after it up to one done natively.  The process is up to date afterwards,
+// so an outcome or error is simply returned by `execute`.  A panic can't
+// unwind through native code: it's an error of the instruction instead.
+unsafe extern "C" fn interpret(ctx: *mut Context) -> u32 {
+    let ctx = &mut *ctx;
+    let p = &mut *ctx.p;
+    p.ip = ctx.ip;
+    p.cp = ctx.cp;
+    p.reductions = ctx.reductions;
+    let ref native = ctx.emu.jit.native;
+    let emu = ctx.emu;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut result = interp::instruction(emu, p);
+        while let Ok (None) = result {
+            if native.get(p.ip as usize).cloned().unwrap_or(false)
+                { break }
+            result = interp::instruction(emu, p);
+        }
+        result
+    }));
+    let result = result.unwrap_or_else(|_| Err (Error::BadCode(p.ip)));
+    ctx.x = p.x.as_mut_ptr();
+    ctx.ip = p.ip;
+    ctx.cp = p.cp;
+    ctx.reductions = p.reductions;
+    match result {
+        Ok (None) => NEXT,
+        Ok (Some (outcome)) => {
+            ctx.result = Some (Ok (outcome));
+            DONE
+        },
+        Err (e) => {
+            ctx.result = Some (Err (e));
+            DONE
+        }
+    }
+}
+
+// Enter native code at the given address, with the context in `rbx` and
+// X registers in `r12`.  Native code leaves through `Asm::exit`.
+fn trampoline() -> Asm {
+    let mut asm = Asm::new();
+    asm.emit(&[0x53, 0x41, 0x54, 0x41, 0x55]);     // push rbx; push r12; push r13
+    asm.emit(&[0x48, 0x89, 0xfb]);                 // mov rbx, rdi
+    asm.load_x();
+    asm.emit(&[0xff, 0xe6]);                       // jmp rsi
+    asm
+}
+
+// Where a term keeps its tag, and a small integer its value, as the
+// layout of `Term` is defined.
+const TAG: i32 = 0;
+const VALUE: i32 = 8;
+
+// The tags native code tests for.
+const SMALL: u8 = tag(&Term::Small(0));
+const ATOM: u8 = tag(&Term::Atom(0));
+const NIL: u8 = tag(&Term::Nil);
+const CONS: u8 = tag(&Term::Cons(0));
+const BOXED: u8 = tag(&Term::Boxed(0));
+
+const fn tag(term: &Term) -> u8 {
+    unsafe { *(term as *const Term as *const u8) }
+}
+
+// Native code for `code[start ..]`, and the offset in it of each
+// instruction, with whether it's done natively.
+fn translate(code: &[code::Word], start: usize)
+    -> (Asm, Vec<Option<(usize, bool)>>)
+{
+    let mut asm = Asm::new();
+    let mut offsets = vec![None; code.len()];
+    asm.exit = asm.code.len();
+    asm.emit(&[0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]); // pop r13; pop r12; pop rbx; ret
+    asm.leave_continue = asm.code.len();
+    asm.leave(CONTINUE);
+    let term_size = mem::size_of::<Term>() as i32;
+    let x = |n: u32| if (n as usize) < MAX_X_REGS { Some (n as i32 * term_size) } else { None };
+    let mut ip = start;
+    while ip < code.len() {
+        let offset = asm.code.len();
+        let op = code::Op::decode(code, ip);
+        let next = ip + op.size();
+        let native = match (op.code, &op.args[..]) {
+            (BEAMOpcode::label, _) |
+            (BEAMOpcode::line, _) => true,
+            (BEAMOpcode::move_x_x, &[(ArgTag::x, src), (ArgTag::x, dst)])
+                if (src as usize) < MAX_X_REGS && (dst as usize) < MAX_X_REGS => {
+                for word in 0 .. term_size / 8 {
+                    asm.load_rax_x(src as i32 * term_size + word * 8);
+                    asm.store_rax_x(dst as i32 * term_size + word * 8);
+                }
+                true
+            },
+            (BEAMOpcode::jump, &[(ArgTag::f, target)]) => {
+                asm.jump(target);
+                true
+            },
+            (BEAMOpcode::call, &[_, (ArgTag::f, target)]) => {
+                asm.set(CP, next as u32);
+                asm.reduce_and_jump(target);
+                true
+            },
+            (BEAMOpcode::call_only, &[_, (ArgTag::f, target)]) => {
+                asm.reduce_and_jump(target);
+                true
+            },
+            (BEAMOpcode::return_, _) => {
+                asm.emit(&[0x8b, 0x43, CP]);       // mov eax, [rbx + CP]
+                asm.emit(&[0x89, 0x43, IP]);       // mov [rbx + IP], eax
+                asm.dispatch();
+                true
+            },
+            // A passed test falls through to the next instruction, which
+            // is translated right after.
+            (BEAMOpcode::is_atom_x, &[(ArgTag::f, fail), (ArgTag::x, src)]) |
+            (BEAMOpcode::is_nil_x, &[(ArgTag::f, fail), (ArgTag::x, src)]) |
+            (BEAMOpcode::is_nonempty_list_x, &[(ArgTag::f, fail), (ArgTag::x, src)])
+                if x(src).is_some() => {
+                let tag = match op.code {
+                    BEAMOpcode::is_atom_x => ATOM,
+                    BEAMOpcode::is_nil_x => NIL,
+                    _ => CONS
+                };
+                asm.cmp_tag(x(src).unwrap() + TAG, tag);
+                asm.jump_if(NE, fail);
+                true
+            },
+            (BEAMOpcode::is_list_x, &[(ArgTag::f, fail), (ArgTag::x, src)])
+                if x(src).is_some() => {
+                asm.cmp_tag(x(src).unwrap() + TAG, NIL);
+                asm.jump_if(E, next as u32);
+                asm.cmp_tag(x(src).unwrap() + TAG, CONS);
+                asm.jump_if(NE, fail);
+                true
+            },
+            // Only boxed terms need a look at the heap.
+            (BEAMOpcode::is_integer_x, &[(ArgTag::f, fail), (ArgTag::x, src)]) |
+            (BEAMOpcode::is_tuple_x, &[(ArgTag::f, fail), (ArgTag::x, src)])
+                if x(src).is_some() => {
+                if op.code == BEAMOpcode::is_integer_x {
+                    asm.cmp_tag(x(src).unwrap() + TAG, SMALL);
+                    asm.jump_if(E, next as u32);
+                }
+                asm.cmp_tag(x(src).unwrap() + TAG, BOXED);
+                asm.jump_if(NE, fail);
+                asm.interpret(ip, next);
+                true
+            },
+            // i_plus Fail Live Src1 Src2 Dst: small integers without
+            // overflow; anything else is up to the interpreter.
+            (BEAMOpcode::i_plus, &[_, _, a, b, (ArgTag::x, dst)]) |
+            (BEAMOpcode::i_minus, &[_, _, a, b, (ArgTag::x, dst)]) |
+            (BEAMOpcode::i_times, &[_, _, a, b, (ArgTag::x, dst)])
+                if x(dst).is_some() &&
+                   [a, b].iter().all(|&(tag, n)| tag == ArgTag::i || x(n).is_some()) => {
+                let mut slow = vec![];
+                for (&(tag, n), &reg) in [a, b].iter().zip([RAX, RCX].iter()) {
+                    if tag == ArgTag::i {
+                        asm.mov_imm(reg, n as i32 as i64 as u64);
+                    } else {
+                        asm.cmp_tag(x(n).unwrap() + TAG, SMALL);
+                        slow.push(asm.jump_forward(NE));
+                        asm.load_x_reg(reg, x(n).unwrap() + VALUE);
+                    }
+                }
+                match op.code {
+                    BEAMOpcode::i_plus => asm.emit(&[0x48, 0x01, 0xc8]),        // add rax, rcx
+                    BEAMOpcode::i_minus => asm.emit(&[0x48, 0x29, 0xc8]),       // sub rax, rcx
+                    _ => asm.emit(&[0x48, 0x0f, 0xaf, 0xc1])                    // imul rax, rcx
+                }
+                slow.push(asm.jump_forward(O));
+                // The tag's word, then the value's.
+                asm.mov_imm(RCX, SMALL as u64);
+                asm.store_x_reg(RCX, x(dst).unwrap() + TAG);
+                asm.store_x_reg(RAX, x(dst).unwrap() + VALUE);
+                asm.reduce_and_jump(next as u32);
+                let to = asm.code.len();
+                for at in slow
+                    { asm.patch(at, to) }
+                asm.interpret(ip, next);
+                true
+            },
+            _ => {
+                asm.interpret(ip, next);
+                false
+            }
+        };
+        offsets[ip] = Some ((offset, native));
+        ip = next;
+    }
+    // Jumps out of the module, or to the middle of an instruction, can't
+    // happen; they'd be left to `execute` anyway.
+    let fixups = mem::replace(&mut asm.fixups, vec![]);
+    for (at, target) in fixups {
+        let offset = offsets.get(target as usize).cloned().and_then(|offset| offset);
+        let to = match offset {
+            Some ((offset, _)) => offset,
+            None => asm.leave_stub(target)
+        };
+        asm.patch(at, to);
+    }
+    (asm, offsets)
+}
+
+// A tiny x86-64 assembler, for the few encodings native code needs.  The
+// context is in `rbx` and X registers in `r12`.
+struct Asm {
+    code:   Vec<u8>,
+    // Offset of the code leaving native code with the status in `eax`.
+    exit:   usize,
+    // Offset of the code leaving native code to continue at `ip`.
+    leave_continue: usize,
+    // Offsets of 32 bit jump displacements, with their code index.
+    fixups: Vec<(usize, CodeIdx)>
+}
+
+impl Asm {
+
+    fn new() -> Asm {
+        Asm { code: vec![], exit: 0, leave_continue: 0, fixups: vec![] }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, n: u32) {
+        self.emit(&n.to_le_bytes());
+    }
+
+    fn emit_u64(&mut self, n: u64) {
+        self.emit(&n.to_le_bytes());
+    }
+
+    // A displacement to `to`, from the end of the instruction.
+    fn rel32(&mut self, to: usize) {
+        let from = self.code.len() + 4;
+        self.emit_u32((to as i64 - from as i64) as i32 as u32);
+    }
+
+    fn patch(&mut self, at: usize, to: usize) {
+        let rel = (to as i64 - (at + 4) as i64) as i32;
+        self.code[at .. at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    // A displacement to the native code of instruction `target`.
+    fn fixup(&mut self, target: CodeIdx) {
+        let at = self.code.len();
+        self.fixups.push((at, target));
+        self.emit_u32(0);
+    }
+
+    // Call `interpret` for the instruction at `ip`, and go on where it
+    // left off.
+    fn interpret(&mut self, ip: usize, next: usize) {
+        self.set(IP, ip as u32);
+        self.emit(&[0x48, 0x89, 0xdf]);            // mov rdi, rbx
+        self.emit(&[0x48, 0xb8]);                  // mov rax, interpret
+        self.emit_u64(interpret as *const () as u64);
+        self.emit(&[0xff, 0xd0]);                  // call rax
+        self.emit(&[0x85, 0xc0]);                  // test eax, eax
+        self.emit(&[0x0f, 0x85]);                  // jnz exit
+        let exit = self.exit;
+        self.rel32(exit);
+        self.load_x();
+        // Most likely with the next instruction.
+        self.emit(&[0x81, 0x7b, IP]);              // cmp dword [rbx + IP], next
+        self.emit_u32(next as u32);
+        self.jump_if(E, next as u32);
+        self.dispatch();
+    }
+
+    fn load_x(&mut self) {
+        self.emit(&[0x4c, 0x8b, 0x63, X]);         // mov r12, [rbx + X]
+    }
+
+    fn load_rax_x(&mut self, disp: i32) {
+        self.emit(&[0x49, 0x8b, 0x84, 0x24]);      // mov rax, [r12 + disp]
+        self.emit_u32(disp as u32);
+    }
+
+    fn store_rax_x(&mut self, disp: i32) {
+        self.emit(&[0x49, 0x89, 0x84, 0x24]);      // mov [r12 + disp], rax
+        self.emit_u32(disp as u32);
+    }
+
+    // mov reg, [r12 + disp], for `RAX` or `RCX`
+    fn load_x_reg(&mut self, reg: u8, disp: i32) {
+        self.emit(&[0x49, 0x8b, 0x84 | reg << 3, 0x24]);
+        self.emit_u32(disp as u32);
+    }
+
+    // mov [r12 + disp], reg
+    fn store_x_reg(&mut self, reg: u8, disp: i32) {
+        self.emit(&[0x49, 0x89, 0x84 | reg << 3, 0x24]);
+        self.emit_u32(disp as u32);
+    }
+
+    // mov reg, n
+    fn mov_imm(&mut self, reg: u8, n: u64) {
+        self.emit(&[0x48, 0xb8 | reg]);
+        self.emit_u64(n);
+    }
+
+    // cmp byte [r12 + disp], tag
+    fn cmp_tag(&mut self, disp: i32, tag: u8) {
+        self.emit(&[0x41, 0x80, 0xbc, 0x24]);
+        self.emit_u32(disp as u32);
+        self.emit(&[tag]);
+    }
+
+    // A conditional jump to the native code of instruction `target`.
+    fn jump_if(&mut self, condition: u8, target: CodeIdx) {
+        self.emit(&[0x0f, 0x80 | condition]);
+        self.fixup(target);
+    }
+
+    // A conditional jump further on; returns the offset of its
+    // displacement, to `patch`.
+    fn jump_forward(&mut self, condition: u8) -> usize {
+        self.emit(&[0x0f, 0x80 | condition]);
+        let at = self.code.len();
+        self.emit_u32(0);
+        at
+    }
+
+    // mov dword [rbx + field], n
+    fn set(&mut self, field: u8, n: u32) {
+        self.emit(&[0xc7, 0x43, field]);
+        self.emit_u32(n);
+    }
+
+    fn jump(&mut self, target: CodeIdx) {
+        self.emit(&[0xe9]);
+        self.fixup(target);
+    }
+
+    fn leave(&mut self, status: u32) {
+        self.emit(&[0xb8]);                        // mov eax, status
+        self.emit_u32(status);
+        self.emit(&[0xe9]);                        // jmp exit
+        let exit = self.exit;
+        self.rel32(exit);
+    }
+
+    // Jump to the native code of `ip`, if any.
+    fn dispatch(&mut self) {
+        self.emit(&[0x8b, 0x43, IP]);              // mov eax, [rbx + IP]
+        self.emit(&[0x48, 0x8b, 0x4b, ENTRIES]);   // mov rcx, [rbx + ENTRIES]
+        self.emit(&[0x48, 0x8b, 0x04, 0xc1]);      // mov rax, [rcx + rax * 8]
+        self.emit(&[0x48, 0x85, 0xc0]);            // test rax, rax
+        self.emit(&[0x0f, 0x84]);                  // jz leave_continue
+        let leave = self.leave_continue;
+        self.rel32(leave);
+        self.emit(&[0xff, 0xe0]);                  // jmp rax
+    }
+
+    // Code continuing at `target` in `execute`; returns its offset.
+    fn leave_stub(&mut self, target: CodeIdx) -> usize {
+        let offset = self.code.len();
+        self.set(IP, target);
+        self.leave(CONTINUE);
+        offset
+    }
+
+    // Like `interp::reduce`: jump to `target`, unless the time slice is
+    // over.
+    fn reduce_and_jump(&mut self, target: CodeIdx) {
+        self.emit(&[0x48, 0x83, 0x7b, REDUCTIONS, 0x00]); // cmp qword [rbx + REDUCTIONS], 0
+        self.emit(&[0x0f, 0x85]);                  // jne more
+        let more = self.code.len();
+        self.emit_u32(0);
+        self.set(IP, target);
+        self.leave(YIELD);
+        let to = self.code.len();
+        self.patch(more, to);
+        self.emit(&[0x48, 0xff, 0x4b, REDUCTIONS]); // dec qword [rbx + REDUCTIONS]
+        self.jump(target);
+    }
+
+}
+
+// Executable memory holding native code.
+struct Memory {
+    address:    usize,
+    len:        usize
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(address: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn mprotect(address: *mut u8, len: usize, prot: i32) -> i32;
+    fn munmap(address: *mut u8, len: usize) -> i32;
+}
+
+impl Memory {
+
+    // Memory is written first, then made executable, but never both.
+    fn new(code: &[u8]) -> Option<Memory> {
+        let len = code.len().max(1);
+        unsafe {
+            let address = mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE,
+                               MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if address as isize == -1
+                { return None }
+            let memory = Memory { address: address as usize, len: len };
+            ptr::copy_nonoverlapping(code.as_ptr(), address, code.len());
+            if mprotect(address, len, PROT_READ | PROT_EXEC) != 0
+                { return None }
+            Some (memory)
+        }
+    }
+
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        unsafe { munmap(self.address as *mut u8, self.len); }
+    }
+}
+
+// Every fixture gives the same results with and without the JIT.  Other
+// tests of running code run both ways too, see `test_emus`.
+#[test]
+fn test_jit() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut emu = Emu::new();
+    assert!(emu.jit.enabled);
+    emu.load_module(&::erlang_dir().join("fac.beam")).unwrap();
+    emu.load_module(&::erlang_dir().join("fac2.beam")).unwrap();
+    // count(N) counts down from N in a loop, sorting numbers with a
+    // `select_val`, and catches the `badarith` of the last step; down(N)
+    // counts down by recursion, over several time slices.
+    let mut m = Module::new("jit_test");
+    let minus = m.import("erlang", "-", 2);
+    let count = m.function("count", 1);
+    let (step, zero, small, caught) = (m.new_label(), m.new_label(), m.new_label(), m.new_label());
+    m.op("allocate", vec![Arg::U(1), Arg::U(1)]);
+    m.op("try", vec![Arg::Y(0), Arg::F(caught)]);
+    m.place(step);
+    m.op("select_val", vec![Arg::X(0), Arg::F(small),
+                            Arg::List(vec![Arg::I(0), Arg::F(zero), Arg::A("last"), Arg::F(zero)])]);
+    m.place(small);
+    m.op("move", vec![Arg::X(0), Arg::X(1)]);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(2), Arg::U(minus), Arg::X(1), Arg::I(1), Arg::X(0)]);
+    m.op("jump", vec![Arg::F(step)]);
+    m.place(zero);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(1), Arg::U(minus), Arg::A("last"), Arg::I(1), Arg::X(0)]);
+    m.place(caught);
+    m.op("try_case", vec![Arg::Y(0)]);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("deallocate", vec![Arg::U(1)]);
+    m.op("return", vec![]);
+    let down = m.function("down", 1);
+    let (more, done) = (m.new_label(), m.new_label());
+    m.op("select_val", vec![Arg::X(0), Arg::F(more), Arg::List(vec![Arg::I(0), Arg::F(done)])]);
+    m.place(more);
+    m.op("move", vec![Arg::X(0), Arg::X(1)]);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(2), Arg::U(minus), Arg::X(1), Arg::I(1), Arg::X(0)]);
+    m.op("call_only", vec![Arg::U(1), Arg::F(down)]);
+    m.place(done);
+    m.op("move", vec![Arg::A("done"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("down", 1, down);
+    // kind(X) -> integer, nil, atom, cons, tuple or other, by type tests;
+    // list(X) -> whether X is a list.
+    let kind = m.function("kind", 1);
+    for &(test, name) in [("is_integer", "integer"), ("is_nil", "nil"), ("is_atom", "atom"),
+                          ("is_nonempty_list", "cons"), ("is_tuple", "tuple")].iter() {
+        let next = m.new_label();
+        m.op(test, vec![Arg::F(next), Arg::X(0)]);
+        m.op("move", vec![Arg::A(name), Arg::X(0)]);
+        m.op("return", vec![]);
+        m.place(next);
+    }
+    m.op("move", vec![Arg::A("other"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("kind", 1, kind);
+    let list = m.function("list", 1);
+    let no = m.new_label();
+    m.op("is_list", vec![Arg::F(no), Arg::X(0)]);
+    m.op("move", vec![Arg::A("true"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(no);
+    m.op("move", vec![Arg::A("false"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("list", 1, list);
+    // add(X, Y) -> X + Y.
+    let plus = m.import("erlang", "+", 2);
+    let add = m.function("add", 2);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(2), Arg::U(plus), Arg::X(0), Arg::X(1), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("add", 2, add);
+    m.export("count", 1, count);
+    m.load(&mut emu).unwrap();
+    let int = etf::Term::Integer;
+    let calls: Vec<(&str, &str, Vec<etf::Term>)> = vec![
+        ("fac", "fac", vec![int(25)]),
+        ("fac2", "fac", vec![int(20)]),
+        ("fac2", "fac", vec![etf::Term::Atom("a".to_string())]),
+        ("fac2", "nope", vec![]),
+        ("jit_test", "count", vec![int(3)]),
+        ("jit_test", "count", vec![int(100000)]),
+        ("jit_test", "down", vec![int(100000)]),
+        ("jit_test", "add", vec![int(1), int(2)]),
+        ("jit_test", "add", vec![int(i64::MAX), int(1)]),
+        ("jit_test", "add", vec![int(1), etf::Term::Float(0.5)]),
+        ("jit_test", "add", vec![int(1), etf::Term::Nil])
+    ];
+    let values = vec![int(7), etf::Term::BigInteger(false, vec![1; 12]), etf::Term::Nil,
+                      etf::Term::Atom("a".to_string()),
+                      etf::Term::List(vec![int(1)], Box::new(etf::Term::Nil)),
+                      etf::Term::Tuple(vec![int(1)]), etf::Term::Float(1.5)];
+    let calls: Vec<(&str, &str, Vec<etf::Term>)> =
+        calls.into_iter()
+             .chain(values.iter().map(|v| ("jit_test", "kind", vec![v.clone()])))
+             .chain(values.iter().map(|v| ("jit_test", "list", vec![v.clone()])))
+             .collect();
+    let mut results = vec![];
+    for &(module, function, ref args) in calls.iter()
+        { results.push(emu.call(module, function, args)) }
+    assert_eq!(Ok (int(2432902008176640000)), results[1]);
+    assert_eq!(Ok (etf::Term::Atom("badarith".to_string())), results[4]);
+    assert_eq!(Ok (etf::Term::Atom("done".to_string())), results[6]);
+    assert_eq!(Ok (int(3)), results[7]);
+    assert_eq!(Err (etf::Term::Atom("badarith".to_string())), results[10]);
+    let atoms = |names: &[&str]| names.iter().map(|name| Ok (etf::Term::Atom(name.to_string())))
+                                      .collect::<Vec<_>>();
+    assert_eq!(atoms(&["integer", "integer", "nil", "atom", "cons", "tuple", "other"]), &results[11 .. 18]);
+    assert_eq!(atoms(&["false", "false", "true", "false", "true", "false", "false"]), &results[18 ..]);
+    // Without calls into the interpreter.
+    for &opcode in [BEAMOpcode::is_integer_x, BEAMOpcode::is_nil_x, BEAMOpcode::is_atom_x,
+                    BEAMOpcode::is_nonempty_list_x, BEAMOpcode::is_tuple_x, BEAMOpcode::is_list_x,
+                    BEAMOpcode::i_plus, BEAMOpcode::i_minus].iter() {
+        let ips: Vec<usize> = (0 .. emu.code.len()).filter(|&ip| code::is_instruction(emu.code[ip]) &&
+                                                               code::opcode(emu.code[ip]) == opcode)
+                                                   .collect();
+        assert!(!ips.is_empty() && ips.iter().all(|&ip| emu.jit.native[ip]), "{}", opcode.name());
+    }
+    emu.jit.enabled = false;
+    for (&(module, function, ref args), result) in calls.iter().zip(results.iter())
+        { assert_eq!(*result, emu.call(module, function, args)) }
+}
+
+#[test]
+fn test_panic() {
+    use asm::{ Arg, Module };
+    use bif;
+    use etf;
+    use std::panic;
+    fn boom(_: &Emu, _: &mut Process, _: &[Term]) -> bif::BifResult {
+        panic!("boom")
+    }
+    let mut emu = Emu::new();
+    emu.register_bif("jit_panic", "boom", 0, boom);
+    // f() -> jit_panic:boom(), called from native code.
+    let mut m = Module::new("jit_panic");
+    let boom = m.import("jit_panic", "boom", 0);
+    let f = m.function("f", 0);
+    m.op("call_ext_only", vec![Arg::U(0), Arg::U(boom)]);
+    m.export("f", 0, f);
+    m.load(&mut emu).unwrap();
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = emu.call("jit_panic", "f", &[]);
+    panic::set_hook(hook);
+    match result {
+        Err (etf::Term::Tuple(ref reason)) => assert_eq!(etf::Term::Atom("bad_code".to_string()), reason[0]),
+        other => panic!("{:?}", other)
+    }
+}
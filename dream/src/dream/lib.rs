@@ -1,69 +1,132 @@
+extern crate flate2;
+
+// Like `try!`, but for `Option`.
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some (x) => x, None => return None })
+}
+
+pub mod arith;
+#[cfg(test)]
+pub mod asm;
 pub mod atoms;
 pub mod beam;
+pub mod bif;
+pub mod bignum;
+pub mod binary;
 pub mod code;
+pub mod compare;
+pub mod docs;
+pub mod etf;
 pub mod exports;
+pub mod heap;
+pub mod interp;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod list;
 pub mod loader;
+pub mod map;
+pub mod math;
+pub mod process;
+pub mod profile;
+pub mod replay;
+pub mod sched;
+pub mod term;
+pub mod transform;
+pub mod tuple;
 
 pub use atoms::AtomTable;
 pub use beam::{ Beam, Chunk };
-pub use exports::{ CodeIdx, ExportTable };
+pub use bif::{ Bif, BifTable };
+pub use exports::{ CodeIdx, ExportTable, FunEntry, Import, Location };
 
 pub type Label = u32;
 
+use heap::Heap;
 use std::path::Path;
+use std::sync::Mutex;
+use term::Term;
 
 pub struct Emu {
-    pub atoms:      AtomTable,
-    pub exports:    ExportTable,
-    //pub code:       CodeTable
+    pub atoms:          AtomTable,
+    pub exports:        ExportTable,
+    pub bifs:           BifTable,
+    // Code of all loaded modules as one stream, see `code::Word`; see
+    // `interp::HALT` for index 0.
+    pub code:           Vec<code::Word>,
+    pub imports:        Vec<Import>,
+    // Literals of all loaded modules, copied to the process heap on use.
+    pub literal_heap:   Heap,
+    pub literals:       Vec<Term>,
+    // Bit syntax strings of all loaded modules.
+    pub strings:        Vec<u8>,
+    // Decoded `select_val` and `select_tuple_arity` lists of all loaded
+    // modules, see `code::JumpTable`.
+    pub jump_tables:    Vec<code::JumpTable>,
+    // Funs of all loaded modules, indexed by `make_fun2`.
+    pub funs:           Vec<FunEntry>,
+    // Source locations of all loaded modules, indexed by `line`
+    // instructions; index 0 stands for no location.
+    pub lines:          Vec<Location>,
+    // Superinstructions fused in modules loaded from now on, by name, see
+    // `transform::SUPERINSTRUCTIONS`: loading fails on unknown names.
+    pub superinstructions: Vec<String>,
+    // Counts of executed opcode sequences while profiling, see `profile`.
+    pub opcode_profile: Option<Mutex<profile::Profile>>,
+    // The module handling calls of missing functions, `error_handler` by
+    // default: see `interp::undefined`.
+    pub error_handler:  exports::Module,
+    pub processes:      process::ProcessTable,
+    pub scheduler:      sched::Scheduler,
+    // Native code of all loaded modules.
+    #[cfg(feature = "jit")]
+    pub jit:            jit::Jit
 }
 
 impl Emu {
 
     pub fn new() -> Emu {
-        Emu { atoms: AtomTable::new(),
-              exports: ExportTable::new() }
+        let atoms = AtomTable::new();
+        let processes = process::ProcessTable::new(&atoms);
+        let error_handler = atoms.add("error_handler");
+        let mut code = vec![];
+        code::Op { code: code::BEAMOpcode::int_code_end, args: vec![] }.encode(&mut code);
+        let mut emu = Emu { atoms: atoms,
+                            exports: ExportTable::new(),
+                            bifs: BifTable::new(),
+                            code: code,
+                            imports: vec![],
+                            literal_heap: Heap::new(),
+                            literals: vec![],
+                            strings: vec![],
+                            jump_tables: vec![],
+                            funs: vec![],
+                            lines: vec![Location { file: String::new(), line: 0 }],
+                            superinstructions: transform::superinstructions(),
+                            opcode_profile: None,
+                            error_handler: error_handler,
+                            processes: processes,
+                            scheduler: sched::Scheduler::new(1),
+                            #[cfg(feature = "jit")]
+                            jit: jit::Jit::new() };
+        bif::register_defaults(&emu.atoms, &mut emu.bifs);
+        emu
     }
 
     pub fn load_module(&mut self, path: &Path) -> Result<(), String> {
         let modname = try!( modname_from_path(path) );
-        let beam = try!( Beam::from_file(path) );
-        let atom_chunk = try!( get_chunk(&beam, "Atom") );
-        let expt_chunk = try!( get_chunk(&beam, "ExpT") );
-        let mod_atoms = AtomTable::from_chunk(atom_chunk);
-        load_atoms(self, &mod_atoms);
-        load_exports(self, &modname, &mod_atoms, expt_chunk);
-        Ok (())
+        let mut state = try!( loader::State::new(path)
+                                  .map_err(|e| format!("can't load {}: {:?}", modname, e)) );
+        loader::load(&mut state, self)
+            .map_err(|e| format!("can't load {}: {:?}", modname, e))
     }
 
-}
-
-fn get_chunk<'beam>(beam: &'beam Beam,
-                    chunk: &str) -> Result<&'beam Chunk, String> {
-    beam.chunk(chunk).ok_or(format!("chunk {:?} not found", chunk))
-}
-
-fn load_atoms(emu: &mut Emu, mod_atoms: &AtomTable) {
-    for &(_, ref atom) in mod_atoms.list().iter() {
-        emu.atoms.add(atom);
+    // Make `module:function/arity` a BIF.  Only modules loaded afterwards
+    // will call it directly, see `bif` for details.
+    pub fn register_bif(&mut self, module: &str, function: &str, arity: usize, bif: Bif) {
+        let mfa = (self.atoms.add(module), self.atoms.add(function), arity);
+        self.bifs.put(mfa, bif);
     }
-}
 
-fn load_exports(emu: &mut Emu, modname: &String,
-                mod_atoms: &AtomTable, expt_chunk: &Chunk) {
-    let ref mut atoms = emu.atoms;
-    let ref mut exports = emu.exports;
-    let modname_emu_index = lookup_or_add(atoms, modname);
-    let mod_exports = exports::from_chunk(expt_chunk);
-    for mod_export in mod_exports {
-        let fun = mod_atoms.get_atom(mod_export.function as atoms::AtomIndex)
-            .expect("atom not found");
-        let fun_emu_index = lookup_or_add(atoms, &fun);
-        let mfa = (modname_emu_index as atoms::AtomIndex,
-                   fun_emu_index as atoms::AtomIndex,
-                   mod_export.arity as exports::Arity);
-        exports.put(mfa, mod_export.label);
-    }
 }
 
 fn modname_from_path(path: &Path) -> Result<String, String> {
@@ -72,10 +135,6 @@ fn modname_from_path(path: &Path) -> Result<String, String> {
         .ok_or(format!("can't build module name from {:?}", path))
 }
 
-fn lookup_or_add(atoms: &mut AtomTable, atom: &String) -> atoms::AtomIndex {
-    atoms.add(atom)
-}
-
 #[test]
 fn test_modname_from_path() {
     let path = Path::new("path/to/enlightenment.beam");
@@ -86,3 +145,161 @@ fn test_modname_from_path() {
         }
     }
 }
+
+#[cfg(test)]
+fn erlang_dir() -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../erlang")
+}
+
+// An emulator for each way of running code: interpreted, and natively
+// if the `jit` feature is on.  Tests of running code go through all.
+#[cfg(test)]
+fn test_emus() -> Vec<Emu> {
+    #[allow(unused_mut)]
+    let mut emus = vec![Emu::new()];
+    #[cfg(feature = "jit")]
+    {
+        emus[0].jit.enabled = false;
+        emus.push(Emu::new());
+    }
+    emus
+}
+
+#[test]
+fn test_run_fac() {
+    for mut emu in test_emus() {
+        emu.load_module(&erlang_dir().join("fac.beam")).unwrap();
+        let result = emu.call("fac", "fac", &[etf::Term::Integer(25)]).unwrap();
+        assert_eq!("15511210043330985984000000", result.to_string());
+    }
+}
+
+// Not a test, but a benchmark of the interpreter: run it with
+// `cargo test --release -- --ignored --nocapture bench_interp`.
+#[test]
+#[ignore]
+fn bench_interp() {
+    use asm::{ Arg, Module };
+    use std::time::Instant;
+    let mut emu = Emu::new();
+    emu.load_module(&erlang_dir().join("fac.beam")).unwrap();
+    // seq(0, Acc) -> Acc; seq(N, Acc) -> seq(N - 1, [N|Acc]).
+    // sum([H|T], Acc) -> sum(T, Acc + H); sum([], Acc) -> Acc.
+    // run(N) -> sum(seq(N, []), 0).
+    let mut m = Module::new("bench");
+    let (plus, minus) = (m.import("erlang", "+", 2), m.import("erlang", "-", 2));
+    let seq = m.function("seq", 2);
+    let more = m.new_label();
+    m.op("is_eq_exact", vec![Arg::F(more), Arg::X(0), Arg::I(0)]);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(more);
+    m.op("put_list", vec![Arg::X(0), Arg::X(1), Arg::X(1)]);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(2), Arg::U(minus), Arg::X(0), Arg::I(1), Arg::X(0)]);
+    m.op("call_only", vec![Arg::U(2), Arg::F(seq)]);
+    let sum = m.function("sum", 2);
+    let done = m.new_label();
+    m.op("is_nonempty_list", vec![Arg::F(done), Arg::X(0)]);
+    m.op("get_list", vec![Arg::X(0), Arg::X(2), Arg::X(0)]);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(3), Arg::U(plus), Arg::X(1), Arg::X(2), Arg::X(1)]);
+    m.op("call_only", vec![Arg::U(2), Arg::F(sum)]);
+    m.place(done);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("return", vec![]);
+    let run = m.function("run", 1);
+    m.op("allocate", vec![Arg::U(0), Arg::U(1)]);
+    m.op("move", vec![Arg::Nil, Arg::X(1)]);
+    m.op("call", vec![Arg::U(2), Arg::F(seq)]);
+    m.op("move", vec![Arg::I(0), Arg::X(1)]);
+    m.op("call_last", vec![Arg::U(2), Arg::F(sum), Arg::U(0)]);
+    m.export("run", 1, run);
+    m.load(&mut emu).unwrap();
+    let bench = |name: &str, n: u32, module: &str, function: &str, arg: i64, expected: &str| {
+        let start = Instant::now();
+        for _ in 0 .. n {
+            let result = emu.call(module, function, &[etf::Term::Integer(arg)]).unwrap();
+            assert_eq!(expected, result.to_string());
+        }
+        let elapsed = start.elapsed();
+        let ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        println!("{}: {} us per call", name, ns / n as u64 / 1000);
+    };
+    bench("fac:fac(25)", 20000, "fac", "fac", 25, "15511210043330985984000000");
+    bench("bench:run(100000)", 20, "bench", "run", 100000, "5000050000");
+}
+
+#[test]
+fn test_run_fac2_and_errors() {
+    for mut emu in test_emus() {
+        emu.load_module(&erlang_dir().join("fac2.beam")).unwrap();
+        assert!(emu.lines.iter().any(|l| l.file == "fac2.erl" && l.line > 0));
+        assert_eq!(Ok (etf::Term::Integer(120)), emu.call("fac2", "fac", &[etf::Term::Integer(5)]));
+        assert_eq!(Err (etf::Term::Atom("badarith".to_string())),
+                   emu.call("fac2", "fac", &[etf::Term::Atom("a".to_string())]));
+        assert_eq!(Err (etf::Term::Atom("undef".to_string())), emu.call("fac2", "nope", &[]));
+    }
+}
+
+#[test]
+fn test_undefined_label() {
+    use asm::{ Arg, Module };
+    // f() -> jump to a label which is never placed.
+    let mut m = Module::new("broken");
+    let f = m.function("f", 0);
+    let nowhere = m.new_label();
+    m.op("jump", vec![Arg::F(nowhere)]);
+    m.export("f", 0, f);
+    let mut emu = Emu::new();
+    assert!(m.load(&mut emu).is_err());
+    assert_eq!(Err (etf::Term::Atom("undef".to_string())), emu.call("broken", "f", &[]));
+}
+
+#[test]
+fn test_unknown_superinstruction() {
+    let mut emu = Emu::new();
+    emu.superinstructions = vec!["move_x_x+move_x_x".to_string()];
+    let error = emu.load_module(&erlang_dir().join("fac.beam")).unwrap_err();
+    assert!(error.contains("UnknownSuperinstruction"), "{}", error);
+}
+
+#[test]
+fn test_register_bif() {
+    fn answer(_: &Emu, _: &mut process::Process, _: &[Term]) -> bif::BifResult {
+        Ok (Term::Small(42))
+    }
+    let mut emu = Emu::new();
+    emu.register_bif("dream", "answer", 0, answer);
+    let mfa = (emu.atoms.add("dream"), emu.atoms.add("answer"), 0);
+    assert!(emu.bifs.get(mfa).is_some());
+}
+
+#[test]
+fn test_hot_code_loading() {
+    use asm::{ Arg, Module };
+    // caller:run() -> callee:answer().
+    let mut caller = Module::new("caller");
+    let answer = caller.import("callee", "answer", 0);
+    let run = caller.function("run", 0);
+    caller.op("call_ext_only", vec![Arg::U(0), Arg::U(answer)]);
+    caller.export("run", 0, run);
+    let callee = |version: i64, exported: bool| {
+        let mut m = Module::new("callee");
+        let answer = m.function("answer", 0);
+        m.op("move", vec![Arg::I(version), Arg::X(0)]);
+        m.op("return", vec![]);
+        if exported
+            { m.export("answer", 0, answer) }
+        m
+    };
+    for mut emu in test_emus() {
+        caller.load(&mut emu).unwrap();
+        let undef = Err (etf::Term::Atom("undef".to_string()));
+        assert_eq!(undef, emu.call("caller", "run", &[]));
+        callee(1, true).load(&mut emu).unwrap();
+        assert_eq!(Ok (etf::Term::Integer(1)), emu.call("caller", "run", &[]));
+        callee(2, true).load(&mut emu).unwrap();
+        assert_eq!(Ok (etf::Term::Integer(2)), emu.call("caller", "run", &[]));
+        callee(3, false).load(&mut emu).unwrap();
+        assert_eq!(undef, emu.call("caller", "run", &[]));
+    }
+}
@@ -0,0 +1,148 @@
+// List BIFs.
+//
+// Lists are chains of cons cells, see `term`.  BIFs which need a proper
+// list fail with `badarg` on an improper one.
+
+use bif::{ self, BifResult };
+use compare;
+use process::Process;
+use term::Term;
+use super::Emu;
+
+// The elements of a proper list, or `badarg`.
+fn elements(p: &Process, list: Term) -> Result<Vec<Term>, bif::Error> {
+    list.list_elements(&p.heap).ok_or(bif::Error::Badarg)
+}
+
+// erlang:hd/1
+pub fn hd_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    match args[0] {
+        Term::Cons(ptr) => Ok (p.heap.get(ptr)),
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:tl/1
+pub fn tl_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    match args[0] {
+        Term::Cons(ptr) => Ok (p.heap.get(ptr + 1)),
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:length/1
+pub fn length_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let mut length = 0;
+    let mut t = args[0];
+    while let Term::Cons(ptr) = t {
+        length += 1;
+        t = p.heap.get(ptr + 1);
+    }
+    if t != Term::Nil
+        { return Err (bif::Error::Badarg) }
+    Ok (Term::Small(length))
+}
+
+// erlang:'++'/2: the second list isn't copied, nor even checked.
+pub fn append_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let front = try!( elements(p, args[0]) );
+    let mut list = args[1];
+    for &e in front.iter().rev()
+        { list = Term::cons(&mut p.heap, e, list) }
+    Ok (list)
+}
+
+// erlang:'--'/2: the first list without the first occurrence of each
+// element of the second one.
+pub fn subtract_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let (mut left, right) = (try!( elements(p, args[0]) ), try!( elements(p, args[1]) ));
+    for &r in right.iter() {
+        let found = left.iter().position(|&l| compare::eq_exact(&p.heap, &emu.atoms, l, r));
+        if let Some (i) = found
+            { left.remove(i); }
+    }
+    Ok (Term::list(&mut p.heap, &left))
+}
+
+// lists:reverse/2: the first list reversed, in front of the second.
+pub fn reverse_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let front = try!( elements(p, args[0]) );
+    let mut list = args[1];
+    for &e in front.iter()
+        { list = Term::cons(&mut p.heap, e, list) }
+    Ok (list)
+}
+
+// lists:keyfind/3: the first tuple whose Nth element is equal (`==`) to
+// the key, or `false`.
+pub fn keyfind_3(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let (key, n) = match args[1] {
+        Term::Small(n) if n >= 1 => (args[0], n as usize),
+        _ => return Err (bif::Error::Badarg)
+    };
+    for t in try!( elements(p, args[2]) ) {
+        let found = match t.tuple_elements(&p.heap) {
+            Some (elements) if elements.len() >= n =>
+                compare::eq(&p.heap, &emu.atoms, elements[n - 1], key),
+            _ => false
+        };
+        if found
+            { return Ok (t) }
+    }
+    Ok (bif::boolean(emu, false))
+}
+
+// lists:member/2: whether an element matches (`=:=`).
+pub fn member_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let list = try!( elements(p, args[1]) );
+    let found = list.iter().any(|&e| compare::eq_exact(&p.heap, &emu.atoms, e, args[0]));
+    Ok (bif::boolean(emu, found))
+}
+
+#[test]
+fn test_lists() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("lists_test");
+    let bif = |m: &mut Module, name: &'static str, module: &str, function: &str, arity: u32| {
+        let import = m.import(module, function, arity);
+        let f = m.function(name, arity);
+        m.op("call_ext_only", vec![Arg::U(arity), Arg::U(import)]);
+        m.export(name, arity, f);
+    };
+    bif(&mut m, "hd", "erlang", "hd", 1);
+    bif(&mut m, "tl", "erlang", "tl", 1);
+    bif(&mut m, "length", "erlang", "length", 1);
+    bif(&mut m, "append", "erlang", "++", 2);
+    bif(&mut m, "subtract", "erlang", "--", 2);
+    bif(&mut m, "reverse", "lists", "reverse", 2);
+    bif(&mut m, "keyfind", "lists", "keyfind", 3);
+    bif(&mut m, "member", "lists", "member", 2);
+    let mut emu = Emu::new();
+    m.load(&mut emu).unwrap();
+    let call = |function: &str, args: &[etf::Term]| match emu.call("lists_test", function, args) {
+        Ok (t) => t.to_string(),
+        Err (t) => format!("failed: {}", t)
+    };
+    let (int, atom) = (etf::Term::Integer, |name: &str| etf::Term::Atom(name.to_string()));
+    let list = |elements: Vec<etf::Term>| etf::Term::List(elements, Box::new(etf::Term::Nil));
+    let abc = list(vec![atom("a"), atom("b"), atom("c")]);
+    let improper = etf::Term::List(vec![int(1)], Box::new(int(2)));
+    assert_eq!("a", call("hd", &[abc.clone()]));
+    assert_eq!("[b,c]", call("tl", &[abc.clone()]));
+    assert_eq!("failed: badarg", call("hd", &[etf::Term::Nil]));
+    assert_eq!("3", call("length", &[abc.clone()]));
+    assert_eq!("failed: badarg", call("length", &[improper.clone()]));
+    assert_eq!("[a,b,c|2]", call("append", &[abc.clone(), int(2)]));
+    assert_eq!("failed: badarg", call("append", &[improper.clone(), etf::Term::Nil]));
+    let ones = list(vec![int(1), etf::Term::Float(1.0), int(1), int(2)]);
+    assert_eq!("[1.0,1]", call("subtract", &[ones.clone(), list(vec![int(2), int(1), int(3)])]));
+    assert_eq!("[c,b,a,d]", call("reverse", &[abc.clone(), list(vec![atom("d")])]));
+    let tuples = list(vec![atom("x"), etf::Term::Tuple(vec![atom("k"), int(1)]),
+                           etf::Term::Tuple(vec![atom("k"), etf::Term::Float(2.0)])]);
+    assert_eq!("{k,2.0}", call("keyfind", &[int(2), int(2), tuples.clone()]));
+    assert_eq!("false", call("keyfind", &[int(2), int(3), tuples.clone()]));
+    assert_eq!("failed: badarg", call("keyfind", &[int(2), int(0), tuples.clone()]));
+    assert_eq!("true", call("member", &[etf::Term::Float(1.0), ones.clone()]));
+    assert_eq!("false", call("member", &[etf::Term::Float(2.0), ones.clone()]));
+}
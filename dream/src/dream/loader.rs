@@ -2,9 +2,19 @@ use super::{ AtomTable,
              Beam,
              CodeIdx,
              code,
+             Emu,
              ExportTable,
              Label };
-use super::code::{ ArgTag, BEAMOpcode, CodeChunk };
+use super::atoms::AtomIndex;
+use super::beam::TypeTable;
+use super::bignum::BigInt;
+use super::code::{ ArgTag, BEAMOpcode, CodeChunk, JumpTable, TypedOperand };
+use super::etf;
+use super::exports::{ self, FunEntry, Import, Location };
+use super::term::{ self, Term };
+use super::transform;
+use flate2::read::ZlibDecoder;
+use std::io::Read;
 use std::path::Path;
 
 pub struct State<'a> {
@@ -12,8 +22,31 @@ pub struct State<'a> {
     pub beam_file:      Beam,
     pub atoms:          Option<AtomTable>,
     pub code:           Option<Vec<code::Op>>,
-    pub labels:         Option<Vec<(Label, CodeIdx)>>,
-    pub exports:        Option<ExportTable>
+    pub big_integers:   Vec<BigInt>,
+    pub typed_operands: Vec<TypedOperand>,
+    // Offsets into the module's code of labels 1, 2, ..., if defined.
+    pub labels:         Option<Vec<Option<CodeIdx>>>,
+    pub exports:        Option<ExportTable>,
+    pub types:          Option<TypeTable>,
+    pub literals:       Option<Vec<etf::Term>>,
+    // The `StrT` chunk: strings of the bit syntax, all run together.
+    pub strings:        Option<Vec<u8>>,
+    // Module, function and arity as indices into the module's atom table.
+    pub imports:        Option<Vec<(u32, u32, u32)>>,
+    pub funs:           Option<Vec<Fun>>,
+    // Items of the `Line` chunk; `line` instructions refer to them
+    // starting from 1.
+    pub lines:          Option<Vec<Location>>
+}
+
+// An entry of the `FunT` chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fun {
+    pub function:   u32,
+    // Including the free variables.
+    pub arity:      u32,
+    pub label:      Label,
+    pub num_free:   u32
 }
 
 impl<'a> State<'a> {
@@ -25,7 +58,15 @@ impl<'a> State<'a> {
                      atoms: None,
                      exports: None,
                      code: None,
-                     labels: None } )
+                     big_integers: vec![],
+                     typed_operands: vec![],
+                     labels: None,
+                     types: None,
+                     literals: None,
+                     strings: None,
+                     imports: None,
+                     funs: None,
+                     lines: None } )
     }
 
 }
@@ -37,7 +78,13 @@ pub enum Error<'a> {
     ModuleNameMismatch(/* module: */ String, /* file: */ String),
     ChunkNotFound(&'a str),
     ChunkLoadError,
-    LoaderError
+    LoaderError,
+    // Index of a literal we can't represent yet.
+    UnsupportedLiteral(usize),
+    // A `bif*` or `gc_bif*` instruction refers to an unknown BIF.
+    UnknownBif(String),
+    // `Emu::superinstructions` names no superinstruction.
+    UnknownSuperinstruction(String)
 }
 
 pub type LoadResult<'a> = Result<(), Error<'a>>;
@@ -53,7 +100,7 @@ pub fn load_atoms<'a>(loader: &mut State) -> LoadResult<'a> {
 pub fn check_module_name<'a>(loader: &State) -> LoadResult<'a> {
     let file = loader.module_name;
     if let Some (ref atoms) = loader.atoms {
-        let module = try! (atoms.get_atom(0).ok_or(Error::LoaderError));
+        let module = try! (atoms.get_atom(1).ok_or(Error::LoaderError));
         if file == module { Ok (()) }
         else {
             Err (Error::ModuleNameMismatch(module, file.to_string()))
@@ -70,14 +117,183 @@ pub fn load_code<'a>(loader: &mut State) -> LoadResult<'a> {
     let code_chunk = try! (CodeChunk::from_chunk(chunk)
                                      .map_err(|_| Error::ChunkLoadError));
     loader.code = Some (code_chunk.code);
+    loader.big_integers = code_chunk.big_integers;
+    loader.typed_operands = code_chunk.typed_operands;
+    Ok (())
+}
+
+// The literal table is zlib-compressed, unless its uncompressed size is 0.
+// Modules without literals have no `LitT` chunk at all.
+pub fn load_literals<'a>(loader: &mut State) -> LoadResult<'a> {
+    let mut literals = vec![];
+    if let Some (chunk) = loader.beam_file.chunk("LitT") {
+        let ref data = chunk.data;
+        if data.len() < 4
+            { return Err (Error::ChunkLoadError) }
+        let table = if be_u32(&data[0..4]) == 0 { data[4..].to_vec() }
+                    else {
+                        let mut table = vec![];
+                        try!( ZlibDecoder::new(&data[4..]).read_to_end(&mut table)
+                                                          .or(Err (Error::ChunkLoadError)) );
+                        table
+                    };
+        if table.len() < 4
+            { return Err (Error::ChunkLoadError) }
+        let mut offset = 4;
+        for _ in 0 .. be_u32(&table[0..4]) {
+            if table.len() < offset + 4
+                { return Err (Error::ChunkLoadError) }
+            let len = be_u32(&table[offset .. offset + 4]) as usize;
+            offset += 4;
+            if table.len() < offset + len
+                { return Err (Error::ChunkLoadError) }
+            let literal = try!( etf::decode(&table[offset .. offset + len])
+                                    .or(Err (Error::ChunkLoadError)) );
+            literals.push(literal);
+            offset += len;
+        }
+    }
+    loader.literals = Some (literals);
+    Ok (())
+}
+
+// Specialise instructions and fuse the given superinstructions, see
+// `transform`.  Constants are added to the literals, so these must be
+// loaded first, and so must be the imports and types the rules look at.
+pub fn transform_code<'a>(loader: &mut State, superinstructions: &[String]) -> LoadResult<'a> {
+    if let Some (name) = transform::unknown_superinstruction(superinstructions)
+        { return Err (Error::UnknownSuperinstruction(name.clone())) }
+    match (&loader.atoms, &loader.code, &loader.imports, &mut loader.literals) {
+        (&Some (ref atoms), &Some (ref code), &Some (ref imports), &mut Some (ref mut literals)) => {
+            let mut ctx = transform::Context { atoms: atoms, imports: imports,
+                                               types: loader.types.as_ref(),
+                                               typed_operands: &loader.typed_operands,
+                                               at: 0, literals: literals,
+                                               superinstructions: superinstructions };
+            let code = transform::transform(&mut ctx, code);
+            loader.code = Some (code);
+            Ok (())
+        },
+        _ => Err (Error::LoaderError)
+    }
+}
+
+// Modules without bit syntax strings may have no `StrT` chunk.
+pub fn load_strings<'a>(loader: &mut State) -> LoadResult<'a> {
+    loader.strings = Some (loader.beam_file.chunk("StrT")
+                                           .map_or(vec![], |chunk| chunk.data.clone()));
+    Ok (())
+}
+
+pub fn load_imports<'a>(loader: &mut State) -> LoadResult<'a> {
+    let ref beam = loader.beam_file;
+    let chunk = try! (beam.chunk("ImpT")
+                          .ok_or(Error::ChunkNotFound("ImpT")));
+    let ref data = chunk.data;
+    let mut imports = vec![];
+    if data.len() >= 4 {
+        for entry in data[4..].chunks(12) {
+            if entry.len() < 12
+                { continue }
+            imports.push((be_u32(&entry[0..4]),
+                          be_u32(&entry[4..8]),
+                          be_u32(&entry[8..12])));
+        }
+    }
+    loader.imports = Some (imports);
+    Ok (())
+}
+
+// Modules defining no funs have no `FunT` chunk.  Each entry is the
+// function, arity, label, index, number of free variables and old unique
+// value of a fun; we only need the first three and the free variables.
+pub fn load_funs<'a>(loader: &mut State) -> LoadResult<'a> {
+    let mut funs = vec![];
+    if let Some (chunk) = loader.beam_file.chunk("FunT") {
+        let ref data = chunk.data;
+        if data.len() >= 4 {
+            for entry in data[4..].chunks(24) {
+                if entry.len() < 24
+                    { continue }
+                funs.push(Fun { function: be_u32(&entry[0..4]),
+                                arity: be_u32(&entry[4..8]),
+                                label: be_u32(&entry[8..12]),
+                                num_free: be_u32(&entry[16..20]) });
+            }
+        }
+    }
+    loader.funs = Some (funs);
+    Ok (())
+}
+
+// Modules compiled without line information have no `Line` chunk.
+// After a header of version, flags, number of `line` instructions, number
+// of items and number of file names, the items follow in the compact term
+// encoding: a line number, or a file name index applying to the items
+// after it, 0 being the module's own source file.  The file names come
+// last, each preceded by its 16 bit length.
+pub fn load_lines<'a>(loader: &mut State) -> LoadResult<'a> {
+    let mut lines = vec![];
+    if let Some (chunk) = loader.beam_file.chunk("Line") {
+        let ref data = chunk.data;
+        if data.len() < 20
+            { return Err (Error::ChunkLoadError) }
+        let (n_items, n_files) = (be_u32(&data[12..16]), be_u32(&data[16..20]) as usize);
+        let mut i = 20;
+        let mut items = vec![];
+        let mut file = 0;
+        while items.len() < n_items as usize {
+            match try!( code::read_operand(&mut i, data).or(Err (Error::ChunkLoadError)) ) {
+                (ArgTag::i, line) => items.push((file, line)),
+                (ArgTag::a, index) if index as usize <= n_files => file = index as usize,
+                _ => return Err (Error::ChunkLoadError)
+            }
+        }
+        let mut files = vec![format!("{}.erl", loader.module_name)];
+        for _ in 0 .. n_files {
+            if data.len() < i + 2
+                { return Err (Error::ChunkLoadError) }
+            let len = (data[i] as usize) << 8 | data[i + 1] as usize;
+            let name = try!( data.get(i + 2 .. i + 2 + len).ok_or(Error::ChunkLoadError) );
+            files.push(String::from_utf8_lossy(name).into_owned());
+            i += 2 + len;
+        }
+        for (file, line) in items.into_iter()
+            { lines.push(Location { file: files[file].clone(), line: line }) }
+    }
+    loader.lines = Some (lines);
+    Ok (())
+}
+
+// The `Type` chunk is optional - it's only present in modules compiled
+// by OTP 25 or newer, so its absence is not an error.  Types are only
+// hints: a chunk we can't decode, e.g. of a newer version, is ignored.
+pub fn load_types<'a>(loader: &mut State) -> LoadResult<'a> {
+    loader.types = loader.beam_file.chunk("Type").and_then(TypeTable::from_chunk);
     Ok (())
 }
 
+// Labels resolve to offsets into the code stream the module is linked to,
+// so each instruction counts for its size there.
 pub fn load_labels<'a>(loader: &mut State) -> LoadResult<'a> {
     let mut labels = vec![];
     if let Some (ref code) = loader.code {
-        for (i, op) in code.iter().enumerate() {
-            try! (load_label(&mut labels, i, op));
+        let mut offset = 0;
+        for op in code.iter() {
+            if op.code == BEAMOpcode::label {
+                match op.args.get(0) {
+                    Some (&(ArgTag::u, label)) if label > 0 => {
+                        let i = label as usize - 1;
+                        if labels.len() <= i
+                            { labels.resize(i + 1, None) }
+                        if labels[i].is_some()
+                            { return Err (Error::LoaderError) }
+                        labels[i] = Some (offset);
+                    },
+                    _ => return Err (Error::LoaderError)
+                }
+            }
+            offset += linked_size(op) as CodeIdx;
         }
         loader.labels = Some (labels);
         Ok (())
@@ -86,19 +302,11 @@ pub fn load_labels<'a>(loader: &mut State) -> LoadResult<'a> {
     }
 }
 
-fn load_label<'a>(labels: &mut Vec<(Label, CodeIdx)>, i: usize, op: &code::Op)
-    -> LoadResult<'a>
-{
-    if let (BEAMOpcode::label, ref args) = (op.code, &op.args) {
-        if let (ArgTag::u, idx) = args[0] {
-            labels.push((idx, i as u32));
-            Ok (())
-        } else {
-            Err (Error::LoaderError)
-        }
-    } else {
-        Ok (())
-    }
+// `select_val` and `select_tuple_arity` with a jump table keep only their
+// source, fail label and table operands, see `link`.
+pub fn linked_size(op: &code::Op) -> usize {
+    if JumpTable::from_op(op).is_some() { 4 }
+    else { op.size() }
 }
 
 pub fn replace_jumps<'a>(loader: &mut State) -> LoadResult<'a> {
@@ -106,21 +314,214 @@ pub fn replace_jumps<'a>(loader: &mut State) -> LoadResult<'a> {
                                                              &mut loader.code)
     {
         for op in code.iter_mut()
-            { replace_jump(labels, &mut op.args) }
+            { try!( replace_jump(labels, &mut op.args) ) }
         Ok (())
     } else {
         Err (Error::LoaderError)
     }
 }
 
-fn replace_jump(labels: &Vec<(Label, CodeIdx)>, args: &mut [(ArgTag, CodeIdx)]) {
+fn replace_jump<'a>(labels: &[Option<CodeIdx>], args: &mut [(ArgTag, u32)]) -> LoadResult<'a> {
     for &mut (ref tag, ref mut arg) in args.iter_mut() {
         match tag {
-            &ArgTag::f if *arg != 0 =>
-                *arg = labels[(*arg - 1) as usize].1,
+            &ArgTag::f if *arg != 0 => *arg = try!( label(labels, *arg) ),
             _ => {}
         }
     }
+    Ok (())
+}
+
+// The offset of a label, which has to be defined.
+fn label<'a>(labels: &[Option<CodeIdx>], label: u32) -> Result<CodeIdx, Error<'a>> {
+    match label.checked_sub(1).and_then(|i| labels.get(i as usize)) {
+        Some (&Some (offset)) => Ok (offset),
+        _ => Err (Error::LoaderError)
+    }
+}
+
+// Add the module to the emulator.  Atoms, literals, imports and funs are
+// translated to the emulator's tables, code is appended to the emulator's
+// code area with jump targets offset accordingly, and exported functions
+// are registered.
+pub fn link<'a>(loader: &mut State, emu: &mut Emu) -> LoadResult<'a> {
+    let (atoms, code, labels, literals, strings, imports, funs, lines) =
+        match (&loader.atoms, &loader.code, &loader.labels, &loader.literals,
+               &loader.strings, &loader.imports, &loader.funs, &loader.lines) {
+            (&Some (ref a), &Some (ref c), &Some (ref l), &Some (ref lit), &Some (ref s),
+             &Some (ref i), &Some (ref f), &Some (ref ln)) =>
+                (a, c, l, lit, s, i, f, ln),
+            _ => return Err (Error::LoaderError)
+        };
+    let mut atom_map: Vec<AtomIndex> = vec![0];
+    for (_, atom) in atoms.list()
+        { atom_map.push(emu.atoms.add(&atom)) }
+    let atom = |index: u32| atom_map.get(index as usize).cloned()
+                                    .ok_or(Error::LoaderError);
+    let literal_base = emu.literals.len();
+    for (i, literal) in literals.iter().enumerate() {
+        let t = try!( term::from_etf(&mut emu.literal_heap, &emu.atoms, literal)
+                          .ok_or(Error::UnsupportedLiteral(i)) );
+        emu.literals.push(t);
+    }
+    let string_base = emu.strings.len() as u32;
+    emu.strings.extend_from_slice(strings);
+    let import_base = emu.imports.len();
+    for &(m, f, a) in imports.iter() {
+        let mfa = (try!( atom(m) ), try!( atom(f) ), a as usize);
+        emu.imports.push(match emu.bifs.get(mfa) {
+            Some (bif) => Import::Bif(bif),
+            None => Import::Export(emu.exports.entry(mfa))
+        });
+    }
+    let module = try!( atom(1) );
+    let code_base = emu.code.len() as CodeIdx;
+    let fun_base = emu.funs.len();
+    for fun in funs.iter() {
+        let target = try!( label(labels, fun.label) );
+        emu.funs.push(FunEntry { mfa: (module, try!( atom(fun.function) ), fun.arity as usize),
+                                 code: target + code_base,
+                                 num_free: fun.num_free as usize });
+    }
+    // Line 0 means no location, in any module.
+    let line_base = emu.lines.len() as u32 - 1;
+    emu.lines.extend(lines.iter().cloned());
+    for op in code.iter() {
+        let mut op = op.clone();
+        let import_arg = import_operand(op.code);
+        let strings_arg = string_operands(&op, atoms);
+        for (n, arg) in op.args.iter_mut().enumerate() {
+            *arg = match *arg {
+                (ArgTag::a, index) => (ArgTag::a, try!( atom(index) ) as u32),
+                (ArgTag::f, label) if label != 0 => (ArgTag::f, label + code_base),
+                (ArgTag::q, index) => (ArgTag::q, index + literal_base as u32),
+                (ArgTag::o, index) => {
+                    let big = try!( loader.big_integers.get(index as usize)
+                                          .ok_or(Error::LoaderError) );
+                    let t = Term::integer(&mut emu.literal_heap, big);
+                    emu.literals.push(t);
+                    (ArgTag::q, emu.literals.len() as u32 - 1)
+                },
+                (ArgTag::u, index) if Some (n) == import_arg => {
+                    let import = index as usize + import_base;
+                    try!( check_import(emu, op.code, import) );
+                    // Calls link straight to the export table entry.
+                    match emu.imports[import] {
+                        Import::Export(export) => (ArgTag::e, export),
+                        Import::Bif(_) => (ArgTag::u, import as u32)
+                    }
+                },
+                (ArgTag::u, offset) if strings_arg.contains(&n) => {
+                    if offset as usize > strings.len()
+                        { return Err (Error::LoaderError) }
+                    (ArgTag::u, offset + string_base)
+                },
+                (ArgTag::u, index) if n == 0 && op.code == BEAMOpcode::make_fun2 =>
+                    (ArgTag::u, index + fun_base as u32),
+                (ArgTag::u, index) if op.code == BEAMOpcode::line && index != 0 => {
+                    if index as usize > lines.len()
+                        { return Err (Error::LoaderError) }
+                    (ArgTag::u, index + line_base)
+                },
+                other => other
+            }
+        }
+        // `select_val` and `select_tuple_arity` dispatch through a table.
+        if let Some (table) = JumpTable::from_op(&op) {
+            emu.jump_tables.push(table);
+            op.args.truncate(2);
+            op.args.push((ArgTag::t, emu.jump_tables.len() as u32 - 1));
+        }
+        op.encode(&mut emu.code);
+    }
+    let expt_chunk = try! (loader.beam_file.chunk("ExpT")
+                                           .ok_or(Error::ChunkNotFound("ExpT")));
+    emu.exports.unload(module);
+    for export in exports::from_chunk(expt_chunk) {
+        let target = try!( label(labels, export.label) );
+        let mfa = (module, try!( atom(export.function) ), export.arity as usize);
+        emu.exports.put(mfa, target + code_base);
+    }
+    #[cfg(feature = "jit")]
+    emu.jit.compile(&emu.code, code_base as usize);
+    Ok (())
+}
+
+// Position of the operand referring to the import table, if any.
+fn import_operand(opcode: BEAMOpcode) -> Option<usize> {
+    match opcode {
+        BEAMOpcode::bif0 => Some (0),
+        BEAMOpcode::call_ext |
+        BEAMOpcode::call_ext_last |
+        BEAMOpcode::call_ext_only |
+        BEAMOpcode::bif1 |
+        BEAMOpcode::bif2 => Some (1),
+        BEAMOpcode::gc_bif1 |
+        BEAMOpcode::gc_bif2 |
+        BEAMOpcode::gc_bif3 => Some (2),
+        _ => None
+    }
+}
+
+// Positions of the operands which are offsets into the `StrT` chunk.
+fn string_operands(op: &code::Op, atoms: &AtomTable) -> Vec<usize> {
+    match op.code {
+        BEAMOpcode::bs_put_string => vec![1],
+        BEAMOpcode::bs_match_string => vec![3],
+        // bs_create_bin Fail Alloc Live Unit Dst Segments: each segment is
+        // Type Seg Unit Flags Value Size, the value of a `string` segment
+        // being an offset.
+        BEAMOpcode::bs_create_bin =>
+            (6 .. op.args.len()).step_by(6)
+                                .filter(|&n| match op.args[n] {
+                                    (ArgTag::a, index) =>
+                                        atoms.get_atom(index as usize).map_or(false, |a| a == "string"),
+                                    _ => false
+                                })
+                                .map(|n| n + 4)
+                                .collect(),
+        _ => vec![]
+    }
+}
+
+// Guard BIF instructions must refer to BIFs, `call_ext*` may refer to any
+// function.
+fn check_import<'a>(emu: &Emu, opcode: BEAMOpcode, import: usize) -> LoadResult<'a> {
+    match (opcode, emu.imports.get(import)) {
+        (_, None) => Err (Error::LoaderError),
+        (BEAMOpcode::call_ext, _) |
+        (BEAMOpcode::call_ext_last, _) |
+        (BEAMOpcode::call_ext_only, _) |
+        (_, Some (&Import::Bif(_))) => Ok (()),
+        (_, Some (&Import::Export(export))) => {
+            let (m, f, a) = emu.exports.mfa(export);
+            Err (Error::UnknownBif(format!("{}:{}/{}",
+                                           emu.atoms.get_atom(m).unwrap_or_default(),
+                                           emu.atoms.get_atom(f).unwrap_or_default(),
+                                           a)))
+        }
+    }
+}
+
+// Run all the loading steps and add the module to the emulator.
+pub fn load<'a>(loader: &mut State<'a>, emu: &mut Emu) -> LoadResult<'a> {
+    try!( load_atoms(loader) );
+    try!( check_module_name(loader) );
+    try!( load_code(loader) );
+    try!( load_literals(loader) );
+    try!( load_imports(loader) );
+    try!( load_types(loader) );
+    try!( transform_code(loader, &emu.superinstructions) );
+    try!( load_labels(loader) );
+    try!( replace_jumps(loader) );
+    try!( load_strings(loader) );
+    try!( load_funs(loader) );
+    try!( load_lines(loader) );
+    link(loader, emu)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 |
+    (bytes[2] as u32) << 8 | bytes[3] as u32
 }
 
 fn module_name(path: &Path) -> Result<&str, Error> {
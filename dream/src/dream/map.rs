@@ -0,0 +1,623 @@
+// Maps.
+//
+// Like on the real BEAM, maps of up to `SMALL_MAP_LIMIT` keys are
+// flatmaps: their keys in term order followed by the values, searched by
+// bisection.  Larger maps are hash array mapped tries (HAMTs) on `hash` of
+// the keys: each node has a bitmap of which of its 32 slots - one for each
+// value of the next 5 bits of the hash - are used, followed by an entry
+// for each used slot: a `[Key|Value]` cons cell or another node.  Keys
+// whose hashes agree on all the bits the trie looks at share a flatmap at
+// the bottom.  Maps are immutable: an update copies the nodes on the path
+// to the entry.
+//
+// Heap layout, after the header:
+//
+//   Header::FlatMap(n)         n keys, then their n values
+//   Header::HashMap(n)         the root node of a HAMT of n keys
+//   Header::HamtNode(bitmap)   an entry for each bit set
+//
+// Keys match with `=:=`, so `1` and `1.0` are different keys.
+
+use atoms::AtomTable;
+use bif::{ self, BifResult };
+use compare;
+use heap::Heap;
+use process::Process;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use term::{ Header, Ptr, Term };
+use super::Emu;
+
+pub const SMALL_MAP_LIMIT: usize = 32;
+
+// Hash bits used at each level of a HAMT, and the levels before they run
+// out.
+const BITS_PER_LEVEL: usize = 5;
+const MAX_DEPTH: usize = 12;
+
+pub fn is_map(heap: &Heap, t: Term) -> bool {
+    size(heap, t).is_some()
+}
+
+pub fn size(heap: &Heap, t: Term) -> Option<usize> {
+    match t.header(heap) {
+        Some (Header::FlatMap(n)) | Some (Header::HashMap(n)) => Some (n),
+        _ => None
+    }
+}
+
+pub fn empty(heap: &mut Heap) -> Term {
+    flatmap(heap, &[])
+}
+
+// A map of `pairs`; the last value of a key given twice wins.
+pub fn from_pairs(heap: &mut Heap, atoms: &AtomTable, pairs: &[(Term, Term)]) -> Term {
+    let mut sorted = pairs.to_vec();
+    // Stable, so the last value of a key stays last.
+    sorted.sort_by(|a, b| compare::compare_exact(heap, atoms, a.0, b.0));
+    let mut unique: Vec<(Term, Term)> = Vec::with_capacity(sorted.len());
+    for (k, v) in sorted {
+        match unique.last_mut() {
+            Some (last) if compare::eq_exact(heap, atoms, last.0, k) => last.1 = v,
+            _ => unique.push((k, v))
+        }
+    }
+    if unique.len() <= SMALL_MAP_LIMIT
+        { return flatmap(heap, &unique) }
+    let root = Term::Boxed(heap.alloc(&[Term::Header(Header::HamtNode(0))]));
+    let mut map = Term::Boxed(heap.alloc(&[Term::Header(Header::HashMap(0)), root]));
+    for &(k, v) in unique.iter()
+        { map = put(heap, atoms, map, k, v).unwrap() }
+    map
+}
+
+// Keys and values, in the map's own order: term order of the keys for a
+// flatmap, hash order for a HAMT.
+pub fn pairs(heap: &Heap, map: Term) -> Option<Vec<(Term, Term)>> {
+    let mut pairs = vec![];
+    match (map, try_opt!( map.header(heap) )) {
+        (Term::Boxed(_), Header::FlatMap(_)) => push_flat(heap, map, &mut pairs),
+        (Term::Boxed(ptr), Header::HashMap(_)) => push_node(heap, heap.get(ptr + 1), &mut pairs),
+        _ => return None
+    }
+    Some (pairs)
+}
+
+// Keys and values, in term order of the keys.
+pub fn sorted_pairs(heap: &Heap, atoms: &AtomTable, map: Term) -> Option<Vec<(Term, Term)>> {
+    let mut pairs = try_opt!( pairs(heap, map) );
+    if let Some (Header::HashMap(_)) = map.header(heap)
+        { pairs.sort_by(|a, b| compare::compare_exact(heap, atoms, a.0, b.0)) }
+    Some (pairs)
+}
+
+fn push_flat(heap: &Heap, flat: Term, pairs: &mut Vec<(Term, Term)>) {
+    let (keys, values) = flat_parts(heap, flat);
+    pairs.extend(keys.iter().cloned().zip(values.iter().cloned()));
+}
+
+fn push_node(heap: &Heap, node: Term, pairs: &mut Vec<(Term, Term)>) {
+    let (ptr, header) = boxed(heap, node);
+    match header {
+        Header::HamtNode(bitmap) => {
+            for &entry in heap.slice(ptr + 1, bitmap.count_ones() as usize) {
+                match entry {
+                    Term::Cons(leaf) => pairs.push((heap.get(leaf), heap.get(leaf + 1))),
+                    _ => push_node(heap, entry, pairs)
+                }
+            }
+        },
+        _ => push_flat(heap, node, pairs)
+    }
+}
+
+pub fn get(heap: &Heap, atoms: &AtomTable, map: Term, key: Term) -> Option<Term> {
+    match try_opt!( map.header(heap) ) {
+        Header::FlatMap(_) => flat_get(heap, atoms, map, key),
+        Header::HashMap(_) => {
+            let h = hash(heap, key);
+            let (ptr, _) = boxed(heap, map);
+            let mut node = heap.get(ptr + 1);
+            for depth in 0 .. {
+                let (ptr, header) = boxed(heap, node);
+                let bitmap = match header {
+                    Header::HamtNode(bitmap) => bitmap,
+                    _ => return flat_get(heap, atoms, node, key)
+                };
+                let bit = 1 << slot(h, depth);
+                if bitmap & bit == 0
+                    { return None }
+                node = heap.get(ptr + 1 + (bitmap & (bit - 1)).count_ones() as usize);
+                if let Term::Cons(leaf) = node {
+                    return if compare::eq_exact(heap, atoms, heap.get(leaf), key) {
+                        Some (heap.get(leaf + 1))
+                    } else {
+                        None
+                    }
+                }
+            }
+            None
+        },
+        _ => None
+    }
+}
+
+fn flat_get(heap: &Heap, atoms: &AtomTable, flat: Term, key: Term) -> Option<Term> {
+    let (keys, values) = flat_parts(heap, flat);
+    // The fast path: immediate keys, e.g. atoms, are equal only if they're
+    // the same word.
+    let found = match key {
+        Term::Boxed(_) | Term::Cons(_) =>
+            keys.binary_search_by(|&k| compare::compare_exact(heap, atoms, k, key)).ok(),
+        _ => keys.iter().position(|&k| k == key)
+    };
+    found.map(|i| values[i])
+}
+
+// `map` with `key` associated with `value`, if `map` is a map.
+pub fn put(heap: &mut Heap, atoms: &AtomTable, map: Term, key: Term, value: Term)
+    -> Option<Term>
+{
+    match try_opt!( map.header(heap) ) {
+        Header::FlatMap(n) => {
+            let (pairs, added) = flat_put(heap, atoms, map, key, value);
+            if added && n + 1 > SMALL_MAP_LIMIT { Some (from_pairs(heap, atoms, &pairs)) }
+            else { Some (flatmap(heap, &pairs)) }
+        },
+        Header::HashMap(n) => {
+            let (ptr, _) = boxed(heap, map);
+            let root = heap.get(ptr + 1);
+            let h = hash(heap, key);
+            let (root, added) = insert(heap, atoms, root, key, value, h, 0);
+            let n = if added { n + 1 } else { n };
+            Some (Term::Boxed(heap.alloc(&[Term::Header(Header::HashMap(n)), root])))
+        },
+        _ => None
+    }
+}
+
+// `map` without `key`, if `map` is a map.
+pub fn remove(heap: &mut Heap, atoms: &AtomTable, map: Term, key: Term) -> Option<Term> {
+    match try_opt!( map.header(heap) ) {
+        Header::FlatMap(_) => {
+            let mut pairs = try_opt!( pairs(heap, map) );
+            match pairs.binary_search_by(|&(k, _)| compare::compare_exact(heap, atoms, k, key)) {
+                Ok (i) => { pairs.remove(i); },
+                Err (_) => return Some (map)
+            }
+            Some (flatmap(heap, &pairs))
+        },
+        Header::HashMap(n) => {
+            let (ptr, _) = boxed(heap, map);
+            let root = heap.get(ptr + 1);
+            let h = hash(heap, key);
+            let root = match delete(heap, atoms, root, key, h, 0) {
+                Deletion::Absent => return Some (map),
+                Deletion::Deleted(Some (root)) => root,
+                Deletion::Deleted(None) =>
+                    Term::Boxed(heap.alloc(&[Term::Header(Header::HamtNode(0))]))
+            };
+            let map = Term::Boxed(heap.alloc(&[Term::Header(Header::HashMap(n - 1)), root]));
+            // Back to a flatmap once small enough.
+            if n - 1 <= SMALL_MAP_LIMIT {
+                let pairs = try_opt!( pairs(heap, map) );
+                return Some (from_pairs(heap, atoms, &pairs))
+            }
+            Some (map)
+        },
+        _ => None
+    }
+}
+
+fn flatmap(heap: &mut Heap, sorted: &[(Term, Term)]) -> Term {
+    let mut words = Vec::with_capacity(2 * sorted.len() + 1);
+    words.push(Term::Header(Header::FlatMap(sorted.len())));
+    words.extend(sorted.iter().map(|&(k, _)| k));
+    words.extend(sorted.iter().map(|&(_, v)| v));
+    Term::Boxed(heap.alloc(&words))
+}
+
+fn flat_parts(heap: &Heap, flat: Term) -> (&[Term], &[Term]) {
+    match boxed(heap, flat) {
+        (ptr, Header::FlatMap(n)) => (heap.slice(ptr + 1, n), heap.slice(ptr + 1 + n, n)),
+        _ => (&[], &[])
+    }
+}
+
+// The pairs of a flatmap with `key` put in, and whether it's a new key.
+fn flat_put(heap: &Heap, atoms: &AtomTable, flat: Term, key: Term, value: Term)
+    -> (Vec<(Term, Term)>, bool)
+{
+    let mut pairs = vec![];
+    push_flat(heap, flat, &mut pairs);
+    match pairs.binary_search_by(|&(k, _)| compare::compare_exact(heap, atoms, k, key)) {
+        Ok (i) => {
+            pairs[i].1 = value;
+            (pairs, false)
+        },
+        Err (i) => {
+            pairs.insert(i, (key, value));
+            (pairs, true)
+        }
+    }
+}
+
+fn boxed(heap: &Heap, t: Term) -> (Ptr, Header) {
+    match (t, t.header(heap)) {
+        (Term::Boxed(ptr), Some (header)) => (ptr, header),
+        _ => panic!("not a map node: {:?}", t)
+    }
+}
+
+fn slot(h: u64, depth: usize) -> usize {
+    (h >> (BITS_PER_LEVEL * depth)) as usize & ((1 << BITS_PER_LEVEL) - 1)
+}
+
+fn node(heap: &mut Heap, bitmap: usize, entries: &[Term]) -> Term {
+    let mut words = Vec::with_capacity(entries.len() + 1);
+    words.push(Term::Header(Header::HamtNode(bitmap)));
+    words.extend_from_slice(entries);
+    Term::Boxed(heap.alloc(&words))
+}
+
+// Insert into the subtrie at `node`, `depth` levels down: the new node and
+// whether it's a new key.
+fn insert(heap: &mut Heap, atoms: &AtomTable, node_term: Term, key: Term, value: Term,
+          h: u64, depth: usize) -> (Term, bool)
+{
+    let (ptr, header) = boxed(heap, node_term);
+    let bitmap = match header {
+        Header::HamtNode(bitmap) => bitmap,
+        _ => {
+            let (pairs, added) = flat_put(heap, atoms, node_term, key, value);
+            return (flatmap(heap, &pairs), added)
+        }
+    };
+    let bit = 1 << slot(h, depth);
+    let index = (bitmap & (bit - 1)).count_ones() as usize;
+    let mut entries = heap.slice(ptr + 1, bitmap.count_ones() as usize).to_vec();
+    if bitmap & bit == 0 {
+        entries.insert(index, Term::cons(heap, key, value));
+        return (node(heap, bitmap | bit, &entries), true)
+    }
+    let (entry, added) = match entries[index] {
+        Term::Cons(leaf) => {
+            let other = heap.get(leaf);
+            let new = Term::cons(heap, key, value);
+            if compare::eq_exact(heap, atoms, other, key) { (new, false) }
+            else {
+                let other_hash = hash(heap, other);
+                (split(heap, atoms, entries[index], other_hash, new, h, depth + 1), true)
+            }
+        },
+        sub => insert(heap, atoms, sub, key, value, h, depth + 1)
+    };
+    entries[index] = entry;
+    (node(heap, bitmap, &entries), added)
+}
+
+// A subtrie of two leaves whose hashes agree up to `depth`.
+fn split(heap: &mut Heap, atoms: &AtomTable, a: Term, a_hash: u64, b: Term, b_hash: u64,
+         depth: usize) -> Term
+{
+    if depth >= MAX_DEPTH {
+        let mut pairs = vec![];
+        for &leaf in [a, b].iter() {
+            if let Term::Cons(ptr) = leaf
+                { pairs.push((heap.get(ptr), heap.get(ptr + 1))) }
+        }
+        pairs.sort_by(|x, y| compare::compare_exact(heap, atoms, x.0, y.0));
+        return flatmap(heap, &pairs)
+    }
+    let (a_slot, b_slot) = (slot(a_hash, depth), slot(b_hash, depth));
+    if a_slot == b_slot {
+        let sub = split(heap, atoms, a, a_hash, b, b_hash, depth + 1);
+        node(heap, 1 << a_slot, &[sub])
+    } else if a_slot < b_slot {
+        node(heap, 1 << a_slot | 1 << b_slot, &[a, b])
+    } else {
+        node(heap, 1 << a_slot | 1 << b_slot, &[b, a])
+    }
+}
+
+enum Deletion {
+    Absent,
+    // What takes the place of the subtrie, if anything.
+    Deleted(Option<Term>)
+}
+
+fn delete(heap: &mut Heap, atoms: &AtomTable, node_term: Term, key: Term, h: u64,
+          depth: usize) -> Deletion
+{
+    let (ptr, header) = boxed(heap, node_term);
+    let bitmap = match header {
+        Header::HamtNode(bitmap) => bitmap,
+        _ => {
+            let mut pairs = vec![];
+            push_flat(heap, node_term, &mut pairs);
+            match pairs.binary_search_by(|&(k, _)| compare::compare_exact(heap, atoms, k, key)) {
+                Ok (i) => { pairs.remove(i); },
+                Err (_) => return Deletion::Absent
+            }
+            // A single key left moves up as a leaf.
+            return Deletion::Deleted(Some (match pairs.len() {
+                1 => Term::cons(heap, pairs[0].0, pairs[0].1),
+                _ => flatmap(heap, &pairs)
+            }))
+        }
+    };
+    let bit = 1 << slot(h, depth);
+    if bitmap & bit == 0
+        { return Deletion::Absent }
+    let index = (bitmap & (bit - 1)).count_ones() as usize;
+    let mut entries = heap.slice(ptr + 1, bitmap.count_ones() as usize).to_vec();
+    let replacement = match entries[index] {
+        Term::Cons(leaf) if compare::eq_exact(heap, atoms, heap.get(leaf), key) => None,
+        Term::Cons(_) => return Deletion::Absent,
+        sub => match delete(heap, atoms, sub, key, h, depth + 1) {
+            Deletion::Absent => return Deletion::Absent,
+            Deletion::Deleted(replacement) => replacement
+        }
+    };
+    let bitmap = match replacement {
+        Some (entry) => {
+            entries[index] = entry;
+            bitmap
+        },
+        None => {
+            entries.remove(index);
+            bitmap & !bit
+        }
+    };
+    match (entries.len(), entries.first()) {
+        (0, _) => Deletion::Deleted(None),
+        (1, Some (&leaf @ Term::Cons(_))) if depth > 0 => Deletion::Deleted(Some (leaf)),
+        _ => Deletion::Deleted(Some (node(heap, bitmap, &entries)))
+    }
+}
+
+// A hash of `t` which agrees for terms which are `=:=`.
+pub fn hash(heap: &Heap, t: Term) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_term(heap, t, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_term(heap: &Heap, t: Term, hasher: &mut DefaultHasher) {
+    let mut t = t;
+    // Loop instead of recursing on list tails.
+    while let Term::Cons(ptr) = t {
+        hasher.write_u8(1);
+        hash_term(heap, heap.get(ptr), hasher);
+        t = heap.get(ptr + 1);
+    }
+    match t {
+        Term::Nil => hasher.write_u8(2),
+        Term::Small(i) => {
+            hasher.write_u8(3);
+            hasher.write_i64(i);
+        },
+        Term::Atom(a) => {
+            hasher.write_u8(4);
+            hasher.write_usize(a);
+        },
+        Term::Pid(pid) => {
+            hasher.write_u8(5);
+            hasher.write_usize(pid);
+        },
+        Term::Ref(r) => {
+            hasher.write_u8(6);
+            hasher.write_usize(r);
+        },
+        Term::Boxed(ptr) => {
+            let header = match heap.get(ptr) {
+                Term::Header(header) => header,
+                _ => return
+            };
+            match header {
+                Header::FlatMap(n) | Header::HashMap(n) => {
+                    // Independent of the order of the keys.
+                    hasher.write_u8(7);
+                    hasher.write_usize(n);
+                    let mut sum: u64 = 0;
+                    for (k, v) in pairs(heap, t).unwrap_or_default() {
+                        let mut pair = DefaultHasher::new();
+                        hash_term(heap, k, &mut pair);
+                        hash_term(heap, v, &mut pair);
+                        sum = sum.wrapping_add(pair.finish());
+                    }
+                    hasher.write_u64(sum);
+                },
+                Header::Float => {
+                    // 0.0 and -0.0 are equal.
+                    hasher.write_u8(8);
+                    hasher.write_u64((t.float_value(heap).unwrap_or(0.0) + 0.0).to_bits());
+                },
+                _ if t.is_bitstring(heap) => {
+                    let bits = t.bits(heap).unwrap();
+                    hasher.write_u8(9);
+                    hasher.write_usize(bits.size);
+                    hasher.write(&bits.read_bytes(0, bits.size));
+                },
+                // Tuples, funs and bignums: the header and what follows.
+                _ => {
+                    hasher.write_u8(10);
+                    for &word in heap.slice(ptr, 1 + header.size()) {
+                        match word {
+                            Term::Header(_) => hasher.write_usize(header.size()),
+                            Term::Raw(w) => hasher.write_u64(w),
+                            other => hash_term(heap, other, hasher)
+                        }
+                    }
+                }
+            }
+        },
+        _ => hasher.write_u8(11)
+    }
+}
+
+fn badmap(emu: &Emu, p: &mut Process, t: Term) -> bif::Error {
+    let tag = Term::Atom(emu.atoms.add("badmap"));
+    bif::Error::Error(Term::tuple(&mut p.heap, &[tag, t]))
+}
+
+fn badkey(emu: &Emu, p: &mut Process, t: Term) -> bif::Error {
+    let tag = Term::Atom(emu.atoms.add("badkey"));
+    bif::Error::Error(Term::tuple(&mut p.heap, &[tag, t]))
+}
+
+fn map_arg(emu: &Emu, p: &mut Process, t: Term) -> Result<Term, bif::Error> {
+    if is_map(&p.heap, t) { Ok (t) } else { Err (badmap(emu, p, t)) }
+}
+
+// maps:get/2 and erlang:map_get/2
+pub fn get_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[1]) );
+    match get(&p.heap, &emu.atoms, map, args[0]) {
+        Some (value) => Ok (value),
+        None => Err (badkey(emu, p, args[0]))
+    }
+}
+
+// maps:get/3; the third argument is the default.
+pub fn get_3(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[1]) );
+    Ok (get(&p.heap, &emu.atoms, map, args[0]).unwrap_or(args[2]))
+}
+
+// maps:find/2
+pub fn find_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[1]) );
+    match get(&p.heap, &emu.atoms, map, args[0]) {
+        Some (value) => {
+            let ok = Term::Atom(emu.atoms.add("ok"));
+            Ok (Term::tuple(&mut p.heap, &[ok, value]))
+        },
+        None => Ok (Term::Atom(emu.atoms.add("error")))
+    }
+}
+
+// maps:is_key/2 and erlang:is_map_key/2
+pub fn is_key_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[1]) );
+    Ok (bif::boolean(emu, get(&p.heap, &emu.atoms, map, args[0]).is_some()))
+}
+
+// maps:put/3
+pub fn put_3(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[2]) );
+    Ok (put(&mut p.heap, &emu.atoms, map, args[0], args[1]).unwrap())
+}
+
+// maps:remove/2
+pub fn remove_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[1]) );
+    Ok (remove(&mut p.heap, &emu.atoms, map, args[0]).unwrap())
+}
+
+// maps:size/1 and erlang:map_size/1
+pub fn size_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    match size(&p.heap, args[0]) {
+        Some (n) => Ok (Term::Small(n as i64)),
+        None => Err (badmap(emu, p, args[0]))
+    }
+}
+
+// erlang:is_map/1
+pub fn is_map_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    Ok (bif::boolean(emu, is_map(&p.heap, args[0])))
+}
+
+// maps:keys/1
+pub fn keys_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[0]) );
+    let keys: Vec<Term> = pairs(&p.heap, map).unwrap().into_iter().map(|(k, _)| k).collect();
+    Ok (Term::list(&mut p.heap, &keys))
+}
+
+// maps:values/1, in the order of maps:keys/1.
+pub fn values_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[0]) );
+    let values: Vec<Term> = pairs(&p.heap, map).unwrap().into_iter().map(|(_, v)| v).collect();
+    Ok (Term::list(&mut p.heap, &values))
+}
+
+// maps:to_list/1
+pub fn to_list_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let map = try!( map_arg(emu, p, args[0]) );
+    let mut tuples = vec![];
+    for (k, v) in pairs(&p.heap, map).unwrap()
+        { tuples.push(Term::tuple(&mut p.heap, &[k, v])) }
+    Ok (Term::list(&mut p.heap, &tuples))
+}
+
+// maps:from_list/1; later pairs win.
+pub fn from_list_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let elements = try!( args[0].list_elements(&p.heap).ok_or(bif::Error::Badarg) );
+    let mut pairs = Vec::with_capacity(elements.len());
+    for e in elements.iter() {
+        match e.tuple_elements(&p.heap) {
+            Some (kv) if kv.len() == 2 => pairs.push((kv[0], kv[1])),
+            _ => return Err (bif::Error::Badarg)
+        }
+    }
+    Ok (from_pairs(&mut p.heap, &emu.atoms, &pairs))
+}
+
+// maps:merge/2; the values of the second map win.
+pub fn merge_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let (a, b) = (try!( map_arg(emu, p, args[0]) ), try!( map_arg(emu, p, args[1]) ));
+    let (mut pairs, more) = (pairs(&p.heap, a).unwrap(), pairs(&p.heap, b).unwrap());
+    if pairs.len() + more.len() <= SMALL_MAP_LIMIT {
+        pairs.extend(more);
+        return Ok (from_pairs(&mut p.heap, &emu.atoms, &pairs))
+    }
+    let mut map = a;
+    for (k, v) in more
+        { map = put(&mut p.heap, &emu.atoms, map, k, v).unwrap() }
+    Ok (map)
+}
+
+#[test]
+fn test_maps() {
+    let mut heap = Heap::new();
+    let atoms = AtomTable::new();
+    let n = 1000;
+    let mut map = empty(&mut heap);
+    for i in 0 .. n {
+        let (k, v) = (Term::Small(i), Term::Atom(atoms.add(&format!("v{}", i))));
+        map = put(&mut heap, &atoms, map, k, v).unwrap();
+        assert_eq!(Some (i as usize + 1), size(&heap, map));
+    }
+    assert_eq!(Some (Header::HashMap(n as usize)), map.header(&heap));
+    let one = Term::float(&mut heap, 1.0);
+    assert_eq!(None, get(&heap, &atoms, map, one));
+    for i in 0 .. n
+        { assert_eq!(Some (Term::Atom(atoms.add(&format!("v{}", i)))), get(&heap, &atoms, map, Term::Small(i))) }
+    // Replacing a value doesn't change the size.
+    let replaced = put(&mut heap, &atoms, map, Term::Small(7), Term::Nil).unwrap();
+    assert_eq!(Some (n as usize), size(&heap, replaced));
+    assert_eq!(Some (Term::Nil), get(&heap, &atoms, replaced, Term::Small(7)));
+    assert!(get(&heap, &atoms, map, Term::Small(7)) != Some (Term::Nil));
+    // Equal keys hash the same, whatever their representation.
+    let tuple = Term::tuple(&mut heap, &[one, Term::Nil]);
+    let other = Term::float(&mut heap, 1.0);
+    let copy = Term::tuple(&mut heap, &[other, Term::Nil]);
+    assert_eq!(hash(&heap, tuple), hash(&heap, copy));
+    map = put(&mut heap, &atoms, map, tuple, Term::Small(-1)).unwrap();
+    assert_eq!(Some (Term::Small(-1)), get(&heap, &atoms, map, copy));
+    map = remove(&mut heap, &atoms, map, copy).unwrap();
+    assert_eq!(None, get(&heap, &atoms, map, tuple));
+    for i in 0 .. n {
+        map = remove(&mut heap, &atoms, map, Term::Small(i)).unwrap();
+        assert_eq!(None, get(&heap, &atoms, map, Term::Small(i)));
+        if i + 1 < n
+            { assert!(get(&heap, &atoms, map, Term::Small(i + 1)).is_some()) }
+    }
+    assert_eq!(Some (Header::FlatMap(0)), map.header(&heap));
+    // Flatmaps keep their keys in order, and the last value wins.
+    let (a, b) = (Term::Atom(atoms.add("a")), Term::Atom(atoms.add("b")));
+    let small = from_pairs(&mut heap, &atoms, &[(b, Term::Small(1)), (a, Term::Small(2)),
+                                                 (b, Term::Small(3))]);
+    assert_eq!(Some (vec![(a, Term::Small(2)), (b, Term::Small(3))]), pairs(&heap, small));
+}
@@ -0,0 +1,158 @@
+// The `math` module.
+//
+// Arguments may be integers or floats; results are always floats.  A
+// result which isn't finite, e.g. of `sqrt(-1)` or `log(0)`, is a
+// `badarith` error, as on the real BEAM.
+
+use bif::{ self, BifResult };
+use process::Process;
+use std::f64::consts;
+use term::Term;
+use super::Emu;
+
+fn float_arg(p: &Process, t: Term) -> Result<f64, bif::Error> {
+    t.number(&p.heap).map(|n| n.to_f64()).ok_or(bif::Error::Badarg)
+}
+
+fn float_result(p: &mut Process, f: f64) -> BifResult {
+    if f.is_finite() { Ok (Term::float(&mut p.heap, f)) }
+    else { Err (bif::Error::Badarith) }
+}
+
+fn unary(p: &mut Process, args: &[Term], f: fn(f64) -> f64) -> BifResult {
+    let x = try!( float_arg(p, args[0]) );
+    float_result(p, f(x))
+}
+
+fn binary(p: &mut Process, args: &[Term], f: fn(f64, f64) -> f64) -> BifResult {
+    let (x, y) = (try!( float_arg(p, args[0]) ), try!( float_arg(p, args[1]) ));
+    float_result(p, f(x, y))
+}
+
+// math:pi/0
+pub fn pi_0(_: &Emu, p: &mut Process, _: &[Term]) -> BifResult {
+    float_result(p, consts::PI)
+}
+
+pub fn sqrt_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::sqrt)
+}
+
+pub fn exp_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::exp)
+}
+
+pub fn log_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::ln)
+}
+
+pub fn log2_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::log2)
+}
+
+pub fn log10_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::log10)
+}
+
+pub fn sin_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::sin)
+}
+
+pub fn cos_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::cos)
+}
+
+pub fn tan_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::tan)
+}
+
+pub fn asin_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::asin)
+}
+
+pub fn acos_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::acos)
+}
+
+pub fn atan_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::atan)
+}
+
+pub fn sinh_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::sinh)
+}
+
+pub fn cosh_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::cosh)
+}
+
+pub fn tanh_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::tanh)
+}
+
+pub fn asinh_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::asinh)
+}
+
+pub fn acosh_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::acosh)
+}
+
+pub fn atanh_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::atanh)
+}
+
+pub fn ceil_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::ceil)
+}
+
+pub fn floor_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    unary(p, args, f64::floor)
+}
+
+pub fn pow_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    binary(p, args, f64::powf)
+}
+
+pub fn atan2_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    binary(p, args, f64::atan2)
+}
+
+// math:fmod/2: the remainder has the sign of the first argument.
+pub fn fmod_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    binary(p, args, |x, y| x % y)
+}
+
+#[test]
+fn test_math() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("m");
+    let bif = |m: &mut Module, function: &'static str, arity: u32| {
+        let import = m.import("math", function, arity);
+        let f = m.function(function, arity);
+        m.op("call_ext_only", vec![Arg::U(arity), Arg::U(import)]);
+        m.export(function, arity, f);
+    };
+    bif(&mut m, "pi", 0);
+    bif(&mut m, "sqrt", 1);
+    bif(&mut m, "log", 1);
+    bif(&mut m, "pow", 2);
+    bif(&mut m, "fmod", 2);
+    let mut emu = Emu::new();
+    m.load(&mut emu).unwrap();
+    let call = |function: &str, args: &[etf::Term]| match emu.call("m", function, args) {
+        Ok (t) => t.to_string(),
+        Err (t) => format!("failed: {}", t)
+    };
+    let (int, float) = (etf::Term::Integer, etf::Term::Float);
+    assert_eq!("3.141592653589793", call("pi", &[]));
+    assert_eq!("3.0", call("sqrt", &[int(9)]));
+    assert_eq!("failed: badarith", call("sqrt", &[int(-1)]));
+    assert_eq!("failed: badarg", call("sqrt", &[etf::Term::Nil]));
+    assert_eq!("0.0", call("log", &[float(1.0)]));
+    assert_eq!("failed: badarith", call("log", &[int(0)]));
+    assert_eq!("1024.0", call("pow", &[int(2), float(10.0)]));
+    assert_eq!("failed: badarith", call("pow", &[float(10.0), int(400)]));
+    assert_eq!("-1.5", call("fmod", &[float(-5.5), int(2)]));
+}
@@ -0,0 +1,1044 @@
+// Erlang processes.
+//
+// Every process has its own registers, stack and heap, so terms are never
+// shared between processes: spawn arguments and messages are copied to the
+// heap of the receiving process.  Only large binaries, which live off-heap,
+// are shared.
+//
+// The process table is shared by all schedulers.  A running process is
+// taken out of it for the duration of its time slice, so signals - messages,
+// exit signals, link and monitor requests - and timeouts for it are left in
+// its table entry, to be picked up when it looks at its mailbox or goes back
+// into the table.  Signals from one process to another are handled in the
+// order they were sent.
+//
+// When a process exits, linked processes get an exit signal with its exit
+// reason and monitoring processes a `{'DOWN', Ref, process, Pid, Reason}`
+// message.  An exit signal kills the receiver, unless the reason is `normal`
+// or the receiver traps exits, in which case it gets an
+// `{'EXIT', From, Reason}` message instead.  `exit(Pid, kill)` kills even
+// processes trapping exits, with reason `killed`.
+
+use atoms::{ AtomIndex, AtomTable };
+use bif::{ self, BifResult };
+use binary::Builder;
+use code::ArgTag;
+use exports::{ CodeIdx, MFA };
+use heap::{ Heap, FULLSWEEP_AFTER };
+use interp::{ Class, HALT, MAX_FLOAT_REGS, MAX_X_REGS };
+use sched::{ Priority, TimerId };
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use term::Term;
+use super::Emu;
+
+pub type Pid = usize;
+
+// A reference, as made by `erlang:make_ref/0`.
+pub type Ref = usize;
+
+pub struct Process {
+    pub pid:            Pid,
+    pub x:              Vec<Term>,
+    // Float registers, unboxed floats for float arithmetic.
+    pub fr:             Vec<f64>,
+    // Frames: continuation pointer followed by the Y registers,
+    // y(0) being on top of the stack.
+    pub stack:          Vec<Term>,
+    pub heap:           Heap,
+    // Instruction pointer.
+    pub ip:             CodeIdx,
+    // Continuation pointer, i.e. the return address.
+    pub cp:             CodeIdx,
+    // Reductions left in the current time slice.
+    pub reductions:     usize,
+    pub mailbox:        VecDeque<Term>,
+    // Selective receive: index of the next message to look at.
+    pub save:           usize,
+    // `recv_mark`: the label of the receive and the mailbox length at the
+    // time, so `recv_set` can skip the messages which were there already.
+    pub recv_mark:      Option<(CodeIdx, usize)>,
+    // Suspended in `wait` or `wait_timeout` until a message arrives.
+    pub waiting:        bool,
+    // The `after` timer of the current receive, if any.
+    pub timer:          Option<TimerId>,
+    // The timer fired: `wait_timeout` goes on to the `timeout` instruction.
+    pub timed_out:      bool,
+    pub priority:       Priority,
+    // The scheduler the process last ran on.
+    pub scheduler:      usize,
+    // The function the process was spawned with.
+    pub initial_call:   MFA,
+    pub links:          HashSet<Pid>,
+    // Monitors of this process on others: reference and monitored process.
+    pub monitors:       Vec<(Ref, Pid)>,
+    // Monitors of others on this process: reference and monitoring process.
+    pub monitored_by:   Vec<(Ref, Pid)>,
+    // Exit signals become `'EXIT'` messages.
+    pub trap_exit:      bool,
+    // Killed by an exit signal; the exit reason, on `heap`.
+    pub exiting:        Option<Term>,
+    // An exception was caught; x(1) .. x(3) hold its class, reason and
+    // stack trace until `catch_end` or `try_case` picks them up.
+    pub caught:         bool,
+    // Class and stack trace of the last exception caught, for
+    // `erlang:get_stacktrace/0` and `raise`.
+    pub stacktrace:     Option<(Class, Term)>,
+    // A binary under construction by `bs_init2` and friends: the bits put
+    // so far, its size in bits and the register it goes to when complete.
+    pub construction:   Option<(Builder, usize, (ArgTag, u32))>
+}
+
+impl Process {
+
+    pub fn new(pid: Pid, initial_call: MFA) -> Process {
+        Process { pid: pid,
+                  x: vec![Term::Nil; MAX_X_REGS],
+                  fr: vec![0.0; MAX_FLOAT_REGS],
+                  stack: vec![],
+                  heap: Heap::new(),
+                  ip: HALT,
+                  cp: HALT,
+                  reductions: 0,
+                  mailbox: VecDeque::new(),
+                  save: 0,
+                  recv_mark: None,
+                  waiting: false,
+                  timer: None,
+                  timed_out: false,
+                  priority: Priority::Normal,
+                  scheduler: 0,
+                  initial_call: initial_call,
+                  links: HashSet::new(),
+                  monitors: vec![],
+                  monitored_by: vec![],
+                  trap_exit: false,
+                  exiting: None,
+                  caught: false,
+                  stacktrace: None,
+                  construction: None }
+    }
+
+    // Garbage collect the heap.  The roots are the first `live` X
+    // registers, `extra` - e.g. the arguments of a BIF about to be called -
+    // the stack, the mailbox, the exit reason and the stack trace.  The
+    // other X registers are cleared, so they never point to garbage.
+    // `need` is the number of words about to be allocated.
+    pub fn garbage_collect(&mut self, need: usize, live: usize, extra: &mut [Term],
+                           major: bool) {
+        let live = live.min(MAX_X_REGS);
+        {
+            let mut gc = self.heap.collect(major);
+            for t in self.x[.. live].iter_mut().chain(extra.iter_mut())
+                                               .chain(self.stack.iter_mut())
+                                               .chain(self.mailbox.iter_mut())
+                { *t = gc.evacuate(*t) }
+            if let Some (ref mut reason) = self.exiting
+                { *reason = gc.evacuate(*reason) }
+            if let Some ((_, ref mut stacktrace)) = self.stacktrace
+                { *stacktrace = gc.evacuate(*stacktrace) }
+            gc.finish(need);
+        }
+        for t in self.x[live ..].iter_mut()
+            { *t = Term::Nil }
+    }
+
+}
+
+// A term on its own heap, waiting to be copied to the receiver's.
+struct Message {
+    heap:   Heap,
+    term:   Term
+}
+
+impl Message {
+
+    fn new(heap: &Heap, term: Term) -> Message {
+        let mut message = Message { heap: Heap::new(), term: Term::Nil };
+        message.term = message.heap.copy_term(heap, term);
+        message
+    }
+
+}
+
+enum Signal {
+    Message(Message),
+    // A `'DOWN'` message for the monitor with the given reference.
+    Down(Ref, Message),
+    // Sender, exit reason and whether it's due to a link, rather than
+    // `exit/2`.
+    Exit(Pid, Message, bool),
+    Link(Pid),
+    Unlink(Pid),
+    // Reference and monitoring process.
+    Monitor(Ref, Pid),
+    Demonitor(Ref),
+    // A major collection, see `erlang:garbage_collect/1`.
+    GarbageCollect
+}
+
+struct Entry {
+    // `None` while the process is running.
+    process:    Option<Process>,
+    signals:    Vec<Signal>,
+    timeouts:   Vec<TimerId>
+}
+
+// Atoms used in exit signals and the messages they turn into.
+struct Atoms {
+    exit:       AtomIndex,
+    down:       AtomIndex,
+    process:    AtomIndex,
+    kill:       AtomIndex,
+    killed:     AtomIndex,
+    normal:     AtomIndex,
+    noproc:     AtomIndex
+}
+
+pub struct ProcessTable {
+    next_pid:   AtomicUsize,
+    next_ref:   AtomicUsize,
+    // `fullsweep_after` of new processes.
+    fullsweep_after: AtomicUsize,
+    entries:    Mutex<HashMap<Pid, Entry>>,
+    atoms:      Atoms
+}
+
+impl ProcessTable {
+
+    pub fn new(atoms: &AtomTable) -> ProcessTable {
+        ProcessTable { next_pid: AtomicUsize::new(0),
+                       next_ref: AtomicUsize::new(0),
+                       fullsweep_after: AtomicUsize::new(FULLSWEEP_AFTER),
+                       entries: Mutex::new(HashMap::new()),
+                       atoms: Atoms { exit: atoms.add("EXIT"),
+                                      down: atoms.add("DOWN"),
+                                      process: atoms.add("process"),
+                                      kill: atoms.add("kill"),
+                                      killed: atoms.add("killed"),
+                                      normal: atoms.add("normal"),
+                                      noproc: atoms.add("noproc") } }
+    }
+
+    // A new process; it's up to the caller to `insert` it into the table.
+    pub fn create(&self, initial_call: MFA) -> Process {
+        let mut process = Process::new(self.next_pid.fetch_add(1, Ordering::SeqCst),
+                                       initial_call);
+        process.heap.fullsweep_after = self.fullsweep_after.load(Ordering::SeqCst);
+        process
+    }
+
+    pub fn make_ref(&self) -> Ref {
+        self.next_ref.fetch_add(1, Ordering::SeqCst)
+    }
+
+    pub fn insert(&self, process: Process) {
+        let pid = process.pid;
+        let entry = Entry { process: Some (process), signals: vec![], timeouts: vec![] };
+        self.entries.lock().unwrap().insert(pid, entry);
+    }
+
+    // Take a process out of the table to run it.
+    pub fn take(&self, pid: Pid) -> Option<Process> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = try_opt!( entries.get_mut(&pid) );
+        let mut process = try_opt!( entry.process.take() );
+        receive(entry, &mut process, &self.atoms);
+        Some (process)
+    }
+
+    // Put a process back after its time slice.  Returns whether it's
+    // runnable, i.e. it isn't waiting or got a message, timeout or exit
+    // signal since it last looked.
+    pub fn put(&self, mut process: Process) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.get_mut(&process.pid) {
+            Some (entry) => entry,
+            None => return false
+        };
+        if process.waiting {
+            receive(entry, &mut process, &self.atoms);
+            process.waiting = !has_news(&process);
+        }
+        let runnable = !process.waiting;
+        entry.process = Some (process);
+        runnable
+    }
+
+    // The running process `p` exited.  Signals it didn't get to see are
+    // applied first, so no link or monitor set up in the meantime is
+    // missed; once it's gone, signals to it are dropped.
+    pub fn remove(&self, p: &mut Process) {
+        let entry = self.entries.lock().unwrap().remove(&p.pid);
+        if let Some (mut entry) = entry
+            { receive(&mut entry, p, &self.atoms) }
+    }
+
+    // Pick up signals and timeouts of the running process `p`.
+    pub fn receive(&self, p: &mut Process) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some (entry) = entries.get_mut(&p.pid)
+            { receive(entry, p, &self.atoms) }
+    }
+
+    // Deliver `signal`.  Returns `None` if there's no such process, or
+    // whether it has to be woken up, with its priority and scheduler.
+    fn signal(&self, to: Pid, signal: Signal) -> Option<Option<(Priority, usize)>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = try_opt!( entries.get_mut(&to) );
+        entry.signals.push(signal);
+        Some (wake(entry, &self.atoms))
+    }
+
+    // The receive timer `id` of `pid` fired.  Returns the priority and
+    // scheduler of the process if it has to be woken up.
+    pub fn timeout(&self, pid: Pid, id: TimerId) -> Option<(Priority, usize)> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = try_opt!( entries.get_mut(&pid) );
+        entry.timeouts.push(id);
+        wake(entry, &self.atoms)
+    }
+
+    pub fn contains(&self, pid: Pid) -> bool {
+        self.entries.lock().unwrap().contains_key(&pid)
+    }
+
+    pub fn pids(&self) -> Vec<Pid> {
+        let mut pids: Vec<Pid> = self.entries.lock().unwrap().keys().cloned().collect();
+        pids.sort();
+        pids
+    }
+
+}
+
+fn receive(entry: &mut Entry, p: &mut Process, atoms: &Atoms) {
+    for signal in entry.signals.drain(..) {
+        match signal {
+            Signal::Message(message) => {
+                let term = p.heap.copy_term(&message.heap, message.term);
+                p.mailbox.push_back(term);
+            },
+            Signal::Down(r, message) => {
+                p.monitors.retain(|&(m, _)| m != r);
+                let term = p.heap.copy_term(&message.heap, message.term);
+                p.mailbox.push_back(term);
+            },
+            // The link is gone if `p` unlinked in the meantime.
+            Signal::Exit(from, _, true) if !p.links.remove(&from) => {},
+            Signal::Exit(from, reason, linked) => {
+                let reason = p.heap.copy_term(&reason.heap, reason.term);
+                exit_signal(p, from, reason, linked, atoms);
+            },
+            Signal::Link(from) => { p.links.insert(from); },
+            Signal::Unlink(from) => { p.links.remove(&from); },
+            Signal::Monitor(r, watcher) => p.monitored_by.push((r, watcher)),
+            Signal::Demonitor(r) => p.monitored_by.retain(|&(m, _)| m != r),
+            // Whatever the process is doing, its X registers are valid.
+            Signal::GarbageCollect => p.garbage_collect(0, MAX_X_REGS, &mut [], true)
+        }
+    }
+    for id in entry.timeouts.drain(..) {
+        if p.timer == Some (id) {
+            p.timer = None;
+            p.timed_out = true;
+        }
+    }
+}
+
+// An exit signal from `from` with `reason`, which lives on the heap of `p`.
+fn exit_signal(p: &mut Process, from: Pid, reason: Term, linked: bool, atoms: &Atoms) {
+    if p.exiting.is_some()
+        { return }
+    if reason == Term::Atom(atoms.kill) && !linked {
+        p.exiting = Some (Term::Atom(atoms.killed));
+    } else if p.trap_exit {
+        let msg = Term::tuple(&mut p.heap, &[Term::Atom(atoms.exit), Term::Pid(from), reason]);
+        p.mailbox.push_back(msg);
+    } else if reason != Term::Atom(atoms.normal) {
+        p.exiting = Some (reason);
+    }
+}
+
+// Whether a waiting process has something new to look at.
+fn has_news(p: &Process) -> bool {
+    p.save < p.mailbox.len() || p.timed_out || p.exiting.is_some()
+}
+
+// A waiting process in the table which got a message, timeout or deadly
+// exit signal becomes runnable.
+fn wake(entry: &mut Entry, atoms: &Atoms) -> Option<(Priority, usize)> {
+    let mut process = try_opt!( entry.process.take() );
+    let woken = if process.waiting {
+        receive(entry, &mut process, atoms);
+        process.waiting = !has_news(&process);
+        if process.waiting { None }
+        else { Some ((process.priority, process.scheduler)) }
+    } else {
+        None
+    };
+    entry.process = Some (process);
+    woken
+}
+
+impl Emu {
+
+    // Spawn a process calling `module:function` with `args`, which live
+    // on `heap`.  A function which isn't exported makes the process fail
+    // with `undef` once it's scheduled.
+    pub fn spawn(&self, module: &str, function: &str,
+                 heap: &Heap, args: &[Term]) -> Pid {
+        let mfa = (self.atoms.add(module), self.atoms.add(function), args.len());
+        let ip = self.exports.get(mfa).unwrap_or(HALT);
+        spawn_mfa(self, mfa, ip, heap, args, 0, None)
+    }
+
+    // The running process `p` exited with `reason`, which lives on its
+    // heap: take it out of the process table and notify its links and
+    // monitors.
+    pub fn exit_process(&self, p: &mut Process, reason: Term) {
+        self.processes.remove(p);
+        if let Some (id) = p.timer.take()
+            { self.scheduler.cancel_timer(id) }
+        for &pid in p.links.iter()
+            { self.signal(pid, Signal::Exit(p.pid, Message::new(&p.heap, reason), true)); }
+        let atoms = &self.processes.atoms;
+        for (r, watcher) in mem::replace(&mut p.monitored_by, vec![]) {
+            let down = Term::tuple(&mut p.heap, &[Term::Atom(atoms.down), Term::Ref(r),
+                                                  Term::Atom(atoms.process), Term::Pid(p.pid),
+                                                  reason]);
+            self.signal(watcher, Signal::Down(r, Message::new(&p.heap, down)));
+        }
+        for &(r, pid) in p.monitors.iter()
+            { self.signal(pid, Signal::Demonitor(r)); }
+    }
+
+    // Send `signal` to `to`, waking it up if need be.  Returns false if
+    // there's no such process (any more).
+    fn signal(&self, to: Pid, signal: Signal) -> bool {
+        match self.processes.signal(to, signal) {
+            Some (Some ((priority, scheduler))) => {
+                self.scheduler.enqueue(to, priority, scheduler);
+                true
+            },
+            Some (None) => true,
+            None => false
+        }
+    }
+
+}
+
+// The new process is queued on `scheduler`, linked to `link` if given.
+fn spawn_mfa(emu: &Emu, mfa: MFA, ip: CodeIdx, heap: &Heap, args: &[Term],
+             scheduler: usize, link: Option<Pid>) -> Pid {
+    let mut process = emu.processes.create(mfa);
+    for (i, &arg) in args.iter().enumerate()
+        { process.x[i] = process.heap.copy_term(heap, arg) }
+    process.ip = ip;
+    process.scheduler = scheduler;
+    process.links.extend(link);
+    let pid = process.pid;
+    emu.processes.insert(process);
+    emu.scheduler.enqueue(pid, Priority::Normal, scheduler);
+    pid
+}
+
+// Send `msg`, which lives on the heap of `p`, to `to`.  Messages to
+// processes which don't exist (any more) are dropped.
+pub fn send(emu: &Emu, p: &mut Process, to: Term, msg: Term) -> BifResult {
+    let pid = match to {
+        Term::Pid(pid) => pid,
+        _ => return Err (bif::Error::Badarg)
+    };
+    if pid == p.pid {
+        p.mailbox.push_back(msg);
+    } else {
+        emu.signal(pid, Signal::Message(Message::new(&p.heap, msg)));
+    }
+    Ok (msg)
+}
+
+// erlang:send/2, erlang:'!'/2
+pub fn send_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    send(emu, p, args[0], args[1])
+}
+
+fn spawn_fun(emu: &Emu, p: &mut Process, fun: Term, link: bool) -> BifResult {
+    let (index, free) = match fun.fun_parts(&p.heap) {
+        Some ((index, free)) if emu.funs[index].mfa.2 == free.len() => (index, free),
+        _ => return Err (bif::Error::Badarg)
+    };
+    let entry = emu.funs[index];
+    let parent = if link { Some (p.pid) } else { None };
+    let pid = spawn_mfa(emu, entry.mfa, entry.code, &p.heap, free, p.scheduler, parent);
+    if link
+        { p.links.insert(pid); }
+    Ok (Term::Pid(pid))
+}
+
+fn spawn_call(emu: &Emu, p: &mut Process, args: &[Term], link: bool) -> BifResult {
+    match (args[0], args[1], args[2].list_elements(&p.heap)) {
+        (Term::Atom(m), Term::Atom(f), Some (call_args)) => {
+            let mfa = (m, f, call_args.len());
+            let ip = emu.exports.get(mfa).unwrap_or(HALT);
+            let parent = if link { Some (p.pid) } else { None };
+            let pid = spawn_mfa(emu, mfa, ip, &p.heap, &call_args, p.scheduler, parent);
+            if link
+                { p.links.insert(pid); }
+            Ok (Term::Pid(pid))
+        },
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:spawn/1
+pub fn spawn_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    spawn_fun(emu, p, args[0], false)
+}
+
+// erlang:spawn/3
+pub fn spawn_3(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    spawn_call(emu, p, args, false)
+}
+
+// erlang:spawn_link/1
+pub fn spawn_link_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    spawn_fun(emu, p, args[0], true)
+}
+
+// erlang:spawn_link/3
+pub fn spawn_link_3(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    spawn_call(emu, p, args, true)
+}
+
+// erlang:self/0
+pub fn self_0(_: &Emu, p: &mut Process, _: &[Term]) -> BifResult {
+    Ok (Term::Pid(p.pid))
+}
+
+// erlang:make_ref/0
+pub fn make_ref_0(emu: &Emu, _: &mut Process, _: &[Term]) -> BifResult {
+    Ok (Term::Ref(emu.processes.make_ref()))
+}
+
+// erlang:process_flag/2; the `priority` and `trap_exit` flags.
+pub fn process_flag_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let name = |t: Term| match t {
+        Term::Atom(a) => emu.atoms.get_atom(a),
+        _ => None
+    };
+    match (name(args[0]), name(args[1])) {
+        (Some (ref flag), Some (ref level)) if flag == "priority" => {
+            let priority = try!( Priority::from_name(level).ok_or(bif::Error::Badarg) );
+            let old = p.priority;
+            p.priority = priority;
+            Ok (Term::Atom(emu.atoms.add(old.name())))
+        },
+        (Some (ref flag), Some (ref value)) if flag == "trap_exit" &&
+                                               (value == "true" || value == "false") => {
+            let old = p.trap_exit;
+            p.trap_exit = value == "true";
+            Ok (bif::boolean(emu, old))
+        },
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:garbage_collect/0
+pub fn garbage_collect_0(emu: &Emu, p: &mut Process, _: &[Term]) -> BifResult {
+    p.garbage_collect(0, MAX_X_REGS, &mut [], true);
+    Ok (bif::boolean(emu, true))
+}
+
+// erlang:garbage_collect/1; false if there's no such process.  Another
+// process is collected when it gets the request.
+pub fn garbage_collect_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let pid = try!( pid_arg(args[0]) );
+    if pid == p.pid
+        { return garbage_collect_0(emu, p, args) }
+    Ok (bif::boolean(emu, emu.signal(pid, Signal::GarbageCollect)))
+}
+
+// erlang:system_flag/2; the `fullsweep_after` flag, for processes spawned
+// from now on.
+pub fn system_flag_2(emu: &Emu, _: &mut Process, args: &[Term]) -> BifResult {
+    match (args[0], args[1]) {
+        (Term::Atom(flag), Term::Small(n))
+                if emu.atoms.get_atom(flag).as_ref().map(|f| &f[..]) == Some ("fullsweep_after") &&
+                   n >= 0 => {
+            let old = emu.processes.fullsweep_after.swap(n as usize, Ordering::SeqCst);
+            Ok (Term::Small(old as i64))
+        },
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:exit/1
+pub fn exit_1(_: &Emu, _: &mut Process, args: &[Term]) -> BifResult {
+    Err (bif::Error::Exit(args[0]))
+}
+
+// erlang:exit/2
+pub fn exit_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let (to, reason) = match args[0] {
+        Term::Pid(pid) => (pid, args[1]),
+        _ => return Err (bif::Error::Badarg)
+    };
+    let atoms = &emu.processes.atoms;
+    if to != p.pid {
+        emu.signal(to, Signal::Exit(p.pid, Message::new(&p.heap, reason), false));
+    } else if reason == Term::Atom(atoms.kill) {
+        return Err (bif::Error::Exit(Term::Atom(atoms.killed)))
+    } else if p.trap_exit {
+        exit_signal(p, to, reason, false, atoms);
+    } else {
+        // Unlike an exit signal from another process, this one kills
+        // the caller even with reason `normal`.
+        return Err (bif::Error::Exit(reason))
+    }
+    Ok (bif::boolean(emu, true))
+}
+
+fn pid_arg(t: Term) -> Result<Pid, bif::Error> {
+    match t {
+        Term::Pid(pid) => Ok (pid),
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:link/1
+pub fn link_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let pid = try!( pid_arg(args[0]) );
+    if pid != p.pid && !p.links.contains(&pid) {
+        if emu.signal(pid, Signal::Link(p.pid)) {
+            p.links.insert(pid);
+        } else {
+            // Like a link to a process which exits right away.
+            let noproc = Term::Atom(emu.processes.atoms.noproc);
+            if !p.trap_exit
+                { return Err (bif::Error::Exit(noproc)) }
+            exit_signal(p, pid, noproc, true, &emu.processes.atoms);
+        }
+    }
+    Ok (bif::boolean(emu, true))
+}
+
+// erlang:unlink/1
+pub fn unlink_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let pid = try!( pid_arg(args[0]) );
+    if p.links.remove(&pid)
+        { emu.signal(pid, Signal::Unlink(p.pid)); }
+    Ok (bif::boolean(emu, true))
+}
+
+// erlang:monitor/2; only processes can be monitored.
+pub fn monitor_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let atoms = &emu.processes.atoms;
+    if args[0] != Term::Atom(atoms.process)
+        { return Err (bif::Error::Badarg) }
+    let pid = try!( pid_arg(args[1]) );
+    let r = emu.processes.make_ref();
+    if emu.signal(pid, Signal::Monitor(r, p.pid)) {
+        p.monitors.push((r, pid));
+    } else {
+        let down = Term::tuple(&mut p.heap, &[Term::Atom(atoms.down), Term::Ref(r),
+                                              Term::Atom(atoms.process), args[1],
+                                              Term::Atom(atoms.noproc)]);
+        p.mailbox.push_back(down);
+    }
+    Ok (Term::Ref(r))
+}
+
+// erlang:demonitor/1
+pub fn demonitor_1(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    demonitor(emu, p, args[0], false, false)
+}
+
+// erlang:demonitor/2, with options `flush` (remove the `'DOWN'` message,
+// if already received) and `info` (return whether the monitor was still
+// there).
+pub fn demonitor_2(emu: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let options = try!( args[1].list_elements(&p.heap).ok_or(bif::Error::Badarg) );
+    let (mut flush, mut info) = (false, false);
+    for option in options.iter() {
+        match *option {
+            Term::Atom(a) if emu.atoms.get_atom(a).as_ref().map(|a| &a[..]) == Some ("flush") =>
+                flush = true,
+            Term::Atom(a) if emu.atoms.get_atom(a).as_ref().map(|a| &a[..]) == Some ("info") =>
+                info = true,
+            _ => return Err (bif::Error::Badarg)
+        }
+    }
+    demonitor(emu, p, args[0], flush, info)
+}
+
+fn demonitor(emu: &Emu, p: &mut Process, r: Term, flush: bool, info: bool) -> BifResult {
+    let r = match r {
+        Term::Ref(r) => r,
+        _ => return Err (bif::Error::Badarg)
+    };
+    let found = match p.monitors.iter().position(|&(m, _)| m == r) {
+        Some (i) => {
+            let (_, pid) = p.monitors.remove(i);
+            emu.signal(pid, Signal::Demonitor(r));
+            true
+        },
+        None => false
+    };
+    if flush {
+        emu.processes.receive(p);
+        let down = Term::Atom(emu.processes.atoms.down);
+        let heap = &p.heap;
+        p.mailbox.retain(|msg| match msg.tuple_elements(heap) {
+            Some (elements) => elements.len() != 5 || elements[0] != down ||
+                               elements[1] != Term::Ref(r),
+            None => true
+        });
+        p.save = p.save.min(p.mailbox.len());
+    }
+    Ok (bif::boolean(emu, found || !info))
+}
+
+#[test]
+fn test_spawn() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("procs");
+    // child(X) -> X.
+    let child = m.function("child", 1);
+    m.op("return", vec![]);
+    m.export("child", 1, child);
+    // by_mfa() -> spawn(procs, child, [7]).
+    let by_mfa = m.function("by_mfa", 0);
+    m.op("move", vec![Arg::A("procs"), Arg::X(0)]);
+    m.op("move", vec![Arg::A("child"), Arg::X(1)]);
+    m.op("move", vec![Arg::Lit(etf::Term::List(vec![etf::Term::Integer(7)], Box::new(etf::Term::Nil))), Arg::X(2)]);
+    let spawn_3 = m.import("erlang", "spawn", 3);
+    m.op("call_ext_only", vec![Arg::U(3), Arg::U(spawn_3)]);
+    m.export("by_mfa", 0, by_mfa);
+    // by_fun(X) -> spawn(fun() -> X end).
+    let body = m.function("-by_fun/1-fun-0-", 1);
+    m.op("return", vec![]);
+    let fun = m.fun("-by_fun/1-fun-0-", 0, body, 1);
+    let by_fun = m.function("by_fun", 1);
+    m.op("make_fun2", vec![Arg::U(fun)]);
+    let spawn_1 = m.import("erlang", "spawn", 1);
+    m.op("call_ext_only", vec![Arg::U(1), Arg::U(spawn_1)]);
+    m.export("by_fun", 1, by_fun);
+    // me() -> self().
+    let me = m.function("me", 0);
+    let self_0 = m.import("erlang", "self", 0);
+    m.op("bif0", vec![Arg::U(self_0), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("me", 0, me);
+    let mut emu = Emu::new();
+    m.load(&mut emu).unwrap();
+    // Pids are handed out in order: the caller gets 0, the child 1.
+    assert_eq!("<0.1.0>", emu.call("procs", "by_mfa", &[]).unwrap().to_string());
+    assert_eq!("<0.3.0>", emu.call("procs", "by_fun", &[etf::Term::Integer(1)]).unwrap().to_string());
+    assert_eq!("<0.4.0>", emu.call("procs", "me", &[]).unwrap().to_string());
+    // The children ran too, and are gone.
+    assert!(emu.processes.pids().is_empty());
+}
+
+#[test]
+fn test_receive() {
+    use asm::{ Arg, Module };
+    use etf;
+    use std::time::{ Duration, Instant };
+    let mut m = Module::new("pingpong");
+    let spawn_3 = m.import("erlang", "spawn", 3);
+    let self_0 = m.import("erlang", "self", 0);
+    let plus = m.import("erlang", "+", 2);
+    // server() -> receive {From, N} -> From ! {self(), N + 1}, server() end.
+    let server = m.function("server", 0);
+    let (wait, no_match) = (m.new_label(), m.new_label());
+    m.op("loop_rec", vec![Arg::F(wait), Arg::X(0)]);
+    m.op("test_arity", vec![Arg::F(no_match), Arg::X(0), Arg::U(2)]);
+    m.op("get_tuple_element", vec![Arg::X(0), Arg::U(0), Arg::X(1)]);
+    m.op("get_tuple_element", vec![Arg::X(0), Arg::U(1), Arg::X(2)]);
+    m.op("remove_message", vec![]);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(3), Arg::U(plus), Arg::X(2), Arg::I(1), Arg::X(2)]);
+    m.op("bif0", vec![Arg::U(self_0), Arg::X(3)]);
+    m.op("put_tuple", vec![Arg::U(2), Arg::X(4)]);
+    m.op("put", vec![Arg::X(3)]);
+    m.op("put", vec![Arg::X(2)]);
+    m.op("move", vec![Arg::X(1), Arg::X(0)]);
+    m.op("move", vec![Arg::X(4), Arg::X(1)]);
+    m.op("send", vec![]);
+    m.op("call_only", vec![Arg::U(0), Arg::F(server)]);
+    m.place(no_match);
+    m.op("loop_rec_end", vec![Arg::F(server)]);
+    m.place(wait);
+    m.op("wait", vec![Arg::F(server)]);
+    m.export("server", 0, server);
+    // client(N) ->
+    //     S = spawn(pingpong, server, []),
+    //     self() ! junk,
+    //     S ! {self(), N},
+    //     receive {S, R} -> R end.
+    let client = m.function("client", 1);
+    m.op("allocate", vec![Arg::U(2), Arg::U(1)]);
+    m.op("move", vec![Arg::X(0), Arg::Y(0)]);
+    m.op("move", vec![Arg::A("pingpong"), Arg::X(0)]);
+    m.op("move", vec![Arg::A("server"), Arg::X(1)]);
+    m.op("move", vec![Arg::Nil, Arg::X(2)]);
+    m.op("call_ext", vec![Arg::U(3), Arg::U(spawn_3)]);
+    m.op("move", vec![Arg::X(0), Arg::Y(1)]);
+    m.op("bif0", vec![Arg::U(self_0), Arg::X(0)]);
+    m.op("move", vec![Arg::A("junk"), Arg::X(1)]);
+    m.op("send", vec![]);
+    m.op("bif0", vec![Arg::U(self_0), Arg::X(2)]);
+    m.op("put_tuple", vec![Arg::U(2), Arg::X(1)]);
+    m.op("put", vec![Arg::X(2)]);
+    m.op("put", vec![Arg::Y(0)]);
+    m.op("move", vec![Arg::Y(1), Arg::X(0)]);
+    m.op("send", vec![]);
+    let (receive, wait, no_match) = (m.label(), m.new_label(), m.new_label());
+    m.op("loop_rec", vec![Arg::F(wait), Arg::X(0)]);
+    m.op("test_arity", vec![Arg::F(no_match), Arg::X(0), Arg::U(2)]);
+    m.op("get_tuple_element", vec![Arg::X(0), Arg::U(0), Arg::X(1)]);
+    m.op("is_eq_exact", vec![Arg::F(no_match), Arg::X(1), Arg::Y(1)]);
+    m.op("get_tuple_element", vec![Arg::X(0), Arg::U(1), Arg::X(0)]);
+    m.op("remove_message", vec![]);
+    m.op("deallocate", vec![Arg::U(2)]);
+    m.op("return", vec![]);
+    m.place(no_match);
+    m.op("loop_rec_end", vec![Arg::F(receive)]);
+    m.place(wait);
+    m.op("wait", vec![Arg::F(receive)]);
+    m.export("client", 1, client);
+    // sleep(T) -> receive _ -> got after T -> timeout end.
+    let sleep = m.function("sleep", 1);
+    let wait = m.new_label();
+    m.op("loop_rec", vec![Arg::F(wait), Arg::X(1)]);
+    m.op("remove_message", vec![]);
+    m.op("move", vec![Arg::A("got"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(wait);
+    m.op("wait_timeout", vec![Arg::F(sleep), Arg::X(0)]);
+    m.op("timeout", vec![]);
+    m.op("move", vec![Arg::A("timeout"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("sleep", 1, sleep);
+    // marked() ->
+    //     self() ! old, recv_mark, self() ! new, recv_set,
+    //     receive M -> M end.
+    let marked = m.function("marked", 0);
+    let (receive, wait) = (m.new_label(), m.new_label());
+    m.op("bif0", vec![Arg::U(self_0), Arg::X(0)]);
+    m.op("move", vec![Arg::A("old"), Arg::X(1)]);
+    m.op("send", vec![]);
+    m.op("recv_mark", vec![Arg::F(receive)]);
+    m.op("bif0", vec![Arg::U(self_0), Arg::X(0)]);
+    m.op("move", vec![Arg::A("new"), Arg::X(1)]);
+    m.op("send", vec![]);
+    m.op("recv_set", vec![Arg::F(receive)]);
+    m.place(receive);
+    m.op("loop_rec", vec![Arg::F(wait), Arg::X(0)]);
+    m.op("remove_message", vec![]);
+    m.op("return", vec![]);
+    m.place(wait);
+    m.op("wait", vec![Arg::F(receive)]);
+    m.export("marked", 0, marked);
+    // pinged(T) -> S = spawn(pingpong, server, []), S ! {self(), 1}, sleep(T).
+    let pinged = m.function("pinged", 1);
+    m.op("allocate", vec![Arg::U(1), Arg::U(1)]);
+    m.op("move", vec![Arg::X(0), Arg::Y(0)]);
+    m.op("move", vec![Arg::A("pingpong"), Arg::X(0)]);
+    m.op("move", vec![Arg::A("server"), Arg::X(1)]);
+    m.op("move", vec![Arg::Nil, Arg::X(2)]);
+    m.op("call_ext", vec![Arg::U(3), Arg::U(spawn_3)]);
+    m.op("bif0", vec![Arg::U(self_0), Arg::X(2)]);
+    m.op("put_tuple", vec![Arg::U(2), Arg::X(1)]);
+    m.op("put", vec![Arg::X(2)]);
+    m.op("put", vec![Arg::I(1)]);
+    m.op("send", vec![]);
+    m.op("move", vec![Arg::Y(0), Arg::X(0)]);
+    m.op("call_last", vec![Arg::U(1), Arg::F(sleep), Arg::U(1)]);
+    m.export("pinged", 1, pinged);
+    let mut emu = Emu::new();
+    m.load(&mut emu).unwrap();
+    let atom = |name: &str| etf::Term::Atom(name.to_string());
+    assert_eq!(Ok (etf::Term::Integer(42)), emu.call("pingpong", "client", &[etf::Term::Integer(41)]));
+    let start = Instant::now();
+    assert_eq!(Ok (atom("timeout")), emu.call("pingpong", "sleep", &[etf::Term::Integer(20)]));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(Ok (atom("timeout")), emu.call("pingpong", "sleep", &[etf::Term::Integer(0)]));
+    assert_eq!(Err (atom("blocked")), emu.call("pingpong", "sleep", &[atom("infinity")]));
+    assert_eq!(Err (atom("timeout_value")), emu.call("pingpong", "sleep", &[atom("never")]));
+    assert_eq!(Ok (atom("new")), emu.call("pingpong", "marked", &[]));
+    // Once the reply is in, the timer is gone and doesn't hold up `run`.
+    let heap = ::heap::Heap::new();
+    let start = Instant::now();
+    emu.spawn("pingpong", "pinged", &heap, &[::term::Term::Small(60000)]);
+    emu.run();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    // The same with the client and server on different schedulers.
+    emu.set_schedulers(4);
+    for n in 0 .. 20
+        { assert_eq!(Ok (etf::Term::Integer(n + 1)), emu.call("pingpong", "client", &[etf::Term::Integer(n)])) }
+    assert_eq!(Ok (atom("timeout")), emu.call("pingpong", "sleep", &[etf::Term::Integer(5)]));
+    // And in deterministic mode, where time is virtual.
+    emu.set_chooser(::replay::Chooser::from_seed(1));
+    assert_eq!(Ok (etf::Term::Integer(2)), emu.call("pingpong", "client", &[etf::Term::Integer(1)]));
+    let start = emu.scheduler.now();
+    emu.spawn("pingpong", "pinged", &heap, &[::term::Term::Small(60000)]);
+    emu.run();
+    assert!(emu.scheduler.now() < start + 60000 * 1000);
+    assert_eq!(Ok (atom("timeout")), emu.call("pingpong", "sleep", &[etf::Term::Integer(60000)]));
+}
+
+#[test]
+fn test_links_and_monitors() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("sup");
+    let exit_1 = m.import("erlang", "exit", 1);
+    let exit_2 = m.import("erlang", "exit", 2);
+    let spawn_3 = m.import("erlang", "spawn", 3);
+    let spawn_link_3 = m.import("erlang", "spawn_link", 3);
+    let link_1 = m.import("erlang", "link", 1);
+    let unlink_1 = m.import("erlang", "unlink", 1);
+    let monitor_2 = m.import("erlang", "monitor", 2);
+    let demonitor_2 = m.import("erlang", "demonitor", 2);
+    let process_flag_2 = m.import("erlang", "process_flag", 2);
+    let spawn = |m: &mut Module, bif: u32, function: &'static str| {
+        m.op("move", vec![Arg::A("sup"), Arg::X(0)]);
+        m.op("move", vec![Arg::A(function), Arg::X(1)]);
+        m.op("move", vec![Arg::Nil, Arg::X(2)]);
+        m.op("call_ext", vec![Arg::U(3), Arg::U(bif)]);
+    };
+    // crash() -> exit(boom).
+    let crash = m.function("crash", 0);
+    m.op("move", vec![Arg::A("boom"), Arg::X(0)]);
+    m.op("call_ext_only", vec![Arg::U(1), Arg::U(exit_1)]);
+    m.export("crash", 0, crash);
+    // quick() -> ok.
+    let quick = m.function("quick", 0);
+    m.op("move", vec![Arg::A("ok"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.export("quick", 0, quick);
+    // forever() -> receive _ -> forever() end.
+    let forever = m.function("forever", 0);
+    let wait = m.new_label();
+    m.op("loop_rec", vec![Arg::F(wait), Arg::X(0)]);
+    m.op("remove_message", vec![]);
+    m.op("call_only", vec![Arg::U(0), Arg::F(forever)]);
+    m.place(wait);
+    m.op("wait", vec![Arg::F(forever)]);
+    m.export("forever", 0, forever);
+    // recv() -> receive M -> M after 100 -> quiet end.
+    let recv = m.function("recv", 0);
+    let wait = m.new_label();
+    m.op("loop_rec", vec![Arg::F(wait), Arg::X(0)]);
+    m.op("remove_message", vec![]);
+    m.op("return", vec![]);
+    m.place(wait);
+    m.op("wait_timeout", vec![Arg::F(recv), Arg::I(100)]);
+    m.op("timeout", vec![]);
+    m.op("move", vec![Arg::A("quiet"), Arg::X(0)]);
+    m.op("return", vec![]);
+    // trapper() ->
+    //     process_flag(trap_exit, true), spawn_link(sup, crash, []), recv().
+    let trapper = m.function("trapper", 0);
+    m.op("allocate", vec![Arg::U(0), Arg::U(0)]);
+    m.op("move", vec![Arg::A("trap_exit"), Arg::X(0)]);
+    m.op("move", vec![Arg::A("true"), Arg::X(1)]);
+    m.op("call_ext", vec![Arg::U(2), Arg::U(process_flag_2)]);
+    spawn(&mut m, spawn_link_3, "crash");
+    m.op("call_last", vec![Arg::U(0), Arg::F(recv), Arg::U(0)]);
+    m.export("trapper", 0, trapper);
+    // doomed() -> spawn_link(sup, crash, []), forever().
+    let doomed = m.function("doomed", 0);
+    m.op("allocate", vec![Arg::U(0), Arg::U(0)]);
+    spawn(&mut m, spawn_link_3, "crash");
+    m.op("call_last", vec![Arg::U(0), Arg::F(forever), Arg::U(0)]);
+    m.export("doomed", 0, doomed);
+    // survivor() -> spawn_link(sup, quick, []), recv().
+    let survivor = m.function("survivor", 0);
+    m.op("allocate", vec![Arg::U(0), Arg::U(0)]);
+    spawn(&mut m, spawn_link_3, "quick");
+    m.op("call_last", vec![Arg::U(0), Arg::F(recv), Arg::U(0)]);
+    m.export("survivor", 0, survivor);
+    // unlinked() -> P = spawn(sup, crash, []), link(P), unlink(P), recv().
+    let unlinked = m.function("unlinked", 0);
+    m.op("allocate", vec![Arg::U(1), Arg::U(0)]);
+    spawn(&mut m, spawn_3, "crash");
+    m.op("move", vec![Arg::X(0), Arg::Y(0)]);
+    m.op("call_ext", vec![Arg::U(1), Arg::U(link_1)]);
+    m.op("move", vec![Arg::Y(0), Arg::X(0)]);
+    m.op("call_ext", vec![Arg::U(1), Arg::U(unlink_1)]);
+    m.op("call_last", vec![Arg::U(0), Arg::F(recv), Arg::U(1)]);
+    m.export("unlinked", 0, unlinked);
+    // unwatched() ->
+    //     R = monitor(process, spawn(sup, crash, [])),
+    //     demonitor(R, [flush, info]), recv().
+    let unwatched = m.function("unwatched", 0);
+    m.op("allocate", vec![Arg::U(0), Arg::U(0)]);
+    spawn(&mut m, spawn_3, "crash");
+    m.op("move", vec![Arg::X(0), Arg::X(1)]);
+    m.op("move", vec![Arg::A("process"), Arg::X(0)]);
+    m.op("call_ext", vec![Arg::U(2), Arg::U(monitor_2)]);
+    let options = etf::Term::List(vec![etf::Term::Atom("flush".to_string()),
+                                       etf::Term::Atom("info".to_string())],
+                                  Box::new(etf::Term::Nil));
+    m.op("move", vec![Arg::Lit(options), Arg::X(1)]);
+    m.op("call_ext", vec![Arg::U(2), Arg::U(demonitor_2)]);
+    m.op("call_last", vec![Arg::U(0), Arg::F(recv), Arg::U(0)]);
+    m.export("unwatched", 0, unwatched);
+    // watch(F, How) ->
+    //     P = spawn(sup, F, []), monitor(process, P),
+    //     exit(P, normal), exit(P, How), recv().
+    let watch = m.function("watch", 2);
+    m.op("allocate", vec![Arg::U(2), Arg::U(2)]);
+    m.op("move", vec![Arg::X(1), Arg::Y(1)]);
+    m.op("move", vec![Arg::X(0), Arg::X(1)]);
+    m.op("move", vec![Arg::A("sup"), Arg::X(0)]);
+    m.op("move", vec![Arg::Nil, Arg::X(2)]);
+    m.op("call_ext", vec![Arg::U(3), Arg::U(spawn_3)]);
+    m.op("move", vec![Arg::X(0), Arg::Y(0)]);
+    m.op("move", vec![Arg::X(0), Arg::X(1)]);
+    m.op("move", vec![Arg::A("process"), Arg::X(0)]);
+    m.op("call_ext", vec![Arg::U(2), Arg::U(monitor_2)]);
+    m.op("move", vec![Arg::Y(0), Arg::X(0)]);
+    m.op("move", vec![Arg::A("normal"), Arg::X(1)]);
+    m.op("call_ext", vec![Arg::U(2), Arg::U(exit_2)]);
+    m.op("move", vec![Arg::Y(0), Arg::X(0)]);
+    m.op("move", vec![Arg::Y(1), Arg::X(1)]);
+    m.op("call_ext", vec![Arg::U(2), Arg::U(exit_2)]);
+    m.op("call_last", vec![Arg::U(0), Arg::F(recv), Arg::U(2)]);
+    m.export("watch", 2, watch);
+    let mut emu = Emu::new();
+    m.load(&mut emu).unwrap();
+    let atom = |name: &str| etf::Term::Atom(name.to_string());
+    // The last element of an `'EXIT'` or `'DOWN'` message.
+    let reason = |emu: &Emu, function: &str, args: &[etf::Term]| {
+        match emu.call("sup", function, args) {
+            Ok (etf::Term::Tuple(elements)) => {
+                assert!(elements[0] == atom("EXIT") || elements[0] == atom("DOWN"));
+                elements.last().unwrap().to_string()
+            },
+            other => panic!("not an exit message: {:?}", other)
+        }
+    };
+    for &schedulers in [1, 4].iter() {
+        emu.set_schedulers(schedulers);
+        assert_eq!("boom", reason(&emu, "trapper", &[]));
+        assert_eq!(Err (atom("boom")), emu.call("sup", "doomed", &[]));
+        assert_eq!(Ok (atom("quiet")), emu.call("sup", "survivor", &[]));
+        assert_eq!(Ok (atom("quiet")), emu.call("sup", "unlinked", &[]));
+        // `normal` doesn't kill, `kill` can't be trapped.
+        assert_eq!("killed", reason(&emu, "watch", &[atom("forever"), atom("kill")]));
+        assert_eq!("shutdown", reason(&emu, "watch", &[atom("forever"), atom("shutdown")]));
+        assert!(emu.processes.pids().is_empty());
+    }
+    // With one scheduler, children don't run before their parent waits.
+    emu.set_schedulers(1);
+    assert_eq!(Ok (atom("quiet")), emu.call("sup", "unwatched", &[]));
+    assert_eq!("boom", reason(&emu, "watch", &[atom("crash"), atom("normal")]));
+    let down = emu.call("sup", "watch", &[atom("quick"), atom("normal")]).unwrap().to_string();
+    assert!(down.starts_with("{'DOWN',#Ref<0.0.0.") && down.ends_with(".0>,normal}"),
+            "{}", down);
+}
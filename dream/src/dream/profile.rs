@@ -0,0 +1,143 @@
+// Opcode profiling.
+//
+// While `Emu::opcode_profile` is set, the interpreter counts the pairs and
+// triples of instructions it executes one right after the other in the
+// code, i.e. the sequences which could be fused into superinstructions
+// (see `transform`).  A jump, or a `label` or `line` instruction, starts a
+// new sequence.
+//
+// Specialised instructions are counted as the generic ones they stand for
+// (see `transform::generic`), so that the report names sequences the way
+// `transform::SUPERINSTRUCTIONS` does whatever is fused already, and its
+// names can be passed to `Emu::superinstructions` as they are.
+
+use code::BEAMOpcode;
+use exports::CodeIdx;
+use std::collections::HashMap;
+use transform;
+
+pub struct Profile {
+    pairs:      HashMap<(u8, u8), u64>,
+    triples:    HashMap<(u8, u8, u8), u64>
+}
+
+// The last instructions executed in sequence, and where the sequence
+// goes on.
+pub struct Sequence {
+    next:       CodeIdx,
+    last:       Vec<u8>
+}
+
+impl Sequence {
+    pub fn new() -> Sequence {
+        Sequence { next: 0, last: vec![] }
+    }
+}
+
+impl Profile {
+
+    pub fn new() -> Profile {
+        Profile { pairs: HashMap::new(), triples: HashMap::new() }
+    }
+
+    // Count the instruction at `ip`, whose next instruction is at `next`.
+    pub fn count(&mut self, seq: &mut Sequence, ip: CodeIdx, opcode: BEAMOpcode, next: CodeIdx) {
+        if ip != seq.next
+            { seq.last.clear() }
+        seq.next = next;
+        match transform::generic(opcode) {
+            &[] => self.count_generic(seq, opcode),
+            generic => for &opcode in generic
+                { self.count_generic(seq, opcode) }
+        }
+    }
+
+    fn count_generic(&mut self, seq: &mut Sequence, opcode: BEAMOpcode) {
+        match opcode {
+            BEAMOpcode::label | BEAMOpcode::line => {
+                seq.last.clear();
+                return
+            },
+            _ => {}
+        }
+        let op = opcode as u8;
+        if let Some (&b) = seq.last.last()
+            { *self.pairs.entry((b, op)).or_insert(0) += 1 }
+        if seq.last.len() == 2
+            { *self.triples.entry((seq.last[0], seq.last[1], op)).or_insert(0) += 1 }
+        if seq.last.len() == 2
+            { seq.last.remove(0); }
+        seq.last.push(op);
+    }
+
+    pub fn merge(&mut self, other: Profile) {
+        for (pair, n) in other.pairs
+            { *self.pairs.entry(pair).or_insert(0) += n }
+        for (triple, n) in other.triples
+            { *self.triples.entry(triple).or_insert(0) += n }
+    }
+
+    // The `limit` most frequent pairs and triples, named like
+    // `transform::SUPERINSTRUCTIONS`, e.g. `move+move`.
+    pub fn report(&self, limit: usize) -> String {
+        let pairs = self.pairs.iter().map(|(&(a, b), &n)| (n, name(&[a, b])));
+        let triples = self.triples.iter().map(|(&(a, b, c), &n)| (n, name(&[a, b, c])));
+        let mut s = String::new();
+        s.push_str("opcode pairs:\n");
+        s.push_str(&format_counts(pairs.collect(), limit));
+        s.push_str("opcode triples:\n");
+        s.push_str(&format_counts(triples.collect(), limit));
+        s
+    }
+
+}
+
+fn name(opcodes: &[u8]) -> String {
+    let names: Vec<&str> = opcodes.iter()
+                                  .map(|&op| BEAMOpcode::from_code(op).map_or("(invalid opcode)", |op| op.name()))
+                                  .collect();
+    names.join("+")
+}
+
+// Most frequent first.
+fn format_counts(mut counts: Vec<(u64, String)>, limit: usize) -> String {
+    counts.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let mut s = String::new();
+    for &(n, ref name) in counts.iter().take(limit) {
+        // Those which can be fused, see `Emu::superinstructions`.
+        let fusable = transform::SUPERINSTRUCTIONS.iter().any(|&(s, _)| s == &name[..]);
+        s.push_str(&format!("{:12} {}{}\n", n, name, if fusable { " (superinstruction)" } else { "" }))
+    }
+    s
+}
+
+#[test]
+fn test_opcode_profile() {
+    use etf;
+    use std::sync::Mutex;
+    use super::Emu;
+    // The same counts whether superinstructions are fused or not.
+    for fused in vec![vec![], transform::superinstructions()] {
+        let mut emu = Emu::new();
+        emu.superinstructions = fused;
+        emu.load_module(&::erlang_dir().join("fac.beam")).unwrap();
+        emu.opcode_profile = Some (Mutex::new(Profile::new()));
+        assert_eq!(Ok (etf::Term::Integer(3628800)), emu.call("fac", "fac", &[etf::Term::Integer(10)]));
+        let report = emu.opcode_profile.take().unwrap().into_inner().unwrap().report(100);
+        let count = |name: &str| report.lines()
+                                       .find(|line| line.split_whitespace().nth(1) == Some (name))
+                                       .and_then(|line| line.split_whitespace().next())
+                                       .map(|n| n.parse::<u64>().unwrap());
+        // Ten steps of fac/2, then its final clause.
+        assert_eq!(Some (11), count("is_tuple+test_arity+get_tuple_element"));
+        assert_eq!(Some (10), count("move+move"));
+        // Not in sequence: there's a `line` in between.
+        assert_eq!(None, count("gc_bif2+gc_bif2"));
+        assert!(report.contains(" move+move (superinstruction)\n"));
+        assert!(report.starts_with("opcode pairs:\n"));
+    }
+    // Reported sequences can be fused as they're named.
+    let mut emu = Emu::new();
+    emu.superinstructions = vec!["is_tuple+test_arity+get_tuple_element".to_string(), "move+move".to_string()];
+    emu.load_module(&::erlang_dir().join("fac.beam")).unwrap();
+}
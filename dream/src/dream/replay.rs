@@ -0,0 +1,135 @@
+// Deterministic scheduling.
+//
+// In deterministic mode there's a single scheduler, time is virtual and
+// every scheduling decision - which process runs next, how long its time
+// slice is, whether a due timer fires before it - is a choice made by a
+// `Chooser`.  Choices come from a PRNG seeded by the user, so a run with a
+// given seed always interleaves processes the same way.
+//
+// The choices of a run can be recorded and replayed later, e.g. to debug
+// an interleaving found by trying seeds.  A recording is a text file with
+// one choice per line.  A replay which doesn't match the run, as it asks
+// for a choice the recording doesn't have or can't make, stops with a
+// `Divergence` rather than going on with other decisions.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, Read, Write };
+use std::path::Path;
+
+// SplitMix64; small, fast and good enough to shuffle processes around.
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+}
+
+pub struct Chooser {
+    // Choices come from the PRNG, or from a recording to replay.
+    rng:        Option<Rng>,
+    replay:     VecDeque<u64>,
+    recorded:   Vec<u64>
+}
+
+// How a replay differs from the recorded run, at the given choice (counting
+// from 0).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Divergence {
+    // The recorded choice isn't in `0 .. n`.
+    OutOfRange(/* index: */ usize, /* choice: */ u64, /* n: */ u64),
+    // There are no recorded choices left.
+    Exhausted(usize)
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Divergence::OutOfRange(index, choice, n) =>
+                write!(f, "choice {} is {}, out of 0 .. {}", index, choice, n),
+            Divergence::Exhausted(index) =>
+                write!(f, "choice {} isn't recorded", index)
+        }
+    }
+}
+
+impl Chooser {
+
+    pub fn from_seed(seed: u64) -> Chooser {
+        Chooser { rng: Some (Rng::new(seed)), replay: VecDeque::new(), recorded: vec![] }
+    }
+
+    pub fn from_choices(choices: Vec<u64>) -> Chooser {
+        Chooser { rng: None, replay: choices.into_iter().collect(), recorded: vec![] }
+    }
+
+    // A number in `0 .. n`, `n` being positive.
+    pub fn choose(&mut self, n: u64) -> Result<u64, Divergence> {
+        assert!(n > 0);
+        let index = self.recorded.len();
+        let choice = match self.rng {
+            Some (ref mut rng) => rng.next() % n,
+            None => match self.replay.pop_front() {
+                Some (choice) if choice < n => choice,
+                Some (choice) => return Err (Divergence::OutOfRange(index, choice, n)),
+                None => return Err (Divergence::Exhausted(index))
+            }
+        };
+        self.recorded.push(choice);
+        Ok (choice)
+    }
+
+    pub fn recorded(&self) -> &[u64] {
+        &self.recorded
+    }
+
+}
+
+pub fn save(path: &Path, choices: &[u64]) -> io::Result<()> {
+    let mut file = try!( File::create(path) );
+    for choice in choices.iter()
+        { try!( writeln!(file, "{}", choice) ) }
+    Ok (())
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<u64>> {
+    let mut text = String::new();
+    try!( File::open(path).and_then(|mut f| f.read_to_string(&mut text)) );
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.trim().parse::<u64>()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+        .collect()
+}
+
+#[test]
+fn test_replay() {
+    let mut chooser = Chooser::from_seed(42);
+    let choices: Vec<u64> = (1 .. 100).map(|n| chooser.choose(n).unwrap()).collect();
+    assert_eq!(&choices[..], chooser.recorded());
+    let mut replayed = Chooser::from_choices(choices.clone());
+    assert_eq!(choices, (1 .. 100).map(|n| replayed.choose(n).unwrap()).collect::<Vec<u64>>());
+    let mut again = Chooser::from_seed(42);
+    assert_eq!(choices, (1 .. 100).map(|n| again.choose(n).unwrap()).collect::<Vec<u64>>());
+    // A replay of another run stops where it diverges.
+    let mut diverged = Chooser::from_choices(vec![0, 5]);
+    assert_eq!(Ok (0), diverged.choose(2));
+    assert_eq!(Err (Divergence::OutOfRange(1, 5, 2)), diverged.choose(2));
+    let mut exhausted = Chooser::from_choices(vec![1]);
+    assert_eq!(Ok (1), exhausted.choose(2));
+    assert_eq!(Err (Divergence::Exhausted(1)), exhausted.choose(2));
+}
@@ -0,0 +1,570 @@
+// Process scheduling.
+//
+// Runnable processes wait in a run queue.  Each gets a time slice of
+// `REDUCTIONS` function and BIF calls in turn, after which it's put back at
+// the end of the queue, so no process can keep the others from running.
+//
+// There's a queue per priority level.  `max` and `high` processes always
+// run before the others; `low` ones get a turn after `LOW_SKIPS` `normal`
+// ones, or when no `normal` process is runnable.
+//
+// A process waiting for a message isn't in the queue; sending it a message
+// or the firing of its `after` timer puts it back.
+//
+// There are one or more schedulers, each running in its own thread with its
+// own run queue.  Processes are queued on the scheduler they last ran on
+// (or, when spawned, the one of their parent); a scheduler whose queue is
+// empty steals from the others, so processes migrate to idle schedulers.
+//
+// In deterministic mode (see `replay`) there's a single scheduler and
+// virtual time; the order processes run in and the length of their time
+// slices are chosen by a seeded PRNG instead.  Schedulers stop if a replay
+// diverges.
+
+use etf;
+use interp::{ self, Class, Exception, Outcome };
+use process::Pid;
+use replay::{ Chooser, Divergence };
+use std::cmp::Reverse;
+use std::collections::{ BinaryHeap, VecDeque };
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+use term;
+use super::Emu;
+
+pub const REDUCTIONS: usize = 2000;
+
+pub const LOW_SKIPS: usize = 8;
+
+// How long an idle scheduler waits before looking for work again.
+const IDLE_WAIT: Duration = Duration::from_millis(1);
+
+// Deterministic mode: one in `TIMER_ODDS` time slices is preceded by the
+// firing of a timer, if one is pending.
+const TIMER_ODDS: u64 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+    Max
+}
+
+impl Priority {
+
+    pub fn from_name(name: &str) -> Option<Priority> {
+        match name {
+            "low" => Some (Priority::Low),
+            "normal" => Some (Priority::Normal),
+            "high" => Some (Priority::High),
+            "max" => Some (Priority::Max),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::Max => "max"
+        }
+    }
+
+}
+
+pub type TimerId = u64;
+
+// The run queue of a single scheduler.
+pub struct RunQueue {
+    // Indexed by priority.
+    queues:     [VecDeque<Pid>; 4],
+    // `normal` processes run since a `low` one last did.
+    low_skips:  usize
+}
+
+impl RunQueue {
+
+    pub fn new() -> RunQueue {
+        RunQueue { queues: [VecDeque::new(), VecDeque::new(),
+                            VecDeque::new(), VecDeque::new()],
+                   low_skips: 0 }
+    }
+
+    pub fn enqueue(&mut self, pid: Pid, priority: Priority) {
+        self.queues[priority as usize].push_back(pid);
+    }
+
+    pub fn next(&mut self) -> Option<Pid> {
+        for &priority in [Priority::Max, Priority::High].iter() {
+            if let Some (pid) = self.queues[priority as usize].pop_front()
+                { return Some (pid) }
+        }
+        let (normal, low) = (Priority::Normal as usize, Priority::Low as usize);
+        if self.low_skips >= LOW_SKIPS || self.queues[normal].is_empty() {
+            if let Some (pid) = self.queues[low].pop_front() {
+                self.low_skips = 0;
+                return Some (pid)
+            }
+        }
+        let pid = self.queues[normal].pop_front();
+        if pid.is_some()
+            { self.low_skips += 1 }
+        pid
+    }
+
+    // Give away the process which would run last, of the highest priority.
+    pub fn steal(&mut self) -> Option<Pid> {
+        self.queues.iter_mut().rev().filter_map(|queue| queue.pop_back()).next()
+    }
+
+    // Deterministic mode: the processes `next` would choose from, i.e.
+    // those of the highest priority, `low` counting as `normal`.
+    fn candidates(&self) -> usize {
+        match (self.queues[Priority::Max as usize].len(), self.queues[Priority::High as usize].len()) {
+            (0, 0) => self.queues[Priority::Normal as usize].len() +
+                      self.queues[Priority::Low as usize].len(),
+            (0, high) => high,
+            (max, _) => max
+        }
+    }
+
+    // Take the `n`th of the `candidates`.
+    fn take(&mut self, n: usize) -> Option<Pid> {
+        let normal = self.queues[Priority::Normal as usize].len();
+        let (priority, n) = match (self.queues[Priority::Max as usize].len(),
+                                   self.queues[Priority::High as usize].len()) {
+            (0, 0) if n < normal => (Priority::Normal, n),
+            (0, 0) => (Priority::Low, n - normal),
+            (0, _) => (Priority::High, n),
+            _ => (Priority::Max, n)
+        };
+        self.queues[priority as usize].remove(n)
+    }
+
+}
+
+pub struct Scheduler {
+    run_queues: Vec<Mutex<RunQueue>>,
+    // Receive timeouts by deadline in microseconds, earliest first.
+    timers:     Mutex<BinaryHeap<Reverse<(u64, TimerId, Pid)>>>,
+    next_timer: AtomicU64,
+    // Processes which are queued or running.
+    work:       AtomicUsize,
+    start:      Instant,
+    // Deterministic mode: scheduling choices and virtual time.
+    chooser:    Option<Mutex<Chooser>>,
+    clock:      AtomicU64,
+    diverged:   Mutex<Option<Divergence>>
+}
+
+impl Scheduler {
+
+    pub fn new(schedulers: usize) -> Scheduler {
+        Scheduler { run_queues: (0 .. schedulers.max(1)).map(|_| Mutex::new(RunQueue::new()))
+                                                         .collect(),
+                    timers: Mutex::new(BinaryHeap::new()),
+                    next_timer: AtomicU64::new(0),
+                    work: AtomicUsize::new(0),
+                    start: Instant::now(),
+                    chooser: None,
+                    clock: AtomicU64::new(0),
+                    diverged: Mutex::new(None) }
+    }
+
+    // Number of schedulers.
+    pub fn count(&self) -> usize {
+        self.run_queues.len()
+    }
+
+    // Microseconds since the scheduler started, or virtual time.
+    pub fn now(&self) -> u64 {
+        match self.chooser {
+            Some (_) => self.clock.load(Ordering::SeqCst),
+            None => self.start.elapsed().as_micros() as u64
+        }
+    }
+
+    pub fn start_timer(&self, pid: Pid, ms: u64) -> TimerId {
+        let id = self.next_timer.fetch_add(1, Ordering::SeqCst);
+        let deadline = self.now().saturating_add(ms.saturating_mul(1000));
+        self.timers.lock().unwrap().push(Reverse((deadline, id, pid)));
+        id
+    }
+
+    // The timer isn't needed any more: forget it, so it doesn't keep `run`
+    // waiting or virtual time jumping to its deadline.
+    pub fn cancel_timer(&self, id: TimerId) {
+        self.timers.lock().unwrap().retain(|&Reverse((_, timer, _))| timer != id);
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.timers.lock().unwrap().peek().map(|&Reverse((deadline, _, _))| deadline)
+    }
+
+    // Queue `pid` on `scheduler`.
+    pub fn enqueue(&self, pid: Pid, priority: Priority, scheduler: usize) {
+        self.work.fetch_add(1, Ordering::SeqCst);
+        let n = self.count();
+        self.run_queues[scheduler % n].lock().unwrap().enqueue(pid, priority);
+    }
+
+    // The next process for `scheduler` to run, stolen from another one if
+    // its own queue is empty.
+    pub fn next(&self, scheduler: usize) -> Option<Pid> {
+        let n = self.count();
+        if let Some (pid) = self.run_queues[scheduler % n].lock().unwrap().next()
+            { return Some (pid) }
+        (1 .. n).filter_map(|i| self.run_queues[(scheduler + i) % n].lock().unwrap().steal())
+                .next()
+    }
+
+    // A process taken with `next` is done with its time slice.
+    fn done(&self) {
+        self.work.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // No process can become runnable any more.  Timers only fire with the
+    // lock held, see `Emu::fire_timers`, so a timer can't be turned into
+    // work behind our back.
+    fn finished(&self) -> bool {
+        let timers = self.timers.lock().unwrap();
+        timers.is_empty() && self.work.load(Ordering::SeqCst) == 0
+    }
+
+}
+
+pub enum Slice {
+    // There's no runnable process.
+    Idle,
+    // The process ran out of reductions or is waiting for a message.
+    Ran(Pid),
+    // The process is gone, leaving its result or exit reason behind.
+    Exited(Pid, Result<etf::Term, etf::Term>),
+    // The replayed choices don't match this run.
+    Diverged(Divergence)
+}
+
+impl Emu {
+
+    // Number of scheduler threads used by `run` and `call`.
+    pub fn set_schedulers(&mut self, schedulers: usize) {
+        let old = ::std::mem::replace(&mut self.scheduler, Scheduler::new(schedulers));
+        for queue in old.run_queues.into_iter() {
+            let mut queue = queue.into_inner().unwrap();
+            for &priority in [Priority::Max, Priority::High, Priority::Normal, Priority::Low].iter() {
+                while let Some (pid) = queue.queues[priority as usize].pop_front()
+                    { self.scheduler.enqueue(pid, priority, 0) }
+            }
+        }
+        *self.scheduler.timers.get_mut().unwrap() = old.timers.into_inner().unwrap();
+        self.scheduler.next_timer = old.next_timer;
+        self.scheduler.start = old.start;
+    }
+
+    // Switch to deterministic scheduling, making choices with `chooser`.
+    pub fn set_chooser(&mut self, chooser: Chooser) {
+        self.set_schedulers(1);
+        let now = self.scheduler.now();
+        self.scheduler.clock = AtomicU64::new(now);
+        self.scheduler.chooser = Some (Mutex::new(chooser));
+    }
+
+    // The choices made so far in deterministic mode.
+    pub fn recorded_choices(&self) -> Vec<u64> {
+        match self.scheduler.chooser {
+            Some (ref chooser) => chooser.lock().unwrap().recorded().to_vec(),
+            None => vec![]
+        }
+    }
+
+    // Where the replay diverged, if it did: `run` and `call` stopped there.
+    pub fn replay_divergence(&self) -> Option<Divergence> {
+        self.scheduler.diverged.lock().unwrap().clone()
+    }
+
+    // Run a time slice of the next runnable process on `scheduler`.
+    pub fn run_slice(&self, scheduler: usize) -> Slice {
+        if let Some (ref chooser) = self.scheduler.chooser
+            { return self.run_chosen_slice(chooser) }
+        self.fire_timers();
+        let pid = match self.scheduler.next(scheduler) {
+            Some (pid) => pid,
+            None => return Slice::Idle
+        };
+        self.run_pid(pid, scheduler, REDUCTIONS)
+    }
+
+    // Deterministic mode: a due timer fires when there's nothing else to
+    // do, or before any time slice by chance; virtual time then jumps to
+    // its deadline.
+    fn run_chosen_slice(&self, chooser: &Mutex<Chooser>) -> Slice {
+        match self.choose_slice(chooser) {
+            Ok (Some ((pid, reductions))) => self.run_pid(pid, 0, reductions),
+            Ok (None) => Slice::Idle,
+            Err (divergence) => Slice::Diverged(divergence)
+        }
+    }
+
+    // The process to run, if any, and the length of its time slice.
+    fn choose_slice(&self, chooser: &Mutex<Chooser>) -> Result<Option<(Pid, usize)>, Divergence> {
+        let mut chooser = chooser.lock().unwrap();
+        if let Some (deadline) = self.scheduler.next_deadline() {
+            let candidates = self.scheduler.run_queues[0].lock().unwrap().candidates();
+            if candidates == 0 || try!( chooser.choose(TIMER_ODDS) ) == 0 {
+                self.scheduler.clock.fetch_max(deadline, Ordering::SeqCst);
+                self.fire_timers();
+            }
+        }
+        let pid = {
+            let mut queue = self.scheduler.run_queues[0].lock().unwrap();
+            let candidates = queue.candidates() as u64;
+            if candidates == 0
+                { return Ok (None) }
+            let n = if candidates == 1 { 0 } else { try!( chooser.choose(candidates) ) };
+            queue.take(n as usize)
+        };
+        let reductions = try!( chooser.choose(REDUCTIONS as u64) ) as usize + 1;
+        Ok (pid.map(|pid| (pid, reductions)))
+    }
+
+    fn run_pid(&self, pid: Pid, scheduler: usize, reductions: usize) -> Slice {
+        let slice = match self.processes.take(pid) {
+            Some (mut p) => {
+                p.scheduler = scheduler;
+                p.reductions = reductions;
+                self.run_process(p)
+            },
+            None => Slice::Ran(pid)
+        };
+        self.scheduler.done();
+        slice
+    }
+
+    fn run_process(&self, mut p: ::process::Process) -> Slice {
+        let pid = p.pid;
+        // Killed by an exit signal while it wasn't running.
+        let outcome = match p.exiting {
+            Some (reason) => Outcome::Failed(Exception { class: Class::Exit, reason: reason,
+                                                         stacktrace: term::Term::Nil }),
+            None => interp::run(self, &mut p)
+        };
+        match outcome {
+            Outcome::Waiting | Outcome::Yielded => {
+                p.waiting = match outcome { Outcome::Waiting => true, _ => false };
+                let (priority, scheduler) = (p.priority, p.scheduler);
+                if self.processes.put(p)
+                    { self.scheduler.enqueue(pid, priority, scheduler) }
+                Slice::Ran(pid)
+            },
+            Outcome::Returned => {
+                let result = term::to_etf(&p.heap, &self.atoms, &self.funs, p.x[0]);
+                let normal = term::Term::Atom(self.atoms.add("normal"));
+                self.exit_process(&mut p, normal);
+                Slice::Exited(pid, Ok (result))
+            },
+            Outcome::Failed(exception) => {
+                let reason = exception.exit_reason(&mut p.heap, &self.atoms);
+                self.exit_process(&mut p, reason);
+                let reason = exception.error_reason(&mut p.heap, &self.atoms);
+                Slice::Exited(pid, Err (term::to_etf(&p.heap, &self.atoms, &self.funs, reason)))
+            }
+        }
+    }
+
+    // Wake up processes whose receive timed out.
+    fn fire_timers(&self) {
+        let now = self.scheduler.now();
+        let mut timers = self.scheduler.timers.lock().unwrap();
+        while let Some (&Reverse((deadline, id, pid))) = timers.peek() {
+            if deadline > now
+                { break }
+            timers.pop();
+            if let Some ((priority, scheduler)) = self.processes.timeout(pid, id)
+                { self.scheduler.enqueue(pid, priority, scheduler) }
+        }
+    }
+
+    // Run until no process is runnable or waiting for a timeout.
+    pub fn run(&self) {
+        self.schedule(None);
+    }
+
+    // Call `module:function(args...)` in a new process and run all
+    // processes until it's done.  Returns the call's result or the reason
+    // of the exception the process failed with, without the stack trace.
+    pub fn call(&self, module: &str, function: &str, args: &[etf::Term])
+        -> Result<etf::Term, etf::Term>
+    {
+        let mut heap = ::heap::Heap::new();
+        let mut terms = vec![];
+        for arg in args.iter() {
+            let t = try!( term::from_etf(&mut heap, &self.atoms, arg)
+                              .ok_or(etf::Term::Atom("badarg".to_string())) );
+            terms.push(t);
+        }
+        let main = self.spawn(module, function, &heap, &terms);
+        self.schedule(Some (main))
+            .unwrap_or(Err (etf::Term::Atom("blocked".to_string())))
+    }
+
+    // Run the schedulers until `main` exits, or until there's nothing left
+    // to do.  With a single scheduler, everything runs in this thread.
+    fn schedule(&self, main: Option<Pid>) -> Option<Result<etf::Term, etf::Term>> {
+        let result = Mutex::new(None);
+        let stop = AtomicBool::new(false);
+        let n = self.scheduler.count();
+        if n == 1 {
+            self.scheduler_loop(0, main, &result, &stop);
+        } else {
+            thread::scope(|s| {
+                for i in 0 .. n {
+                    let (result, stop) = (&result, &stop);
+                    s.spawn(move || self.scheduler_loop(i, main, result, stop));
+                }
+            });
+        }
+        result.into_inner().unwrap()
+    }
+
+    fn scheduler_loop(&self, scheduler: usize, main: Option<Pid>,
+                      result: &Mutex<Option<Result<etf::Term, etf::Term>>>,
+                      stop: &AtomicBool) {
+        while !stop.load(Ordering::SeqCst) {
+            match self.run_slice(scheduler) {
+                Slice::Exited(pid, r) if Some (pid) == main => {
+                    *result.lock().unwrap() = Some (r);
+                    stop.store(true, Ordering::SeqCst);
+                },
+                Slice::Idle if self.scheduler.finished() =>
+                    stop.store(true, Ordering::SeqCst),
+                Slice::Diverged(divergence) => {
+                    *self.scheduler.diverged.lock().unwrap() = Some (divergence);
+                    *result.lock().unwrap() = Some (Err (etf::Term::Atom("replay_diverged".to_string())));
+                    stop.store(true, Ordering::SeqCst);
+                },
+                Slice::Idle => {
+                    // Other schedulers may still make work for us; with a
+                    // single one, only a timer can.
+                    let wait = match self.scheduler.next_deadline() {
+                        Some (deadline) =>
+                            Duration::from_micros(deadline.saturating_sub(self.scheduler.now())),
+                        None => IDLE_WAIT
+                    };
+                    thread::sleep(if self.scheduler.count() == 1 { wait }
+                                  else { wait.min(IDLE_WAIT) });
+                },
+                _ => {}
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+fn run_slices(emu: &mut Emu) -> Vec<Pid> {
+    let mut pids = vec![];
+    loop {
+        match emu.run_slice(0) {
+            Slice::Idle | Slice::Diverged(_) => return pids,
+            Slice::Ran(pid) | Slice::Exited(pid, _) => pids.push(pid)
+        }
+    }
+}
+
+// loop(0) -> done; loop(N) -> loop(N - 1).
+#[cfg(test)]
+fn load_loop(emu: &mut Emu) {
+    use asm::{ Arg, Module };
+    let mut m = Module::new("rr");
+    let l = m.function("loop", 1);
+    let next = m.new_label();
+    m.op("is_eq_exact", vec![Arg::F(next), Arg::X(0), Arg::I(0)]);
+    m.op("move", vec![Arg::A("done"), Arg::X(0)]);
+    m.op("return", vec![]);
+    m.place(next);
+    let minus = m.import("erlang", "-", 2);
+    m.op("gc_bif2", vec![Arg::F(0), Arg::U(1), Arg::U(minus), Arg::X(0), Arg::I(1), Arg::X(0)]);
+    m.op("call_only", vec![Arg::U(1), Arg::F(l)]);
+    m.export("loop", 1, l);
+    m.load(emu).unwrap();
+}
+
+#[test]
+fn test_round_robin() {
+    let mut emu = Emu::new();
+    load_loop(&mut emu);
+    let heap = ::heap::Heap::new();
+    // Each iteration costs two reductions, for `-` and the call.
+    let long = emu.spawn("rr", "loop", &heap, &[::term::Term::Small(3 * REDUCTIONS as i64 / 2)]);
+    let short = emu.spawn("rr", "loop", &heap, &[::term::Term::Small(10)]);
+    // The short one is done in one time slice, the long one needs three.
+    assert_eq!(vec![long, short, long, long], run_slices(&mut emu));
+}
+
+#[test]
+fn test_priorities() {
+    let mut scheduler = RunQueue::new();
+    for pid in 0 .. 10
+        { scheduler.enqueue(pid, Priority::Normal) }
+    scheduler.enqueue(10, Priority::Low);
+    scheduler.enqueue(11, Priority::High);
+    scheduler.enqueue(12, Priority::Max);
+    let mut order = vec![];
+    while let Some (pid) = scheduler.next()
+        { order.push(pid) }
+    // The low priority process gets its turn after `LOW_SKIPS` normal ones.
+    assert_eq!(vec![12, 11, 0, 1, 2, 3, 4, 5, 6, 7, 10, 8, 9], order);
+}
+
+#[test]
+fn test_work_stealing() {
+    let scheduler = Scheduler::new(2);
+    for pid in 0 .. 3
+        { scheduler.enqueue(pid, Priority::Normal, 0) }
+    // The idle scheduler takes the process queued last.
+    assert_eq!(Some (2), scheduler.next(1));
+    assert_eq!(Some (0), scheduler.next(0));
+}
+
+#[test]
+fn test_smp() {
+    let mut emu = Emu::new();
+    load_loop(&mut emu);
+    emu.set_schedulers(4);
+    let heap = ::heap::Heap::new();
+    for _ in 0 .. 16
+        { emu.spawn("rr", "loop", &heap, &[::term::Term::Small(10 * REDUCTIONS as i64)]); }
+    emu.run();
+    assert!(emu.processes.pids().is_empty());
+    assert_eq!(Ok (etf::Term::Atom("done".to_string())),
+               emu.call("rr", "loop", &[etf::Term::Integer(100)]));
+}
+
+#[test]
+fn test_deterministic() {
+    use replay::{ Chooser, Divergence };
+    let slices = |chooser: Chooser| {
+        let mut emu = Emu::new();
+        load_loop(&mut emu);
+        emu.set_chooser(chooser);
+        let heap = ::heap::Heap::new();
+        for n in 1 .. 5
+            { emu.spawn("rr", "loop", &heap, &[::term::Term::Small(n * REDUCTIONS as i64)]); }
+        (run_slices(&mut emu), emu.recorded_choices())
+    };
+    let (order, choices) = slices(Chooser::from_seed(7));
+    assert_eq!((order.clone(), choices.clone()), slices(Chooser::from_seed(7)));
+    assert_eq!((order.clone(), choices.clone()), slices(Chooser::from_choices(choices.clone())));
+    assert!(order != slices(Chooser::from_seed(8)).0);
+    // A replay running out of choices stops the run.
+    let mut emu = Emu::new();
+    load_loop(&mut emu);
+    emu.set_chooser(Chooser::from_choices(choices[.. 3].to_vec()));
+    assert_eq!(Err (etf::Term::Atom("replay_diverged".to_string())),
+               emu.call("rr", "loop", &[etf::Term::Integer(10 * REDUCTIONS as i64)]));
+    assert_eq!(Some (Divergence::Exhausted(3)), emu.replay_divergence());
+}
@@ -0,0 +1,626 @@
+// Runtime representation of Erlang terms.
+//
+// A `Term` is a single heap word.  Immediates (small integers, atoms, nil)
+// fit in the word itself.  Everything else lives on a `Heap`: a boxed
+// object is a `Header` word followed by its payload, pointed to by
+// `Term::Boxed`, while a list cell is two words (head and tail) pointed to
+// by `Term::Cons`.
+
+use atoms::{ AtomIndex, AtomTable };
+use binary::{ Binary, Bits, HEAP_BINARY_LIMIT };
+use bignum::{ BigInt, Digit };
+use etf;
+use exports::{ CodeIdx, FunEntry };
+use heap::Heap;
+use map;
+use process::Pid;
+use std::borrow::Cow;
+
+// Index of a heap word.
+pub type Ptr = usize;
+
+// The layout is defined, as native code tests tags and reads small
+// integers (see `jit`): the tag is the first byte, and the payload of a
+// variant starts at offset 8.  `u8` rather than `C, u8` keeps a `Header`
+// in two words, and a `Term` in three.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Term {
+    Nil,
+    // Integers in machine word range; anything bigger is a bignum.
+    Small(i64),
+    Atom(AtomIndex),
+    Pid(Pid),
+    // A reference, see `erlang:make_ref/0`.
+    Ref(usize),
+    Cons(Ptr),
+    Boxed(Ptr),
+    // Only found on the heap as the first word of a boxed object.
+    Header(Header),
+    // Only found on the heap as payload of a boxed object
+    // (bignum digits, float bits).
+    Raw(u64),
+    // Only found on the stack: continuation pointer of a frame.
+    CP(CodeIdx),
+    // Only found in Y registers: the handler of a `catch` or `try`.
+    Catch(CodeIdx),
+    // Only found on a heap being garbage collected: where the object which
+    // was there has been copied to.
+    Moved(Ptr)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Header {
+    Tuple(usize),
+    // Sign (true if negative) and the number of words holding the digits.
+    Big(bool, usize),
+    Float,
+    // Number of free variables; they follow the index into `Emu::funs`.
+    Fun(usize),
+    // Binaries of the given number of bytes, see `binary`.
+    HeapBin(usize),
+    ProcBin(usize),
+    // A bitstring of the given number of bits.
+    SubBin(usize),
+    // A match context with the given number of saved positions.
+    MatchCtx(usize),
+    // Maps, see `map`: a flatmap of the given number of keys, a HAMT of the
+    // given number of keys, and a HAMT node with the given bitmap of slots.
+    FlatMap(usize),
+    HashMap(usize),
+    HamtNode(usize)
+}
+
+impl Header {
+
+    // Number of words following the header.
+    pub fn size(&self) -> usize {
+        match *self {
+            Header::Tuple(arity) => arity,
+            Header::Big(_, words) => words,
+            Header::Float => 1,
+            Header::Fun(num_free) => 1 + num_free,
+            Header::HeapBin(_) => 1,
+            Header::ProcBin(_) => 1,
+            Header::SubBin(_) => 2,
+            Header::MatchCtx(slots) => 2 + slots,
+            Header::FlatMap(keys) => 2 * keys,
+            Header::HashMap(_) => 1,
+            Header::HamtNode(bitmap) => bitmap.count_ones() as usize
+        }
+    }
+
+    // Whether the words following the header are terms, rather than raw
+    // data.
+    pub fn has_terms(&self) -> bool {
+        match *self {
+            Header::Tuple(_) | Header::Fun(_) | Header::SubBin(_) | Header::MatchCtx(_) |
+            Header::FlatMap(_) | Header::HashMap(_) | Header::HamtNode(_) => true,
+            _ => false
+        }
+    }
+
+}
+
+// A number unpacked from the heap, ready for arithmetic.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    Small(i64),
+    Big(BigInt),
+    Float(f64)
+}
+
+impl Number {
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            &Number::Small(i) => i as f64,
+            &Number::Big(ref b) => b.to_f64(),
+            &Number::Float(f) => f
+        }
+    }
+
+    // Only meaningful for integers.
+    pub fn to_big(&self) -> BigInt {
+        match self {
+            &Number::Small(i) => BigInt::from_i64(i),
+            &Number::Big(ref b) => b.clone(),
+            &Number::Float(f) => BigInt::from_f64(f)
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        match self {
+            &Number::Float(_) => true,
+            _ => false
+        }
+    }
+
+}
+
+impl Term {
+
+    pub fn float(heap: &mut Heap, f: f64) -> Term {
+        Term::Boxed(heap.alloc(&[Term::Header(Header::Float), Term::Raw(f.to_bits())]))
+    }
+
+    // A small integer if it fits in a word, a bignum otherwise.
+    pub fn integer(heap: &mut Heap, n: &BigInt) -> Term {
+        if let Some (i) = n.to_i64()
+            { return Term::Small(i) }
+        let digits = n.digits();
+        let mut words = vec![Term::Header(Header::Big(n.is_negative(),
+                                                      (digits.len() + 1) / 2))];
+        for pair in digits.chunks(2) {
+            let high = pair.get(1).cloned().unwrap_or(0) as u64;
+            words.push(Term::Raw(high << 32 | pair[0] as u64));
+        }
+        Term::Boxed(heap.alloc(&words))
+    }
+
+    pub fn number_to_term(heap: &mut Heap, n: &Number) -> Term {
+        match n {
+            &Number::Small(i) => Term::Small(i),
+            &Number::Big(ref b) => Term::integer(heap, b),
+            &Number::Float(f) => Term::float(heap, f)
+        }
+    }
+
+    pub fn tuple(heap: &mut Heap, elements: &[Term]) -> Term {
+        let mut words = Vec::with_capacity(elements.len() + 1);
+        words.push(Term::Header(Header::Tuple(elements.len())));
+        words.extend_from_slice(elements);
+        Term::Boxed(heap.alloc(&words))
+    }
+
+    pub fn fun(heap: &mut Heap, index: usize, free: &[Term]) -> Term {
+        let mut words = Vec::with_capacity(free.len() + 2);
+        words.push(Term::Header(Header::Fun(free.len())));
+        words.push(Term::Raw(index as u64));
+        words.extend_from_slice(free);
+        Term::Boxed(heap.alloc(&words))
+    }
+
+    // A heap binary, or a ProcBin if `bytes` is too large for the heap.
+    pub fn binary(heap: &mut Heap, bytes: &[u8]) -> Term {
+        if bytes.len() > HEAP_BINARY_LIMIT
+            { return Term::Boxed(heap.alloc_proc_bin(Binary::new(bytes.to_vec()))) }
+        Term::Boxed(heap.alloc_heap_bin(bytes))
+    }
+
+    // A bitstring of the first `bits` bits of `bytes`.
+    pub fn bitstring(heap: &mut Heap, bytes: &[u8], bits: usize) -> Term {
+        if bits % 8 == 0
+            { return Term::binary(heap, &bytes[.. bits / 8]) }
+        let whole = Term::binary(heap, &bytes[.. (bits + 7) / 8]);
+        Term::sub_bits(heap, whole, 0, bits).unwrap()
+    }
+
+    // `len` bytes of `binary` from `offset` on, without copying them.
+    pub fn sub_binary(heap: &mut Heap, binary: Term, offset: usize, len: usize)
+        -> Option<Term>
+    {
+        Term::sub_bits(heap, binary, 8 * offset, 8 * len)
+    }
+
+    // `len` bits of `bitstring` from bit `offset` on, without copying them.
+    pub fn sub_bits(heap: &mut Heap, bitstring: Term, offset: usize, len: usize)
+        -> Option<Term>
+    {
+        let ptr = match bitstring {
+            Term::Boxed(ptr) => ptr,
+            _ => return None
+        };
+        let size = try_opt!( bitstring.bit_size(heap) );
+        if offset.checked_add(len).map_or(true, |end| end > size)
+            { return None }
+        // A slice of a slice refers to the original binary.
+        let (offset, original) = match heap.get(ptr) {
+            Term::Header(Header::SubBin(_)) => match heap.get(ptr + 1) {
+                Term::Small(start) => (start as usize + offset, heap.get(ptr + 2)),
+                _ => return None
+            },
+            _ => (offset, bitstring)
+        };
+        let words = [Term::Header(Header::SubBin(len)), Term::Small(offset as i64), original];
+        Some (Term::Boxed(heap.alloc(&words)))
+    }
+
+    pub fn cons(heap: &mut Heap, head: Term, tail: Term) -> Term {
+        Term::Cons(heap.alloc(&[head, tail]))
+    }
+
+    // A proper list of `elements`.
+    pub fn list(heap: &mut Heap, elements: &[Term]) -> Term {
+        elements.iter().rev().fold(Term::Nil, |tail, &head| Term::cons(heap, head, tail))
+    }
+
+    // A string, i.e. a list of character codes.
+    pub fn string(heap: &mut Heap, s: &str) -> Term {
+        let chars: Vec<Term> = s.chars().map(|c| Term::Small(c as i64)).collect();
+        Term::list(heap, &chars)
+    }
+
+    pub fn header(&self, heap: &Heap) -> Option<Header> {
+        match *self {
+            Term::Boxed(ptr) => match heap.get(ptr) {
+                Term::Header(header) => Some (header),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    pub fn number(&self, heap: &Heap) -> Option<Number> {
+        match *self {
+            Term::Small(i) => Some (Number::Small(i)),
+            Term::Boxed(ptr) => match heap.get(ptr) {
+                Term::Header(Header::Float) =>
+                    self.float_value(heap).map(Number::Float),
+                Term::Header(Header::Big(negative, words)) => {
+                    let mut digits: Vec<Digit> = Vec::with_capacity(2 * words);
+                    for word in heap.slice(ptr + 1, words) {
+                        if let &Term::Raw(w) = word {
+                            digits.push(w as Digit);
+                            digits.push((w >> 32) as Digit);
+                        }
+                    }
+                    Some (Number::Big(BigInt::from_parts(negative, digits)))
+                },
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    pub fn float_value(&self, heap: &Heap) -> Option<f64> {
+        match (self.header(heap), *self) {
+            (Some (Header::Float), Term::Boxed(ptr)) => match heap.get(ptr + 1) {
+                Term::Raw(bits) => Some (f64::from_bits(bits)),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    pub fn tuple_elements<'h>(&self, heap: &'h Heap) -> Option<&'h [Term]> {
+        match (self.header(heap), *self) {
+            (Some (Header::Tuple(arity)), Term::Boxed(ptr)) =>
+                Some (heap.slice(ptr + 1, arity)),
+            _ => None
+        }
+    }
+
+    // Index into `Emu::funs` and free variables.
+    pub fn fun_parts<'h>(&self, heap: &'h Heap) -> Option<(usize, &'h [Term])> {
+        match (self.header(heap), *self) {
+            (Some (Header::Fun(num_free)), Term::Boxed(ptr)) => match heap.get(ptr + 1) {
+                Term::Raw(index) => Some ((index as usize, heap.slice(ptr + 2, num_free))),
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    // The bits of a bitstring, borrowed from the binary holding them.
+    pub fn bits<'h>(&self, heap: &'h Heap) -> Option<Bits<'h>> {
+        let ptr = match *self {
+            Term::Boxed(ptr) => ptr,
+            _ => return None
+        };
+        match try_opt!( self.header(heap) ) {
+            Header::HeapBin(size) => {
+                let bytes = try_opt!( heap.heap_bin_bytes(ptr) );
+                Some (Bits { bytes: Cow::Borrowed(bytes), offset: 0, size: 8 * size })
+            },
+            Header::ProcBin(size) => match heap.get(ptr + 1) {
+                Term::Raw(index) => {
+                    let bytes = heap.off_heap_binary(index as usize).bytes();
+                    Some (Bits { bytes: Cow::Borrowed(bytes), offset: 0, size: 8 * size })
+                },
+                _ => None
+            },
+            Header::SubBin(size) => match (heap.get(ptr + 1), heap.get(ptr + 2)) {
+                (Term::Small(offset), original) => {
+                    let bits = try_opt!( original.bits(heap) );
+                    Some (Bits { bytes: bits.bytes, offset: bits.offset + offset as usize, size: size })
+                },
+                _ => None
+            },
+            _ => None
+        }
+    }
+
+    // The bytes of a binary, i.e. a bitstring of a whole number of bytes.
+    pub fn binary_bytes<'h>(&self, heap: &'h Heap) -> Option<Cow<'h, [u8]>> {
+        self.bits(heap).and_then(|bits| bits.into_bytes())
+    }
+
+    pub fn bit_size(&self, heap: &Heap) -> Option<usize> {
+        match try_opt!( self.header(heap) ) {
+            Header::HeapBin(bytes) | Header::ProcBin(bytes) => Some (8 * bytes),
+            Header::SubBin(bits) => Some (bits),
+            _ => None
+        }
+    }
+
+    pub fn is_bitstring(&self, heap: &Heap) -> bool {
+        match self.header(heap) {
+            Some (Header::HeapBin(_)) | Some (Header::ProcBin(_)) | Some (Header::SubBin(_)) => true,
+            _ => false
+        }
+    }
+
+    pub fn is_binary(&self, heap: &Heap) -> bool {
+        match self.header(heap) {
+            Some (Header::HeapBin(_)) | Some (Header::ProcBin(_)) => true,
+            Some (Header::SubBin(bits)) => bits % 8 == 0,
+            _ => false
+        }
+    }
+
+    // Elements of a proper list.
+    pub fn list_elements(&self, heap: &Heap) -> Option<Vec<Term>> {
+        let mut elements = vec![];
+        let mut t = *self;
+        while let Term::Cons(ptr) = t {
+            elements.push(heap.get(ptr));
+            t = heap.get(ptr + 1);
+        }
+        if t == Term::Nil { Some (elements) } else { None }
+    }
+
+    pub fn is_number(&self, heap: &Heap) -> bool {
+        match *self {
+            Term::Small(_) => true,
+            Term::Boxed(_) => match self.header(heap) {
+                Some (Header::Float) | Some (Header::Big(_, _)) => true,
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    pub fn is_atom(&self) -> bool {
+        match *self {
+            Term::Atom(_) => true,
+            _ => false
+        }
+    }
+
+    pub fn is_cons(&self) -> bool {
+        match *self {
+            Term::Cons(_) => true,
+            _ => false
+        }
+    }
+
+    pub fn is_integer(&self, heap: &Heap) -> bool {
+        match *self {
+            Term::Small(_) => true,
+            Term::Boxed(_) => match self.header(heap) {
+                Some (Header::Big(_, _)) => true,
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+}
+
+// Build a term from its external format representation.
+// Pids, ports, references and funs aren't supported.
+pub fn from_etf(heap: &mut Heap, atoms: &AtomTable, t: &etf::Term) -> Option<Term> {
+    match t {
+        &etf::Term::Atom(ref name) => Some (Term::Atom(atoms.add(name))),
+        &etf::Term::Integer(i) => Some (Term::Small(i)),
+        &etf::Term::BigInteger(negative, ref digits) =>
+            Some (Term::integer(heap, &BigInt::from_bytes_le(negative, digits))),
+        &etf::Term::Float(f) => Some (Term::float(heap, f)),
+        &etf::Term::Tuple(ref elements) => {
+            let mut terms = vec![];
+            for e in elements.iter()
+                { terms.push(try_opt!( from_etf(heap, atoms, e) )) }
+            Some (Term::tuple(heap, &terms))
+        },
+        &etf::Term::Nil => Some (Term::Nil),
+        &etf::Term::List(ref elements, ref tail) => {
+            let mut list = try_opt!( from_etf(heap, atoms, tail) );
+            for e in elements.iter().rev() {
+                let head = try_opt!( from_etf(heap, atoms, e) );
+                list = Term::cons(heap, head, list);
+            }
+            Some (list)
+        },
+        &etf::Term::Binary(ref bytes) => Some (Term::binary(heap, bytes)),
+        &etf::Term::BitBinary(ref bytes, bits) if bits >= 1 && bits <= 8 && !bytes.is_empty() =>
+            Some (Term::bitstring(heap, bytes, 8 * (bytes.len() - 1) + bits as usize)),
+        &etf::Term::Map(ref pairs) => {
+            let mut terms = vec![];
+            for &(ref k, ref v) in pairs.iter() {
+                let key = try_opt!( from_etf(heap, atoms, k) );
+                terms.push((key, try_opt!( from_etf(heap, atoms, v) )));
+            }
+            Some (map::from_pairs(heap, atoms, &terms))
+        },
+        _ => None
+    }
+}
+
+// The inverse of `from_etf`.  Funs only keep their module, name and arity.
+pub fn to_etf(heap: &Heap, atoms: &AtomTable, funs: &[FunEntry], t: Term) -> etf::Term {
+    let atom = |index| etf::Term::Atom(atoms.get_atom(index).unwrap_or_default());
+    match t {
+        Term::Nil => etf::Term::Nil,
+        Term::Small(i) => etf::Term::Integer(i),
+        Term::Atom(index) => atom(index),
+        Term::Pid(pid) => {
+            let node = etf::Term::Atom("nonode@nohost".to_string());
+            let mut bytes = etf::encode(&node)[1..].to_vec();
+            bytes.extend_from_slice(&(pid as u32).to_be_bytes());
+            bytes.extend_from_slice(&[0; 8]);
+            etf::Term::Opaque(etf::NEW_PID_EXT, bytes)
+        },
+        Term::Ref(n) => {
+            let node = etf::Term::Atom("nonode@nohost".to_string());
+            let mut bytes = 3u16.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&etf::encode(&node)[1..]);
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&(n as u32).to_be_bytes());
+            bytes.extend_from_slice(&[0; 8]);
+            etf::Term::Opaque(etf::NEWER_REFERENCE_EXT, bytes)
+        },
+        Term::Cons(_) => {
+            let mut elements = vec![];
+            let mut tail = t;
+            while let Term::Cons(ptr) = tail {
+                elements.push(to_etf(heap, atoms, funs, heap.get(ptr)));
+                tail = heap.get(ptr + 1);
+            }
+            etf::Term::List(elements, Box::new(to_etf(heap, atoms, funs, tail)))
+        },
+        Term::Boxed(_) => {
+            if let Some (elements) = t.tuple_elements(heap) {
+                return etf::Term::Tuple(elements.iter()
+                                                .map(|&e| to_etf(heap, atoms, funs, e))
+                                                .collect())
+            }
+            if let Some ((index, _)) = t.fun_parts(heap) {
+                let (m, f, a) = funs[index].mfa;
+                let mut bytes = vec![];
+                for part in [atom(m), atom(f), etf::Term::Integer(a as i64)].iter()
+                    { bytes.extend_from_slice(&etf::encode(part)[1..]) }
+                return etf::Term::Opaque(etf::EXPORT_EXT, bytes)
+            }
+            // Keys in term order, so equal maps encode the same.
+            if let Some (pairs) = map::sorted_pairs(heap, atoms, t) {
+                return etf::Term::Map(pairs.into_iter()
+                                           .map(|(k, v)| (to_etf(heap, atoms, funs, k),
+                                                          to_etf(heap, atoms, funs, v)))
+                                           .collect())
+            }
+            if let Some (bitstring) = bitstring_to_etf(heap, t)
+                { return bitstring }
+            match t.number(heap) {
+                Some (Number::Small(i)) => etf::Term::Integer(i),
+                Some (Number::Big(b)) => {
+                    let mut bytes = vec![];
+                    for d in b.digits().iter()
+                        { bytes.extend_from_slice(&d.to_le_bytes()) }
+                    while bytes.last() == Some (&0)
+                        { bytes.pop(); }
+                    etf::Term::BigInteger(b.is_negative(), bytes)
+                },
+                Some (Number::Float(f)) => etf::Term::Float(f),
+                None => etf::Term::Nil
+            }
+        },
+        _ => etf::Term::Nil
+    }
+}
+
+fn bitstring_to_etf(heap: &Heap, t: Term) -> Option<etf::Term> {
+    let bits = try_opt!( t.bits(heap) );
+    let size = bits.size;
+    let bytes = bits.read_bytes(0, size);
+    Some (if size % 8 == 0 { etf::Term::Binary(bytes) }
+          else { etf::Term::BitBinary(bytes, (size % 8) as u8) })
+}
+
+// Erlang syntax, like `io:format("~p", [Term])` but on a single line.
+pub fn format(heap: &Heap, atoms: &AtomTable, t: Term) -> String {
+    match t {
+        Term::Nil => "[]".to_string(),
+        Term::Small(i) => i.to_string(),
+        Term::Atom(index) => {
+            let name = atoms.get_atom(index).unwrap_or("?".to_string());
+            etf::Term::Atom(name).to_string()
+        },
+        Term::Pid(pid) => format!("<0.{}.0>", pid),
+        Term::Ref(n) => format!("#Ref<0.0.0.{}>", n),
+        Term::Cons(_) => format_list(heap, atoms, t),
+        Term::Boxed(_) => match (t.header(heap), t.number(heap)) {
+            (Some (Header::Tuple(_)), _) => {
+                let elements: Vec<String> = t.tuple_elements(heap).unwrap().iter()
+                                             .map(|&e| format(heap, atoms, e))
+                                             .collect();
+                format!("{{{}}}", elements.join(","))
+            },
+            (_, Some (Number::Big(b))) => b.to_string(),
+            (_, Some (Number::Float(f))) => format!("{:?}", f),
+            (Some (Header::Fun(_)), _) => format!("#Fun<{}>", t.fun_parts(heap).unwrap().0),
+            (Some (Header::FlatMap(_)), _) | (Some (Header::HashMap(_)), _) => {
+                let pairs: Vec<String> = map::sorted_pairs(heap, atoms, t).unwrap().iter()
+                                         .map(|&(k, v)| format!("{} => {}",
+                                                                format(heap, atoms, k),
+                                                                format(heap, atoms, v)))
+                                         .collect();
+                format!("#{{{}}}", pairs.join(","))
+            },
+            _ if t.is_bitstring(heap) => bitstring_to_etf(heap, t).unwrap().to_string(),
+            _ => format!("#Boxed<{:?}>", t)
+        },
+        _ => format!("{:?}", t)
+    }
+}
+
+fn format_list(heap: &Heap, atoms: &AtomTable, list: Term) -> String {
+    let mut elements = vec![];
+    let mut chars = String::new();
+    let mut printable = true;
+    let mut t = list;
+    while let Term::Cons(ptr) = t {
+        let head = heap.get(ptr);
+        match head {
+            Term::Small(c) if c >= 32 && c < 127 => chars.push(c as u8 as char),
+            Term::Small(c) if c == 10 => chars.push('\n'),
+            _ => printable = false
+        }
+        elements.push(format(heap, atoms, head));
+        t = heap.get(ptr + 1);
+    }
+    if printable && t == Term::Nil
+        { return format!("{:?}", chars) }
+    let tail = if t == Term::Nil { String::new() }
+               else { format!("|{}", format(heap, atoms, t)) };
+    format!("[{}{}]", elements.join(","), tail)
+}
+
+#[test]
+fn test_integer_normalization() {
+    let mut heap = Heap::new();
+    assert_eq!(Term::Small(-7), Term::integer(&mut heap, &BigInt::from_i64(-7)));
+    assert_eq!(0, heap.len());
+    let big = BigInt::from_i64(i64::max_value()).mul(&BigInt::from_i64(-3));
+    let term = Term::integer(&mut heap, &big);
+    assert_eq!(Some (Header::Big(true, 2)), term.header(&heap));
+    assert_eq!(Some (Number::Big(big)), term.number(&heap));
+    assert!(term.is_integer(&heap));
+}
+
+#[test]
+fn test_from_etf_and_format() {
+    let mut heap = Heap::new();
+    let atoms = AtomTable::new();
+    let t = etf::Term::Tuple(vec![etf::Term::Atom("state".to_string()),
+                                  etf::Term::Integer(1),
+                                  etf::Term::List(vec![etf::Term::Integer(104),
+                                                       etf::Term::Integer(105)],
+                                                  Box::new(etf::Term::Nil)),
+                                  etf::Term::List(vec![etf::Term::Float(1.5)],
+                                                  Box::new(etf::Term::Atom("t".to_string())))]);
+    let term = from_etf(&mut heap, &atoms, &t).unwrap();
+    assert_eq!("{state,1,\"hi\",[1.5|t]}", format(&heap, &atoms, term));
+}
+
+#[test]
+fn test_boxed_terms() {
+    let mut heap = Heap::new();
+    let f = Term::float(&mut heap, 2.5);
+    let t = Term::tuple(&mut heap, &[Term::Atom(1), f]);
+    assert_eq!(Some (2.5), f.float_value(&heap));
+    assert_eq!(Some (&[Term::Atom(1), f][..]), t.tuple_elements(&heap));
+    assert!(!t.is_number(&heap));
+    assert!(f.is_number(&heap) && !f.is_integer(&heap));
+}
@@ -0,0 +1,477 @@
+// Load-time instruction specialization.
+//
+// Like BEAM's `ops.tab`, a list of rules rewrites the generic instructions
+// of a module into specialised internal ones (see
+// `code::INTERNAL_OPERATIONS`) whose operands are known in advance: the
+// interpreter then reads registers and constants directly instead of
+// matching on operand tags.  Rules are tried in order at each instruction;
+// the first which applies replaces the instructions it matched, and the
+// instructions it produces aren't transformed again.
+//
+// Only the most frequent forms are specialised: moves, tuple element
+// access, list construction and type tests of X registers, comparisons
+// with immediates, `+`, `-` and `*` on X registers and integers, and
+// `test_heap` without an allocation list.  `call_ext` and `select_val`
+// need no rule, as the loader already resolves their export table entry
+// and jump table.  Any other instruction, or operand, stays generic and
+// is decoded by tag at runtime.
+//
+// Transformation runs before labels are resolved, so a rule may merge
+// instructions, or drop them: type tests of registers whose type the
+// compiler recorded in the `Type` chunk (OTP 25+) are known to pass.
+// Constants become literals of the module, which `link` relocates like
+// any other.
+//
+// Superinstructions are merged instructions too: sequences frequent
+// enough, according to an opcode profile (see `profile`), to be worth
+// executing with one dispatch.  Which of them are fused is configurable,
+// see `Emu::superinstructions`; they're tried before the other rules.
+
+use atoms::AtomTable;
+use beam::{ self, Type, TypeTable };
+use code::{ ArgTag, BEAMOpcode, Op, TypedOperand };
+use etf;
+
+pub struct Context<'a> {
+    pub atoms:      &'a AtomTable,
+    // The module's imports, as in `loader::State`.
+    pub imports:    &'a [(u32, u32, u32)],
+    // The module's `Type` chunk, if any, and its type-tagged registers.
+    pub types:      Option<&'a TypeTable>,
+    pub typed_operands: &'a [TypedOperand],
+    // Index of the instruction rules look at, set by `transform`.
+    pub at:         usize,
+    // The module's literal table, which constants are added to.
+    pub literals:   &'a mut Vec<etf::Term>,
+    // Names of the superinstructions to fuse.
+    pub superinstructions: &'a [String]
+}
+
+// A rule looks at the instructions from the current one on.  If it
+// applies, it returns how many of them it replaces, and with what.
+pub type Rule = fn(&mut Context, &[Op]) -> Option<(usize, Vec<Op>)>;
+
+pub const RULES: &'static [(&'static str, Rule)] = &[
+    ("move x x => move_x_x", move_x_x),
+    ("move c x => move_c_x", move_c_x),
+    ("is_eq_exact f x c => is_eq_exact_immed", is_eq_exact_immed),
+    ("is_tuple f x; test_arity f x u => is_tuple_of_arity", is_tuple_of_arity),
+    ("call_ext* u erlang:apply/2,3 => apply_list*", apply_list),
+    ("is_* f tr => , if the register's type passes the test", known_type_test),
+    ("get_tuple_element x u x => get_tuple_element_x", get_tuple_element_x),
+    ("put_list x x x => put_list_x", put_list_x),
+    ("gc_bif2 f u erlang:+/2,-/2,*/2 xi xi x => i_plus, i_minus, i_times", arithmetic),
+    ("is_* f x => is_*_x", type_test_x),
+    ("test_heap u u => test_heap_u", test_heap_u)
+];
+
+// Sequences of generic instructions, named by their opcodes.
+pub const SUPERINSTRUCTIONS: &'static [(&'static str, Rule)] = &[
+    ("is_tuple+test_arity+get_tuple_element", is_tuple_arity_get),
+    ("is_nonempty_list+get_list", is_nonempty_get_list),
+    ("move+move", move2_x_x)
+];
+
+// The names of all superinstructions, fused by default.
+pub fn superinstructions() -> Vec<String> {
+    SUPERINSTRUCTIONS.iter().map(|&(name, _)| name.to_string()).collect()
+}
+
+// The first of `names` which isn't a superinstruction, if any.
+pub fn unknown_superinstruction(names: &[String]) -> Option<&String> {
+    names.iter().find(|name| !SUPERINSTRUCTIONS.iter().any(|&(s, _)| s == &name[..]))
+}
+
+// The generic instructions an internal one stands for, none for generic
+// ones: profiles of transformed code name sequences by these, like
+// `SUPERINSTRUCTIONS` does.
+pub fn generic(opcode: BEAMOpcode) -> &'static [BEAMOpcode] {
+    match opcode {
+        BEAMOpcode::move_x_x | BEAMOpcode::move_c_x => &[BEAMOpcode::move_],
+        BEAMOpcode::is_eq_exact_immed => &[BEAMOpcode::is_eq_exact],
+        BEAMOpcode::is_tuple_of_arity => &[BEAMOpcode::is_tuple, BEAMOpcode::test_arity],
+        BEAMOpcode::is_tuple_arity_get => &[BEAMOpcode::is_tuple, BEAMOpcode::test_arity, BEAMOpcode::get_tuple_element],
+        BEAMOpcode::is_nonempty_get_list => &[BEAMOpcode::is_nonempty_list, BEAMOpcode::get_list],
+        BEAMOpcode::move2_x_x => &[BEAMOpcode::move_, BEAMOpcode::move_],
+        BEAMOpcode::apply_list => &[BEAMOpcode::call_ext],
+        BEAMOpcode::apply_list_last => &[BEAMOpcode::call_ext_last],
+        BEAMOpcode::apply_list_only => &[BEAMOpcode::call_ext_only],
+        BEAMOpcode::get_tuple_element_x => &[BEAMOpcode::get_tuple_element],
+        BEAMOpcode::put_list_x => &[BEAMOpcode::put_list],
+        BEAMOpcode::i_plus | BEAMOpcode::i_minus | BEAMOpcode::i_times => &[BEAMOpcode::gc_bif2],
+        BEAMOpcode::is_integer_x => &[BEAMOpcode::is_integer],
+        BEAMOpcode::is_atom_x => &[BEAMOpcode::is_atom],
+        BEAMOpcode::is_nil_x => &[BEAMOpcode::is_nil],
+        BEAMOpcode::is_list_x => &[BEAMOpcode::is_list],
+        BEAMOpcode::is_nonempty_list_x => &[BEAMOpcode::is_nonempty_list],
+        BEAMOpcode::is_tuple_x => &[BEAMOpcode::is_tuple],
+        BEAMOpcode::test_heap_u => &[BEAMOpcode::test_heap],
+        _ => &[]
+    }
+}
+
+pub fn transform(ctx: &mut Context, code: &[Op]) -> Vec<Op> {
+    let superinstructions: Vec<Rule> =
+        SUPERINSTRUCTIONS.iter()
+                         .filter(|&&(name, _)| ctx.superinstructions.iter().any(|s| s == name))
+                         .map(|&(_, rule)| rule)
+                         .collect();
+    let rules: Vec<Rule> = superinstructions.into_iter()
+                                            .chain(RULES.iter().map(|&(_, rule)| rule))
+                                            .collect();
+    let mut out = Vec::with_capacity(code.len());
+    let mut i = 0;
+    'ops: while i < code.len() {
+        ctx.at = i;
+        for &rule in rules.iter() {
+            if let Some ((n, ops)) = rule(ctx, &code[i ..]) {
+                out.extend(ops);
+                i += n;
+                continue 'ops
+            }
+        }
+        out.push(code[i].clone());
+        i += 1;
+    }
+    out
+}
+
+impl<'a> Context<'a> {
+
+    // The recorded type of operand `arg` of the `n`th instruction from the
+    // current one, if it's a type-tagged register.
+    fn operand_type(&self, n: usize, arg: usize) -> Option<Type> {
+        let types = try_opt!( self.types );
+        self.typed_operands.iter()
+                           .find(|t| t.op == self.at + n && t.arg == arg)
+                           .map(|t| types.get(t.type_index as usize))
+    }
+
+}
+
+fn op(code: BEAMOpcode, args: Vec<(ArgTag, u32)>) -> Option<(usize, Vec<Op>)> {
+    Some ((1, vec![Op { code: code, args: args }]))
+}
+
+// An immediate operand - integer, atom or nil - as a literal index.
+fn constant(ctx: &mut Context, operand: (ArgTag, u32)) -> Option<(ArgTag, u32)> {
+    let term = match operand {
+        (ArgTag::i, value) => etf::Term::Integer(value as i32 as i64),
+        (ArgTag::a, 0) => etf::Term::Nil,
+        (ArgTag::a, index) => etf::Term::Atom(try_opt!( ctx.atoms.get_atom(index as usize) )),
+        _ => return None
+    };
+    // One literal per distinct constant.
+    let index = match ctx.literals.iter().position(|t| *t == term) {
+        Some (index) => index,
+        None => {
+            ctx.literals.push(term);
+            ctx.literals.len() - 1
+        }
+    };
+    Some ((ArgTag::q, index as u32))
+}
+
+fn move_x_x(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    match (code[0].code, &code[0].args[..]) {
+        (BEAMOpcode::move_, &[src @ (ArgTag::x, _), dst @ (ArgTag::x, _)]) =>
+            op(BEAMOpcode::move_x_x, vec![src, dst]),
+        _ => None
+    }
+}
+
+fn move_c_x(ctx: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    match (code[0].code, &code[0].args[..]) {
+        (BEAMOpcode::move_, &[src, dst @ (ArgTag::x, _)]) => {
+            let c = try_opt!( constant(ctx, src) );
+            op(BEAMOpcode::move_c_x, vec![c, dst])
+        },
+        _ => None
+    }
+}
+
+fn is_eq_exact_immed(ctx: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    match (code[0].code, &code[0].args[..]) {
+        (BEAMOpcode::is_eq_exact, &[fail, src @ (ArgTag::x, _), c]) => {
+            let c = try_opt!( constant(ctx, c) );
+            op(BEAMOpcode::is_eq_exact_immed, vec![fail, src, c])
+        },
+        _ => None
+    }
+}
+
+fn is_tuple_of_arity(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    if code.len() < 2
+        { return None }
+    match (code[0].code, &code[0].args[..], code[1].code, &code[1].args[..]) {
+        (BEAMOpcode::is_tuple, &[fail, src @ (ArgTag::x, _)],
+         BEAMOpcode::test_arity, &[fail2, src2, arity @ (ArgTag::u, _)])
+            if fail == fail2 && src == src2 =>
+            Some ((2, vec![Op { code: BEAMOpcode::is_tuple_of_arity,
+                                args: vec![fail, src, arity] }])),
+        _ => None
+    }
+}
+
+// erlang:apply/2,3 can't be a BIF since it doesn't return: it calls.
+fn apply_list(ctx: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    let (arity, import) = match &code[0].args[..] {
+        &[arity @ (ArgTag::u, _), (ArgTag::u, import), ..] => (arity, import),
+        _ => return None
+    };
+    let &(m, f, a) = try_opt!( ctx.imports.get(import as usize) );
+    let name = |index: u32| ctx.atoms.get_atom(index as usize);
+    if name(m).as_ref().map(|s| &s[..]) != Some ("erlang") ||
+       name(f).as_ref().map(|s| &s[..]) != Some ("apply") || (a != 2 && a != 3)
+        { return None }
+    match code[0].code {
+        BEAMOpcode::call_ext => op(BEAMOpcode::apply_list, vec![arity]),
+        BEAMOpcode::call_ext_last => op(BEAMOpcode::apply_list_last, vec![arity, code[0].args[2]]),
+        BEAMOpcode::call_ext_only => op(BEAMOpcode::apply_list_only, vec![arity]),
+        _ => None
+    }
+}
+
+fn get_tuple_element_x(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    match (code[0].code, &code[0].args[..]) {
+        (BEAMOpcode::get_tuple_element, &[src @ (ArgTag::x, _), index @ (ArgTag::u, _), dst @ (ArgTag::x, _)]) =>
+            op(BEAMOpcode::get_tuple_element_x, vec![src, index, dst]),
+        _ => None
+    }
+}
+
+fn put_list_x(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    match (code[0].code, &code[0].args[..]) {
+        (BEAMOpcode::put_list, &[head @ (ArgTag::x, _), tail @ (ArgTag::x, _), dst @ (ArgTag::x, _)]) =>
+            op(BEAMOpcode::put_list_x, vec![head, tail, dst]),
+        _ => None
+    }
+}
+
+fn arithmetic(ctx: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    let (fail, live, import, a, b, dst) = match (code[0].code, &code[0].args[..]) {
+        (BEAMOpcode::gc_bif2, &[fail, live, (ArgTag::u, import), a, b, dst @ (ArgTag::x, _)]) =>
+            (fail, live, import, a, b, dst),
+        _ => return None
+    };
+    match (a.0, b.0) {
+        (ArgTag::x, ArgTag::x) | (ArgTag::x, ArgTag::i) | (ArgTag::i, ArgTag::x) => {},
+        _ => return None
+    }
+    let &(m, f, _) = try_opt!( ctx.imports.get(import as usize) );
+    if ctx.atoms.get_atom(m as usize).as_ref().map(|s| &s[..]) != Some ("erlang")
+        { return None }
+    let code = match ctx.atoms.get_atom(f as usize).as_ref().map(|s| &s[..]) {
+        Some ("+") => BEAMOpcode::i_plus,
+        Some ("-") => BEAMOpcode::i_minus,
+        Some ("*") => BEAMOpcode::i_times,
+        _ => return None
+    };
+    op(code, vec![fail, live, a, b, dst])
+}
+
+fn type_test_x(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    let specialised = match code[0].code {
+        BEAMOpcode::is_integer => BEAMOpcode::is_integer_x,
+        BEAMOpcode::is_atom => BEAMOpcode::is_atom_x,
+        BEAMOpcode::is_nil => BEAMOpcode::is_nil_x,
+        BEAMOpcode::is_list => BEAMOpcode::is_list_x,
+        BEAMOpcode::is_nonempty_list => BEAMOpcode::is_nonempty_list_x,
+        BEAMOpcode::is_tuple => BEAMOpcode::is_tuple_x,
+        _ => return None
+    };
+    match &code[0].args[..] {
+        &[fail, src @ (ArgTag::x, _)] => op(specialised, vec![fail, src]),
+        _ => None
+    }
+}
+
+fn test_heap_u(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    match (code[0].code, &code[0].args[..]) {
+        (BEAMOpcode::test_heap, &[need @ (ArgTag::u, _), live @ (ArgTag::u, _)]) =>
+            op(BEAMOpcode::test_heap_u, vec![need, live]),
+        _ => None
+    }
+}
+
+fn known_type_test(ctx: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    let bits = match code[0].code {
+        BEAMOpcode::is_integer => beam::TYPE_INTEGER,
+        BEAMOpcode::is_float => beam::TYPE_FLOAT,
+        BEAMOpcode::is_number => beam::TYPE_INTEGER | beam::TYPE_FLOAT,
+        BEAMOpcode::is_atom => beam::TYPE_ATOM,
+        BEAMOpcode::is_nil => beam::TYPE_NIL,
+        BEAMOpcode::is_list => beam::TYPE_CONS | beam::TYPE_NIL,
+        BEAMOpcode::is_nonempty_list => beam::TYPE_CONS,
+        BEAMOpcode::is_tuple => beam::TYPE_TUPLE,
+        BEAMOpcode::is_bitstr => beam::TYPE_BITSTRING,
+        BEAMOpcode::is_map => beam::TYPE_MAP,
+        BEAMOpcode::is_function => beam::TYPE_FUN,
+        _ => return None
+    };
+    let t = try_opt!( ctx.operand_type(0, 1) );
+    // The empty type means unreachable code, best left alone.
+    if t.bits != 0 && t.is(bits) { Some ((1, vec![])) }
+    else { None }
+}
+
+fn is_tuple_arity_get(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    if code.len() < 3
+        { return None }
+    match (code[0].code, &code[0].args[..], code[1].code, &code[1].args[..],
+           code[2].code, &code[2].args[..]) {
+        (BEAMOpcode::is_tuple, &[fail, src @ (ArgTag::x, _)],
+         BEAMOpcode::test_arity, &[fail2, src2, arity @ (ArgTag::u, _)],
+         BEAMOpcode::get_tuple_element, &[src3, index @ (ArgTag::u, _), dst])
+            if fail == fail2 && src == src2 && src == src3 =>
+            Some ((3, vec![Op { code: BEAMOpcode::is_tuple_arity_get,
+                                args: vec![fail, src, arity, index, dst] }])),
+        _ => None
+    }
+}
+
+fn is_nonempty_get_list(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    if code.len() < 2
+        { return None }
+    match (code[0].code, &code[0].args[..], code[1].code, &code[1].args[..]) {
+        (BEAMOpcode::is_nonempty_list, &[fail, src @ (ArgTag::x, _)],
+         BEAMOpcode::get_list, &[src2, head, tail]) if src == src2 =>
+            Some ((2, vec![Op { code: BEAMOpcode::is_nonempty_get_list,
+                                args: vec![fail, src, head, tail] }])),
+        _ => None
+    }
+}
+
+fn move2_x_x(_: &mut Context, code: &[Op]) -> Option<(usize, Vec<Op>)> {
+    if code.len() < 2
+        { return None }
+    match (code[0].code, &code[0].args[..], code[1].code, &code[1].args[..]) {
+        (BEAMOpcode::move_, &[src1 @ (ArgTag::x, _), dst1 @ (ArgTag::x, _)],
+         BEAMOpcode::move_, &[src2 @ (ArgTag::x, _), dst2 @ (ArgTag::x, _)]) =>
+            Some ((2, vec![Op { code: BEAMOpcode::move2_x_x,
+                                args: vec![src1, dst1, src2, dst2] }])),
+        _ => None
+    }
+}
+
+#[test]
+fn test_transform() {
+    let atoms = AtomTable::new();
+    let ok = atoms.add("ok");
+    let mut literals = vec![etf::Term::Float(1.5)];
+    let code = vec![
+        Op { code: BEAMOpcode::move_, args: vec![(ArgTag::x, 1), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::move_, args: vec![(ArgTag::a, ok as u32), (ArgTag::x, 2)] },
+        Op { code: BEAMOpcode::move_, args: vec![(ArgTag::q, 0), (ArgTag::x, 3)] },
+        Op { code: BEAMOpcode::move_, args: vec![(ArgTag::x, 1), (ArgTag::y, 0)] },
+        Op { code: BEAMOpcode::is_eq_exact, args: vec![(ArgTag::f, 7), (ArgTag::x, 0), (ArgTag::a, ok as u32)] },
+        Op { code: BEAMOpcode::is_tuple, args: vec![(ArgTag::f, 7), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::test_arity, args: vec![(ArgTag::f, 7), (ArgTag::x, 0), (ArgTag::u, 2)] },
+        Op { code: BEAMOpcode::is_tuple, args: vec![(ArgTag::f, 7), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::test_arity, args: vec![(ArgTag::f, 8), (ArgTag::x, 0), (ArgTag::u, 2)] }
+    ];
+    let mut ctx = Context { atoms: &atoms, imports: &[], types: None, typed_operands: &[], at: 0, literals: &mut literals, superinstructions: &[] };
+    let out = transform(&mut ctx, &code);
+    let names: Vec<&str> = out.iter().map(|op| op.name()).collect();
+    assert_eq!(vec!["move_x_x", "move_c_x", "move", "move", "is_eq_exact_immed",
+                    "is_tuple_of_arity", "is_tuple_x", "test_arity"], names);
+    // The atom became a literal, shared by both instructions using it.
+    assert_eq!(vec![etf::Term::Float(1.5), etf::Term::Atom("ok".to_string())], literals);
+    assert_eq!((ArgTag::q, 1), out[1].args[0]);
+    assert_eq!((ArgTag::q, 1), out[4].args[2]);
+    assert_eq!(vec![(ArgTag::f, 7), (ArgTag::x, 0), (ArgTag::u, 2)], out[5].args);
+}
+
+#[test]
+fn test_specialised_operands() {
+    let atoms = AtomTable::new();
+    let (erlang, plus, times, div) = (atoms.add("erlang"), atoms.add("+"), atoms.add("*"), atoms.add("div"));
+    let imports = [(erlang as u32, plus as u32, 2), (erlang as u32, times as u32, 2),
+                   (erlang as u32, div as u32, 2)];
+    let mut literals = vec![];
+    let gc_bif2 = |import, a, b| Op { code: BEAMOpcode::gc_bif2,
+                                     args: vec![(ArgTag::f, 0), (ArgTag::u, 2), (ArgTag::u, import), a, b, (ArgTag::x, 0)] };
+    let code = vec![
+        gc_bif2(0, (ArgTag::x, 0), (ArgTag::i, 1)),
+        gc_bif2(1, (ArgTag::x, 0), (ArgTag::x, 1)),
+        gc_bif2(2, (ArgTag::x, 0), (ArgTag::x, 1)),
+        gc_bif2(0, (ArgTag::y, 0), (ArgTag::i, 1)),
+        Op { code: BEAMOpcode::put_list, args: vec![(ArgTag::x, 0), (ArgTag::x, 1), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::put_list, args: vec![(ArgTag::x, 0), (ArgTag::a, 0), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::get_tuple_element, args: vec![(ArgTag::x, 0), (ArgTag::u, 1), (ArgTag::y, 0)] },
+        Op { code: BEAMOpcode::test_heap, args: vec![(ArgTag::u, 2), (ArgTag::u, 1)] },
+        Op { code: BEAMOpcode::test_heap, args: vec![(ArgTag::z, 2), (ArgTag::u, 0), (ArgTag::u, 1), (ArgTag::u, 1)] },
+        Op { code: BEAMOpcode::is_atom, args: vec![(ArgTag::f, 7), (ArgTag::x, 3)] },
+        Op { code: BEAMOpcode::is_atom, args: vec![(ArgTag::f, 7), (ArgTag::y, 3)] }
+    ];
+    let out = transform(&mut Context { atoms: &atoms, imports: &imports, types: None, typed_operands: &[], at: 0,
+                                       literals: &mut literals, superinstructions: &[] }, &code);
+    let names: Vec<&str> = out.iter().map(|op| op.name()).collect();
+    // Other BIFs, Y registers and allocation lists stay generic.
+    assert_eq!(vec!["i_plus", "i_times", "gc_bif2", "gc_bif2", "put_list_x", "put_list",
+                    "get_tuple_element", "test_heap_u", "test_heap", "is_atom_x", "is_atom"], names);
+    assert_eq!(vec![(ArgTag::f, 0), (ArgTag::u, 2), (ArgTag::x, 0), (ArgTag::i, 1), (ArgTag::x, 0)],
+               out[0].args);
+}
+
+#[test]
+fn test_superinstructions() {
+    let atoms = AtomTable::new();
+    let mut literals = vec![];
+    let code = vec![
+        Op { code: BEAMOpcode::is_tuple, args: vec![(ArgTag::f, 7), (ArgTag::x, 1)] },
+        Op { code: BEAMOpcode::test_arity, args: vec![(ArgTag::f, 7), (ArgTag::x, 1), (ArgTag::u, 2)] },
+        Op { code: BEAMOpcode::get_tuple_element, args: vec![(ArgTag::x, 1), (ArgTag::u, 0), (ArgTag::x, 2)] },
+        Op { code: BEAMOpcode::get_tuple_element, args: vec![(ArgTag::x, 1), (ArgTag::u, 1), (ArgTag::x, 3)] },
+        Op { code: BEAMOpcode::move_, args: vec![(ArgTag::x, 1), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::move_, args: vec![(ArgTag::x, 2), (ArgTag::x, 1)] },
+        Op { code: BEAMOpcode::is_nonempty_list, args: vec![(ArgTag::f, 8), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::get_list, args: vec![(ArgTag::x, 0), (ArgTag::x, 1), (ArgTag::y, 0)] },
+        Op { code: BEAMOpcode::move_, args: vec![(ArgTag::x, 1), (ArgTag::x, 0)] }
+    ];
+    let all = superinstructions();
+    let out = transform(&mut Context { atoms: &atoms, imports: &[], types: None, typed_operands: &[], at: 0, literals: &mut literals,
+                                       superinstructions: &all }, &code);
+    let names: Vec<&str> = out.iter().map(|op| op.name()).collect();
+    assert_eq!(vec!["is_tuple_arity_get", "get_tuple_element_x", "move2_x_x",
+                    "is_nonempty_get_list", "move_x_x"], names);
+    assert_eq!(vec![(ArgTag::f, 7), (ArgTag::x, 1), (ArgTag::u, 2), (ArgTag::u, 0), (ArgTag::x, 2)],
+               out[0].args);
+    assert_eq!(vec![(ArgTag::x, 1), (ArgTag::x, 0), (ArgTag::x, 2), (ArgTag::x, 1)], out[2].args);
+    // Only those configured are fused.
+    let some = vec!["move+move".to_string()];
+    let out = transform(&mut Context { atoms: &atoms, imports: &[], types: None, typed_operands: &[], at: 0, literals: &mut literals,
+                                       superinstructions: &some }, &code);
+    let names: Vec<&str> = out.iter().map(|op| op.name()).collect();
+    assert_eq!(vec!["is_tuple_of_arity", "get_tuple_element_x", "get_tuple_element_x", "move2_x_x",
+                    "is_nonempty_list_x", "get_list", "move_x_x"], names);
+}
+
+#[test]
+fn test_type_hints() {
+    let atoms = AtomTable::new();
+    let mut literals = vec![];
+    let integer = Type { bits: beam::TYPE_INTEGER, min: Some (0), max: Some (10), unit: None };
+    let types = TypeTable { version: 1, types: vec![Type::any(), integer] };
+    let code = vec![
+        Op { code: BEAMOpcode::is_integer, args: vec![(ArgTag::f, 7), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::is_number, args: vec![(ArgTag::f, 7), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::is_tuple, args: vec![(ArgTag::f, 7), (ArgTag::x, 0)] },
+        Op { code: BEAMOpcode::is_integer, args: vec![(ArgTag::f, 7), (ArgTag::x, 1)] },
+        Op { code: BEAMOpcode::is_integer, args: vec![(ArgTag::f, 7), (ArgTag::x, 2)] }
+    ];
+    let typed = |op, type_index| TypedOperand { op: op, arg: 1, type_index: type_index };
+    let typed_operands = vec![typed(0, 1), typed(1, 1), typed(2, 1), typed(3, 0)];
+    let out = transform(&mut Context { atoms: &atoms, imports: &[], types: Some (&types),
+                                       typed_operands: &typed_operands, at: 0,
+                                       literals: &mut literals, superinstructions: &[] }, &code);
+    // Tests of integers known to pass are gone; x1 may be anything, x2
+    // isn't type-tagged.
+    let names: Vec<&str> = out.iter().map(|op| op.name()).collect();
+    assert_eq!(vec!["is_tuple_x", "is_integer_x", "is_integer_x"], names);
+    // Without a `Type` chunk nothing is known.
+    let out = transform(&mut Context { atoms: &atoms, imports: &[], types: None,
+                                       typed_operands: &typed_operands, at: 0,
+                                       literals: &mut literals, superinstructions: &[] }, &code);
+    assert_eq!(code.len(), out.len());
+}
@@ -0,0 +1,111 @@
+// Tuple BIFs.
+//
+// Tuples are immutable: `setelement/3` copies.  Element indices are
+// 1-based, as in Erlang.
+
+use bif::{ self, BifResult };
+use process::Process;
+use term::Term;
+use super::Emu;
+
+// Tuples can't be larger than this.
+pub const MAX_ARITY: usize = (1 << 24) - 1;
+
+// The elements of a tuple, or `badarg`.
+fn elements(p: &Process, tuple: Term) -> Result<Vec<Term>, bif::Error> {
+    tuple.tuple_elements(&p.heap).map(|elements| elements.to_vec()).ok_or(bif::Error::Badarg)
+}
+
+// A 1-based index into a tuple of `arity` elements, made 0-based.
+fn index(t: Term, arity: usize) -> Result<usize, bif::Error> {
+    match t {
+        Term::Small(i) if i >= 1 && i as usize <= arity => Ok (i as usize - 1),
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:element/2
+pub fn element_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let elements = try!( args[1].tuple_elements(&p.heap).ok_or(bif::Error::Badarg) );
+    let i = try!( index(args[0], elements.len()) );
+    Ok (elements[i])
+}
+
+// erlang:setelement/3
+pub fn setelement_3(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let mut elements = try!( elements(p, args[1]) );
+    let i = try!( index(args[0], elements.len()) );
+    elements[i] = args[2];
+    Ok (Term::tuple(&mut p.heap, &elements))
+}
+
+// erlang:tuple_size/1
+pub fn tuple_size_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    match args[0].tuple_elements(&p.heap) {
+        Some (elements) => Ok (Term::Small(elements.len() as i64)),
+        None => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:make_tuple/2
+pub fn make_tuple_2(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    match args[0] {
+        Term::Small(n) if n >= 0 && n as usize <= MAX_ARITY =>
+            Ok (Term::tuple(&mut p.heap, &vec![args[1]; n as usize])),
+        _ => Err (bif::Error::Badarg)
+    }
+}
+
+// erlang:list_to_tuple/1
+pub fn list_to_tuple_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let elements = try!( args[0].list_elements(&p.heap).ok_or(bif::Error::Badarg) );
+    if elements.len() > MAX_ARITY
+        { return Err (bif::Error::Badarg) }
+    Ok (Term::tuple(&mut p.heap, &elements))
+}
+
+// erlang:tuple_to_list/1
+pub fn tuple_to_list_1(_: &Emu, p: &mut Process, args: &[Term]) -> BifResult {
+    let elements = try!( elements(p, args[0]) );
+    Ok (Term::list(&mut p.heap, &elements))
+}
+
+#[test]
+fn test_tuples() {
+    use asm::{ Arg, Module };
+    use etf;
+    let mut m = Module::new("tuples");
+    let bif = |m: &mut Module, name: &'static str, function: &str, arity: u32| {
+        let import = m.import("erlang", function, arity);
+        let f = m.function(name, arity);
+        m.op("call_ext_only", vec![Arg::U(arity), Arg::U(import)]);
+        m.export(name, arity, f);
+    };
+    bif(&mut m, "element", "element", 2);
+    bif(&mut m, "setelement", "setelement", 3);
+    bif(&mut m, "size", "tuple_size", 1);
+    bif(&mut m, "make", "make_tuple", 2);
+    bif(&mut m, "from_list", "list_to_tuple", 1);
+    bif(&mut m, "to_list", "tuple_to_list", 1);
+    let mut emu = Emu::new();
+    m.load(&mut emu).unwrap();
+    let call = |function: &str, args: &[etf::Term]| match emu.call("tuples", function, args) {
+        Ok (t) => t.to_string(),
+        Err (t) => format!("failed: {}", t)
+    };
+    let (int, atom) = (etf::Term::Integer, |name: &str| etf::Term::Atom(name.to_string()));
+    let abc = etf::Term::Tuple(vec![atom("a"), atom("b"), atom("c")]);
+    assert_eq!("b", call("element", &[int(2), abc.clone()]));
+    assert_eq!("failed: badarg", call("element", &[int(4), abc.clone()]));
+    assert_eq!("failed: badarg", call("element", &[int(0), abc.clone()]));
+    assert_eq!("{a,b,7}", call("setelement", &[int(3), abc.clone(), int(7)]));
+    assert_eq!("3", call("size", &[abc.clone()]));
+    assert_eq!("failed: badarg", call("size", &[etf::Term::Nil]));
+    assert_eq!("{x,x}", call("make", &[int(2), atom("x")]));
+    assert_eq!("{}", call("make", &[int(0), atom("x")]));
+    assert_eq!("failed: badarg", call("make", &[int(-1), atom("x")]));
+    assert_eq!("[a,b,c]", call("to_list", &[abc.clone()]));
+    let list = etf::Term::List(vec![int(1), int(2)], Box::new(etf::Term::Nil));
+    assert_eq!("{1,2}", call("from_list", &[list]));
+    assert_eq!("failed: badarg", call("from_list", &[abc]));
+}